@@ -0,0 +1,182 @@
+#!/usr/bin/env python3
+# a local OpenID Connect provider for trying out single sign-on, it signs
+# everyone in as the same user without asking:
+#   python3 dev/oidc-provider/provider.py --port 9200 --email ana@example.org
+# then run the server with
+#   OIDC_ISSUER_URL=http://localhost:9200
+#   OIDC_CLIENT_ID=chat
+#   OIDC_CLIENT_SECRET=secret
+# & open http://localhost:8080/auth/oidc/login in a browser. Codes only work
+# once, with the redirect url & PKCE verifier they were asked for, so a broken
+# login fails here like it would at a real provider. --unverified sends the
+# email as unchecked, which the server should refuse
+import argparse
+import base64
+import hashlib
+import json
+import secrets
+import time
+from http.server import BaseHTTPRequestHandler, HTTPServer
+from urllib.parse import parse_qs, urlencode, urlparse
+
+parser = argparse.ArgumentParser()
+parser.add_argument("--port", type=int, default=9200)
+parser.add_argument("--client-id", default="chat")
+parser.add_argument("--client-secret", default="secret")
+parser.add_argument("--email", default="ana@example.org")
+parser.add_argument("--subject", help="the id the user has here, the email's by default")
+parser.add_argument("--name", default="Ana Example")
+parser.add_argument("--unverified", action="store_true", help="say the email isn't verified")
+args = parser.parse_args()
+ISSUER = f"http://localhost:{args.port}"
+
+
+def b64(data):
+    return base64.urlsafe_b64encode(data).rstrip(b"=").decode()
+
+
+def int_bytes(n):
+    return n.to_bytes((n.bit_length() + 7) // 8, "big")
+
+
+def probable_prime(bits):
+    while True:
+        n = secrets.randbits(bits) | (1 << (bits - 1)) | 1
+        if all(n % p for p in (3, 5, 7, 11, 13, 17, 19, 23, 29, 31, 37)) and miller_rabin(n):
+            return n
+
+
+def miller_rabin(n, rounds=40):
+    d, r = n - 1, 0
+    while d % 2 == 0:
+        d, r = d // 2, r + 1
+    for _ in range(rounds):
+        x = pow(secrets.randbelow(n - 3) + 2, d, n)
+        if x in (1, n - 1):
+            continue
+        for _ in range(r - 1):
+            x = pow(x, 2, n)
+            if x == n - 1:
+                break
+        else:
+            return False
+    return True
+
+
+# a fresh RSA key each run, the server fetches it on every login
+E = 65537
+while True:
+    p, q = probable_prime(1024), probable_prime(1024)
+    if p != q and (p - 1) % E and (q - 1) % E:
+        break
+N, D = p * q, pow(E, -1, (p - 1) * (q - 1))
+KID = b64(hashlib.sha256(int_bytes(N)).digest()[:8])
+# DER prefix of a SHA-256 DigestInfo, for PKCS#1 v1.5 signatures
+SHA256_PREFIX = bytes.fromhex("3031300d060960864801650304020105000420")
+
+
+def sign_jwt(claims):
+    header = {"alg": "RS256", "typ": "JWT", "kid": KID}
+    signing_input = f"{b64(json.dumps(header).encode())}.{b64(json.dumps(claims).encode())}"
+    digest_info = SHA256_PREFIX + hashlib.sha256(signing_input.encode()).digest()
+    size = (N.bit_length() + 7) // 8
+    padded = b"\x00\x01" + b"\xff" * (size - len(digest_info) - 3) + b"\x00" + digest_info
+    signature = pow(int.from_bytes(padded, "big"), D, N).to_bytes(size, "big")
+    return f"{signing_input}.{b64(signature)}"
+
+
+# code -> what the login was started with, each one works once
+codes = {}
+
+
+class Provider(BaseHTTPRequestHandler):
+    def do_GET(self):
+        url = urlparse(self.path)
+        query = {key: values[0] for key, values in parse_qs(url.query).items()}
+        if url.path == "/.well-known/openid-configuration":
+            self.send_json(200, {
+                "issuer": ISSUER,
+                "authorization_endpoint": f"{ISSUER}/authorize",
+                "token_endpoint": f"{ISSUER}/token",
+                "jwks_uri": f"{ISSUER}/jwks",
+                "response_types_supported": ["code"],
+                "subject_types_supported": ["public"],
+                "id_token_signing_alg_values_supported": ["RS256"],
+                "code_challenge_methods_supported": ["S256"],
+                "scopes_supported": ["openid", "email", "profile"],
+                "token_endpoint_auth_methods_supported": ["client_secret_basic", "client_secret_post"],
+            })
+        elif url.path == "/jwks":
+            self.send_json(200, {"keys": [{
+                "kty": "RSA", "use": "sig", "alg": "RS256", "kid": KID,
+                "n": b64(int_bytes(N)), "e": b64(int_bytes(E)),
+            }]})
+        elif url.path == "/authorize":
+            if query.get("client_id") != args.client_id or query.get("response_type") != "code":
+                return self.send_json(400, {"error": "unauthorized_client"})
+            if query.get("code_challenge_method") != "S256" or not query.get("code_challenge"):
+                return self.send_json(400, {"error": "invalid_request", "error_description": "PKCE S256 is required"})
+            code = secrets.token_urlsafe(24)
+            codes[code] = query
+            print(f"signed in {args.email} for state {query.get('state')}", flush=True)
+            params = urlencode({"code": code, "state": query.get("state", "")})
+            self.send_response(302)
+            self.send_header("Location", f"{query['redirect_uri']}?{params}")
+            self.end_headers()
+        else:
+            self.send_json(404, {"error": "not_found"})
+
+    def do_POST(self):
+        if urlparse(self.path).path != "/token":
+            return self.send_json(404, {"error": "not_found"})
+        body = self.rfile.read(int(self.headers.get("Content-Length", 0))).decode()
+        form = {key: values[0] for key, values in parse_qs(body).items()}
+        client_id, client_secret = form.get("client_id"), form.get("client_secret")
+        auth = self.headers.get("Authorization", "")
+        if auth.startswith("Basic "):
+            client_id, _, client_secret = base64.b64decode(auth[6:]).decode().partition(":")
+        if (client_id, client_secret) != (args.client_id, args.client_secret):
+            return self.send_json(401, {"error": "invalid_client"})
+        login = codes.pop(form.get("code"), None)
+        if form.get("grant_type") != "authorization_code" or login is None:
+            return self.send_json(400, {"error": "invalid_grant", "error_description": "unknown or used code"})
+        if form.get("redirect_uri") != login["redirect_uri"]:
+            return self.send_json(400, {"error": "invalid_grant", "error_description": "redirect_uri differs"})
+        verifier = form.get("code_verifier", "")
+        if b64(hashlib.sha256(verifier.encode()).digest()) != login["code_challenge"]:
+            print("refused a code with the wrong PKCE verifier", flush=True)
+            return self.send_json(400, {"error": "invalid_grant", "error_description": "PKCE verifier does not match"})
+        now = int(time.time())
+        claims = {
+            "iss": ISSUER,
+            "aud": args.client_id,
+            "sub": args.subject or args.email,
+            "iat": now,
+            "exp": now + 300,
+            "email": args.email,
+            "email_verified": not args.unverified,
+            "name": args.name,
+            "preferred_username": args.email.split("@")[0],
+        }
+        if "nonce" in login:
+            claims["nonce"] = login["nonce"]
+        self.send_json(200, {
+            "access_token": secrets.token_urlsafe(24),
+            "token_type": "Bearer",
+            "expires_in": 300,
+            "id_token": sign_jwt(claims),
+        })
+
+    def send_json(self, status, body):
+        data = json.dumps(body).encode()
+        self.send_response(status)
+        self.send_header("Content-Type", "application/json")
+        self.send_header("Content-Length", str(len(data)))
+        self.end_headers()
+        self.wfile.write(data)
+
+    def log_message(self, *_):
+        pass
+
+
+HTTPServer(("localhost", args.port), Provider).serve_forever()
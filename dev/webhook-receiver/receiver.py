@@ -0,0 +1,55 @@
+#!/usr/bin/env python3
+# a local endpoint for trying out outgoing webhooks, prints every event it's
+# sent & checks its signature:
+#   python3 dev/webhook-receiver/receiver.py --port 9100 --secret <subscription secret>
+# then run the server with WEBHOOK_ALLOW_INTERNAL_URLS=true, so it can post to
+# localhost, & subscribe http://localhost:9100/ to some events. --fail N answers the
+# first N deliveries with a 500, to watch retries & dead lettering
+import argparse
+import hashlib
+import hmac
+import json
+import time
+from http.server import BaseHTTPRequestHandler, HTTPServer
+
+parser = argparse.ArgumentParser()
+parser.add_argument("--port", type=int, default=9100)
+parser.add_argument("--secret", help="the secret the subscription was made with")
+parser.add_argument("--fail", type=int, default=0, help="deliveries to refuse before accepting")
+args = parser.parse_args()
+
+
+class Receiver(BaseHTTPRequestHandler):
+    failures_left = args.fail
+
+    def do_POST(self):
+        body = self.rfile.read(int(self.headers.get("Content-Length", 0)))
+        timestamp = self.headers.get("X-Webhook-Timestamp", "")
+        signature = self.headers.get("X-Webhook-Signature", "")
+        if args.secret:
+            expected = "sha256=" + hmac.new(
+                args.secret.encode(), f"{timestamp}.".encode() + body, hashlib.sha256
+            ).hexdigest()
+            valid = hmac.compare_digest(expected, signature)
+            fresh = timestamp.isdigit() and abs(time.time() - int(timestamp)) < 300
+            checked = "signature ok" if valid and fresh else "BAD SIGNATURE"
+        else:
+            checked = "signature not checked"
+        print(
+            f"delivery {self.headers.get('X-Webhook-Delivery')} "
+            f"{self.headers.get('X-Webhook-Event')} ({checked})"
+        )
+        print(json.dumps(json.loads(body), indent=2), flush=True)
+
+        status = 200
+        if Receiver.failures_left > 0:
+            Receiver.failures_left -= 1
+            status = 500
+        self.send_response(status)
+        self.end_headers()
+
+    def log_message(self, *_):
+        pass
+
+
+HTTPServer(("localhost", args.port), Receiver).serve_forever()
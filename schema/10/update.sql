@@ -0,0 +1,9 @@
+-- failed login counters, keyed by `user:<username>` or `ip:<address>`.
+-- usernames are used rather than ids so unknown usernames are throttled the same way
+create table login_throttles (
+    key text primary key,
+    failures int not null default 0,
+    last_failure_at timestamptz not null default now(),
+    locked_until timestamptz
+);
+create index login_throttles_last_failure on login_throttles(last_failure_at);
@@ -0,0 +1,27 @@
+-- the totp secret has to be readable to check codes, so it's stored as is.
+-- confirmed_at stays null until the user proves their app has it
+create table user_totp (
+    user_id int primary key references users(id) on delete cascade,
+    secret text not null,
+    confirmed_at timestamptz,
+    -- the last time step a code was accepted for, so codes can't be replayed
+    last_used_step bigint,
+    created_at timestamptz not null default now()
+);
+
+create table user_recovery_codes (
+    id serial primary key,
+    user_id int not null references users(id) on delete cascade,
+    code_hash text not null,
+    used_at timestamptz
+);
+create index user_recovery_codes_user on user_recovery_codes(user_id);
+
+-- platform wide switches admins can flip at runtime, unlike .env config
+create table platform_settings (
+    key text primary key,
+    value jsonb not null,
+    updated_by int,
+    updated_at timestamptz not null default now()
+);
+insert into platform_settings (key, value) values ('require_admin_2fa', 'false');
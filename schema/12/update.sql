@@ -0,0 +1,17 @@
+alter table users add column email_verified_at timestamptz;
+
+create type user_token_purpose as enum ('verify_email', 'reset_password');
+
+-- single use tokens sent out by email, only the sha256 of the token is kept
+create table user_tokens (
+    id serial primary key,
+    user_id int not null references users(id) on delete cascade,
+    purpose user_token_purpose not null,
+    token_hash text not null unique,
+    -- the address a verify_email token was sent to, it only verifies that one
+    email text,
+    created_at timestamptz not null default now(),
+    expires_at timestamptz not null,
+    used_at timestamptz
+);
+create index user_tokens_user on user_tokens(user_id, purpose);
@@ -0,0 +1,21 @@
+-- accounts at outside identity providers, linked to a local user.
+-- provider is the issuer url, subject is the provider's id for the person
+create table user_identities (
+    provider text not null,
+    subject text not null,
+    user_id int not null references users(id) on delete cascade,
+    email text,
+    created_at timestamptz not null default now(),
+    last_login_at timestamptz,
+    primary key (provider, subject)
+);
+create index user_identities_user on user_identities(user_id);
+
+-- logins that have gone off to the provider & not come back yet, keyed by the
+-- state parameter. kept in the database so any instance can take the callback
+create table oidc_login_states (
+    state text primary key,
+    pkce_verifier text not null,
+    nonce text not null,
+    created_at timestamptz not null default now()
+);
@@ -0,0 +1,3 @@
+-- memberships added because of a directory group, so they can be taken away
+-- again when the user leaves it, without touching ones made by hand
+alter table group_permissions add column directory_synced boolean not null default false;
@@ -0,0 +1,20 @@
+-- bots are users that only ever act through api tokens, made & looked after
+-- by the user in bot_owner_id
+alter table users add column is_bot boolean not null default false;
+alter table users add column bot_owner_id int references users(id) on delete set null;
+
+-- long lived tokens for scripts, stored hashed like passwords. scopes are
+-- read / write / admin
+create table api_tokens (
+    id serial primary key,
+    user_id int not null references users(id) on delete cascade,
+    name text not null,
+    token_hash text not null unique,
+    scopes text[] not null,
+    created_by int references users(id) on delete set null,
+    created_at timestamptz not null default now(),
+    expires_at timestamptz,
+    last_used_at timestamptz,
+    revoked_at timestamptz
+);
+create index api_tokens_user on api_tokens(user_id);
@@ -0,0 +1,22 @@
+-- urls outside systems can post into a group through, without logging in.
+-- each posts as its own bot user, so messages go through the normal write path
+create table incoming_webhooks (
+    id serial primary key,
+    group_id int not null references groups(id) on delete cascade,
+    bot_user_id int not null references users(id),
+    name text not null,
+    token_hash text not null unique,
+    -- when set, posts must carry an hmac-sha256 signature made with it
+    secret text,
+    rate_limit_per_minute int not null,
+    -- fixed one minute window for the rate limit
+    window_started_at timestamptz not null default now(),
+    window_count int not null default 0,
+    delivery_count bigint not null default 0,
+    rejected_count bigint not null default 0,
+    last_delivery_at timestamptz,
+    created_by int references users(id) on delete set null,
+    created_at timestamptz not null default now(),
+    disabled_at timestamptz
+);
+create index incoming_webhooks_group on incoming_webhooks(group_id);
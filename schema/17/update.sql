@@ -0,0 +1,35 @@
+-- urls that are sent signed json events as things happen. group_id null means
+-- every group, which only platform admins can set up
+create table webhook_subscriptions (
+    id serial primary key,
+    group_id int references groups(id) on delete cascade,
+    url text not null,
+    -- signs every delivery, see the X-Webhook-Signature header
+    secret text not null,
+    event_types text[] not null,
+    created_by int references users(id) on delete set null,
+    created_at timestamptz not null default now(),
+    disabled_at timestamptz
+);
+create index webhook_subscriptions_group on webhook_subscriptions(group_id);
+
+create type webhook_delivery_status as enum ('pending', 'delivered', 'dead');
+
+-- the outbox, one row per event per subscription, written in the same
+-- transaction as the change the event is about
+create table webhook_deliveries (
+    id bigserial primary key,
+    subscription_id int not null references webhook_subscriptions(id) on delete cascade,
+    event_type text not null,
+    payload jsonb not null,
+    status webhook_delivery_status not null default 'pending',
+    attempts int not null default 0,
+    next_attempt_at timestamptz not null default now(),
+    last_attempt_at timestamptz,
+    last_status_code int,
+    last_error text,
+    delivered_at timestamptz,
+    created_at timestamptz not null default now()
+);
+create index webhook_deliveries_due on webhook_deliveries(next_attempt_at) where status = 'pending';
+create index webhook_deliveries_subscription on webhook_deliveries(subscription_id, id);
@@ -0,0 +1,24 @@
+-- set by /mute, a member has the group muted while muted_at is set & muted_until
+-- (if there is one) hasn't passed
+alter table group_permissions add column muted_at timestamptz;
+alter table group_permissions add column muted_until timestamptz;
+
+-- slash commands a group adds itself, run by posting to an http endpoint
+create table group_commands (
+    id serial primary key,
+    group_id int not null references groups(id) on delete cascade,
+    -- without the leading slash
+    name text not null,
+    description text not null default '',
+    url text not null,
+    -- signs what's sent to the endpoint, like outgoing webhooks
+    secret text not null,
+    -- what members need in the group to run it
+    capability text not null default 'write',
+    -- replies posted into the group come from this bot
+    bot_user_id int not null references users(id),
+    created_by int references users(id) on delete set null,
+    created_at timestamptz not null default now(),
+    disabled_at timestamptz
+);
+create unique index group_commands_name on group_commands(group_id, name) where disabled_at is null;
@@ -0,0 +1,26 @@
+alter type message_type add value 'poll';
+
+-- the voting side of a poll message, the message itself keeps the question
+create table polls (
+    message_id int primary key references messages(id) on delete cascade,
+    group_id int not null references groups(id) on delete cascade,
+    question text not null,
+    options text[] not null,
+    multiple_choice boolean not null default false,
+    -- tallies only, nobody can see who voted for what
+    anonymous boolean not null default false,
+    closes_at timestamptz,
+    closed_at timestamptz,
+    -- the system message with the final result
+    result_message_id int references messages(id) on delete set null
+);
+create index polls_due on polls(closes_at) where closed_at is null;
+
+create table poll_votes (
+    message_id int not null references polls(message_id) on delete cascade,
+    user_id int not null references users(id) on delete cascade,
+    -- index into polls.options
+    option_index int not null,
+    voted_at timestamptz not null default now(),
+    primary key (message_id, user_id, option_index)
+);
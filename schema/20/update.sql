@@ -0,0 +1,20 @@
+create type scheduled_message_status as enum ('pending', 'sent', 'cancelled', 'failed');
+
+-- messages waiting to be posted at send_at, the post goes through the same
+-- checks as any other when it's sent
+create table scheduled_messages (
+    id serial primary key,
+    group_id int not null references groups(id) on delete cascade,
+    user_id int not null references users(id) on delete cascade,
+    content text not null,
+    send_at timestamptz not null,
+    status scheduled_message_status not null default 'pending',
+    created_at timestamptz not null default now(),
+    updated_at timestamptz not null default now(),
+    -- the message it became once sent
+    message_id int references messages(id) on delete set null,
+    -- why it couldn't be sent, e.g. the sender lost write access
+    error text
+);
+create index scheduled_messages_due on scheduled_messages(send_at) where status = 'pending';
+create index scheduled_messages_user on scheduled_messages(user_id, group_id);
@@ -0,0 +1,9 @@
+-- days a group's messages are kept, null falls back to the platform wide
+-- message_retention_days setting, & with neither they're kept forever
+alter table groups add column retention_days int check (retention_days between 1 and 36500);
+-- while set nothing in the group is purged, by retention or by deleting the group
+alter table groups add column legal_hold_at timestamptz;
+alter table groups add column legal_hold_by int references users(id) on delete set null;
+alter table groups add column legal_hold_reason text;
+
+create index messages_group_sent on messages(group_id, sent_at);
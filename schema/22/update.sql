@@ -0,0 +1,18 @@
+-- messages that delete themselves at expires_at, set when they're sent or, for
+-- disappear after read messages, when someone other than the sender first reads them
+alter table messages add column expires_at timestamptz;
+alter table messages add column expire_after_read boolean not null default false;
+create index messages_expiring on messages(expires_at) where expires_at is not null;
+
+-- what new messages in the group get unless they say otherwise
+alter table groups add column disappear_after_mins int check (disappear_after_mins between 1 and 525600);
+alter table groups add column disappear_after_read boolean not null default false;
+
+-- the ids of expired messages, kept for a while so clients know to drop their
+-- copies. nothing of the content is kept
+create table expired_messages (
+    message_id int primary key,
+    group_id int not null references groups(id) on delete cascade,
+    expired_at timestamptz not null default now()
+);
+create index expired_messages_group on expired_messages(group_id, expired_at);
@@ -0,0 +1,28 @@
+-- admins could always do everything, give the new export capability to the
+-- admin roles groups already have
+update group_roles set capabilities = array_append(capabilities, 'export')
+where name = 'admin' and not 'export' = any(capabilities);
+
+create type export_format as enum ('jsonl', 'csv', 'html');
+create type export_status as enum ('pending', 'running', 'done', 'failed');
+
+-- a group's history written out to a file in the background, downloadable
+-- until expires_at
+create table group_exports (
+    id serial primary key,
+    group_id int not null references groups(id) on delete cascade,
+    requested_by int references users(id) on delete set null,
+    format export_format not null,
+    status export_status not null default 'pending',
+    -- under EXPORT_DIR, set once the export is done
+    file_name text,
+    size_bytes bigint,
+    message_count bigint,
+    error text,
+    created_at timestamptz not null default now(),
+    started_at timestamptz,
+    finished_at timestamptz,
+    expires_at timestamptz
+);
+create index group_exports_group on group_exports(group_id, created_at);
+create index group_exports_pending on group_exports(created_at) where status in ('pending', 'running');
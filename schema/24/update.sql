@@ -0,0 +1,9 @@
+-- what a slack import brought in, so running it again skips what's already
+-- there. slack_id is the user or channel id, or `<channel id>:<ts>` for messages
+create table slack_imports (
+    kind text not null check (kind in ('user', 'channel', 'message')),
+    slack_id text not null,
+    local_id int not null,
+    imported_at timestamptz not null default now(),
+    primary key (kind, slack_id)
+);
@@ -0,0 +1,43 @@
+-- when the user asked for their account to be deleted, it's purged once the
+-- cooling off period has passed unless they cancel first
+alter table users add column deletion_requested_at timestamptz;
+create index users_deletion_requested on users(deletion_requested_at)
+where deletion_requested_at is not null;
+
+-- messages of deleted users are handed to this placeholder, it can't log in:
+-- its password is the hash of a random secret nobody kept. it's found by the
+-- flag, which registering can't set, & if someone already has the username
+-- this fails rather than making them the placeholder
+alter table users add column is_placeholder boolean not null default false;
+create unique index users_one_placeholder on users(is_placeholder) where is_placeholder;
+insert into users (username, password, salt, display_name, email, disabled_at, is_placeholder)
+values (
+    'deleted-user',
+    '$argon2id$v=19$m=19456,t=2,p=1$lZnHc7F2E3XfiAX9XOdt9A$68QoKxT/YMIcSI+zxTfka79hzEeFFdamkF0z/7iQZxw',
+    '', 'Deleted user', 'deleted-user@deleted.invalid', now(), true
+);
+
+-- what a deleted user did for others stays, with who did it forgotten
+alter table groups drop constraint groups_created_by_fkey,
+    add constraint groups_created_by_fkey foreign key (created_by) references users(id) on delete set null;
+alter table group_permissions drop constraint group_permissions_created_by_fkey,
+    add constraint group_permissions_created_by_fkey foreign key (created_by) references users(id) on delete set null;
+alter table messages drop constraint messages_pinned_by_fkey,
+    add constraint messages_pinned_by_fkey foreign key (pinned_by) references users(id) on delete set null;
+alter table group_join_requests drop constraint group_join_requests_decided_by_fkey,
+    add constraint group_join_requests_decided_by_fkey foreign key (decided_by) references users(id) on delete set null;
+
+-- & what was only theirs goes with them
+alter table group_permissions drop constraint group_permissions_user_id_fkey,
+    add constraint group_permissions_user_id_fkey foreign key (user_id) references users(id) on delete cascade;
+alter table group_role_members drop constraint group_role_members_user_id_fkey,
+    add constraint group_role_members_user_id_fkey foreign key (user_id) references users(id) on delete cascade;
+alter table group_join_requests drop constraint group_join_requests_user_id_fkey,
+    add constraint group_join_requests_user_id_fkey foreign key (user_id) references users(id) on delete cascade;
+alter table group_ownership_transfers drop constraint group_ownership_transfers_from_user_id_fkey,
+    add constraint group_ownership_transfers_from_user_id_fkey foreign key (from_user_id) references users(id) on delete cascade;
+alter table group_ownership_transfers drop constraint group_ownership_transfers_to_user_id_fkey,
+    add constraint group_ownership_transfers_to_user_id_fkey foreign key (to_user_id) references users(id) on delete cascade;
+
+-- messages.sender_user_id & groups.owner_id still refuse: messages are moved
+-- to the placeholder first, & owners have to hand their groups over
@@ -0,0 +1,9 @@
+-- what users say about themselves, shown on their public profile
+alter table users add column bio text,
+    add column time_zone text,
+    add column pronouns text,
+    add column avatar_url text,
+    -- a custom status, gone once status_expires_at passes
+    add column status_text text,
+    add column status_emoji text,
+    add column status_expires_at timestamptz;
@@ -0,0 +1,26 @@
+create type presence_state as enum ('online', 'away', 'dnd', 'offline');
+
+-- a websocket session, or a client sending heartbeats over http, on any
+-- instance. Gone once last_seen_at falls behind PRESENCE_TIMEOUT_SECS
+create table presence_connections (
+    id text primary key,
+    user_id int not null references users(id) on delete cascade,
+    connected_at timestamptz not null default now(),
+    last_seen_at timestamptz not null default now(),
+    -- the last time the client said its user was doing something
+    last_active_at timestamptz not null default now()
+);
+create index presence_connections_user on presence_connections(user_id);
+
+create table user_presence (
+    user_id int primary key references users(id) on delete cascade,
+    -- what the user picked, shown while they're connected. offline hides them
+    manual presence_state,
+    state presence_state not null default 'offline',
+    last_active_at timestamptz,
+    changed_at timestamptz not null default now(),
+    -- the transaction that made the change. instances poll up to the oldest
+    -- one still running, so a change is only passed over once it's committed
+    changed_xid xid8 not null default pg_current_xact_id()
+);
+create index user_presence_changed_xid on user_presence(changed_xid);
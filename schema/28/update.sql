@@ -0,0 +1,5 @@
+-- each run of an export writes its own temporary file & renews heartbeat_at
+-- as it goes. One whose heartbeat stops is taken over by another server, &
+-- the old run's writes are refused once attempt no longer matches
+alter table group_exports add column attempt text,
+    add column heartbeat_at timestamptz;
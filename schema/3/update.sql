@@ -0,0 +1,8 @@
+alter table groups add column topic text;
+alter table groups add column description text;
+alter table groups add column avatar_url text;
+-- archived groups are read-only, history stays readable
+alter table groups add column archived_at timestamptz;
+
+create type MESSAGE_TYPE as enum('text', 'system');
+alter table messages add column message_type MESSAGE_TYPE not null default 'text';
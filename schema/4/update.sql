@@ -0,0 +1,3 @@
+alter type message_type add value 'blocks';
+-- structured content (bot blocks) lives here, content keeps a plain text fallback
+alter table messages add column payload jsonb;
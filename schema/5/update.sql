@@ -0,0 +1,22 @@
+alter table groups add column owner_id integer references users(id);
+update groups set owner_id = created_by;
+-- soft deleted groups can be restored until the grace period runs out & they're purged
+alter table groups add column deleted_at timestamptz;
+
+create table group_ownership_transfers (
+    id serial primary key,
+    group_id integer references groups(id) on delete cascade not null,
+    from_user_id integer references users(id) not null,
+    to_user_id integer references users(id) not null,
+    created_at timestamptz default now(),
+    accepted_at timestamptz,
+    cancelled_at timestamptz
+);
+
+-- purging a group takes its permissions & messages with it, subgroups become top level
+alter table group_permissions drop constraint group_permissions_group_id_fkey,
+    add constraint group_permissions_group_id_fkey foreign key (group_id) references groups(id) on delete cascade;
+alter table messages drop constraint messages_group_id_fkey,
+    add constraint messages_group_id_fkey foreign key (group_id) references groups(id) on delete cascade;
+alter table groups drop constraint groups_parent_group_id_fkey,
+    add constraint groups_parent_group_id_fkey foreign key (parent_group_id) references groups(id) on delete set null;
@@ -0,0 +1,21 @@
+-- private groups can only be joined through a moderator approved join request.
+-- groups made before this stay closed to anyone asking, new ones are open
+-- unless made private
+alter table groups add column private boolean not null default true;
+alter table groups alter column private set default false;
+
+create type JOIN_REQUEST_STATUS as enum('pending', 'approved', 'denied', 'cancelled');
+
+create table group_join_requests (
+    id serial primary key,
+    group_id integer references groups(id) on delete cascade not null,
+    user_id integer references users(id) not null,
+    note text,
+    status JOIN_REQUEST_STATUS not null default 'pending',
+    created_at timestamptz default now(),
+    decided_by integer references users(id),
+    decided_at timestamptz
+);
+
+-- at most one open request per user per group
+create unique index group_join_requests_pending on group_join_requests(group_id, user_id) where status = 'pending';
@@ -0,0 +1,56 @@
+-- roles hold a set of named capabilities, see Capability in src/groups/roles.rs
+create table group_roles (
+    id serial primary key,
+    group_id integer references groups(id) on delete cascade not null,
+    name text not null,
+    capabilities text[] not null default '{}',
+    created_at timestamptz default now(),
+    unique (group_id, name)
+);
+
+create table group_role_members (
+    role_id integer references group_roles(id) on delete cascade not null,
+    user_id integer references users(id) not null,
+    created_at timestamptz default now(),
+    primary key (role_id, user_id)
+);
+
+-- whether a user may do something in a group, the one place that decides.
+-- the owner can do everything, everyone else needs a role with the capability.
+-- deleted groups allow nothing. src/groups/roles.rs has_capability asks this,
+-- & queries listing what a user can see filter with it
+create function has_capability(user_id int, group_id int, capability text) returns boolean as $$
+    select exists(
+        select 1 from groups g where g.id = $2 and g.deleted_at is null and (
+            g.owner_id = $1 or exists(
+                select 1 from group_role_members m join group_roles r on r.id = m.role_id
+                where r.group_id = g.id and m.user_id = $1 and $3 = any(r.capabilities)
+            )
+        )
+    )
+$$ language sql stable;
+
+-- every group gets the default roles, matching the old permission flags
+insert into group_roles (group_id, name, capabilities)
+select g.id, r.name, r.capabilities from groups g cross join (values
+    ('reader', '{read}'::text[]),
+    ('writer', '{read,write}'::text[]),
+    ('moderator', '{read,write,delete_others,pin,invite,moderate,manage_group}'::text[]),
+    ('admin', '{read,write,delete_others,pin,invite,moderate,manage_group,manage_roles}'::text[])
+) as r(name, capabilities);
+
+insert into group_role_members (role_id, user_id)
+select distinct r.id, gp.user_id from group_permissions gp
+join group_roles r on r.group_id = gp.group_id
+where (r.name = 'reader' and gp.read)
+    or (r.name = 'writer' and gp.write)
+    or (r.name = 'moderator' and gp.moderate)
+    or (r.name = 'admin' and gp.admin);
+
+-- group_permissions is now just the membership list
+alter table group_permissions drop column "read", drop column write, drop column moderate, drop column "admin";
+delete from group_permissions a using group_permissions b where a.id > b.id and a.group_id = b.group_id and a.user_id = b.user_id;
+alter table group_permissions add constraint group_permissions_member unique (group_id, user_id);
+
+alter table messages add column pinned_at timestamptz;
+alter table messages add column pinned_by integer references users(id);
@@ -0,0 +1,14 @@
+-- disabled accounts can't log in, set & cleared by platform admins
+alter table users add column disabled_at timestamptz;
+
+create table audit_log (
+    id bigserial primary key,
+    -- not a foreign key, entries have to outlive the users they mention
+    actor_id integer,
+    action text not null,
+    target text,
+    details jsonb,
+    created_at timestamptz not null default now()
+);
+create index audit_log_actor on audit_log(actor_id);
+create index audit_log_action on audit_log(action);
@@ -0,0 +1,15 @@
+alter table audit_log add column ip text;
+-- each entry hashes the one before it, see src/audit/audit.rs for the format.
+-- entries written before this migration have no hash & are reported as unchained
+alter table audit_log add column prev_hash text;
+alter table audit_log add column hash text;
+create index audit_log_created_at on audit_log(created_at);
+
+create function audit_log_append_only() returns trigger as $$
+begin
+    raise exception 'audit_log is append-only';
+end;
+$$ language plpgsql;
+
+create trigger audit_log_append_only before update or delete or truncate on audit_log
+    for each statement execute function audit_log_append_only();
@@ -0,0 +1,794 @@
+use crate::audit::audit::{audit_event, verify_audit_log};
+use crate::auth::{generate_token, TokenStore};
+use crate::custom_types::{GroupType, MessageType, UserRole};
+use crate::extractors::extractors::User;
+use crate::login::login::create_ciphertext;
+use crate::login::throttle;
+use crate::server::app_state::AppState;
+use actix_web::{get, post, put, web, HttpRequest, HttpResponse, Responder};
+use serde::{Deserialize, Serialize};
+use serde_json::json;
+use sqlx::{Pool, Postgres};
+use std::sync::Arc;
+
+const TEMPORARY_PASSWORD_LENGTH: usize = 20;
+
+/// Checks the user holds a platform role that may use the admin api (ADMIN or SUPER),
+/// returning that role or the response to send back
+pub async fn require_platform_admin(
+    user_id: i32,
+    pool: &Pool<Postgres>,
+) -> Result<UserRole, HttpResponse> {
+    match sqlx::query!(
+        r#"select role as "role: UserRole" from users where id = $1 and disabled_at is null"#,
+        user_id
+    )
+    .fetch_optional(pool)
+    .await
+    {
+        Ok(Some(rec)) if rec.role == UserRole::ADMIN || rec.role == UserRole::SUPER => Ok(rec.role),
+        Ok(_) => {
+            Err(HttpResponse::Forbidden().body(format!("user {} is not a platform admin", user_id)))
+        }
+        Err(e) => Err(HttpResponse::InternalServerError().body(e.to_string())),
+    }
+}
+
+/// Records an admin action against the acting admin
+async fn audit(
+    pool: &Pool<Postgres>,
+    req: &HttpRequest,
+    actor_id: i32,
+    action: &str,
+    target: String,
+    details: Option<serde_json::Value>,
+) {
+    audit_event(pool, req, Some(actor_id), action, Some(target), details).await
+}
+
+#[derive(Deserialize)]
+struct UserSearch {
+    q: Option<String>,
+    limit: Option<i64>,
+    offset: Option<i64>,
+}
+
+#[derive(Serialize)]
+struct AdminUser {
+    id: i32,
+    username: String,
+    display_name: String,
+    email: String,
+    role: UserRole,
+    created_at: Option<chrono::DateTime<chrono::Utc>>,
+    disabled_at: Option<chrono::DateTime<chrono::Utc>>,
+}
+
+#[get("/users")]
+async fn handle_list_users(
+    search: web::Query<UserSearch>,
+    user: User,
+    app: web::Data<AppState>,
+    req: HttpRequest,
+) -> impl Responder {
+    if let Err(resp) = require_platform_admin(user.user_id, &app.pool).await {
+        return resp;
+    }
+    let rows = match sqlx::query_as!(
+        AdminUser,
+        r#"select id, username, display_name, email, role as "role: UserRole", created_at, disabled_at
+        from users
+        where $1::text is null or username ilike '%' || $1 || '%' or email ilike '%' || $1 || '%'
+            or display_name ilike '%' || $1 || '%'
+        order by id limit $2 offset $3"#,
+        search.q,
+        search.limit.unwrap_or(50).clamp(1, 500),
+        search.offset.unwrap_or(0).max(0)
+    )
+    .fetch_all(&app.pool)
+    .await
+    {
+        Ok(rows) => rows,
+        Err(e) => return HttpResponse::InternalServerError().body(e.to_string()),
+    };
+    audit(
+        &app.pool,
+        &req,
+        user.user_id,
+        "admin.users.search",
+        "users".to_owned(),
+        Some(json!({ "q": search.q })),
+    )
+    .await;
+    HttpResponse::Ok().json(rows)
+}
+
+#[get("/users/{user_id}")]
+async fn handle_get_user(
+    user_id: web::Path<i32>,
+    user: User,
+    app: web::Data<AppState>,
+    req: HttpRequest,
+) -> impl Responder {
+    if let Err(resp) = require_platform_admin(user.user_id, &app.pool).await {
+        return resp;
+    }
+    let user_id = user_id.into_inner();
+    match sqlx::query_as!(
+        AdminUser,
+        r#"select id, username, display_name, email, role as "role: UserRole", created_at, disabled_at
+        from users where id = $1"#,
+        user_id
+    )
+    .fetch_optional(&app.pool)
+    .await
+    {
+        Ok(Some(row)) => {
+            audit(&app.pool, &req, user.user_id, "admin.users.view", format!("user:{user_id}"), None).await;
+            HttpResponse::Ok().json(row)
+        }
+        Ok(None) => HttpResponse::NotFound().body(format!("user {} not found", user_id)),
+        Err(e) => HttpResponse::InternalServerError().body(e.to_string()),
+    }
+}
+
+#[post("/users/{user_id}/disable")]
+async fn handle_disable_user(
+    user_id: web::Path<i32>,
+    user: User,
+    app: web::Data<AppState>,
+    req: HttpRequest,
+    tokenstore: web::Data<Arc<TokenStore>>,
+) -> impl Responder {
+    let actor_role = match require_platform_admin(user.user_id, &app.pool).await {
+        Ok(role) => role,
+        Err(resp) => return resp,
+    };
+    let user_id = user_id.into_inner();
+    if user_id == user.user_id {
+        return HttpResponse::BadRequest().body("admins can't disable themselves");
+    }
+    if let Err(resp) = require_outranks(actor_role, user_id, &app.pool).await {
+        return resp;
+    }
+    match sqlx::query!(
+        r#"update users set disabled_at = now() where id = $1 and disabled_at is null"#,
+        user_id
+    )
+    .execute(&app.pool)
+    .await
+    {
+        Ok(res) if res.rows_affected() > 0 => {
+            let sessions = tokenstore.invalidate_user(user_id);
+            audit(
+                &app.pool,
+                &req,
+                user.user_id,
+                "admin.users.disable",
+                format!("user:{user_id}"),
+                Some(json!({ "sessions_ended": sessions })),
+            )
+            .await;
+            HttpResponse::Ok().body("")
+        }
+        Ok(_) => HttpResponse::NotFound().body(format!("no enabled user {} found", user_id)),
+        Err(e) => HttpResponse::InternalServerError().body(e.to_string()),
+    }
+}
+
+#[post("/users/{user_id}/enable")]
+async fn handle_enable_user(
+    user_id: web::Path<i32>,
+    user: User,
+    app: web::Data<AppState>,
+    req: HttpRequest,
+) -> impl Responder {
+    let actor_role = match require_platform_admin(user.user_id, &app.pool).await {
+        Ok(role) => role,
+        Err(resp) => return resp,
+    };
+    let user_id = user_id.into_inner();
+    if let Err(resp) = require_outranks(actor_role, user_id, &app.pool).await {
+        return resp;
+    }
+    match sqlx::query!(
+        r#"update users set disabled_at = null where id = $1 and disabled_at is not null"#,
+        user_id
+    )
+    .execute(&app.pool)
+    .await
+    {
+        Ok(res) if res.rows_affected() > 0 => {
+            audit(
+                &app.pool,
+                &req,
+                user.user_id,
+                "admin.users.enable",
+                format!("user:{user_id}"),
+                None,
+            )
+            .await;
+            HttpResponse::Ok().body("")
+        }
+        Ok(_) => HttpResponse::NotFound().body(format!("no disabled user {} found", user_id)),
+        Err(e) => HttpResponse::InternalServerError().body(e.to_string()),
+    }
+}
+
+#[post("/users/{user_id}/logout")]
+async fn handle_force_logout(
+    user_id: web::Path<i32>,
+    user: User,
+    app: web::Data<AppState>,
+    req: HttpRequest,
+    tokenstore: web::Data<Arc<TokenStore>>,
+) -> impl Responder {
+    let actor_role = match require_platform_admin(user.user_id, &app.pool).await {
+        Ok(role) => role,
+        Err(resp) => return resp,
+    };
+    let user_id = user_id.into_inner();
+    if let Err(resp) = require_outranks(actor_role, user_id, &app.pool).await {
+        return resp;
+    }
+    let sessions = tokenstore.invalidate_user(user_id);
+    audit(
+        &app.pool,
+        &req,
+        user.user_id,
+        "admin.users.logout",
+        format!("user:{user_id}"),
+        Some(json!({ "sessions_ended": sessions })),
+    )
+    .await;
+    HttpResponse::Ok().json(format!("ended {sessions} sessions for user {user_id}"))
+}
+
+#[derive(Serialize)]
+struct LoginLock {
+    key: String,
+    failures: i32,
+    last_failure_at: chrono::DateTime<chrono::Utc>,
+    locked_until: Option<chrono::DateTime<chrono::Utc>>,
+}
+
+#[get("/login_locks")]
+async fn handle_list_login_locks(user: User, app: web::Data<AppState>) -> impl Responder {
+    if let Err(resp) = require_platform_admin(user.user_id, &app.pool).await {
+        return resp;
+    }
+    match sqlx::query_as!(
+        LoginLock,
+        r#"select key, failures, last_failure_at, locked_until from login_throttles
+        where locked_until > now() order by locked_until desc"#
+    )
+    .fetch_all(&app.pool)
+    .await
+    {
+        Ok(rows) => HttpResponse::Ok().json(rows),
+        Err(e) => HttpResponse::InternalServerError().body(e.to_string()),
+    }
+}
+
+#[post("/users/{user_id}/unlock")]
+async fn handle_unlock_user(
+    user_id: web::Path<i32>,
+    user: User,
+    app: web::Data<AppState>,
+    req: HttpRequest,
+) -> impl Responder {
+    if let Err(resp) = require_platform_admin(user.user_id, &app.pool).await {
+        return resp;
+    }
+    let user_id = user_id.into_inner();
+    let username = match sqlx::query!(r#"select username from users where id = $1"#, user_id)
+        .fetch_optional(&app.pool)
+        .await
+    {
+        Ok(Some(rec)) => rec.username,
+        Ok(None) => return HttpResponse::NotFound().body(format!("user {} not found", user_id)),
+        Err(e) => return HttpResponse::InternalServerError().body(e.to_string()),
+    };
+    unlock(
+        &app.pool,
+        &req,
+        user.user_id,
+        throttle::account_key(&username),
+        format!("user:{user_id}"),
+    )
+    .await
+}
+
+#[post("/ips/{ip}/unlock")]
+async fn handle_unlock_ip(
+    ip: web::Path<String>,
+    user: User,
+    app: web::Data<AppState>,
+    req: HttpRequest,
+) -> impl Responder {
+    if let Err(resp) = require_platform_admin(user.user_id, &app.pool).await {
+        return resp;
+    }
+    let ip = ip.into_inner();
+    unlock(
+        &app.pool,
+        &req,
+        user.user_id,
+        throttle::ip_key(&ip),
+        format!("ip:{ip}"),
+    )
+    .await
+}
+
+/// Clears the failed login count for `key`, whether or not it's currently locked
+async fn unlock(
+    pool: &Pool<Postgres>,
+    req: &HttpRequest,
+    actor_id: i32,
+    key: String,
+    target: String,
+) -> HttpResponse {
+    match throttle::clear(&key, pool).await {
+        Ok(true) => {
+            audit(pool, req, actor_id, "admin.login.unlock", target, None).await;
+            HttpResponse::Ok().body("")
+        }
+        Ok(false) => {
+            HttpResponse::NotFound().body(format!("no failed logins recorded for {target}"))
+        }
+        Err(e) => HttpResponse::InternalServerError().body(e.to_string()),
+    }
+}
+
+#[derive(Deserialize, Default)]
+struct ResetPasswordRequest {
+    password: Option<String>,
+}
+
+#[derive(Serialize)]
+struct ResetPasswordResponse {
+    // only set when the password was generated, it isn't stored anywhere else
+    temporary_password: Option<String>,
+}
+
+#[post("/users/{user_id}/reset_password")]
+async fn handle_reset_password(
+    user_id: web::Path<i32>,
+    body: Option<web::Json<ResetPasswordRequest>>,
+    user: User,
+    app: web::Data<AppState>,
+    req: HttpRequest,
+    tokenstore: web::Data<Arc<TokenStore>>,
+) -> impl Responder {
+    let actor_role = match require_platform_admin(user.user_id, &app.pool).await {
+        Ok(role) => role,
+        Err(resp) => return resp,
+    };
+    let user_id = user_id.into_inner();
+    if let Err(resp) = require_outranks(actor_role, user_id, &app.pool).await {
+        return resp;
+    }
+    let body = body.map(|b| b.into_inner()).unwrap_or_default();
+    let (password, temporary_password) = match body.password {
+        Some(password) => (password, None),
+        None => {
+            let generated: String = generate_token()
+                .chars()
+                .take(TEMPORARY_PASSWORD_LENGTH)
+                .collect();
+            (generated.clone(), Some(generated))
+        }
+    };
+    let (ciphertext, salt) = match create_ciphertext(&password) {
+        Ok(val) => val,
+        Err(e) => return HttpResponse::InternalServerError().body(e.to_string()),
+    };
+    match sqlx::query!(
+        r#"update users set password = $1, salt = $2 where id = $3"#,
+        ciphertext,
+        salt,
+        user_id
+    )
+    .execute(&app.pool)
+    .await
+    {
+        Ok(res) if res.rows_affected() > 0 => {
+            // whoever had the old password shouldn't stay logged in
+            let sessions = tokenstore.invalidate_user(user_id);
+            audit(
+                &app.pool,
+                &req,
+                user.user_id,
+                "admin.users.reset_password",
+                format!("user:{user_id}"),
+                Some(json!({ "generated": temporary_password.is_some(), "sessions_ended": sessions })),
+            )
+            .await;
+            HttpResponse::Ok().json(ResetPasswordResponse { temporary_password })
+        }
+        Ok(_) => HttpResponse::NotFound().body(format!("user {} not found", user_id)),
+        Err(e) => HttpResponse::InternalServerError().body(e.to_string()),
+    }
+}
+
+#[derive(Deserialize)]
+struct ChangeRoleRequest {
+    role: UserRole,
+}
+
+#[put("/users/{user_id}/role")]
+async fn handle_change_role(
+    user_id: web::Path<i32>,
+    body: web::Json<ChangeRoleRequest>,
+    user: User,
+    app: web::Data<AppState>,
+    req: HttpRequest,
+) -> impl Responder {
+    let actor_role = match require_platform_admin(user.user_id, &app.pool).await {
+        Ok(role) => role,
+        Err(resp) => return resp,
+    };
+    let user_id = user_id.into_inner();
+    let current = match sqlx::query!(
+        r#"select role as "role: UserRole" from users where id = $1"#,
+        user_id
+    )
+    .fetch_optional(&app.pool)
+    .await
+    {
+        Ok(Some(rec)) => rec.role,
+        Ok(None) => return HttpResponse::NotFound().body(format!("user {} not found", user_id)),
+        Err(e) => return HttpResponse::InternalServerError().body(e.to_string()),
+    };
+    if !can_change_role(actor_role, current, body.role) {
+        return HttpResponse::Forbidden().body(format!(
+            "a {:?} user can't change a {:?} user to {:?}",
+            actor_role, current, body.role
+        ));
+    }
+    match sqlx::query!(
+        r#"update users set role = $1 where id = $2"#,
+        body.role as UserRole,
+        user_id
+    )
+    .execute(&app.pool)
+    .await
+    {
+        Ok(_) => {
+            audit(
+                &app.pool,
+                &req,
+                user.user_id,
+                "admin.users.change_role",
+                format!("user:{user_id}"),
+                Some(json!({ "from": current, "to": body.role })),
+            )
+            .await;
+            HttpResponse::Ok().body("")
+        }
+        Err(e) => HttpResponse::InternalServerError().body(e.to_string()),
+    }
+}
+
+fn role_rank(role: UserRole) -> u8 {
+    match role {
+        UserRole::NORMAL => 0,
+        UserRole::ADMIN => 1,
+        UserRole::SUPER => 2,
+    }
+}
+
+/// SUPER users can act on anyone, ADMINs only on users below them
+fn outranks(actor: UserRole, target: UserRole) -> bool {
+    actor == UserRole::SUPER || role_rank(target) < role_rank(actor)
+}
+
+/// SUPER users can change anyone, ADMINs can only change users below them
+/// & can't hand out more than they have themselves
+fn can_change_role(actor: UserRole, from: UserRole, to: UserRole) -> bool {
+    outranks(actor, from) && (actor == UserRole::SUPER || role_rank(to) <= role_rank(actor))
+}
+
+/// Returns an error response unless the acting admin outranks `user_id`
+async fn require_outranks(
+    actor: UserRole,
+    user_id: i32,
+    pool: &Pool<Postgres>,
+) -> Result<(), HttpResponse> {
+    match sqlx::query!(
+        r#"select role as "role: UserRole" from users where id = $1"#,
+        user_id
+    )
+    .fetch_optional(pool)
+    .await
+    {
+        Ok(Some(rec)) if outranks(actor, rec.role) => Ok(()),
+        Ok(Some(rec)) => Err(HttpResponse::Forbidden().body(format!(
+            "a {:?} user can't act on a {:?} user",
+            actor, rec.role
+        ))),
+        Ok(None) => Err(HttpResponse::NotFound().body(format!("user {} not found", user_id))),
+        Err(e) => Err(HttpResponse::InternalServerError().body(e.to_string())),
+    }
+}
+
+#[derive(Serialize)]
+struct AdminGroup {
+    id: i32,
+    r#type: GroupType,
+    group_name: String,
+    parent_group_id: Option<i32>,
+    owner_id: Option<i32>,
+    private: bool,
+    created_at: Option<chrono::DateTime<chrono::Utc>>,
+    archived_at: Option<chrono::DateTime<chrono::Utc>>,
+    deleted_at: Option<chrono::DateTime<chrono::Utc>>,
+    retention_days: Option<i32>,
+    legal_hold_at: Option<chrono::DateTime<chrono::Utc>>,
+    legal_hold_reason: Option<String>,
+    members: Vec<AdminGroupMember>,
+}
+
+#[derive(Serialize)]
+struct AdminGroupMember {
+    user_id: i32,
+    username: String,
+    roles: Vec<String>,
+}
+
+#[get("/groups/{group_id}")]
+async fn handle_get_group(
+    group_id: web::Path<i32>,
+    user: User,
+    app: web::Data<AppState>,
+    req: HttpRequest,
+) -> impl Responder {
+    if let Err(resp) = require_platform_admin(user.user_id, &app.pool).await {
+        return resp;
+    }
+    let group_id = group_id.into_inner();
+    let group = match sqlx::query!(
+        r#"select id, type as "type: GroupType", group_name, parent_group_id, owner_id, private,
+        created_at, archived_at, deleted_at, retention_days, legal_hold_at, legal_hold_reason
+        from groups where id = $1"#,
+        group_id
+    )
+    .fetch_optional(&app.pool)
+    .await
+    {
+        Ok(Some(group)) => group,
+        Ok(None) => return HttpResponse::NotFound().body(format!("group {} not found", group_id)),
+        Err(e) => return HttpResponse::InternalServerError().body(e.to_string()),
+    };
+    let members = match sqlx::query_as!(
+        AdminGroupMember,
+        r#"select gp.user_id, u.username,
+        coalesce(array_agg(r.name) filter (where r.name is not null), '{}') as "roles!"
+        from group_permissions gp join users u on u.id = gp.user_id
+        left join group_role_members m on m.user_id = gp.user_id
+            and m.role_id in (select id from group_roles where group_id = gp.group_id)
+        left join group_roles r on r.id = m.role_id
+        where gp.group_id = $1 group by gp.user_id, u.username order by gp.user_id"#,
+        group_id
+    )
+    .fetch_all(&app.pool)
+    .await
+    {
+        Ok(members) => members,
+        Err(e) => return HttpResponse::InternalServerError().body(e.to_string()),
+    };
+    audit(
+        &app.pool,
+        &req,
+        user.user_id,
+        "admin.groups.view",
+        format!("group:{group_id}"),
+        None,
+    )
+    .await;
+    HttpResponse::Ok().json(AdminGroup {
+        id: group.id,
+        r#type: group.r#type,
+        group_name: group.group_name,
+        parent_group_id: group.parent_group_id,
+        owner_id: group.owner_id,
+        private: group.private,
+        created_at: group.created_at,
+        archived_at: group.archived_at,
+        deleted_at: group.deleted_at,
+        retention_days: group.retention_days,
+        legal_hold_at: group.legal_hold_at,
+        legal_hold_reason: group.legal_hold_reason,
+        members,
+    })
+}
+
+#[derive(Deserialize)]
+struct Page {
+    limit: Option<i64>,
+    offset: Option<i64>,
+}
+
+#[derive(Serialize)]
+struct AdminMessage {
+    id: i32,
+    sender_user_id: i32,
+    content: String,
+    message_type: MessageType,
+    payload: Option<serde_json::Value>,
+    sent_at: Option<chrono::DateTime<chrono::Utc>>,
+}
+
+#[get("/groups/{group_id}/messages")]
+async fn handle_get_group_messages(
+    group_id: web::Path<i32>,
+    page: web::Query<Page>,
+    user: User,
+    app: web::Data<AppState>,
+    req: HttpRequest,
+) -> impl Responder {
+    if let Err(resp) = require_platform_admin(user.user_id, &app.pool).await {
+        return resp;
+    }
+    let group_id = group_id.into_inner();
+    match sqlx::query_as!(
+        AdminMessage,
+        r#"select id, sender_user_id, content, message_type as "message_type: MessageType",
+        payload, sent_at from messages where group_id = $1
+        order by sent_at asc limit $2 offset $3"#,
+        group_id,
+        page.limit.unwrap_or(100).clamp(1, 1000),
+        page.offset.unwrap_or(0).max(0)
+    )
+    .fetch_all(&app.pool)
+    .await
+    {
+        Ok(rows) => {
+            audit(
+                &app.pool,
+                &req,
+                user.user_id,
+                "admin.groups.read_messages",
+                format!("group:{group_id}"),
+                Some(json!({ "offset": page.offset, "limit": page.limit })),
+            )
+            .await;
+            HttpResponse::Ok().json(rows)
+        }
+        Err(e) => HttpResponse::InternalServerError().body(e.to_string()),
+    }
+}
+
+#[derive(Deserialize)]
+struct AuditSearch {
+    actor_id: Option<i32>,
+    action: Option<String>,
+    from: Option<chrono::DateTime<chrono::Utc>>,
+    to: Option<chrono::DateTime<chrono::Utc>>,
+    limit: Option<i64>,
+    offset: Option<i64>,
+}
+
+#[derive(Serialize)]
+struct AuditLogEntry {
+    id: i64,
+    actor_id: Option<i32>,
+    action: String,
+    target: Option<String>,
+    details: Option<serde_json::Value>,
+    ip: Option<String>,
+    created_at: chrono::DateTime<chrono::Utc>,
+    hash: Option<String>,
+}
+
+/// Searches the audit log, `action` matches on prefix so `admin.users` finds
+/// every admin action taken on users
+#[get("/audit")]
+async fn handle_search_audit(
+    search: web::Query<AuditSearch>,
+    user: User,
+    app: web::Data<AppState>,
+    req: HttpRequest,
+) -> impl Responder {
+    if let Err(resp) = require_platform_admin(user.user_id, &app.pool).await {
+        return resp;
+    }
+    let rows = match sqlx::query_as!(
+        AuditLogEntry,
+        r#"select id, actor_id, action, target, details, ip, created_at, hash from audit_log
+        where ($1::integer is null or actor_id = $1)
+            and ($2::text is null or action like $2 || '%')
+            and ($3::timestamptz is null or created_at >= $3)
+            and ($4::timestamptz is null or created_at < $4)
+        order by id desc limit $5 offset $6"#,
+        search.actor_id,
+        search.action,
+        search.from,
+        search.to,
+        search.limit.unwrap_or(100).clamp(1, 1000),
+        search.offset.unwrap_or(0).max(0)
+    )
+    .fetch_all(&app.pool)
+    .await
+    {
+        Ok(rows) => rows,
+        Err(e) => return HttpResponse::InternalServerError().body(e.to_string()),
+    };
+    audit(
+        &app.pool,
+        &req,
+        user.user_id,
+        "admin.audit.search",
+        "audit_log".to_owned(),
+        Some(json!({ "actor_id": search.actor_id, "action": search.action })),
+    )
+    .await;
+    HttpResponse::Ok().json(rows)
+}
+
+#[get("/audit/verify")]
+async fn handle_verify_audit(user: User, app: web::Data<AppState>) -> impl Responder {
+    if let Err(resp) = require_platform_admin(user.user_id, &app.pool).await {
+        return resp;
+    }
+    match verify_audit_log(&app.pool).await {
+        Ok(result) => HttpResponse::Ok().json(result),
+        Err(e) => HttpResponse::InternalServerError().body(e.to_string()),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn super_can_change_anyone() {
+        assert!(can_change_role(
+            UserRole::SUPER,
+            UserRole::SUPER,
+            UserRole::NORMAL
+        ));
+        assert!(can_change_role(
+            UserRole::SUPER,
+            UserRole::NORMAL,
+            UserRole::SUPER
+        ));
+    }
+
+    #[test]
+    fn admin_can_promote_to_admin() {
+        assert!(can_change_role(
+            UserRole::ADMIN,
+            UserRole::NORMAL,
+            UserRole::ADMIN
+        ));
+        assert!(!can_change_role(
+            UserRole::ADMIN,
+            UserRole::NORMAL,
+            UserRole::SUPER
+        ));
+    }
+
+    #[test]
+    fn admins_only_act_below_them() {
+        assert!(outranks(UserRole::ADMIN, UserRole::NORMAL));
+        assert!(!outranks(UserRole::ADMIN, UserRole::ADMIN));
+        assert!(!outranks(UserRole::ADMIN, UserRole::SUPER));
+        assert!(outranks(UserRole::SUPER, UserRole::SUPER));
+        assert!(!outranks(UserRole::NORMAL, UserRole::NORMAL));
+    }
+
+    #[test]
+    fn admin_cant_demote_peers() {
+        assert!(!can_change_role(
+            UserRole::ADMIN,
+            UserRole::ADMIN,
+            UserRole::NORMAL
+        ));
+        assert!(!can_change_role(
+            UserRole::ADMIN,
+            UserRole::SUPER,
+            UserRole::NORMAL
+        ));
+    }
+}
@@ -0,0 +1,50 @@
+pub mod admin;
+pub mod settings;
+pub mod slack_import;
+use actix_web::web;
+use admin::{
+    handle_change_role, handle_disable_user, handle_enable_user, handle_force_logout,
+    handle_get_group, handle_get_group_messages, handle_get_user, handle_list_login_locks,
+    handle_list_users, handle_reset_password, handle_search_audit, handle_unlock_ip,
+    handle_unlock_user, handle_verify_audit,
+};
+use crate::webhooks::incoming::handle_admin_list_webhooks;
+use crate::webhooks::subscriptions::{
+    handle_admin_create_subscription, handle_admin_delete_subscription,
+    handle_admin_list_deliveries, handle_admin_list_subscriptions, handle_admin_retry_delivery,
+};
+use crate::messages::retention::{
+    handle_lift_legal_hold, handle_place_legal_hold, handle_set_retention,
+};
+use settings::{handle_get_settings, handle_put_setting};
+
+pub fn admin_routes(cfg: &mut web::ServiceConfig) {
+    cfg.service(
+        web::scope("/admin")
+            .service(handle_list_users)
+            .service(handle_get_user)
+            .service(handle_disable_user)
+            .service(handle_enable_user)
+            .service(handle_force_logout)
+            .service(handle_reset_password)
+            .service(handle_unlock_user)
+            .service(handle_unlock_ip)
+            .service(handle_list_login_locks)
+            .service(handle_change_role)
+            .service(handle_get_group)
+            .service(handle_get_group_messages)
+            .service(handle_set_retention)
+            .service(handle_place_legal_hold)
+            .service(handle_lift_legal_hold)
+            .service(handle_search_audit)
+            .service(handle_verify_audit)
+            .service(handle_get_settings)
+            .service(handle_put_setting)
+            .service(handle_admin_list_webhooks)
+            .service(handle_admin_list_subscriptions)
+            .service(handle_admin_create_subscription)
+            .service(handle_admin_delete_subscription)
+            .service(handle_admin_list_deliveries)
+            .service(handle_admin_retry_delivery),
+    );
+}
@@ -0,0 +1,175 @@
+use super::admin::require_platform_admin;
+use crate::audit::audit::audit_event;
+use crate::auth::TokenStore;
+use crate::extractors::extractors::User;
+use crate::login::two_factor::has_two_factor;
+use crate::server::app_state::AppState;
+use actix_web::{get, put, web, HttpRequest, HttpResponse, Responder};
+use serde::Serialize;
+use serde_json::{json, Value};
+use sqlx::{Pool, Postgres};
+use std::sync::Arc;
+
+pub const REQUIRE_ADMIN_2FA: &str = "require_admin_2fa";
+pub const MESSAGE_RETENTION_DAYS: &str = "message_retention_days";
+pub const MAX_RETENTION_DAYS: i32 = 36500;
+
+/// Reads a platform setting, `None` if it was never set
+pub async fn get_setting(key: &str, pool: &Pool<Postgres>) -> Result<Option<Value>, sqlx::Error> {
+    Ok(
+        sqlx::query!(r#"select value from platform_settings where key = $1"#, key)
+            .fetch_optional(pool)
+            .await?
+            .map(|rec| rec.value),
+    )
+}
+
+/// Whether ADMIN & SUPER users have to use two factor auth to log in
+pub async fn require_admin_2fa(pool: &Pool<Postgres>) -> Result<bool, sqlx::Error> {
+    Ok(get_setting(REQUIRE_ADMIN_2FA, pool)
+        .await?
+        .and_then(|value| value.as_bool())
+        .unwrap_or(false))
+}
+
+/// Days messages are kept in groups without their own retention, `None` keeps
+/// them forever
+pub async fn message_retention_days(pool: &Pool<Postgres>) -> Result<Option<i32>, sqlx::Error> {
+    Ok(get_setting(MESSAGE_RETENTION_DAYS, pool)
+        .await?
+        .and_then(|value| parse_retention_days(&value).ok().flatten()))
+}
+
+/// Reads a retention period, `null` for keeping messages forever
+pub fn parse_retention_days(value: &Value) -> Result<Option<i32>, String> {
+    if value.is_null() {
+        return Ok(None);
+    }
+    match value.as_i64() {
+        Some(days) if (1..=MAX_RETENTION_DAYS as i64).contains(&days) => Ok(Some(days as i32)),
+        _ => Err(format!("retention must be null or 1 to {MAX_RETENTION_DAYS} days")),
+    }
+}
+
+#[derive(Serialize)]
+struct Setting {
+    key: String,
+    value: Value,
+    updated_by: Option<i32>,
+    updated_at: chrono::DateTime<chrono::Utc>,
+}
+
+#[get("/settings")]
+async fn handle_get_settings(user: User, app: web::Data<AppState>) -> impl Responder {
+    if let Err(resp) = require_platform_admin(user.user_id, &app.pool).await {
+        return resp;
+    }
+    match sqlx::query_as!(
+        Setting,
+        r#"select key, value, updated_by, updated_at from platform_settings order by key"#
+    )
+    .fetch_all(&app.pool)
+    .await
+    {
+        Ok(rows) => HttpResponse::Ok().json(rows),
+        Err(e) => HttpResponse::InternalServerError().body(e.to_string()),
+    }
+}
+
+#[put("/settings/{key}")]
+async fn handle_put_setting(
+    key: web::Path<String>,
+    value: web::Json<Value>,
+    user: User,
+    app: web::Data<AppState>,
+    req: HttpRequest,
+    tokenstore: web::Data<Arc<TokenStore>>,
+) -> impl Responder {
+    if let Err(resp) = require_platform_admin(user.user_id, &app.pool).await {
+        return resp;
+    }
+    let key = key.into_inner();
+    let value = value.into_inner();
+    let mut sessions_ended = 0;
+    match key.as_str() {
+        REQUIRE_ADMIN_2FA => {
+            let enabled = match value.as_bool() {
+                Some(enabled) => enabled,
+                None => {
+                    return HttpResponse::BadRequest().body(format!("{key} must be true or false"))
+                }
+            };
+            if enabled {
+                // otherwise the admin turning it on would be the first one locked out
+                match has_two_factor(user.user_id, &app.pool).await {
+                    Ok(true) => {}
+                    Ok(false) => {
+                        return HttpResponse::Conflict()
+                            .body("set up two factor auth on your own account first")
+                    }
+                    Err(e) => return HttpResponse::InternalServerError().body(e.to_string()),
+                }
+                // admins already logged in without a second factor have to log in again
+                match sqlx::query!(
+                    r#"select u.id from users u
+                    where u.role in ('admin', 'super') and not exists(
+                        select 1 from user_totp t where t.user_id = u.id and t.confirmed_at is not null
+                    )"#
+                )
+                .fetch_all(&app.pool)
+                .await
+                {
+                    Ok(rows) => {
+                        for row in rows {
+                            sessions_ended += tokenstore.invalidate_user(row.id);
+                        }
+                    }
+                    Err(e) => return HttpResponse::InternalServerError().body(e.to_string()),
+                }
+            }
+        }
+        MESSAGE_RETENTION_DAYS => {
+            if let Err(reason) = parse_retention_days(&value) {
+                return HttpResponse::BadRequest().body(reason);
+            }
+        }
+        _ => return HttpResponse::NotFound().body(format!("unknown setting {key}")),
+    }
+
+    if let Err(e) = sqlx::query!(
+        r#"insert into platform_settings (key, value, updated_by) values ($1, $2, $3)
+        on conflict (key) do update set value = $2, updated_by = $3, updated_at = now()"#,
+        key,
+        value,
+        user.user_id
+    )
+    .execute(&app.pool)
+    .await
+    {
+        return HttpResponse::InternalServerError().body(e.to_string());
+    }
+    audit_event(
+        &app.pool,
+        &req,
+        Some(user.user_id),
+        "admin.settings.update",
+        Some(format!("setting:{key}")),
+        Some(json!({ "value": value, "sessions_ended": sessions_ended })),
+    )
+    .await;
+    HttpResponse::Ok().body("")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn retention_is_null_or_in_range() {
+        assert_eq!(parse_retention_days(&Value::Null), Ok(None));
+        assert_eq!(parse_retention_days(&json!(90)), Ok(Some(90)));
+        assert!(parse_retention_days(&json!(0)).is_err());
+        assert!(parse_retention_days(&json!(MAX_RETENTION_DAYS + 1)).is_err());
+        assert!(parse_retention_days(&json!("90")).is_err());
+    }
+}
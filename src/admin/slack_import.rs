@@ -0,0 +1,760 @@
+use crate::audit::audit::record_audit;
+use crate::auth::generate_token;
+use crate::custom_types::{GroupType, MessageType, UserRole};
+use crate::groups::groups::add_to_group;
+use crate::groups::roles::create_default_roles;
+use crate::login::identity::username_base;
+use crate::login::login::create_ciphertext;
+use chrono::{DateTime, TimeZone, Utc};
+use flate2::read::DeflateDecoder;
+use serde::{Deserialize, Serialize};
+use serde_json::{json, Value};
+use sqlx::{Acquire, PgConnection, Pool, Postgres};
+use std::collections::{BTreeMap, HashMap};
+use std::io::Read;
+use std::path::{Path, PathBuf};
+
+/// Message subtypes that are notices about the channel rather than something someone said
+const NOTICE_SUBTYPES: [&str; 6] = [
+    "channel_join",
+    "channel_leave",
+    "channel_topic",
+    "channel_purpose",
+    "channel_name",
+    "channel_archive",
+];
+
+/// The files of a Slack export, either the zip Slack hands out or a folder it was unpacked to
+pub enum SlackExport {
+    Zip(ZipArchive),
+    Dir(PathBuf),
+}
+
+impl SlackExport {
+    pub fn open(path: &Path) -> Result<SlackExport, String> {
+        if path.is_dir() {
+            return Ok(SlackExport::Dir(path.to_owned()));
+        }
+        let data = std::fs::read(path).map_err(|e| format!("{}: {e}", path.display()))?;
+        Ok(SlackExport::Zip(ZipArchive::parse(data)?))
+    }
+
+    /// The file at `name` (e.g. `users.json`), `None` if the export doesn't have it
+    fn file(&self, name: &str) -> Result<Option<Vec<u8>>, String> {
+        match self {
+            SlackExport::Zip(zip) => zip.read(name),
+            SlackExport::Dir(dir) => match std::fs::read(dir.join(name)) {
+                Ok(data) => Ok(Some(data)),
+                Err(e) if e.kind() == std::io::ErrorKind::NotFound => Ok(None),
+                Err(e) => Err(format!("{name}: {e}")),
+            },
+        }
+    }
+
+    /// The channel's per day message files, oldest first
+    fn day_files(&self, channel: &str) -> Result<Vec<String>, String> {
+        let prefix = format!("{channel}/");
+        let mut names: Vec<String> = match self {
+            SlackExport::Zip(zip) => zip
+                .entries
+                .keys()
+                .filter(|name| name.starts_with(&prefix) && name.ends_with(".json"))
+                .cloned()
+                .collect(),
+            SlackExport::Dir(dir) => match std::fs::read_dir(dir.join(channel)) {
+                Ok(entries) => entries
+                    .filter_map(|entry| entry.ok())
+                    .map(|entry| entry.file_name().to_string_lossy().into_owned())
+                    .filter(|name| name.ends_with(".json"))
+                    .map(|name| format!("{prefix}{name}"))
+                    .collect(),
+                Err(_) => Vec::new(),
+            },
+        };
+        // named YYYY-MM-DD.json, so sorting by name is sorting by day
+        names.sort();
+        Ok(names)
+    }
+
+    /// Parses a JSON file, a missing file is an empty list
+    fn json<T: for<'de> Deserialize<'de> + Default>(&self, name: &str) -> Result<T, String> {
+        match self.file(name)? {
+            Some(data) => serde_json::from_slice(&data).map_err(|e| format!("{name}: {e}")),
+            None => Ok(T::default()),
+        }
+    }
+}
+
+/// Just enough of a zip reader for Slack exports, stored & deflated entries without zip64
+pub struct ZipArchive {
+    data: Vec<u8>,
+    /// name -> (local header offset, compression method, compressed size)
+    entries: HashMap<String, (usize, u16, usize)>,
+}
+
+fn le16(data: &[u8], at: usize) -> Option<u16> {
+    Some(u16::from_le_bytes(data.get(at..at + 2)?.try_into().ok()?))
+}
+
+fn le32(data: &[u8], at: usize) -> Option<u32> {
+    Some(u32::from_le_bytes(data.get(at..at + 4)?.try_into().ok()?))
+}
+
+impl ZipArchive {
+    pub fn parse(data: Vec<u8>) -> Result<ZipArchive, String> {
+        let invalid = || "not a zip file, or a zip64 one".to_owned();
+        // the end of central directory record, found backwards past any trailing comment
+        let eocd = (0..data.len().saturating_sub(21))
+            .rev()
+            .find(|&at| le32(&data, at) == Some(0x06054b50))
+            .ok_or_else(invalid)?;
+        let count = le16(&data, eocd + 10).ok_or_else(invalid)? as usize;
+        let mut at = le32(&data, eocd + 16).ok_or_else(invalid)? as usize;
+        let mut entries = HashMap::new();
+        for _ in 0..count {
+            if le32(&data, at) != Some(0x02014b50) {
+                return Err(invalid());
+            }
+            let field = |offset| le16(&data, at + offset).ok_or_else(invalid);
+            let method = field(10)?;
+            let size = le32(&data, at + 20).ok_or_else(invalid)?;
+            let name_len = field(28)? as usize;
+            let extra_len = field(30)? as usize;
+            let comment_len = field(32)? as usize;
+            let offset = le32(&data, at + 42).ok_or_else(invalid)?;
+            if size == u32::MAX || offset == u32::MAX {
+                return Err(invalid());
+            }
+            let name = data.get(at + 46..at + 46 + name_len).ok_or_else(invalid)?;
+            entries.insert(
+                String::from_utf8_lossy(name).into_owned(),
+                (offset as usize, method, size as usize),
+            );
+            at += 46 + name_len + extra_len + comment_len;
+        }
+        Ok(ZipArchive { data, entries })
+    }
+
+    fn read(&self, name: &str) -> Result<Option<Vec<u8>>, String> {
+        let Some(&(offset, method, size)) = self.entries.get(name) else {
+            return Ok(None);
+        };
+        let corrupt = || format!("{name}: corrupt zip entry");
+        if le32(&self.data, offset) != Some(0x04034b50) {
+            return Err(corrupt());
+        }
+        let name_len = le16(&self.data, offset + 26).ok_or_else(corrupt)? as usize;
+        let extra_len = le16(&self.data, offset + 28).ok_or_else(corrupt)? as usize;
+        let start = offset + 30 + name_len + extra_len;
+        let raw = self.data.get(start..start + size).ok_or_else(corrupt)?;
+        match method {
+            0 => Ok(Some(raw.to_vec())),
+            8 => {
+                let mut out = Vec::new();
+                DeflateDecoder::new(raw)
+                    .read_to_end(&mut out)
+                    .map_err(|e| format!("{name}: {e}"))?;
+                Ok(Some(out))
+            }
+            other => Err(format!("{name}: unsupported compression method {other}")),
+        }
+    }
+}
+
+#[derive(Deserialize, Default)]
+#[serde(default)]
+struct SlackProfile {
+    email: Option<String>,
+    real_name: Option<String>,
+    display_name: Option<String>,
+}
+
+#[derive(Deserialize, Default)]
+#[serde(default)]
+struct SlackUser {
+    id: String,
+    name: String,
+    real_name: Option<String>,
+    is_bot: bool,
+    profile: SlackProfile,
+}
+
+#[derive(Deserialize, Default)]
+#[serde(default)]
+struct SlackText {
+    value: String,
+}
+
+#[derive(Deserialize, Default)]
+#[serde(default)]
+struct SlackChannel {
+    id: String,
+    name: String,
+    created: i64,
+    is_archived: bool,
+    topic: SlackText,
+    purpose: SlackText,
+    members: Vec<String>,
+}
+
+#[derive(Deserialize, Default)]
+#[serde(default)]
+struct SlackReaction {
+    name: String,
+    users: Vec<String>,
+}
+
+#[derive(Deserialize, Default)]
+#[serde(default)]
+struct SlackFile {
+    name: Option<String>,
+    url_private: Option<String>,
+}
+
+#[derive(Deserialize, Default)]
+#[serde(default)]
+struct SlackMessage {
+    #[serde(rename = "type")]
+    kind: String,
+    subtype: Option<String>,
+    user: Option<String>,
+    text: String,
+    ts: String,
+    thread_ts: Option<String>,
+    reactions: Vec<SlackReaction>,
+    files: Vec<SlackFile>,
+}
+
+#[derive(Serialize, Default)]
+pub struct ImportCounts {
+    pub created: u64,
+    /// brought in by an earlier run
+    pub already_imported: u64,
+}
+
+#[derive(Serialize, Default)]
+pub struct UserCounts {
+    pub created: u64,
+    /// already here before any import, matched by email
+    pub matched: u64,
+    pub already_imported: u64,
+}
+
+/// What an import did, or for a dry run what it would do
+#[derive(Serialize, Default)]
+pub struct ImportReport {
+    pub dry_run: bool,
+    pub users: UserCounts,
+    pub groups: ImportCounts,
+    pub messages: ImportCounts,
+    /// messages left out, by reason
+    pub skipped: BTreeMap<String, u64>,
+}
+
+/// Slack ids mapped to what they became here
+#[derive(Default)]
+struct Imported {
+    user_ids: HashMap<String, i32>,
+    usernames: HashMap<String, String>,
+    channel_names: HashMap<String, String>,
+}
+
+/// Slack's `ts`, seconds & microseconds since the epoch, e.g. `1500000000.000100`
+fn parse_ts(ts: &str) -> Option<DateTime<Utc>> {
+    let (secs, micros) = ts.split_once('.').unwrap_or((ts, "0"));
+    let micros: u32 = format!("{micros:0<6}").get(..6)?.parse().ok()?;
+    Utc.timestamp_opt(secs.parse().ok()?, micros * 1000)
+        .single()
+}
+
+/// Turns Slack's markup into plain text, mentions of people & channels become
+/// `@username` & `#name` here, links keep their address
+fn convert_text(
+    text: &str,
+    usernames: &HashMap<String, String>,
+    channels: &HashMap<String, String>,
+) -> String {
+    let mut out = String::new();
+    let mut rest = text;
+    while let Some(open) = rest.find('<') {
+        let Some(close) = rest[open..].find('>') else {
+            break;
+        };
+        out.push_str(&rest[..open]);
+        let inner = &rest[open + 1..open + close];
+        let (target, label) = match inner.split_once('|') {
+            Some((target, label)) => (target, Some(label)),
+            None => (inner, None),
+        };
+        let converted = if let Some(id) = target.strip_prefix('@') {
+            match (usernames.get(id), label) {
+                (Some(name), _) => format!("@{name}"),
+                (None, Some(label)) => format!("@{label}"),
+                (None, None) => format!("@{id}"),
+            }
+        } else if let Some(id) = target.strip_prefix('#') {
+            format!(
+                "#{}",
+                channels.get(id).map(String::as_str).or(label).unwrap_or(id)
+            )
+        } else if let Some(special) = target.strip_prefix('!') {
+            format!("@{}", label.unwrap_or(special))
+        } else {
+            match label {
+                Some(label) if label != target => format!("{label} ({target})"),
+                _ => target.to_owned(),
+            }
+        };
+        out.push_str(&converted);
+        rest = &rest[open + close + 1..];
+    }
+    out.push_str(rest);
+    out.replace("&lt;", "<")
+        .replace("&gt;", ">")
+        .replace("&amp;", "&")
+}
+
+async fn imported_id(
+    kind: &str,
+    slack_id: &str,
+    conn: &mut PgConnection,
+) -> Result<Option<i32>, sqlx::Error> {
+    sqlx::query_scalar!(
+        r#"select local_id from slack_imports where kind = $1 and slack_id = $2"#,
+        kind,
+        slack_id
+    )
+    .fetch_optional(conn)
+    .await
+}
+
+async fn record_import(
+    kind: &str,
+    slack_id: &str,
+    local_id: i32,
+    conn: &mut PgConnection,
+) -> Result<(), sqlx::Error> {
+    sqlx::query!(
+        r#"insert into slack_imports (kind, slack_id, local_id) values ($1, $2, $3)"#,
+        kind,
+        slack_id,
+        local_id
+    )
+    .execute(conn)
+    .await?;
+    Ok(())
+}
+
+/// Finds the user by email, or makes a disabled one with a password nobody knows
+/// that an admin can enable later. Returns their id & whether they're new
+async fn import_user(
+    user: &SlackUser,
+    conn: &mut PgConnection,
+) -> Result<(i32, bool), sqlx::Error> {
+    let email = user.profile.email.clone();
+    if let Some(email) = &email {
+        let existing = sqlx::query_scalar!(
+            r#"select id from users where lower(email) = lower($1)"#,
+            email
+        )
+        .fetch_optional(&mut *conn)
+        .await?;
+        if let Some(id) = existing {
+            return Ok((id, false));
+        }
+    }
+    let email = email.unwrap_or(format!("{}@slack.invalid", user.id.to_lowercase()));
+    let base = username_base(Some(&user.name), &email);
+    let taken: Vec<String> = sqlx::query_scalar!(
+        r#"select username from users where username like $1 || '%'"#,
+        base
+    )
+    .fetch_all(&mut *conn)
+    .await?;
+    let username = std::iter::once(base.clone())
+        .chain((2..).map(|n| format!("{base}{n}")))
+        .find(|name| !taken.contains(name))
+        .unwrap_or(base);
+    let display_name = [
+        &user.profile.display_name,
+        &user.profile.real_name,
+        &user.real_name,
+    ]
+    .into_iter()
+    .flatten()
+    .find(|name| !name.is_empty())
+    .cloned()
+    .unwrap_or(username.clone());
+    let (ciphertext, salt) =
+        create_ciphertext(&generate_token()).map_err(|e| sqlx::Error::Protocol(e.to_string()))?;
+    let id = sqlx::query_scalar!(
+        r#"insert into users (username, password, salt, display_name, email, is_bot, disabled_at)
+        values ($1, $2, $3, $4, $5, $6, now()) returning id"#,
+        username,
+        ciphertext,
+        salt,
+        display_name,
+        email,
+        user.is_bot
+    )
+    .fetch_one(&mut *conn)
+    .await?;
+    Ok((id, true))
+}
+
+/// Makes the group a channel becomes, owned by the admin running the import
+/// with the channel's members as writers
+async fn import_channel(
+    channel: &SlackChannel,
+    private: bool,
+    admin_id: i32,
+    user_ids: &HashMap<String, i32>,
+    conn: &mut PgConnection,
+) -> Result<i32, sqlx::Error> {
+    let non_empty = |text: &SlackText| Some(text.value.clone()).filter(|v| !v.is_empty());
+    let group_id = sqlx::query_scalar!(
+        r#"insert into groups(group_name, created_by, owner_id, type, private, topic, description, created_at)
+        values ($1, $2, $2, $3, $4, $5, $6, coalesce($7, now())) returning id"#,
+        channel.name,
+        admin_id,
+        GroupType::CHANNEL as GroupType,
+        private,
+        non_empty(&channel.topic),
+        non_empty(&channel.purpose),
+        Utc.timestamp_opt(channel.created, 0).single()
+    )
+    .fetch_one(&mut *conn)
+    .await?;
+    create_default_roles(group_id, conn).await?;
+    add_to_group(group_id, admin_id, admin_id, &["admin".to_owned()], conn).await?;
+    for member in channel.members.iter() {
+        if let Some(&user_id) = user_ids.get(member) {
+            add_to_group(group_id, user_id, admin_id, &["writer".to_owned()], conn).await?;
+        }
+    }
+    Ok(group_id)
+}
+
+/// Brings in a channel's messages with the time they were sent. There are no
+/// threads or reactions here, so a reply's parent & a message's reactions are
+/// kept in its payload under `slack`. Returns how many were imported
+async fn import_messages(
+    channel: &SlackChannel,
+    group_id: i32,
+    export: &SlackExport,
+    imported: &Imported,
+    report: &mut ImportReport,
+    conn: &mut PgConnection,
+) -> Result<(), String> {
+    let mut skip = |reason: &str| *report.skipped.entry(reason.to_owned()).or_default() += 1;
+    let mut counts = ImportCounts::default();
+    for day in export.day_files(&channel.name)? {
+        let messages: Vec<SlackMessage> = export.json(&day)?;
+        for message in messages {
+            if message.kind != "message" {
+                skip("not a message");
+                continue;
+            }
+            let key = format!("{}:{}", channel.id, message.ts);
+            if imported_id("message", &key, conn)
+                .await
+                .map_err(|e| e.to_string())?
+                .is_some()
+            {
+                counts.already_imported += 1;
+                continue;
+            }
+            let Some(sent_at) = parse_ts(&message.ts) else {
+                skip("bad timestamp");
+                continue;
+            };
+            let Some(&sender) = message
+                .user
+                .as_ref()
+                .and_then(|user| imported.user_ids.get(user))
+            else {
+                skip("unknown sender");
+                continue;
+            };
+            let mut content =
+                convert_text(&message.text, &imported.usernames, &imported.channel_names);
+            for file in message.files.iter() {
+                let name = file.name.as_deref().unwrap_or("file");
+                let url = file.url_private.as_deref().unwrap_or_default();
+                content.push_str(format!("\n[{name}] {url}").trim_end());
+            }
+            let content = content.trim().to_owned();
+            if content.is_empty() {
+                skip("empty");
+                continue;
+            }
+            let message_type = match message.subtype.as_deref() {
+                Some(subtype) if NOTICE_SUBTYPES.contains(&subtype) => MessageType::SYSTEM,
+                _ => MessageType::TEXT,
+            };
+            let mut slack = serde_json::Map::new();
+            if let Some(thread_ts) = message.thread_ts.filter(|parent| *parent != message.ts) {
+                slack.insert("thread_ts".to_owned(), json!(thread_ts));
+                slack.insert(
+                    "thread_parent".to_owned(),
+                    json!(
+                        imported_id("message", &format!("{}:{thread_ts}", channel.id), conn)
+                            .await
+                            .map_err(|e| e.to_string())?
+                    ),
+                );
+            }
+            let reactions: Vec<Value> = message
+                .reactions
+                .iter()
+                .map(|reaction| {
+                    let users: Vec<i32> = reaction
+                        .users
+                        .iter()
+                        .filter_map(|user| imported.user_ids.get(user))
+                        .copied()
+                        .collect();
+                    json!({ "name": reaction.name, "users": users })
+                })
+                .collect();
+            if !reactions.is_empty() {
+                slack.insert("reactions".to_owned(), json!(reactions));
+            }
+            let payload = (!slack.is_empty()).then(|| json!({ "slack": slack }));
+            let message_id = sqlx::query_scalar!(
+                r#"insert into messages (sender_user_id, group_id, content, message_type, payload, sent_at)
+                values ($1, $2, $3, $4, $5, $6) returning id"#,
+                sender,
+                group_id,
+                content,
+                message_type as MessageType,
+                payload,
+                sent_at
+            )
+            .fetch_one(&mut *conn)
+            .await
+            .map_err(|e| e.to_string())?;
+            record_import("message", &key, message_id, conn)
+                .await
+                .map_err(|e| e.to_string())?;
+            counts.created += 1;
+        }
+    }
+    report.messages.created += counts.created;
+    report.messages.already_imported += counts.already_imported;
+    Ok(())
+}
+
+/// Imports a Slack export as `admin_username`, who has to be a platform admin.
+/// Users are matched by email or made disabled, public & private channels become
+/// groups & their messages keep their original times. Anything an earlier run
+/// brought in is skipped, so it's safe to run again. It all happens in one
+/// transaction, which a dry run rolls back once the report is made
+pub async fn import_slack(
+    export: &SlackExport,
+    admin_username: &str,
+    dry_run: bool,
+    pool: &Pool<Postgres>,
+) -> Result<ImportReport, String> {
+    let db = |e: sqlx::Error| e.to_string();
+    let admin = sqlx::query!(
+        r#"select id, role as "role: UserRole" from users where username = $1 and disabled_at is null"#,
+        admin_username
+    )
+    .fetch_optional(pool)
+    .await
+    .map_err(db)?;
+    let admin_id = match admin {
+        Some(admin) if admin.role == UserRole::ADMIN || admin.role == UserRole::SUPER => admin.id,
+        _ => return Err(format!("{admin_username} is not a platform admin")),
+    };
+
+    let users: Vec<SlackUser> = export.json("users.json")?;
+    let public: Vec<SlackChannel> = export.json("channels.json")?;
+    let private: Vec<SlackChannel> = export.json("groups.json")?;
+    let mut report = ImportReport {
+        dry_run,
+        ..Default::default()
+    };
+
+    let mut conn = pool.acquire().await.map_err(db)?;
+    let mut tx = conn.begin().await.map_err(db)?;
+
+    let mut imported = Imported::default();
+    for user in users.iter() {
+        let user_id = match imported_id("user", &user.id, &mut tx).await.map_err(db)? {
+            Some(user_id) => {
+                report.users.already_imported += 1;
+                user_id
+            }
+            None => {
+                let (user_id, created) = import_user(user, &mut tx).await.map_err(db)?;
+                if created {
+                    report.users.created += 1;
+                } else {
+                    report.users.matched += 1;
+                }
+                record_import("user", &user.id, user_id, &mut tx)
+                    .await
+                    .map_err(db)?;
+                user_id
+            }
+        };
+        let username = sqlx::query_scalar!(r#"select username from users where id = $1"#, user_id)
+            .fetch_one(&mut *tx)
+            .await
+            .map_err(db)?;
+        imported.user_ids.insert(user.id.clone(), user_id);
+        imported.usernames.insert(user.id.clone(), username);
+    }
+
+    imported.channel_names = public
+        .iter()
+        .chain(private.iter())
+        .map(|channel| (channel.id.clone(), channel.name.clone()))
+        .collect();
+    let channels = public
+        .iter()
+        .map(|channel| (channel, false))
+        .chain(private.iter().map(|channel| (channel, true)));
+    for (channel, private) in channels {
+        let (group_id, created) = match imported_id("channel", &channel.id, &mut tx)
+            .await
+            .map_err(db)?
+        {
+            Some(group_id) => {
+                report.groups.already_imported += 1;
+                (group_id, false)
+            }
+            None => {
+                let group_id =
+                    import_channel(channel, private, admin_id, &imported.user_ids, &mut tx)
+                        .await
+                        .map_err(db)?;
+                record_import("channel", &channel.id, group_id, &mut tx)
+                    .await
+                    .map_err(db)?;
+                report.groups.created += 1;
+                (group_id, true)
+            }
+        };
+        import_messages(channel, group_id, export, &imported, &mut report, &mut tx).await?;
+        // archived once its history is in, as it was in Slack
+        if created && channel.is_archived {
+            sqlx::query!(
+                r#"update groups set archived_at = now() where id = $1"#,
+                group_id
+            )
+            .execute(&mut *tx)
+            .await
+            .map_err(db)?;
+        }
+    }
+
+    if dry_run {
+        tx.rollback().await.map_err(db)?;
+        return Ok(report);
+    }
+    record_audit(
+        &mut tx,
+        Some(admin_id),
+        "import.slack",
+        None,
+        Some(serde_json::to_value(&report).map_err(|e| e.to_string())?),
+        None,
+    )
+    .await
+    .map_err(db)?;
+    tx.commit().await.map_err(db)?;
+    Ok(report)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use flate2::write::DeflateEncoder;
+    use flate2::Compression;
+    use std::io::Write;
+
+    /// A zip holding `files`, the first stored & the rest deflated
+    fn zip(files: &[(&str, &str)]) -> Vec<u8> {
+        let mut data = Vec::new();
+        let mut central = Vec::new();
+        for (i, (name, content)) in files.iter().enumerate() {
+            let (method, body) = if i == 0 {
+                (0u16, content.as_bytes().to_vec())
+            } else {
+                let mut encoder = DeflateEncoder::new(Vec::new(), Compression::default());
+                encoder.write_all(content.as_bytes()).unwrap();
+                (8u16, encoder.finish().unwrap())
+            };
+            let offset = data.len() as u32;
+            let mut local = vec![0u8; 30];
+            local[..4].copy_from_slice(&0x04034b50u32.to_le_bytes());
+            local[8..10].copy_from_slice(&method.to_le_bytes());
+            local[18..22].copy_from_slice(&(body.len() as u32).to_le_bytes());
+            local[26..28].copy_from_slice(&(name.len() as u16).to_le_bytes());
+            data.extend(local);
+            data.extend(name.as_bytes());
+            data.extend(body.iter());
+            let mut entry = vec![0u8; 46];
+            entry[..4].copy_from_slice(&0x02014b50u32.to_le_bytes());
+            entry[10..12].copy_from_slice(&method.to_le_bytes());
+            entry[20..24].copy_from_slice(&(body.len() as u32).to_le_bytes());
+            entry[28..30].copy_from_slice(&(name.len() as u16).to_le_bytes());
+            entry[42..46].copy_from_slice(&offset.to_le_bytes());
+            central.extend(entry);
+            central.extend(name.as_bytes());
+        }
+        let mut eocd = vec![0u8; 22];
+        eocd[..4].copy_from_slice(&0x06054b50u32.to_le_bytes());
+        eocd[10..12].copy_from_slice(&(files.len() as u16).to_le_bytes());
+        eocd[16..20].copy_from_slice(&(data.len() as u32).to_le_bytes());
+        data.extend(central);
+        data.extend(eocd);
+        data
+    }
+
+    #[test]
+    fn reads_stored_and_deflated_entries() {
+        let archive = ZipArchive::parse(zip(&[
+            ("users.json", "[]"),
+            ("general/2020-01-01.json", "[{\"type\":\"message\"}]"),
+        ]))
+        .unwrap();
+        assert_eq!(archive.read("users.json").unwrap().unwrap(), b"[]");
+        assert_eq!(
+            archive.read("general/2020-01-01.json").unwrap().unwrap(),
+            b"[{\"type\":\"message\"}]"
+        );
+        assert!(archive.read("channels.json").unwrap().is_none());
+        assert!(ZipArchive::parse(b"not a zip at all, honestly".to_vec()).is_err());
+    }
+
+    #[test]
+    fn slack_timestamps_keep_microseconds() {
+        let sent = parse_ts("1500000000.000100").unwrap();
+        assert_eq!(sent.timestamp(), 1500000000);
+        assert_eq!(sent.timestamp_subsec_micros(), 100);
+        assert_eq!(parse_ts("1500000000").unwrap().timestamp(), 1500000000);
+        assert!(parse_ts("soon").is_none());
+    }
+
+    #[test]
+    fn slack_markup_becomes_plain_text() {
+        let usernames = HashMap::from([("U1".to_owned(), "ana".to_owned())]);
+        let channels = HashMap::from([("C1".to_owned(), "general".to_owned())]);
+        assert_eq!(
+            convert_text(
+                "<@U1> see <#C1|old-name> &amp; <https://x.io|the docs> <!here> &lt;3",
+                &usernames,
+                &channels
+            ),
+            "@ana see #general & the docs (https://x.io) @here <3"
+        );
+        assert_eq!(
+            convert_text("<@U9|bo> <https://x.io>", &usernames, &channels),
+            "@bo https://x.io"
+        );
+    }
+}
@@ -0,0 +1 @@
+pub mod audit;
\ No newline at end of file
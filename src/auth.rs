@@ -2,16 +2,20 @@ use actix_utils::future::{err, ok, ready, Ready};
 use actix_web::body::EitherBody;
 use actix_web::dev::{forward_ready, Service, ServiceRequest, ServiceResponse, Transform};
 use actix_web::web::Data;
-use actix_web::HttpResponse;
-use actix_web::{error::ErrorUnauthorized, Error, FromRequest};
+use actix_web::error::{ErrorForbidden, ErrorInternalServerError, ErrorUnauthorized};
+use actix_web::{Error, FromRequest, HttpMessage, HttpResponse};
 use chrono::prelude::*;
 use futures_util::future::LocalBoxFuture;
 use log;
 use rand::distributions::Alphanumeric;
 use rand::{thread_rng, Rng};
-use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+use std::rc::Rc;
 use std::sync::Arc;
 
+use crate::server::app_state::AppState;
+use crate::tokens::tokens::{check_api_token, required_scope, TOKEN_PREFIX};
+
 use std::collections::HashMap;
 use std::sync::Mutex;
 
@@ -22,15 +26,29 @@ struct UserLoginValues {
     expiry_time: DateTime<Utc>,
 }
 
+/// A login that got the password right but still owes a second factor
+#[derive(Debug)]
+struct Challenge {
+    user_id: i32,
+    expiry_time: DateTime<Utc>,
+    failures: u8,
+}
+
+const CHALLENGE_MINUTES: i64 = 5;
+const CHALLENGE_MAX_FAILURES: u8 = 5;
+
 #[derive(Debug)]
 pub struct TokenStore {
     tokens: Mutex<HashMap<String, UserLoginValues>>, // Token as key, User ID as value
+    // challenge tokens are kept apart so they can never pass for a session
+    challenges: Mutex<HashMap<String, Challenge>>,
 }
 
 impl TokenStore {
     pub fn new() -> Self {
         TokenStore {
             tokens: Mutex::new(HashMap::new()),
+            challenges: Mutex::new(HashMap::new()),
         }
     }
 
@@ -46,6 +64,10 @@ impl TokenStore {
         for token in expired_tokens {
             values.remove(&token);
         }
+        self.challenges
+            .lock()
+            .unwrap()
+            .retain(|_, challenge| challenge.expiry_time >= now);
     }
 
     pub fn validate_user(&self, user_id: i32) -> String {
@@ -67,6 +89,59 @@ impl TokenStore {
         tokens.remove(&token).unwrap().user_id
     }
 
+    /// Logs a user out everywhere, returns how many sessions were ended
+    pub fn invalidate_user(&self, user_id: i32) -> usize {
+        let mut tokens = self.tokens.lock().unwrap();
+        let before = tokens.len();
+        tokens.retain(|_, login| login.user_id != user_id);
+        before - tokens.len()
+    }
+
+    /// Starts the second step of a login, the token only works for
+    /// [`TokenStore::complete_challenge`] & only for a few minutes
+    pub fn issue_challenge(&self, user_id: i32) -> String {
+        let token = generate_token();
+        self.challenges.lock().unwrap().insert(
+            token.clone(),
+            Challenge {
+                user_id,
+                expiry_time: Utc::now() + chrono::Duration::minutes(CHALLENGE_MINUTES),
+                failures: 0,
+            },
+        );
+        token
+    }
+
+    /// The user a live challenge belongs to
+    pub fn check_challenge(&self, token: &str) -> Option<i32> {
+        let challenges = self.challenges.lock().unwrap();
+        challenges
+            .get(token)
+            .filter(|challenge| challenge.expiry_time >= Utc::now())
+            .map(|challenge| challenge.user_id)
+    }
+
+    /// Counts a wrong code against the challenge, after too many the
+    /// challenge is dropped & the login has to start again
+    pub fn fail_challenge(&self, token: &str) {
+        let mut challenges = self.challenges.lock().unwrap();
+        if let Some(challenge) = challenges.get_mut(token) {
+            challenge.failures += 1;
+            if challenge.failures >= CHALLENGE_MAX_FAILURES {
+                challenges.remove(token);
+            }
+        }
+    }
+
+    /// Swaps a challenge for a full session once the second factor checks out
+    pub fn complete_challenge(&self, token: &str) -> Option<(i32, String)> {
+        let challenge = self.challenges.lock().unwrap().remove(token)?;
+        if challenge.expiry_time < Utc::now() {
+            return None;
+        }
+        Some((challenge.user_id, self.validate_user(challenge.user_id)))
+    }
+
     fn check_token(&self, token: &str) -> Option<i32> {
         let tokens = self.tokens.lock().unwrap();
         match tokens.get(token) {
@@ -76,7 +151,7 @@ impl TokenStore {
     }
 }
 
-fn generate_token() -> String {
+pub fn generate_token() -> String {
     let mut rng = thread_rng();
     let s: String = (&mut rng)
         .sample_iter(Alphanumeric)
@@ -86,9 +161,18 @@ fn generate_token() -> String {
     s
 }
 
-#[derive(Serialize, Deserialize, Debug, Default, Clone)]
-pub struct Authentication {
-    pub token: String,
+/// Tokens handed out (emailed links, api tokens, webhook urls) are stored
+/// hashed, like passwords, so a database leak doesn't hand out working ones
+pub fn hash_token(token: &str) -> String {
+    format!("{:x}", Sha256::digest(token.as_bytes()))
+}
+
+/// How a request proved who it's from, left in the request's extensions by
+/// [`AuthStruct`] so handlers can tell sessions & api tokens apart
+#[derive(Debug, Clone)]
+pub enum Authentication {
+    Session { token: String },
+    ApiToken { id: i32 },
 }
 
 impl FromRequest for Authentication {
@@ -100,37 +184,58 @@ impl FromRequest for Authentication {
         req: &actix_web::HttpRequest,
         _payload: &mut actix_web::dev::Payload,
     ) -> Self::Future {
-        let token_store = req.app_data::<Data<Arc<TokenStore>>>().unwrap();
-        let headers = req.headers();
-        let header_user_id = match headers.get("user_id") {
-            Some(header) => header.to_str().unwrap(),
-            _ => return err(ErrorUnauthorized("No user_id specified in request")),
-        };
+        match req.extensions().get::<Authentication>() {
+            Some(auth) => ok(auth.clone()),
+            None => err(ErrorUnauthorized("not authenticated")),
+        }
+    }
+}
 
-        match headers.get("Authorization") {
-            // todo, check things
-            Some(header) => {
-                let header_auth_token = header.to_str().unwrap();
-                let cached_user_id = (*token_store).check_token(header_auth_token);
-                match cached_user_id {
-                    Some(id) => {
-                        if header_user_id.parse::<i32>().unwrap() == id {
-                            return ok(Authentication {
-                                token: header_auth_token.to_owned(),
-                            });
-                        } else {
-                            // here don't be specific in error as that would give away
-                            // that the token is valid but user_id invalid
-                            return err(ErrorUnauthorized(format!("Token invalid {}", header_auth_token)));
-                        }
-                    }
-                    None => return err(ErrorUnauthorized(format!("Token invalid {}", header_auth_token))),
-                }
-            }
-            _ => err(ErrorUnauthorized(
+/// Checks the `Authorization` token belongs to the `user_id` header, as either
+/// a session from the token store or an api token with the scope the request needs
+async fn authenticate(req: &ServiceRequest) -> Result<Authentication, Error> {
+    let headers = req.headers();
+    let header_user_id = match headers.get("user_id").map(|header| header.to_str()) {
+        Some(Ok(header)) => header.parse::<i32>().ok(),
+        _ => return Err(ErrorUnauthorized("No user_id specified in request")),
+    };
+    let header_auth_token = match headers.get("Authorization").map(|header| header.to_str()) {
+        Some(Ok(header)) => header.to_owned(),
+        _ => {
+            return Err(ErrorUnauthorized(
                 "not authorized, no token found in Authorization",
-            )),
+            ))
         }
+    };
+
+    if header_auth_token.starts_with(TOKEN_PREFIX) {
+        let app = req.app_data::<Data<AppState>>().unwrap();
+        let token = match check_api_token(&header_auth_token, &app.pool).await {
+            Ok(Some(token)) if Some(token.user_id) == header_user_id => token,
+            // don't be specific, that would give away the token is valid but user_id isn't
+            Ok(_) => return Err(ErrorUnauthorized("Token invalid")),
+            Err(e) => return Err(ErrorInternalServerError(e.to_string())),
+        };
+        return match required_scope(req.method(), req.path()) {
+            Some(scope) if token.scopes.contains(&scope) => {
+                Ok(Authentication::ApiToken { id: token.id })
+            }
+            Some(scope) => Err(ErrorForbidden(format!(
+                "token does not have the {} scope",
+                scope.as_str()
+            ))),
+            None => Err(ErrorForbidden("api tokens can't be used for this")),
+        };
+    }
+
+    let token_store = req.app_data::<Data<Arc<TokenStore>>>().unwrap();
+    match token_store.check_token(&header_auth_token) {
+        Some(id) if Some(id) == header_user_id => Ok(Authentication::Session {
+            token: header_auth_token,
+        }),
+        // here don't be specific in error as that would give away
+        // that the token is valid but user_id invalid
+        _ => Err(ErrorUnauthorized("Token invalid")),
     }
 }
 
@@ -145,7 +250,7 @@ pub struct AuthStruct;
 // `B` - type of response's body
 impl<S, B> Transform<S, ServiceRequest> for AuthStruct
 where
-    S: Service<ServiceRequest, Response = ServiceResponse<B>, Error = Error>,
+    S: Service<ServiceRequest, Response = ServiceResponse<B>, Error = Error> + 'static,
     S::Future: 'static,
     B: 'static,
 {
@@ -158,17 +263,20 @@ where
     type Future = Ready<Result<Self::Transform, Self::InitError>>;
 
     fn new_transform(&self, service: S) -> Self::Future {
-        ready(Ok(AuthorizationMiddleware { service }))
+        ready(Ok(AuthorizationMiddleware {
+            service: Rc::new(service),
+        }))
     }
 }
 
 pub struct AuthorizationMiddleware<S> {
-    service: S,
+    // shared with the future, which checks tokens against the database before calling it
+    service: Rc<S>,
 }
 
 impl<S, B> Service<ServiceRequest> for AuthorizationMiddleware<S>
 where
-    S: Service<ServiceRequest, Response = ServiceResponse<B>, Error = Error>,
+    S: Service<ServiceRequest, Response = ServiceResponse<B>, Error = Error> + 'static,
     S::Future: 'static,
     B: 'static,
 {
@@ -178,25 +286,22 @@ where
 
     forward_ready!(service);
 
-    fn call(&self, mut req: ServiceRequest) -> Self::Future {
-        let b = futures::executor::block_on(req.extract::<Authentication>());
-        match b {
-            Ok(_) => {}
-            Err(t) => {
-                log::error!("Authentication error: {t}");
-                return Box::pin(async {
-                    Ok(req
-                        .into_response(HttpResponse::Unauthorized())
-                        .map_into_right_body())
-                });
-            }
-        }
-
-        let fut = self.service.call(req);
-
+    fn call(&self, req: ServiceRequest) -> Self::Future {
+        let service = Rc::clone(&self.service);
         Box::pin(async move {
-            let res = fut.await?;
-
+            match authenticate(&req).await {
+                Ok(auth) => {
+                    req.extensions_mut().insert(auth);
+                }
+                Err(e) => {
+                    log::error!("Authentication error: {e}");
+                    let status = e.as_response_error().status_code();
+                    return Ok(req
+                        .into_response(HttpResponse::build(status).body(e.to_string()))
+                        .map_into_right_body());
+                }
+            }
+            let res = service.call(req).await?;
             Ok(res.map_into_left_body())
         })
     }
@@ -222,6 +327,14 @@ mod tests {
         assert_ne!(a, c);
     }
 
+    #[test]
+    fn token_hash_is_not_the_token() {
+        let token = generate_token();
+        assert_ne!(hash_token(&token), token);
+        assert_eq!(hash_token(&token), hash_token(&token));
+        assert_eq!(hash_token(&token).len(), 64);
+    }
+
     #[test]
     fn create_token_store() {
         let user_id = 2;
@@ -254,4 +367,41 @@ mod tests {
         assert!(token_store.check_token(&user_token).is_none());
         
     }
+
+    #[test]
+    fn invalidate_user() {
+        let token_store = TokenStore::new();
+        let first = token_store.validate_user(1);
+        let second = token_store.validate_user(1);
+        let other = token_store.validate_user(2);
+
+        assert_eq!(token_store.invalidate_user(1), 2);
+        assert!(token_store.check_token(&first).is_none());
+        assert!(token_store.check_token(&second).is_none());
+        assert_eq!(token_store.check_token(&other).unwrap(), 2);
+    }
+
+    #[test]
+    fn challenges_are_not_sessions() {
+        let token_store = TokenStore::new();
+        let challenge = token_store.issue_challenge(3);
+
+        assert!(token_store.check_token(&challenge).is_none());
+        assert_eq!(token_store.check_challenge(&challenge), Some(3));
+        let (user_id, session) = token_store.complete_challenge(&challenge).unwrap();
+        assert_eq!(user_id, 3);
+        assert_eq!(token_store.check_token(&session), Some(3));
+        // a challenge can only be completed once
+        assert!(token_store.complete_challenge(&challenge).is_none());
+    }
+
+    #[test]
+    fn challenges_run_out_of_attempts() {
+        let token_store = TokenStore::new();
+        let challenge = token_store.issue_challenge(3);
+        for _ in 0..CHALLENGE_MAX_FAILURES {
+            token_store.fail_challenge(&challenge);
+        }
+        assert!(token_store.check_challenge(&challenge).is_none());
+    }
 }
\ No newline at end of file
@@ -0,0 +1,332 @@
+use super::commands::{split_args, usage_error, CommandContext, CommandReply};
+use super::custom::list_command_names;
+use crate::audit::audit::audit_event;
+use crate::groups::groups::{add_to_group, remove_from_group, update_group, UpdateGroupRequest};
+use crate::groups::roles::Capability;
+use crate::messages::content::MessageContent;
+use crate::messages::messages::{write_message, write_system_message};
+use actix_web::HttpResponse;
+use chrono::{DateTime, Duration, Utc};
+use serde_json::json;
+
+/// A command every group has
+pub struct Builtin {
+    pub name: &'static str,
+    pub usage: &'static str,
+    pub description: &'static str,
+    pub capability: Capability,
+}
+
+pub const BUILTINS: [Builtin; 7] = [
+    Builtin {
+        name: "me",
+        usage: "/me <action>",
+        description: "post an action, like \"alice waves\"",
+        capability: Capability::Write,
+    },
+    Builtin {
+        name: "topic",
+        usage: "/topic <topic>",
+        description: "set the group's topic",
+        capability: Capability::ManageGroup,
+    },
+    Builtin {
+        name: "invite",
+        usage: "/invite @<username>",
+        description: "add someone to the group",
+        capability: Capability::Invite,
+    },
+    Builtin {
+        name: "leave",
+        usage: "/leave",
+        description: "leave the group",
+        capability: Capability::Read,
+    },
+    Builtin {
+        name: "mute",
+        usage: "/mute [<n>m|<n>h|<n>d]",
+        description: "mute the group, for a while or until you run /mute again",
+        capability: Capability::Read,
+    },
+    Builtin {
+        name: "poll",
+        usage: "/poll [--multiple] [--anonymous] [--closes <n>m|<n>h|<n>d] \"<question>\" \"<option>\" \"<option>\" ...",
+        description: "ask the group a question",
+        capability: Capability::Write,
+    },
+    Builtin {
+        name: "help",
+        usage: "/help",
+        description: "list the commands you can use here",
+        capability: Capability::Read,
+    },
+];
+
+pub async fn run_builtin(
+    builtin: &Builtin,
+    ctx: &CommandContext<'_>,
+) -> Result<CommandReply, HttpResponse> {
+    let mut reply = match builtin.name {
+        "me" => me(builtin, ctx).await,
+        "topic" => topic(builtin, ctx).await,
+        "invite" => invite(builtin, ctx).await,
+        "leave" => leave(ctx).await,
+        "mute" => mute(builtin, ctx).await,
+        "poll" => poll(builtin, ctx).await,
+        "help" => help(ctx).await,
+        _ => unreachable!("every builtin has a handler"),
+    }?;
+    reply.command = builtin.name.to_owned();
+    Ok(reply)
+}
+
+fn internal_error(e: sqlx::Error) -> HttpResponse {
+    HttpResponse::InternalServerError().body(e.to_string())
+}
+
+async fn me(builtin: &Builtin, ctx: &CommandContext<'_>) -> Result<CommandReply, HttpResponse> {
+    if ctx.args.is_empty() {
+        return Err(usage_error(builtin.usage));
+    }
+    let content = MessageContent::Text {
+        content: format!("_{} {}_", ctx.username().await?, ctx.args),
+    };
+    let message_id = write_message(ctx.group_id, ctx.user_id, content, &ctx.app.pool).await?;
+    Ok(CommandReply {
+        message_id: Some(message_id),
+        ..Default::default()
+    })
+}
+
+async fn topic(builtin: &Builtin, ctx: &CommandContext<'_>) -> Result<CommandReply, HttpResponse> {
+    if ctx.args.is_empty() {
+        return Err(usage_error(builtin.usage));
+    }
+    let update = UpdateGroupRequest {
+        topic: Some(ctx.args.to_owned()),
+        ..Default::default()
+    };
+    update_group(ctx.group_id, ctx.user_id, update, &ctx.app.pool)
+        .await
+        .map_err(internal_error)?;
+    Ok(CommandReply::default())
+}
+
+async fn invite(builtin: &Builtin, ctx: &CommandContext<'_>) -> Result<CommandReply, HttpResponse> {
+    let username = ctx.args.trim_start_matches('@');
+    if username.is_empty() || username.contains(char::is_whitespace) {
+        return Err(usage_error(builtin.usage));
+    }
+    let invitee = sqlx::query!(
+        r#"select u.id, exists(
+            select 1 from group_permissions p where p.group_id = $2 and p.user_id = u.id
+        ) as "member!"
+        from users u where u.username = $1 and u.disabled_at is null"#,
+        username,
+        ctx.group_id
+    )
+    .fetch_optional(&ctx.app.pool)
+    .await
+    .map_err(internal_error)?
+    .ok_or_else(|| HttpResponse::NotFound().body(format!("there's no user @{username}")))?;
+    if invitee.member {
+        return Ok(CommandReply {
+            ephemeral: Some(format!("@{username} is already in the group")),
+            ..Default::default()
+        });
+    }
+
+    let roles = ["writer".to_owned()];
+    let inviter = ctx.username().await?;
+    let result = async {
+        let mut tx = ctx.app.pool.begin().await?;
+        add_to_group(ctx.group_id, invitee.id, ctx.user_id, &roles, &mut tx).await?;
+        let message_id = write_system_message(
+            &mut tx,
+            ctx.group_id,
+            ctx.user_id,
+            &format!("{inviter} added {username} to the group"),
+        )
+        .await?;
+        tx.commit().await?;
+        Ok(message_id)
+    }
+    .await;
+    let message_id = result.map_err(internal_error)?;
+    audit_event(
+        &ctx.app.pool,
+        ctx.req,
+        Some(ctx.user_id),
+        "group.member.add",
+        Some(format!("user:{}", invitee.id)),
+        Some(json!({ "group_id": ctx.group_id, "roles": roles })),
+    )
+    .await;
+    Ok(CommandReply {
+        message_id: Some(message_id),
+        ..Default::default()
+    })
+}
+
+async fn leave(ctx: &CommandContext<'_>) -> Result<CommandReply, HttpResponse> {
+    let username = ctx.username().await?;
+    let result = async {
+        let mut tx = ctx.app.pool.begin().await?;
+        if !remove_from_group(ctx.group_id, ctx.user_id, &mut tx).await? {
+            return Ok(None);
+        }
+        let message_id = write_system_message(
+            &mut tx,
+            ctx.group_id,
+            ctx.user_id,
+            &format!("{username} left the group"),
+        )
+        .await?;
+        tx.commit().await?;
+        Ok(Some(message_id))
+    }
+    .await;
+    match result.map_err(internal_error)? {
+        Some(message_id) => {
+            audit_event(
+                &ctx.app.pool,
+                ctx.req,
+                Some(ctx.user_id),
+                "group.member.leave",
+                Some(format!("user:{}", ctx.user_id)),
+                Some(json!({ "group_id": ctx.group_id })),
+            )
+            .await;
+            Ok(CommandReply {
+                message_id: Some(message_id),
+                ..Default::default()
+            })
+        }
+        // owners get here too, their access comes from owning the group
+        None => Err(HttpResponse::BadRequest().body(
+            "you can't leave a group you own or aren't a member of, hand ownership over first",
+        )),
+    }
+}
+
+/// Reads `30m`, `2h` or `7d`
+pub fn parse_duration(text: &str) -> Option<Duration> {
+    let unit = text.chars().last()?;
+    let count: i64 = text[..text.len() - unit.len_utf8()]
+        .parse()
+        .ok()
+        .filter(|count| (1..=100_000).contains(count))?;
+    match unit {
+        'm' => Some(Duration::minutes(count)),
+        'h' => Some(Duration::hours(count)),
+        'd' => Some(Duration::days(count)),
+        _ => None,
+    }
+}
+
+async fn mute(builtin: &Builtin, ctx: &CommandContext<'_>) -> Result<CommandReply, HttpResponse> {
+    let until: Option<DateTime<Utc>> = match ctx.args {
+        "" => None,
+        args => Some(Utc::now() + parse_duration(args).ok_or_else(|| usage_error(builtin.usage))?),
+    };
+    // without a duration it flips, muting for good or unmuting
+    let muted = sqlx::query!(
+        r#"update group_permissions set
+            muted_at = case
+                when $3::timestamptz is not null then now()
+                when muted_at is not null and (muted_until is null or muted_until > now()) then null
+                else now() end,
+            muted_until = $3
+        where group_id = $1 and user_id = $2 returning muted_at"#,
+        ctx.group_id,
+        ctx.user_id,
+        until
+    )
+    .fetch_optional(&ctx.app.pool)
+    .await
+    .map_err(internal_error)?
+    .ok_or_else(|| HttpResponse::BadRequest().body("only members can mute a group"))?
+    .muted_at
+    .is_some();
+    let ephemeral = match (muted, until) {
+        (false, _) => "group unmuted".to_owned(),
+        (true, Some(until)) => format!("group muted until {}", until.to_rfc3339()),
+        (true, None) => "group muted, run /mute again to unmute it".to_owned(),
+    };
+    Ok(CommandReply {
+        ephemeral: Some(ephemeral),
+        ..Default::default()
+    })
+}
+
+async fn poll(builtin: &Builtin, ctx: &CommandContext<'_>) -> Result<CommandReply, HttpResponse> {
+    let mut multiple_choice = false;
+    let mut anonymous = false;
+    let mut closes_at = None;
+    let mut args = split_args(ctx.args).into_iter().peekable();
+    while let Some(flag) = args.next_if(|arg| arg.starts_with("--")) {
+        match flag.as_str() {
+            "--multiple" => multiple_choice = true,
+            "--anonymous" => anonymous = true,
+            "--closes" => {
+                let after = args
+                    .next()
+                    .and_then(|arg| parse_duration(&arg))
+                    .ok_or_else(|| usage_error(builtin.usage))?;
+                closes_at = Some(Utc::now() + after);
+            }
+            _ => return Err(usage_error(builtin.usage)),
+        }
+    }
+    let question = args
+        .next()
+        .filter(|question| !question.is_empty())
+        .ok_or_else(|| usage_error(builtin.usage))?;
+    // the option count & duplicates are checked when the message is validated
+    let content = MessageContent::Poll {
+        question,
+        options: args.filter(|option| !option.is_empty()).collect(),
+        multiple_choice,
+        anonymous,
+        closes_at,
+    };
+    let message_id = write_message(ctx.group_id, ctx.user_id, content, &ctx.app.pool).await?;
+    Ok(CommandReply {
+        message_id: Some(message_id),
+        ..Default::default()
+    })
+}
+
+async fn help(ctx: &CommandContext<'_>) -> Result<CommandReply, HttpResponse> {
+    let mut lines: Vec<String> = BUILTINS
+        .iter()
+        .map(|builtin| format!("{} - {}", builtin.usage, builtin.description))
+        .collect();
+    for (name, description) in list_command_names(ctx.group_id, &ctx.app.pool)
+        .await
+        .map_err(internal_error)?
+    {
+        lines.push(format!("/{name} - {description}"));
+    }
+    Ok(CommandReply {
+        ephemeral: Some(lines.join("\n")),
+        ..Default::default()
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn durations_are_parsed() {
+        assert_eq!(parse_duration("30m"), Some(Duration::minutes(30)));
+        assert_eq!(parse_duration("2h"), Some(Duration::hours(2)));
+        assert_eq!(parse_duration("7d"), Some(Duration::days(7)));
+        assert_eq!(parse_duration("0h"), None);
+        assert_eq!(parse_duration("h"), None);
+        assert_eq!(parse_duration(""), None);
+        assert_eq!(parse_duration("5w"), None);
+        assert_eq!(parse_duration("5é"), None);
+    }
+}
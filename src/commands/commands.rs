@@ -0,0 +1,200 @@
+use super::builtin::{run_builtin, BUILTINS};
+use super::custom::{find_command, run_custom};
+use crate::groups::roles::{has_capability, Capability};
+use crate::server::app_state::AppState;
+use actix_web::{HttpRequest, HttpResponse};
+use serde::Serialize;
+
+/// A `/name args` message, `name` is lowercased
+#[derive(Debug, PartialEq)]
+pub struct Invocation {
+    pub name: String,
+    pub args: String,
+}
+
+/// Reads a message as a command if it starts with a single `/`. A doubled
+/// `//` is how a message that really starts with a slash is written, see
+/// `literal_text`
+pub fn parse_command(text: &str) -> Option<Invocation> {
+    let rest = text.trim_start().strip_prefix('/')?;
+    if rest.starts_with('/') {
+        return None;
+    }
+    let (name, args) = rest.split_once(char::is_whitespace).unwrap_or((rest, ""));
+    let name = name.to_lowercase();
+    if !valid_command_name(&name) {
+        return None;
+    }
+    Some(Invocation {
+        name,
+        args: args.trim().to_owned(),
+    })
+}
+
+/// What a message that isn't a command is stored as, `//etc` becomes `/etc`
+pub fn literal_text(text: String) -> String {
+    match text.trim_start().strip_prefix("//") {
+        Some(rest) => format!("/{rest}"),
+        None => text,
+    }
+}
+
+pub fn valid_command_name(name: &str) -> bool {
+    (1..=32).contains(&name.len())
+        && name
+            .chars()
+            .all(|c| c.is_ascii_lowercase() || c.is_ascii_digit() || c == '-' || c == '_')
+}
+
+/// Splits command arguments on whitespace, keeping `"quoted phrases"` together
+pub fn split_args(args: &str) -> Vec<String> {
+    let mut parts = Vec::new();
+    let mut current = String::new();
+    let mut quoted = false;
+    let mut started = false;
+    for c in args.chars() {
+        match c {
+            '"' => {
+                quoted = !quoted;
+                started = true;
+            }
+            c if c.is_whitespace() && !quoted => {
+                if started {
+                    parts.push(std::mem::take(&mut current));
+                    started = false;
+                }
+            }
+            c => {
+                current.push(c);
+                started = true;
+            }
+        }
+    }
+    if started {
+        parts.push(current);
+    }
+    parts
+}
+
+/// What running a command did, sent back to whoever ran it
+#[derive(Serialize, Debug, Default)]
+pub struct CommandReply {
+    pub command: String,
+    /// the message it posted into the group, if any
+    pub message_id: Option<i32>,
+    /// shown only to whoever ran the command & never stored
+    pub ephemeral: Option<String>,
+}
+
+/// Everything a command gets to work with
+pub struct CommandContext<'a> {
+    pub group_id: i32,
+    pub user_id: i32,
+    pub args: &'a str,
+    pub app: &'a AppState,
+    pub req: &'a HttpRequest,
+}
+
+impl CommandContext<'_> {
+    pub async fn username(&self) -> Result<String, HttpResponse> {
+        sqlx::query_scalar!(r#"select username from users where id = $1"#, self.user_id)
+            .fetch_one(&self.app.pool)
+            .await
+            .map_err(|e| HttpResponse::InternalServerError().body(e.to_string()))
+    }
+}
+
+pub fn usage_error(usage: &str) -> HttpResponse {
+    HttpResponse::BadRequest().body(format!("usage: {usage}"))
+}
+
+/// Finds the command, built in first then the group's own, checks the user
+/// has the capability it needs in the group & runs it
+pub async fn run_command(
+    group_id: i32,
+    user_id: i32,
+    invocation: Invocation,
+    app: &AppState,
+    req: &HttpRequest,
+) -> Result<CommandReply, HttpResponse> {
+    let ctx = CommandContext {
+        group_id,
+        user_id,
+        args: &invocation.args,
+        app,
+        req,
+    };
+    if let Some(builtin) = BUILTINS.iter().find(|b| b.name == invocation.name) {
+        require_capability(&ctx, &invocation.name, builtin.capability).await?;
+        return run_builtin(builtin, &ctx).await;
+    }
+    let command = find_command(group_id, &invocation.name, &app.pool)
+        .await?
+        .ok_or_else(|| {
+            HttpResponse::NotFound().body(format!(
+                "there's no /{} command in this group, /help lists them",
+                invocation.name
+            ))
+        })?;
+    // a capability stored by an older version that no longer exists allows nobody
+    let capability = Capability::from_name(&command.capability).ok_or_else(|| {
+        HttpResponse::Unauthorized().body(format!("/{} can't be run", invocation.name))
+    })?;
+    require_capability(&ctx, &invocation.name, capability).await?;
+    run_custom(&command, &ctx).await
+}
+
+async fn require_capability(
+    ctx: &CommandContext<'_>,
+    name: &str,
+    capability: Capability,
+) -> Result<(), HttpResponse> {
+    match has_capability(ctx.user_id, ctx.group_id, capability, &ctx.app.pool).await {
+        true => Ok(()),
+        false => Err(HttpResponse::Unauthorized().body(format!(
+            "user {} is not permitted to run /{} in group {}",
+            ctx.user_id, name, ctx.group_id
+        ))),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn commands_are_parsed() {
+        assert_eq!(
+            parse_command("/Topic  Release week "),
+            Some(Invocation {
+                name: "topic".to_owned(),
+                args: "Release week".to_owned()
+            })
+        );
+        assert_eq!(
+            parse_command("/leave"),
+            Some(Invocation {
+                name: "leave".to_owned(),
+                args: String::new()
+            })
+        );
+        assert_eq!(parse_command("hello /me"), None);
+        assert_eq!(parse_command("//not a command"), None);
+        assert_eq!(parse_command("/usr/bin is a path"), None);
+    }
+
+    #[test]
+    fn doubled_slash_is_literal() {
+        assert_eq!(literal_text("//shrug".to_owned()), "/shrug");
+        assert_eq!(literal_text("plain".to_owned()), "plain");
+    }
+
+    #[test]
+    fn quoted_args_stay_together() {
+        assert_eq!(
+            split_args(r#""Where for lunch?" Pizza "Thai food"  """#),
+            vec!["Where for lunch?", "Pizza", "Thai food", ""]
+        );
+        assert!(split_args("   ").is_empty());
+    }
+}
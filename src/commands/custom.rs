@@ -0,0 +1,361 @@
+use super::builtin::BUILTINS;
+use super::commands::{valid_command_name, CommandContext, CommandReply};
+use crate::audit::audit::audit_event;
+use crate::auth::generate_token;
+use crate::extractors::extractors::User;
+use crate::groups::roles::Capability;
+use crate::messages::messages::write_message;
+use crate::server::app_state::AppState;
+use crate::tokens::bots::create_group_bot;
+use crate::webhooks::destinations::{check_destination, outgoing_client};
+use crate::webhooks::incoming::{require_manage_group, WebhookPayload};
+use crate::webhooks::signing::{sign, SIGNATURE_HEADER, TIMESTAMP_HEADER};
+use actix_web::{delete, get, post, web, HttpRequest, HttpResponse, Responder};
+use chrono::{DateTime, Utc};
+use serde::{Deserialize, Serialize};
+use serde_json::json;
+use sqlx::{Pool, Postgres};
+
+/// A group's own command, as needed to run it
+pub struct CustomCommand {
+    pub name: String,
+    pub url: String,
+    pub secret: String,
+    pub capability: String,
+    pub bot_user_id: i32,
+}
+
+pub async fn find_command(
+    group_id: i32,
+    name: &str,
+    pool: &Pool<Postgres>,
+) -> Result<Option<CustomCommand>, HttpResponse> {
+    sqlx::query_as!(
+        CustomCommand,
+        r#"select name, url, secret, capability, bot_user_id from group_commands
+        where group_id = $1 and name = $2 and disabled_at is null"#,
+        group_id,
+        name
+    )
+    .fetch_optional(pool)
+    .await
+    .map_err(|e| HttpResponse::InternalServerError().body(e.to_string()))
+}
+
+/// Names & descriptions of a group's own commands, for `/help`
+pub async fn list_command_names(
+    group_id: i32,
+    pool: &Pool<Postgres>,
+) -> Result<Vec<(String, String)>, sqlx::Error> {
+    let rows = sqlx::query!(
+        r#"select name, description from group_commands
+        where group_id = $1 and disabled_at is null order by name"#,
+        group_id
+    )
+    .fetch_all(pool)
+    .await?;
+    Ok(rows
+        .into_iter()
+        .map(|row| (row.name, row.description))
+        .collect())
+}
+
+#[derive(Deserialize, Default, PartialEq, Debug)]
+#[serde(rename_all = "snake_case")]
+enum ResponseType {
+    /// posted into the group by the command's bot
+    InGroup,
+    #[default]
+    Ephemeral,
+}
+
+/// What a command endpoint answers with, a message like an incoming webhook
+/// takes plus where it should go. An empty body means there's nothing to say
+#[derive(Deserialize)]
+struct EndpointResponse {
+    #[serde(default)]
+    response_type: ResponseType,
+    #[serde(flatten)]
+    message: WebhookPayload,
+}
+
+/// Sends the invocation to the command's endpoint, signed like an outgoing
+/// webhook, & passes its answer on
+pub async fn run_custom(
+    command: &CustomCommand,
+    ctx: &CommandContext<'_>,
+) -> Result<CommandReply, HttpResponse> {
+    let body = json!({
+        "command": format!("/{}", command.name),
+        "text": ctx.args,
+        "group_id": ctx.group_id,
+        "user_id": ctx.user_id,
+        "username": ctx.username().await?,
+    })
+    .to_string();
+    let timestamp = Utc::now().timestamp();
+    let allow_internal = ctx.app.config.webhook_allow_internal_urls;
+    let client = outgoing_client(ctx.app.config.command_timeout, allow_internal);
+    let failed = |reason: String| {
+        log::warn!("/{} endpoint failed: {reason}", command.name);
+        HttpResponse::BadGateway().body(format!("/{} didn't answer properly", command.name))
+    };
+    // checked again, the host may have been pointed somewhere else since
+    check_destination(&command.url, allow_internal)
+        .await
+        .map_err(failed)?;
+    let resp = client
+        .post(&command.url)
+        .header(reqwest::header::CONTENT_TYPE, "application/json")
+        .header(TIMESTAMP_HEADER, timestamp.to_string())
+        .header(
+            SIGNATURE_HEADER,
+            sign(&command.secret, timestamp, body.as_bytes()),
+        )
+        .body(body)
+        .send()
+        .await
+        .map_err(|e| failed(e.to_string()))?;
+    if !resp.status().is_success() {
+        return Err(failed(format!("answered {}", resp.status())));
+    }
+    let answer = resp.bytes().await.map_err(|e| failed(e.to_string()))?;
+
+    let mut reply = CommandReply {
+        command: command.name.clone(),
+        ..Default::default()
+    };
+    if answer.iter().all(u8::is_ascii_whitespace) {
+        return Ok(reply);
+    }
+    let answer: EndpointResponse =
+        serde_json::from_slice(&answer).map_err(|e| failed(e.to_string()))?;
+    let content = answer
+        .message
+        .into_content()
+        .map_err(|e| failed(e.to_owned()))?;
+    match answer.response_type {
+        ResponseType::Ephemeral => reply.ephemeral = Some(content.text()),
+        ResponseType::InGroup => {
+            reply.message_id = Some(
+                write_message(ctx.group_id, command.bot_user_id, content, &ctx.app.pool).await?,
+            )
+        }
+    }
+    Ok(reply)
+}
+
+#[derive(Serialize)]
+struct GroupCommand {
+    id: i32,
+    name: String,
+    description: String,
+    url: String,
+    capability: String,
+    bot_user_id: i32,
+    created_by: Option<i32>,
+    created_at: DateTime<Utc>,
+}
+
+#[get("{group_id}/commands")]
+pub async fn handle_list_commands(
+    group_id: web::Path<i32>,
+    user: User,
+    app: web::Data<AppState>,
+) -> impl Responder {
+    let group_id = group_id.into_inner();
+    if let Some(resp) = require_manage_group(user.user_id, group_id, &app).await {
+        return resp;
+    }
+    match sqlx::query_as!(
+        GroupCommand,
+        r#"select id, name, description, url, capability, bot_user_id, created_by, created_at
+        from group_commands where group_id = $1 and disabled_at is null order by name"#,
+        group_id
+    )
+    .fetch_all(&app.pool)
+    .await
+    {
+        Ok(commands) => HttpResponse::Ok().json(commands),
+        Err(e) => HttpResponse::InternalServerError().body(e.to_string()),
+    }
+}
+
+#[derive(Deserialize)]
+struct CreateCommandRequest {
+    name: String,
+    #[serde(default)]
+    description: String,
+    url: String,
+    /// what members need to run it, `write` if not given
+    capability: Option<Capability>,
+}
+
+/// Sent once when a command is added, the secret can't be seen again
+#[derive(Serialize)]
+struct CreatedCommand {
+    id: i32,
+    secret: String,
+}
+
+/// Adds a command to the group, along with the bot its replies are posted as
+#[post("{group_id}/commands")]
+pub async fn handle_create_command(
+    group_id: web::Path<i32>,
+    request: web::Json<CreateCommandRequest>,
+    user: User,
+    app: web::Data<AppState>,
+    req: HttpRequest,
+) -> impl Responder {
+    let group_id = group_id.into_inner();
+    if let Some(resp) = require_manage_group(user.user_id, group_id, &app).await {
+        return resp;
+    }
+    let name = request.name.trim_start_matches('/').to_lowercase();
+    if !valid_command_name(&name) {
+        return HttpResponse::BadRequest()
+            .body("command names are 1 to 32 lowercase letters, digits, - or _");
+    }
+    if BUILTINS.iter().any(|builtin| builtin.name == name) {
+        return HttpResponse::Conflict().body(format!("/{name} is a built in command"));
+    }
+    if let Err(reason) =
+        check_destination(&request.url, app.config.webhook_allow_internal_urls).await
+    {
+        return HttpResponse::BadRequest().body(reason);
+    }
+    let capability = request.capability.unwrap_or(Capability::Write);
+    let secret = generate_token();
+
+    let result = async {
+        let mut tx = app.pool.begin().await?;
+        let bot_id = create_group_bot(
+            "command",
+            &format!("/{name}"),
+            group_id,
+            user.user_id,
+            &mut tx,
+        )
+        .await?;
+        let id = sqlx::query_scalar!(
+            r#"insert into group_commands
+            (group_id, name, description, url, secret, capability, bot_user_id, created_by)
+            values ($1, $2, $3, $4, $5, $6, $7, $8)
+            on conflict (group_id, name) where disabled_at is null do nothing returning id"#,
+            group_id,
+            name,
+            request.description,
+            request.url,
+            secret,
+            capability.as_str(),
+            bot_id,
+            user.user_id
+        )
+        .fetch_optional(&mut *tx)
+        .await?;
+        // dropping the transaction without committing takes the bot back out
+        if id.is_some() {
+            tx.commit().await?;
+        }
+        Ok::<_, sqlx::Error>(id)
+    }
+    .await;
+    let id = match result {
+        Ok(Some(id)) => id,
+        Ok(None) => {
+            return HttpResponse::Conflict().body(format!("the group already has a /{name}"))
+        }
+        Err(e) => return HttpResponse::InternalServerError().body(e.to_string()),
+    };
+    audit_event(
+        &app.pool,
+        &req,
+        Some(user.user_id),
+        "command.create",
+        Some(format!("group:{group_id}")),
+        Some(
+            json!({ "command_id": id, "name": name, "url": request.url, "capability": capability }),
+        ),
+    )
+    .await;
+    HttpResponse::Created().json(CreatedCommand { id, secret })
+}
+
+/// Takes a command out of the group for good, & its bot with it
+#[delete("{group_id}/commands/{command_id}")]
+pub async fn handle_delete_command(
+    path: web::Path<(i32, i32)>,
+    user: User,
+    app: web::Data<AppState>,
+    req: HttpRequest,
+) -> impl Responder {
+    let (group_id, command_id) = path.into_inner();
+    if let Some(resp) = require_manage_group(user.user_id, group_id, &app).await {
+        return resp;
+    }
+    let result = async {
+        let mut tx = app.pool.begin().await?;
+        let bot_id = sqlx::query_scalar!(
+            r#"update group_commands set disabled_at = now()
+            where id = $1 and group_id = $2 and disabled_at is null returning bot_user_id"#,
+            command_id,
+            group_id
+        )
+        .fetch_optional(&mut *tx)
+        .await?;
+        if let Some(bot_id) = bot_id {
+            sqlx::query!(
+                r#"update users set disabled_at = coalesce(disabled_at, now()) where id = $1"#,
+                bot_id
+            )
+            .execute(&mut *tx)
+            .await?;
+        }
+        tx.commit().await?;
+        Ok::<_, sqlx::Error>(bot_id)
+    }
+    .await;
+    match result {
+        Ok(Some(_)) => {
+            audit_event(
+                &app.pool,
+                &req,
+                Some(user.user_id),
+                "command.delete",
+                Some(format!("group:{group_id}")),
+                Some(json!({ "command_id": command_id })),
+            )
+            .await;
+            HttpResponse::Ok().body("")
+        }
+        Ok(None) => HttpResponse::NotFound().body(format!("no command {command_id}")),
+        Err(e) => HttpResponse::InternalServerError().body(e.to_string()),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::messages::content::MessageContent;
+
+    #[test]
+    fn endpoint_responses_default_to_ephemeral() {
+        let answer: EndpointResponse =
+            serde_json::from_str(r#"{"text": "ticket #12 opened"}"#).unwrap();
+        assert_eq!(answer.response_type, ResponseType::Ephemeral);
+        assert!(matches!(
+            answer.message.into_content(),
+            Ok(MessageContent::Text { content }) if content == "ticket #12 opened"
+        ));
+
+        let answer: EndpointResponse = serde_json::from_str(
+            r#"{"response_type": "in_group", "type": "blocks", "blocks": [], "fallback": "x"}"#,
+        )
+        .unwrap();
+        assert_eq!(answer.response_type, ResponseType::InGroup);
+        assert!(matches!(
+            answer.message.into_content(),
+            Ok(MessageContent::Blocks { .. })
+        ));
+    }
+}
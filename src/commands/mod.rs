@@ -0,0 +1,3 @@
+pub mod builtin;
+pub mod commands;
+pub mod custom;
@@ -1,7 +1,7 @@
 use serde::{Deserialize, Serialize};
 use sqlx;
 
-#[derive(sqlx::Type, Serialize, Deserialize, Debug, Clone, Copy)]
+#[derive(sqlx::Type, Serialize, Deserialize, Debug, Clone, Copy, PartialEq)]
 #[sqlx(type_name = "user_role", rename_all = "lowercase")]
 pub enum UserRole {
     ADMIN,
@@ -17,3 +17,72 @@ pub enum GroupType {
     TEAM,
     DM,
 }
+
+#[derive(sqlx::Type, Serialize, Deserialize, Debug, Clone, Copy)]
+#[sqlx(type_name = "message_type", rename_all = "lowercase")]
+pub enum MessageType {
+    TEXT,
+    SYSTEM,
+    BLOCKS,
+    POLL,
+}
+
+#[derive(sqlx::Type, Serialize, Deserialize, Debug, Clone, Copy, PartialEq)]
+#[sqlx(type_name = "join_request_status", rename_all = "lowercase")]
+pub enum JoinRequestStatus {
+    PENDING,
+    APPROVED,
+    DENIED,
+    CANCELLED,
+}
+
+#[derive(sqlx::Type, Serialize, Deserialize, Debug, Clone, Copy, PartialEq)]
+#[sqlx(type_name = "user_token_purpose", rename_all = "lowercase")]
+#[allow(non_camel_case_types)]
+pub enum UserTokenPurpose {
+    VERIFY_EMAIL,
+    RESET_PASSWORD,
+}
+
+#[derive(sqlx::Type, Serialize, Deserialize, Debug, Clone, Copy, PartialEq)]
+#[sqlx(type_name = "webhook_delivery_status", rename_all = "lowercase")]
+pub enum WebhookDeliveryStatus {
+    PENDING,
+    DELIVERED,
+    DEAD,
+}
+
+#[derive(sqlx::Type, Serialize, Deserialize, Debug, Clone, Copy, PartialEq)]
+#[sqlx(type_name = "scheduled_message_status", rename_all = "lowercase")]
+pub enum ScheduledMessageStatus {
+    PENDING,
+    SENT,
+    CANCELLED,
+    FAILED,
+}
+
+#[derive(sqlx::Type, Serialize, Deserialize, Debug, Clone, Copy, PartialEq)]
+#[sqlx(type_name = "export_format", rename_all = "lowercase")]
+pub enum ExportFormat {
+    JSONL,
+    CSV,
+    HTML,
+}
+
+#[derive(sqlx::Type, Serialize, Deserialize, Debug, Clone, Copy, PartialEq)]
+#[sqlx(type_name = "export_status", rename_all = "lowercase")]
+pub enum ExportStatus {
+    PENDING,
+    RUNNING,
+    DONE,
+    FAILED,
+}
+
+#[derive(sqlx::Type, Serialize, Deserialize, Debug, Clone, Copy, PartialEq)]
+#[sqlx(type_name = "presence_state", rename_all = "lowercase")]
+pub enum PresenceState {
+    ONLINE,
+    AWAY,
+    DND,
+    OFFLINE,
+}
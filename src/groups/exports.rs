@@ -0,0 +1,586 @@
+use super::roles::{has_capability, Capability};
+use crate::admin::admin::require_platform_admin;
+use crate::audit::audit::audit_event;
+use crate::custom_types::{ExportFormat, ExportStatus, MessageType};
+use crate::extractors::extractors::User;
+use crate::server::app_state::AppState;
+use crate::server::config::Config;
+use actix_web::http::header::{ContentDisposition, DispositionParam, DispositionType};
+use actix_web::{get, post, web, HttpRequest, HttpResponse, Responder};
+use chrono::{DateTime, Utc};
+use futures::TryStreamExt;
+use serde::{Deserialize, Serialize};
+use serde_json::json;
+use sqlx::{Pool, Postgres};
+use std::path::{Path, PathBuf};
+use std::time::Instant;
+use tokio::io::{AsyncReadExt, AsyncWriteExt, BufWriter};
+use uuid::Uuid;
+
+/// Bytes sent per chunk when an export is downloaded
+const DOWNLOAD_CHUNK: usize = 64 * 1024;
+
+/// Group admins (the `export` capability) & platform admins can export a group
+async fn require_export(
+    user_id: i32,
+    group_id: i32,
+    pool: &Pool<Postgres>,
+) -> Option<HttpResponse> {
+    if has_capability(user_id, group_id, Capability::Export, pool).await {
+        return None;
+    }
+    match require_platform_admin(user_id, pool).await {
+        Ok(_) => None,
+        Err(_) => Some(HttpResponse::Unauthorized().body(format!(
+            "user {} is not permitted to export group {}",
+            user_id, group_id
+        ))),
+    }
+}
+
+/// One message as it's written to an export
+#[derive(Serialize)]
+struct ExportRow {
+    id: i32,
+    sent_at: Option<DateTime<Utc>>,
+    sender_user_id: i32,
+    sender_username: String,
+    sender_display_name: String,
+    message_type: MessageType,
+    content: String,
+    /// blocks, poll options & anything else that isn't plain text
+    payload: Option<serde_json::Value>,
+    pinned_at: Option<DateTime<Utc>>,
+}
+
+impl ExportFormat {
+    fn extension(&self) -> &'static str {
+        match self {
+            ExportFormat::JSONL => "jsonl",
+            ExportFormat::CSV => "csv",
+            ExportFormat::HTML => "html",
+        }
+    }
+
+    fn content_type(&self) -> &'static str {
+        match self {
+            ExportFormat::JSONL => "application/x-ndjson",
+            ExportFormat::CSV => "text/csv; charset=utf-8",
+            ExportFormat::HTML => "text/html; charset=utf-8",
+        }
+    }
+
+    /// What comes before the first message
+    fn header(&self, group_name: &str) -> String {
+        match self {
+            ExportFormat::JSONL => String::new(),
+            ExportFormat::CSV => "id,sent_at,sender_user_id,sender_username,sender_display_name,\
+                message_type,content,payload,pinned_at\n"
+                .to_owned(),
+            ExportFormat::HTML => format!(
+                "<!doctype html>\n<html><head><meta charset=\"utf-8\"><title>{0}</title>\n\
+                <style>body{{font-family:sans-serif;max-width:50em;margin:2em auto}}\
+                .message{{margin:.5em 0}}.meta{{color:#666;font-size:.85em}}\
+                .system{{font-style:italic;color:#666}}pre{{background:#f4f4f4;padding:.5em;overflow:auto}}</style>\n\
+                </head><body>\n<h1>{0}</h1>\n",
+                escape_html(group_name)
+            ),
+        }
+    }
+
+    fn row(&self, row: &ExportRow) -> String {
+        match self {
+            ExportFormat::JSONL => format!("{}\n", serde_json::to_string(row).unwrap_or_default()),
+            ExportFormat::CSV => csv_row(row),
+            ExportFormat::HTML => html_row(row),
+        }
+    }
+
+    /// What comes after the last message
+    fn footer(&self, exported_at: DateTime<Utc>) -> String {
+        match self {
+            ExportFormat::HTML => format!(
+                "<p class=\"meta\">exported {}</p>\n</body></html>\n",
+                exported_at.to_rfc3339()
+            ),
+            _ => String::new(),
+        }
+    }
+}
+
+fn csv_field(field: &str) -> String {
+    match field.contains([',', '"', '\n', '\r']) {
+        true => format!("\"{}\"", field.replace('"', "\"\"")),
+        false => field.to_owned(),
+    }
+}
+
+fn csv_row(row: &ExportRow) -> String {
+    let time = |time: Option<DateTime<Utc>>| time.map(|t| t.to_rfc3339()).unwrap_or_default();
+    let fields = [
+        row.id.to_string(),
+        time(row.sent_at),
+        row.sender_user_id.to_string(),
+        row.sender_username.clone(),
+        row.sender_display_name.clone(),
+        json!(row.message_type)
+            .as_str()
+            .unwrap_or_default()
+            .to_owned(),
+        row.content.clone(),
+        row.payload
+            .as_ref()
+            .map(|p| p.to_string())
+            .unwrap_or_default(),
+        time(row.pinned_at),
+    ];
+    let fields: Vec<String> = fields.iter().map(|field| csv_field(field)).collect();
+    format!("{}\n", fields.join(","))
+}
+
+fn escape_html(text: &str) -> String {
+    text.replace('&', "&amp;")
+        .replace('<', "&lt;")
+        .replace('>', "&gt;")
+        .replace('"', "&quot;")
+        .replace('\'', "&#39;")
+}
+
+fn html_row(row: &ExportRow) -> String {
+    let class = match row.message_type {
+        MessageType::SYSTEM => "message system",
+        _ => "message",
+    };
+    let sent_at = row.sent_at.map(|t| t.to_rfc3339()).unwrap_or_default();
+    let payload = match &row.payload {
+        Some(payload) => format!("<pre>{}</pre>", escape_html(&payload.to_string())),
+        None => String::new(),
+    };
+    format!(
+        "<div class=\"{class}\" id=\"m{}\"><div class=\"meta\">{} &middot; {} (@{})</div>\
+        <div>{}</div>{payload}</div>\n",
+        row.id,
+        escape_html(&sent_at),
+        escape_html(&row.sender_display_name),
+        escape_html(&row.sender_username),
+        escape_html(&row.content).replace('\n', "<br>"),
+    )
+}
+
+/// What a finished export wrote
+struct Written {
+    file_name: String,
+    size_bytes: i64,
+    message_count: i64,
+}
+
+/// A server claims an export for this long, renewing it as it writes
+const LEASE_MINS: i32 = 5;
+/// How often a running export renews its claim
+const LEASE_RENEW_SECS: u64 = 30;
+
+/// Pushes the claim on a running export back, failing once another server has
+/// taken it over
+async fn renew_lease(export_id: i32, attempt: &str, pool: &Pool<Postgres>) -> Result<(), String> {
+    let renewed = sqlx::query!(
+        r#"update group_exports set heartbeat_at = now()
+        where id = $1 and status = 'running' and attempt = $2"#,
+        export_id,
+        attempt
+    )
+    .execute(pool)
+    .await
+    .map_err(|e| e.to_string())?
+    .rows_affected();
+    match renewed {
+        0 => Err("another server took the export over".to_owned()),
+        _ => Ok(()),
+    }
+}
+
+/// Streams the group's messages out of the database into the export's file,
+/// a row at a time so a huge group never sits in memory. The file is written
+/// under a name of its own for this attempt, & only moved into place while
+/// the attempt still holds the export
+async fn write_export(
+    export_id: i32,
+    attempt: &str,
+    group_id: i32,
+    format: ExportFormat,
+    config: &Config,
+    pool: &Pool<Postgres>,
+) -> Result<Written, String> {
+    let dir = Path::new(&config.export_dir);
+    tokio::fs::create_dir_all(dir)
+        .await
+        .map_err(|e| e.to_string())?;
+    let file_name = format!("group-{group_id}-export-{export_id}.{}", format.extension());
+    // a half finished file is never downloaded, nor written by two servers at once
+    let partial = dir.join(format!("{file_name}.{attempt}.part"));
+    let written = write_rows(export_id, attempt, group_id, format, &partial, pool).await;
+    let moved = match written {
+        Ok((size_bytes, message_count)) => renew_lease(export_id, attempt, pool)
+            .await
+            .map(|_| (size_bytes, message_count)),
+        Err(e) => Err(e),
+    };
+    let (size_bytes, message_count) = match moved {
+        Ok(counts) => counts,
+        Err(e) => {
+            if let Err(remove) = tokio::fs::remove_file(&partial).await {
+                log::warn!("could not remove {}: {remove}", partial.display());
+            }
+            return Err(e);
+        }
+    };
+    tokio::fs::rename(&partial, dir.join(&file_name))
+        .await
+        .map_err(|e| e.to_string())?;
+    Ok(Written {
+        file_name,
+        size_bytes,
+        message_count,
+    })
+}
+
+/// Writes the export out to `path`, returning its size & how many messages it holds
+async fn write_rows(
+    export_id: i32,
+    attempt: &str,
+    group_id: i32,
+    format: ExportFormat,
+    path: &Path,
+    pool: &Pool<Postgres>,
+) -> Result<(i64, i64), String> {
+    let group_name =
+        sqlx::query_scalar!(r#"select group_name from groups where id = $1"#, group_id)
+            .fetch_one(pool)
+            .await
+            .map_err(|e| e.to_string())?;
+    let file = tokio::fs::File::create(path)
+        .await
+        .map_err(|e| e.to_string())?;
+    let mut out = BufWriter::new(file);
+
+    let mut rows = sqlx::query_as!(
+        ExportRow,
+        r#"select m.id, m.sent_at, m.sender_user_id, u.username as sender_username,
+        u.display_name as sender_display_name, m.message_type as "message_type: MessageType",
+        m.content, m.payload, m.pinned_at
+        from messages m join users u on u.id = m.sender_user_id
+        where m.group_id = $1 and (m.expires_at is null or m.expires_at > now())
+        order by m.sent_at, m.id"#,
+        group_id
+    )
+    .fetch(pool);
+    let mut message_count = 0;
+    let mut size_bytes = 0;
+    let mut renewed_at = Instant::now();
+    let header = format.header(&group_name);
+    out.write_all(header.as_bytes())
+        .await
+        .map_err(|e| e.to_string())?;
+    size_bytes += header.len() as i64;
+    while let Some(row) = rows.try_next().await.map_err(|e| e.to_string())? {
+        let line = format.row(&row);
+        out.write_all(line.as_bytes())
+            .await
+            .map_err(|e| e.to_string())?;
+        size_bytes += line.len() as i64;
+        message_count += 1;
+        if renewed_at.elapsed().as_secs() >= LEASE_RENEW_SECS {
+            renew_lease(export_id, attempt, pool).await?;
+            renewed_at = Instant::now();
+        }
+    }
+    let footer = format.footer(Utc::now());
+    out.write_all(footer.as_bytes())
+        .await
+        .map_err(|e| e.to_string())?;
+    size_bytes += footer.len() as i64;
+    out.flush().await.map_err(|e| e.to_string())?;
+    Ok((size_bytes, message_count))
+}
+
+/// Runs waiting exports one at a time until there are none left. Each is
+/// claimed with `skip locked` so servers don't run the same one, & one whose
+/// server stopped renewing its claim, e.g. because it died, is picked up again
+/// under a new attempt. The old attempt can't finish it after that
+pub async fn run_pending_exports(
+    config: &Config,
+    pool: &Pool<Postgres>,
+) -> Result<usize, sqlx::Error> {
+    let mut ran = 0;
+    loop {
+        let attempt = Uuid::new_v4().simple().to_string();
+        let claimed = sqlx::query!(
+            r#"update group_exports set status = 'running', started_at = now(),
+            heartbeat_at = now(), attempt = $1
+            where id = (
+                select id from group_exports
+                where status = 'pending' or (status = 'running'
+                    and coalesce(heartbeat_at, started_at) < now() - make_interval(mins => $2))
+                order by created_at limit 1 for update skip locked
+            ) returning id, group_id, format as "format: ExportFormat""#,
+            attempt,
+            LEASE_MINS
+        )
+        .fetch_optional(pool)
+        .await?;
+        let Some(export) = claimed else {
+            return Ok(ran);
+        };
+        let written = write_export(
+            export.id,
+            &attempt,
+            export.group_id,
+            export.format,
+            config,
+            pool,
+        )
+        .await;
+        match written {
+            Ok(written) => {
+                sqlx::query!(
+                    r#"update group_exports set status = 'done', file_name = $3, size_bytes = $4,
+                    message_count = $5, finished_at = now(), expires_at = $6
+                    where id = $1 and attempt = $2"#,
+                    export.id,
+                    attempt,
+                    written.file_name,
+                    written.size_bytes,
+                    written.message_count,
+                    Utc::now() + config.export_ttl
+                )
+                .execute(pool)
+                .await?;
+            }
+            Err(reason) => {
+                log::error!("export {} failed: {reason}", export.id);
+                sqlx::query!(
+                    r#"update group_exports set status = 'failed', error = $3, finished_at = now()
+                    where id = $1 and attempt = $2"#,
+                    export.id,
+                    attempt,
+                    reason
+                )
+                .execute(pool)
+                .await?;
+            }
+        }
+        ran += 1;
+    }
+}
+
+/// Removes the files of exports past their download window
+pub async fn purge_expired_exports(
+    config: &Config,
+    pool: &Pool<Postgres>,
+) -> Result<u64, sqlx::Error> {
+    let expired = sqlx::query!(
+        r#"update group_exports set file_name = null
+        where file_name is not null and expires_at < now() returning file_name as "file_name!""#
+    )
+    .fetch_all(pool)
+    .await?;
+    for export in expired.iter() {
+        let path = PathBuf::from(&config.export_dir).join(&export.file_name);
+        if let Err(e) = tokio::fs::remove_file(&path).await {
+            log::warn!("couldn't remove export {}: {e}", path.display());
+        }
+    }
+    Ok(expired.len() as u64)
+}
+
+#[derive(Serialize)]
+struct GroupExport {
+    id: i32,
+    group_id: i32,
+    requested_by: Option<i32>,
+    format: ExportFormat,
+    status: ExportStatus,
+    size_bytes: Option<i64>,
+    message_count: Option<i64>,
+    error: Option<String>,
+    created_at: DateTime<Utc>,
+    finished_at: Option<DateTime<Utc>>,
+    expires_at: Option<DateTime<Utc>>,
+}
+
+#[derive(Deserialize)]
+struct ExportRequest {
+    format: ExportFormat,
+}
+
+/// Queues an export of the group's whole history, poll it until it's `DONE`
+/// then download it
+#[post("{group_id}/exports")]
+pub async fn handle_create_export(
+    group_id: web::Path<i32>,
+    request: web::Json<ExportRequest>,
+    user: User,
+    app: web::Data<AppState>,
+    req: HttpRequest,
+) -> impl Responder {
+    let group_id = group_id.into_inner();
+    if let Some(resp) = require_export(user.user_id, group_id, &app.pool).await {
+        return resp;
+    }
+    let export = match sqlx::query_as!(
+        GroupExport,
+        r#"insert into group_exports (group_id, requested_by, format) values ($1, $2, $3)
+        returning id, group_id, requested_by, format as "format: ExportFormat",
+        status as "status: ExportStatus", size_bytes, message_count, error, created_at,
+        finished_at, expires_at"#,
+        group_id,
+        user.user_id,
+        request.format as ExportFormat
+    )
+    .fetch_one(&app.pool)
+    .await
+    {
+        Ok(export) => export,
+        Err(e) => return HttpResponse::InternalServerError().body(e.to_string()),
+    };
+    audit_event(
+        &app.pool,
+        &req,
+        Some(user.user_id),
+        "group.export.create",
+        Some(format!("group:{group_id}")),
+        Some(json!({ "export_id": export.id, "format": export.format })),
+    )
+    .await;
+    HttpResponse::Accepted().json(export)
+}
+
+#[get("{group_id}/exports")]
+pub async fn handle_list_exports(
+    group_id: web::Path<i32>,
+    user: User,
+    app: web::Data<AppState>,
+) -> impl Responder {
+    let group_id = group_id.into_inner();
+    if let Some(resp) = require_export(user.user_id, group_id, &app.pool).await {
+        return resp;
+    }
+    match sqlx::query_as!(
+        GroupExport,
+        r#"select id, group_id, requested_by, format as "format: ExportFormat",
+        status as "status: ExportStatus", size_bytes, message_count, error, created_at,
+        finished_at, expires_at
+        from group_exports where group_id = $1 order by created_at desc limit 50"#,
+        group_id
+    )
+    .fetch_all(&app.pool)
+    .await
+    {
+        Ok(exports) => HttpResponse::Ok().json(exports),
+        Err(e) => HttpResponse::InternalServerError().body(e.to_string()),
+    }
+}
+
+/// Streams a finished export's file back
+#[get("{group_id}/exports/{export_id}/download")]
+pub async fn handle_download_export(
+    path: web::Path<(i32, i32)>,
+    user: User,
+    app: web::Data<AppState>,
+    req: HttpRequest,
+) -> impl Responder {
+    let (group_id, export_id) = path.into_inner();
+    if let Some(resp) = require_export(user.user_id, group_id, &app.pool).await {
+        return resp;
+    }
+    let export = match sqlx::query!(
+        r#"select status as "status: ExportStatus", format as "format: ExportFormat", file_name
+        from group_exports where id = $1 and group_id = $2"#,
+        export_id,
+        group_id
+    )
+    .fetch_optional(&app.pool)
+    .await
+    {
+        Ok(Some(export)) => export,
+        Ok(None) => return HttpResponse::NotFound().body(format!("no export {export_id}")),
+        Err(e) => return HttpResponse::InternalServerError().body(e.to_string()),
+    };
+    let file_name = match (export.status, export.file_name) {
+        (ExportStatus::DONE, Some(file_name)) => file_name,
+        (ExportStatus::DONE, None) => {
+            return HttpResponse::Gone().body("the export has expired, start a new one")
+        }
+        (status, _) => {
+            return HttpResponse::Conflict().body(format!("the export is {status:?}, not DONE"))
+        }
+    };
+    let file = match tokio::fs::File::open(Path::new(&app.config.export_dir).join(&file_name)).await
+    {
+        Ok(file) => file,
+        Err(e) => return HttpResponse::InternalServerError().body(e.to_string()),
+    };
+    audit_event(
+        &app.pool,
+        &req,
+        Some(user.user_id),
+        "group.export.download",
+        Some(format!("group:{group_id}")),
+        Some(json!({ "export_id": export_id })),
+    )
+    .await;
+    let chunks = futures::stream::try_unfold(file, |mut file| async move {
+        let mut chunk = vec![0; DOWNLOAD_CHUNK];
+        let read = file.read(&mut chunk).await?;
+        chunk.truncate(read);
+        Ok::<_, std::io::Error>((read > 0).then(|| (web::Bytes::from(chunk), file)))
+    });
+    HttpResponse::Ok()
+        .content_type(export.format.content_type())
+        .insert_header(ContentDisposition {
+            disposition: DispositionType::Attachment,
+            parameters: vec![DispositionParam::Filename(file_name)],
+        })
+        .streaming(chunks)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn row(content: &str) -> ExportRow {
+        ExportRow {
+            id: 7,
+            sent_at: None,
+            sender_user_id: 2,
+            sender_username: "alice".to_owned(),
+            sender_display_name: "Alice <A>".to_owned(),
+            message_type: MessageType::TEXT,
+            content: content.to_owned(),
+            payload: None,
+            pinned_at: None,
+        }
+    }
+
+    #[test]
+    fn csv_fields_are_quoted_when_needed() {
+        assert_eq!(
+            csv_row(&row("hi, \"you\"\nthere")),
+            "7,,2,alice,Alice <A>,TEXT,\"hi, \"\"you\"\"\nthere\",,\n"
+        );
+    }
+
+    #[test]
+    fn html_is_escaped() {
+        let html = html_row(&row("<script>alert('x')</script>"));
+        assert!(html.contains("&lt;script&gt;alert(&#39;x&#39;)&lt;/script&gt;"));
+        assert!(html.contains("Alice &lt;A&gt;"));
+        assert!(!html.contains("<script>"));
+    }
+
+    #[test]
+    fn json_lines_are_one_per_message() {
+        let line = ExportFormat::JSONL.row(&row("two\nlines"));
+        assert_eq!(line.matches('\n').count(), 1);
+        assert!(line.ends_with('\n'));
+    }
+}
@@ -1,16 +1,24 @@
 use std::fmt::Debug;
 
+use crate::audit::audit::audit_event;
 use crate::custom_types::{GroupType, UserRole};
 use crate::extractors::extractors::User;
+use crate::messages::content::MessageContent;
+use crate::messages::ephemeral::MAX_DISAPPEAR_AFTER_MINS;
+use crate::messages::messages::{write_message, write_system_message};
+use crate::webhooks::events::{enqueue_event, WebhookEvent};
 use crate::server::app_state::AppState;
+use super::roles::{assign_roles, create_default_roles, has_capability, Capability};
 use actix_utils::future::{ok, Ready};
-use actix_web::{get, post, put, web, Error, FromRequest, HttpRequest, HttpResponse, Responder};
+use actix_web::{
+    delete, get, patch, post, put, web, Error, FromRequest, HttpRequest, HttpResponse, Responder,
+};
 use futures::executor::block_on;
 use serde::{Deserialize, Serialize};
-use serde_json;
+use serde_json::{self, json};
 use sqlx::postgres::any::AnyConnectionBackend;
 use sqlx::prelude::FromRow;
-use sqlx::{query, query_as, Execute, Executor, Pool, Postgres, Statement};
+use sqlx::{query, query_as, Execute, PgConnection, Pool, Postgres, Statement};
 
 #[derive(Serialize, Deserialize)]
 struct AllGroups {
@@ -21,10 +29,6 @@ struct AllGroups {
     #[serde(skip_serializing, skip_deserializing)]
     created_at: Option<chrono::DateTime<chrono::Utc>>,
     r#type: GroupType,
-    read: bool,
-    write: bool,
-    moderate: bool,
-    admin: bool,
 }
 
 #[get("")]
@@ -35,9 +39,20 @@ async fn handle_get_groups(app: web::Data<AppState>, user: User) -> HttpResponse
 
 #[derive(Serialize)]
 struct GetGroupsReturn {
+    id: i32,
     r#type: GroupType,
     group_name: String,
     parent_group_id: Option<i32>,
+    topic: Option<String>,
+    description: Option<String>,
+    avatar_url: Option<String>,
+    archived_at: Option<chrono::DateTime<chrono::Utc>>,
+    private: bool,
+    // whether the user muted the group with /mute, & until when if it wasn't for good
+    muted: bool,
+    muted_until: Option<chrono::DateTime<chrono::Utc>>,
+    disappear_after_mins: Option<i32>,
+    disappear_after_read: bool,
 }
 
 async fn get_groups(
@@ -49,12 +64,18 @@ async fn get_groups(
             sqlx::query_as!(
                 GetGroupsReturn,
                 r#"select
-            type as "type!: GroupType",
-            group_name, parent_group_id from groups g 
-            join group_permissions gp on gp.group_id = g.id where  gp.user_id = $1
-            and gp.read
+            g.id, g.type as "type!: GroupType",
+            group_name, parent_group_id, topic, description, avatar_url, archived_at, private,
+            disappear_after_mins, disappear_after_read,
+            coalesce((select p.muted_at is not null and (p.muted_until is null or p.muted_until > now())
+                from group_permissions p where p.group_id = g.id and p.user_id = $1), false) as "muted!",
+            (select p.muted_until from group_permissions p
+                where p.group_id = g.id and p.user_id = $1 and p.muted_until > now()) as muted_until
+            from groups g
+            where has_capability($1, g.id, $2)
             "#,
-                user_id
+                user_id,
+                Capability::Read.as_str()
             )
             .fetch_all(pool)
             .await
@@ -63,8 +84,11 @@ async fn get_groups(
             sqlx::query_as!(
                 GetGroupsReturn,
                 r#"select
-            type as "type!: GroupType",
-            group_name, parent_group_id from groups g"#
+            id, type as "type!: GroupType",
+            group_name, parent_group_id, topic, description, avatar_url, archived_at, private,
+            disappear_after_mins, disappear_after_read,
+            false as "muted!", null::timestamptz as muted_until
+            from groups g where deleted_at is null"#
             )
             .fetch_all(pool)
             .await
@@ -78,6 +102,8 @@ struct CreateGroupRequest {
     group_name: String,
     parent_group_id: Option<i32>,
     group_type: GroupType,
+    #[serde(default)]
+    private: bool,
 }
 
 #[post("/create")]
@@ -85,13 +111,16 @@ async fn handle_create_group(
     app: web::Data<AppState>,
     group_req: web::Json<CreateGroupRequest>,
     user: User,
+    req: HttpRequest,
 ) -> HttpResponse {
     create_group(
         &app.pool,
         &group_req.group_name,
         group_req.group_type,
         group_req.parent_group_id,
+        group_req.private,
         user,
+        &req,
     )
     .await
 }
@@ -106,38 +135,60 @@ async fn create_group(
     group_name: &String,
     group_type: GroupType,
     parent_group_id: Option<i32>,
+    private: bool,
     user: User,
+    req: &HttpRequest,
 ) -> HttpResponse {
+    match insert_group(pool, group_name, group_type, parent_group_id, private, user.user_id).await {
+        Ok(group_id) => {
+            audit_event(
+                pool,
+                req,
+                Some(user.user_id),
+                "group.create",
+                Some(format!("group:{group_id}")),
+                Some(json!({ "group_name": group_name, "private": private })),
+            )
+            .await;
+            HttpResponse::Ok().json(CreateGroupResponse { group_id })
+        }
+        Err(e) => HttpResponse::InternalServerError().body(e.to_string()),
+    }
+}
+
+/// Creates the group with its default roles, the creator owns it & is its admin
+async fn insert_group(
+    pool: &Pool<Postgres>,
+    group_name: &String,
+    group_type: GroupType,
+    parent_group_id: Option<i32>,
+    private: bool,
+    user_id: i32,
+) -> Result<i32, sqlx::Error> {
+    let mut tx = pool.begin().await?;
     let group = sqlx::query!(
-        r#"insert into groups(group_name, parent_group_id, created_by, type)
-    values ( $1, $2, $3, $4 ) returning id"#,
+        r#"insert into groups(group_name, parent_group_id, created_by, owner_id, type, private)
+    values ( $1, $2, $3, $3, $4, $5 ) returning id"#,
         group_name,
         parent_group_id,
-        user.user_id,
-        group_type as GroupType
+        user_id,
+        group_type as GroupType,
+        private
     )
-    .fetch_one(pool)
-    .await
-    .unwrap();
-    let result = sqlx::query!(
-        r"insert into group_permissions (user_id, group_id,
-        read, write, moderate, admin)
-        values (
-            $1, $2, $3, $4, $5, $6
-        )
-        returning id
-         ",
-        user.user_id,
+    .fetch_one(&mut *tx)
+    .await?;
+    create_default_roles(group.id, &mut tx).await?;
+    enqueue_event(
+        &mut *tx,
+        WebhookEvent::GroupCreated,
         group.id,
-        true,
-        true,
-        true,
-        false
+        json!({ "group_name": group_name, "type": group_type, "parent_group_id": parent_group_id,
+            "private": private, "created_by": user_id }),
     )
-    .fetch_one(pool)
-    .await;
-
-    HttpResponse::Ok().json(CreateGroupResponse { group_id: group.id })
+    .await?;
+    add_to_group(group.id, user_id, user_id, &["admin".to_owned()], &mut tx).await?;
+    tx.commit().await?;
+    Ok(group.id)
 }
 
 #[get("{group_id}/members")]
@@ -182,58 +233,581 @@ async fn get_group_members(group_id: i32, pool: &Pool<Postgres>) -> impl Respond
 
 #[put("{group_id}/message")]
 async fn write_to_group(
-    group_id: web::Path<u32>,
+    group_id: web::Path<i32>,
+    content: web::Json<MessageContent>,
     app: web::Data<AppState>,
     user: User,
-    req: HttpRequest,
 ) -> impl Responder {
-    let group_id = group_id.into_inner();
-    let pool = &app.pool;
-    let user_id = user.user_id;
-
-    format!("hi")
+    match write_message(group_id.into_inner(), user.user_id, content.into_inner(), &app.pool).await
+    {
+        Ok(id) => HttpResponse::Ok().body(id.to_string()),
+        Err(resp) => resp,
+    }
 }
 
 #[post("{group_id}/add_user/{user_id}")]
 async fn handle_add_to_group(
-    group_id: web::Path<i32>,
-    user_id: web::Path<i32>,
+    path: web::Path<(i32, i32)>,
     user: User,
     app: web::Data<AppState>,
+    req: HttpRequest,
 ) -> impl Responder {
+    let (group_id, user_id) = path.into_inner();
+    let roles = ["writer".to_owned()];
     // check user_id adding is allowed to add
-    if !is_user_admin(user.user_id, group_id.to_owned(), &app.pool).await {
+    if !has_capability(user.user_id, group_id, Capability::Invite, &app.pool).await {
         return HttpResponse::Unauthorized().body(format!(
             "user {} is not permitted to add users to group {}",
             user.user_id,
-            group_id.to_owned()
+            group_id
         ));
     }
-    match add_to_group(group_id.to_owned(), user_id.to_owned(), user.user_id, &app.pool).await {
-        Ok(_val) => HttpResponse::Ok().body(""),
+    let mut conn = match app.pool.acquire().await {
+        Ok(conn) => conn,
+        Err(e) => return HttpResponse::InternalServerError().body(e.to_string()),
+    };
+    match add_to_group(group_id, user_id, user.user_id, &roles, &mut conn).await {
+        Ok(_val) => {
+            audit_event(
+                &app.pool,
+                &req,
+                Some(user.user_id),
+                "group.member.add",
+                Some(format!("user:{user_id}")),
+                Some(json!({ "group_id": group_id, "roles": roles })),
+            )
+            .await;
+            HttpResponse::Ok().body("")
+        }
         Err(_e) => HttpResponse::InternalServerError().body("Unexpected error occured when trying to add user to group")
     }
 }
 
-async fn is_user_admin(user_id: i32, group_id: i32, pool: &Pool<Postgres>) -> bool {
-    match sqlx::query!(
-        r#"select moderate from group_permissions where group_id = $1 and user_id = $2"#,
+/// Adds a member to the group (if they aren't one already) & gives them the named roles
+pub async fn add_to_group(
+    group_id: i32,
+    user_id_added: i32,
+    user_id_adding: i32,
+    role_names: &[String],
+    conn: &mut PgConnection,
+) -> Result<(), sqlx::Error> {
+    let inserted = sqlx::query!(r#"insert into group_permissions (user_id, group_id, created_by) values
+    ( $1, $2, $3) on conflict (group_id, user_id) do nothing
+    "#, user_id_added, group_id, user_id_adding).execute(&mut *conn).await?;
+    if inserted.rows_affected() > 0 {
+        enqueue_event(
+            &mut *conn,
+            WebhookEvent::MemberJoined,
+            group_id,
+            json!({ "user_id": user_id_added, "added_by": user_id_adding, "roles": role_names }),
+        )
+        .await?;
+    }
+    assign_roles(group_id, user_id_added, role_names, conn).await
+}
+
+/// Takes a member out of a group along with their roles, `false` if they
+/// weren't in it. The owner can't be removed, ownership has to be handed over first
+pub async fn remove_from_group(
+    group_id: i32,
+    user_id: i32,
+    conn: &mut PgConnection,
+) -> Result<bool, sqlx::Error> {
+    let removed = sqlx::query!(
+        r#"delete from group_permissions p using groups g
+        where g.id = p.group_id and p.group_id = $1 and p.user_id = $2
+        and g.owner_id is distinct from $2"#,
+        group_id,
+        user_id
+    )
+    .execute(&mut *conn)
+    .await?
+    .rows_affected()
+        > 0;
+    if !removed {
+        return Ok(false);
+    }
+    sqlx::query!(
+        r#"delete from group_role_members m using group_roles r
+        where r.id = m.role_id and r.group_id = $1 and m.user_id = $2"#,
         group_id,
         user_id
     )
+    .execute(&mut *conn)
+    .await?;
+    enqueue_event(
+        &mut *conn,
+        WebhookEvent::MemberLeft,
+        group_id,
+        json!({ "user_id": user_id, "reason": "left" }),
+    )
+    .await?;
+    Ok(true)
+}
+
+
+#[derive(Deserialize, Default)]
+pub struct UpdateGroupRequest {
+    pub group_name: Option<String>,
+    pub topic: Option<String>,
+    pub description: Option<String>,
+    pub avatar_url: Option<String>,
+    pub archived: Option<bool>,
+    pub private: Option<bool>,
+    /// minutes new messages last, 0 stops them disappearing
+    pub disappear_after_mins: Option<i32>,
+    pub disappear_after_read: Option<bool>,
+}
+
+#[patch("{group_id}")]
+async fn handle_update_group(
+    group_id: web::Path<i32>,
+    update: web::Json<UpdateGroupRequest>,
+    user: User,
+    app: web::Data<AppState>,
+) -> impl Responder {
+    let group_id = group_id.into_inner();
+    if !has_capability(user.user_id, group_id, Capability::ManageGroup, &app.pool).await {
+        return HttpResponse::Unauthorized().body(format!(
+            "user {} is not permitted to edit group {}",
+            user.user_id, group_id
+        ));
+    }
+    if let Some(mins) = update.disappear_after_mins {
+        if !(0..=MAX_DISAPPEAR_AFTER_MINS).contains(&mins) {
+            return HttpResponse::BadRequest().body(format!(
+                "disappear_after_mins must be 0 to {MAX_DISAPPEAR_AFTER_MINS}"
+            ));
+        }
+    }
+    match update_group(group_id, user.user_id, update.into_inner(), &app.pool).await {
+        Ok(changes) => HttpResponse::Ok().json(changes),
+        Err(e) => HttpResponse::InternalServerError().body(e.to_string()),
+    }
+}
+
+/// Applies each requested metadata change & posts a system message for it
+/// into the group, all in one transaction. Returns the notices that were posted.
+pub async fn update_group(
+    group_id: i32,
+    user_id: i32,
+    update: UpdateGroupRequest,
+    pool: &Pool<Postgres>,
+) -> Result<Vec<String>, sqlx::Error> {
+    let mut tx = pool.begin().await?;
+    let username = sqlx::query!(r#"select username from users where id = $1"#, user_id)
+        .fetch_one(&mut *tx)
+        .await?
+        .username;
+    let mut changes = Vec::new();
+
+    if let Some(group_name) = update.group_name {
+        sqlx::query!(
+            r#"update groups set group_name = $1 where id = $2"#,
+            group_name,
+            group_id
+        )
+        .execute(&mut *tx)
+        .await?;
+        changes.push(format!("{} renamed the group to \"{}\"", username, group_name));
+    }
+    if let Some(topic) = update.topic {
+        sqlx::query!(r#"update groups set topic = $1 where id = $2"#, topic, group_id)
+            .execute(&mut *tx)
+            .await?;
+        changes.push(format!("{} set the topic to \"{}\"", username, topic));
+    }
+    if let Some(description) = update.description {
+        sqlx::query!(
+            r#"update groups set description = $1 where id = $2"#,
+            description,
+            group_id
+        )
+        .execute(&mut *tx)
+        .await?;
+        changes.push(format!("{} changed the group description", username));
+    }
+    if let Some(avatar_url) = update.avatar_url {
+        sqlx::query!(
+            r#"update groups set avatar_url = $1 where id = $2"#,
+            avatar_url,
+            group_id
+        )
+        .execute(&mut *tx)
+        .await?;
+        changes.push(format!("{} changed the group avatar", username));
+    }
+    if let Some(archived) = update.archived {
+        // only touch archived_at when the state actually flips, so re-archiving
+        // doesn't reset the original archive time
+        let flipped = sqlx::query!(
+            r#"update groups set archived_at = case when $1 then now() else null end
+            where id = $2 and (archived_at is not null) <> $1"#,
+            archived,
+            group_id
+        )
+        .execute(&mut *tx)
+        .await?
+        .rows_affected();
+        if flipped > 0 {
+            match archived {
+                true => changes.push(format!("{} archived the group", username)),
+                false => changes.push(format!("{} unarchived the group", username)),
+            }
+        }
+    }
+
+    if let Some(private) = update.private {
+        let flipped = sqlx::query!(
+            r#"update groups set private = $1 where id = $2 and private <> $1"#,
+            private,
+            group_id
+        )
+        .execute(&mut *tx)
+        .await?
+        .rows_affected();
+        if flipped > 0 {
+            match private {
+                true => changes.push(format!("{} made the group private", username)),
+                false => changes.push(format!("{} made the group public", username)),
+            }
+        }
+    }
+
+    if let Some(mins) = update.disappear_after_mins {
+        let mins = Some(mins).filter(|mins| *mins > 0);
+        let changed = sqlx::query!(
+            r#"update groups set disappear_after_mins = $1
+            where id = $2 and disappear_after_mins is distinct from $1"#,
+            mins,
+            group_id
+        )
+        .execute(&mut *tx)
+        .await?
+        .rows_affected();
+        if changed > 0 {
+            match mins {
+                Some(mins) => changes.push(format!(
+                    "{} set new messages to disappear {} minutes after they're sent",
+                    username, mins
+                )),
+                None => changes.push(format!(
+                    "{} stopped new messages disappearing after a while",
+                    username
+                )),
+            }
+        }
+    }
+    if let Some(after_read) = update.disappear_after_read {
+        let flipped = sqlx::query!(
+            r#"update groups set disappear_after_read = $1
+            where id = $2 and disappear_after_read <> $1"#,
+            after_read,
+            group_id
+        )
+        .execute(&mut *tx)
+        .await?
+        .rows_affected();
+        if flipped > 0 {
+            match after_read {
+                true => changes.push(format!(
+                    "{} set new messages to disappear once they're read",
+                    username
+                )),
+                false => changes.push(format!(
+                    "{} stopped new messages disappearing once they're read",
+                    username
+                )),
+            }
+        }
+    }
+
+    for change in changes.iter() {
+        write_system_message(&mut tx, group_id, user_id, change).await?;
+    }
+    tx.commit().await?;
+    Ok(changes)
+}
+
+async fn is_group_owner(user_id: i32, group_id: i32, pool: &Pool<Postgres>) -> bool {
+    match sqlx::query!(
+        r#"select owner_id from groups where id = $1"#,
+        group_id
+    )
     .fetch_one(pool)
     .await
     {
-        Ok(rec) => return rec.moderate,
-        Err(_e) => return false,
+        Ok(rec) => rec.owner_id == Some(user_id),
+        Err(_e) => false,
+    }
+}
+
+#[derive(Serialize)]
+struct TransferResponse {
+    transfer_id: i32,
+}
+
+#[post("{group_id}/transfer/{user_id}")]
+async fn handle_transfer_ownership(
+    path: web::Path<(i32, i32)>,
+    user: User,
+    app: web::Data<AppState>,
+) -> impl Responder {
+    let (group_id, to_user_id) = path.into_inner();
+    if !is_group_owner(user.user_id, group_id, &app.pool).await {
+        return HttpResponse::Unauthorized().body(format!(
+            "user {} does not own group {}",
+            user.user_id, group_id
+        ));
+    }
+    match start_transfer(group_id, user.user_id, to_user_id, &app.pool).await {
+        Ok(Some(transfer_id)) => HttpResponse::Ok().json(TransferResponse { transfer_id }),
+        Ok(None) => HttpResponse::BadRequest().body(format!(
+            "user {} is not a member of group {}",
+            to_user_id, group_id
+        )),
+        Err(e) => HttpResponse::InternalServerError().body(e.to_string()),
+    }
+}
+
+/// Offers the group to another member, replacing any offer still pending.
+/// Returns `None` when the new owner isn't in the group
+async fn start_transfer(
+    group_id: i32,
+    from_user_id: i32,
+    to_user_id: i32,
+    pool: &Pool<Postgres>,
+) -> Result<Option<i32>, sqlx::Error> {
+    let mut tx = pool.begin().await?;
+    let is_member = sqlx::query!(
+        r#"select 1 as "exists" from group_permissions where group_id = $1 and user_id = $2"#,
+        group_id,
+        to_user_id
+    )
+    .fetch_optional(&mut *tx)
+    .await?
+    .is_some();
+    if !is_member {
+        return Ok(None);
+    }
+    sqlx::query!(
+        r#"update group_ownership_transfers set cancelled_at = now()
+        where group_id = $1 and accepted_at is null and cancelled_at is null"#,
+        group_id
+    )
+    .execute(&mut *tx)
+    .await?;
+    let transfer = sqlx::query!(
+        r#"insert into group_ownership_transfers (group_id, from_user_id, to_user_id)
+        values ($1, $2, $3) returning id"#,
+        group_id,
+        from_user_id,
+        to_user_id
+    )
+    .fetch_one(&mut *tx)
+    .await?;
+    tx.commit().await?;
+    Ok(Some(transfer.id))
+}
+
+#[derive(Serialize)]
+struct PendingTransfer {
+    id: i32,
+    group_id: i32,
+    group_name: String,
+    from_user_id: i32,
+    created_at: Option<chrono::DateTime<chrono::Utc>>,
+}
+
+#[get("transfers")]
+async fn handle_get_transfers(user: User, app: web::Data<AppState>) -> impl Responder {
+    match sqlx::query_as!(
+        PendingTransfer,
+        r#"select t.id, t.group_id, g.group_name, t.from_user_id, t.created_at
+        from group_ownership_transfers t join groups g on g.id = t.group_id
+        where t.to_user_id = $1 and t.accepted_at is null and t.cancelled_at is null
+        and g.deleted_at is null"#,
+        user.user_id
+    )
+    .fetch_all(&app.pool)
+    .await
+    {
+        Ok(rows) => HttpResponse::Ok().json(rows),
+        Err(e) => HttpResponse::InternalServerError().body(e.to_string()),
+    }
+}
+
+#[post("transfers/{transfer_id}/accept")]
+async fn handle_accept_transfer(
+    transfer_id: web::Path<i32>,
+    user: User,
+    app: web::Data<AppState>,
+    req: HttpRequest,
+) -> impl Responder {
+    let transfer_id = transfer_id.into_inner();
+    match accept_transfer(transfer_id, user.user_id, &app.pool).await {
+        Ok(Some(group_id)) => {
+            audit_event(
+                &app.pool,
+                &req,
+                Some(user.user_id),
+                "group.owner.transfer",
+                Some(format!("group:{group_id}")),
+                Some(json!({ "transfer_id": transfer_id, "roles": ["admin"] })),
+            )
+            .await;
+            HttpResponse::Ok().body(group_id.to_string())
+        }
+        Ok(None) => HttpResponse::NotFound().body("no pending transfer found"),
+        Err(e) => HttpResponse::InternalServerError().body(e.to_string()),
+    }
+}
+
+/// Makes the accepting user the owner (& a moderator) of the group, returns the
+/// group id or `None` if there's no pending transfer to them with that id
+async fn accept_transfer(
+    transfer_id: i32,
+    user_id: i32,
+    pool: &Pool<Postgres>,
+) -> Result<Option<i32>, sqlx::Error> {
+    let mut tx = pool.begin().await?;
+    let transfer = match sqlx::query!(
+        r#"update group_ownership_transfers set accepted_at = now()
+        where id = $1 and to_user_id = $2 and accepted_at is null and cancelled_at is null
+        returning group_id"#,
+        transfer_id,
+        user_id
+    )
+    .fetch_optional(&mut *tx)
+    .await?
+    {
+        Some(rec) => rec,
+        None => return Ok(None),
+    };
+    let group = sqlx::query!(
+        r#"update groups set owner_id = $1 where id = $2 and deleted_at is null returning id"#,
+        user_id,
+        transfer.group_id
+    )
+    .fetch_optional(&mut *tx)
+    .await?;
+    if group.is_none() {
+        return Ok(None);
+    }
+    assign_roles(transfer.group_id, user_id, &["admin".to_owned()], &mut tx).await?;
+    let username = sqlx::query!(r#"select username from users where id = $1"#, user_id)
+        .fetch_one(&mut *tx)
+        .await?
+        .username;
+    write_system_message(
+        &mut tx,
+        transfer.group_id,
+        user_id,
+        &format!("{} is now the owner of the group", username),
+    )
+    .await?;
+    tx.commit().await?;
+    Ok(Some(transfer.group_id))
+}
+
+#[delete("transfers/{transfer_id}")]
+async fn handle_cancel_transfer(
+    transfer_id: web::Path<i32>,
+    user: User,
+    app: web::Data<AppState>,
+) -> impl Responder {
+    // either side can call off a pending transfer
+    match sqlx::query!(
+        r#"update group_ownership_transfers set cancelled_at = now()
+        where id = $1 and (from_user_id = $2 or to_user_id = $2)
+        and accepted_at is null and cancelled_at is null"#,
+        transfer_id.into_inner(),
+        user.user_id
+    )
+    .execute(&app.pool)
+    .await
+    {
+        Ok(res) if res.rows_affected() > 0 => HttpResponse::Ok().body(""),
+        Ok(_) => HttpResponse::NotFound().body("no pending transfer found"),
+        Err(e) => HttpResponse::InternalServerError().body(e.to_string()),
     }
 }
 
-async fn add_to_group(group_id: i32, user_id_added: i32, user_id_adding: i32, pool: &Pool<Postgres>) -> Result<i32, sqlx::Error> {
-    match sqlx::query!(r#"insert into group_permissions (user_id, group_id, created_by, write) values
-    ( $1, $2, $3, $4) returning id
-    "#, user_id_added, group_id, user_id_adding, true).fetch_one(pool).await {
-        Ok(val) => return Ok(val.id),
-        Err(e) => return Err(e)
+#[derive(Serialize)]
+struct DeleteGroupResponse {
+    group_id: i32,
+    purge_after: chrono::DateTime<chrono::Utc>,
+}
+
+#[delete("{group_id}")]
+async fn handle_delete_group(
+    group_id: web::Path<i32>,
+    user: User,
+    app: web::Data<AppState>,
+) -> impl Responder {
+    let group_id = group_id.into_inner();
+    if !is_group_owner(user.user_id, group_id, &app.pool).await {
+        return HttpResponse::Unauthorized().body(format!(
+            "user {} does not own group {}",
+            user.user_id, group_id
+        ));
     }
+    match sqlx::query!(
+        r#"update groups set deleted_at = now() where id = $1 and deleted_at is null
+        returning deleted_at as "deleted_at!""#,
+        group_id
+    )
+    .fetch_optional(&app.pool)
+    .await
+    {
+        Ok(Some(rec)) => HttpResponse::Ok().json(DeleteGroupResponse {
+            group_id,
+            purge_after: rec.deleted_at + app.config.group_deletion_grace,
+        }),
+        Ok(None) => HttpResponse::NotFound().body(format!("group {} is already deleted", group_id)),
+        Err(e) => HttpResponse::InternalServerError().body(e.to_string()),
+    }
+}
+
+#[post("{group_id}/restore")]
+async fn handle_restore_group(
+    group_id: web::Path<i32>,
+    user: User,
+    app: web::Data<AppState>,
+) -> impl Responder {
+    let group_id = group_id.into_inner();
+    if !is_group_owner(user.user_id, group_id, &app.pool).await {
+        return HttpResponse::Unauthorized().body(format!(
+            "user {} does not own group {}",
+            user.user_id, group_id
+        ));
+    }
+    match sqlx::query!(
+        r#"update groups set deleted_at = null where id = $1 and deleted_at is not null"#,
+        group_id
+    )
+    .execute(&app.pool)
+    .await
+    {
+        Ok(res) if res.rows_affected() > 0 => HttpResponse::Ok().body(""),
+        Ok(_) => HttpResponse::NotFound().body(format!("group {} is not deleted", group_id)),
+        Err(e) => HttpResponse::InternalServerError().body(e.to_string()),
+    }
+}
+
+/// Permanently removes groups deleted longer than `grace` ago, their permissions,
+/// messages & transfers go with them through the foreign key cascades. Groups
+/// under legal hold wait until it's lifted
+pub async fn purge_deleted_groups(
+    grace: chrono::Duration,
+    pool: &Pool<Postgres>,
+) -> Result<u64, sqlx::Error> {
+    let cutoff = chrono::Utc::now() - grace;
+    let res = sqlx::query!(
+        r#"delete from groups where deleted_at < $1 and legal_hold_at is null"#,
+        cutoff
+    )
+        .execute(pool)
+        .await?;
+    Ok(res.rows_affected())
 }
@@ -0,0 +1,360 @@
+use crate::audit::audit::audit_event;
+use crate::custom_types::{GroupType, JoinRequestStatus};
+use crate::extractors::extractors::User;
+use crate::messages::messages::write_system_message;
+use crate::server::app_state::AppState;
+use actix_web::{delete, get, post, web, HttpRequest, HttpResponse, Responder};
+use serde::{Deserialize, Serialize};
+use serde_json::json;
+use sqlx::{Pool, Postgres};
+
+use super::groups::add_to_group;
+use super::roles::{check_manage_roles, has_capability, Capability};
+
+const MAX_NOTE_LENGTH: usize = 500;
+
+#[derive(Deserialize)]
+struct JoinRequestBody {
+    note: Option<String>,
+}
+
+#[derive(Serialize)]
+struct JoinRequestResponse {
+    request_id: i32,
+    status: JoinRequestStatus,
+}
+
+#[post("{group_id}/join_requests")]
+async fn handle_request_to_join(
+    group_id: web::Path<i32>,
+    body: web::Json<JoinRequestBody>,
+    user: User,
+    app: web::Data<AppState>,
+    req: HttpRequest,
+) -> impl Responder {
+    let group_id = group_id.into_inner();
+    if let Some(note) = &body.note {
+        if note.chars().count() > MAX_NOTE_LENGTH {
+            return HttpResponse::BadRequest().body(format!(
+                "note is longer than {} characters",
+                MAX_NOTE_LENGTH
+            ));
+        }
+    }
+
+    let group = match sqlx::query!(
+        r#"select private, type as "type: GroupType" from groups
+        where id = $1 and deleted_at is null"#,
+        group_id
+    )
+    .fetch_optional(&app.pool)
+    .await
+    {
+        Ok(Some(group)) => group,
+        Ok(None) => return HttpResponse::NotFound().body(format!("group {} not found", group_id)),
+        Err(e) => return HttpResponse::InternalServerError().body(e.to_string()),
+    };
+    if matches!(group.r#type, GroupType::DM) {
+        return HttpResponse::Forbidden().body("direct messages can't be joined");
+    }
+    if is_member(group_id, user.user_id, &app.pool).await {
+        return HttpResponse::Conflict().body(format!(
+            "user {} is already a member of group {}",
+            user.user_id, group_id
+        ));
+    }
+
+    let request_id = match sqlx::query!(
+        r#"insert into group_join_requests (group_id, user_id, note) values ($1, $2, $3)
+        on conflict (group_id, user_id) where status = 'pending' do nothing returning id"#,
+        group_id,
+        user.user_id,
+        body.note
+    )
+    .fetch_optional(&app.pool)
+    .await
+    {
+        Ok(Some(rec)) => rec.id,
+        Ok(None) => {
+            return HttpResponse::Conflict().body(format!(
+                "user {} already has a pending request for group {}",
+                user.user_id, group_id
+            ))
+        }
+        Err(e) => return HttpResponse::InternalServerError().body(e.to_string()),
+    };
+
+    // public groups don't need a moderator, the request goes straight through
+    if !group.private {
+        let defaults = app.config.join_default_roles.clone();
+        return match decide_request(request_id, None, Some(defaults.clone()), &app.pool).await {
+            Ok(_) => {
+                audit_event(
+                    &app.pool,
+                    &req,
+                    None,
+                    "group.member.add",
+                    Some(format!("user:{}", user.user_id)),
+                    Some(json!({ "group_id": group_id, "roles": defaults, "join_request": request_id })),
+                )
+                .await;
+                HttpResponse::Ok().json(JoinRequestResponse {
+                    request_id,
+                    status: JoinRequestStatus::APPROVED,
+                })
+            }
+            Err(e) => HttpResponse::InternalServerError().body(e.to_string()),
+        };
+    }
+    HttpResponse::Ok().json(JoinRequestResponse {
+        request_id,
+        status: JoinRequestStatus::PENDING,
+    })
+}
+
+#[derive(Serialize)]
+struct PendingJoinRequest {
+    id: i32,
+    user_id: i32,
+    username: String,
+    note: Option<String>,
+    created_at: Option<chrono::DateTime<chrono::Utc>>,
+}
+
+#[get("{group_id}/join_requests")]
+async fn handle_get_join_requests(
+    group_id: web::Path<i32>,
+    user: User,
+    app: web::Data<AppState>,
+) -> impl Responder {
+    let group_id = group_id.into_inner();
+    if !has_capability(user.user_id, group_id, Capability::Moderate, &app.pool).await {
+        return HttpResponse::Unauthorized().body(format!(
+            "user {} is not permitted to view join requests for group {}",
+            user.user_id, group_id
+        ));
+    }
+    match sqlx::query_as!(
+        PendingJoinRequest,
+        r#"select r.id, r.user_id, u.username, r.note, r.created_at
+        from group_join_requests r join users u on u.id = r.user_id
+        where r.group_id = $1 and r.status = 'pending'
+        order by r.created_at asc"#,
+        group_id
+    )
+    .fetch_all(&app.pool)
+    .await
+    {
+        Ok(rows) => HttpResponse::Ok().json(rows),
+        Err(e) => HttpResponse::InternalServerError().body(e.to_string()),
+    }
+}
+
+#[derive(Serialize)]
+struct MyJoinRequest {
+    id: i32,
+    group_id: i32,
+    group_name: String,
+    note: Option<String>,
+    status: JoinRequestStatus,
+    created_at: Option<chrono::DateTime<chrono::Utc>>,
+    decided_at: Option<chrono::DateTime<chrono::Utc>>,
+}
+
+#[get("join_requests")]
+async fn handle_get_my_join_requests(user: User, app: web::Data<AppState>) -> impl Responder {
+    match sqlx::query_as!(
+        MyJoinRequest,
+        r#"select r.id, r.group_id, g.group_name, r.note,
+        r.status as "status: JoinRequestStatus", r.created_at, r.decided_at
+        from group_join_requests r join groups g on g.id = r.group_id
+        where r.user_id = $1 and g.deleted_at is null
+        order by r.created_at desc"#,
+        user.user_id
+    )
+    .fetch_all(&app.pool)
+    .await
+    {
+        Ok(rows) => HttpResponse::Ok().json(rows),
+        Err(e) => HttpResponse::InternalServerError().body(e.to_string()),
+    }
+}
+
+#[derive(Deserialize, Default)]
+struct ApproveBody {
+    roles: Option<Vec<String>>,
+}
+
+#[post("join_requests/{request_id}/approve")]
+async fn handle_approve_join_request(
+    request_id: web::Path<i32>,
+    body: Option<web::Json<ApproveBody>>,
+    user: User,
+    app: web::Data<AppState>,
+    req: HttpRequest,
+) -> impl Responder {
+    let request_id = request_id.into_inner();
+    let group_id = match check_moderator(request_id, user.user_id, &app.pool).await {
+        Ok(group_id) => group_id,
+        Err(resp) => return resp,
+    };
+    let body = body.map(|b| b.into_inner()).unwrap_or_default();
+    let defaults = &app.config.join_default_roles;
+    let roles = body.roles.unwrap_or_else(|| defaults.clone());
+    // moderating lets you let people in, not pick roles the defaults don't give
+    if roles.iter().any(|role| !defaults.contains(role)) {
+        if let Some(resp) = check_manage_roles(user.user_id, group_id, &app.pool).await {
+            return resp;
+        }
+    }
+    match decide_request(request_id, Some(user.user_id), Some(roles.clone()), &app.pool).await {
+        Ok(true) => {
+            audit_event(
+                &app.pool,
+                &req,
+                Some(user.user_id),
+                "group.member.add",
+                Some(format!("join_request:{request_id}")),
+                Some(json!({ "roles": roles })),
+            )
+            .await;
+            HttpResponse::Ok().body("")
+        }
+        Ok(false) => HttpResponse::NotFound().body("no pending join request found"),
+        Err(e) => HttpResponse::InternalServerError().body(e.to_string()),
+    }
+}
+
+#[post("join_requests/{request_id}/deny")]
+async fn handle_deny_join_request(
+    request_id: web::Path<i32>,
+    user: User,
+    app: web::Data<AppState>,
+) -> impl Responder {
+    let request_id = request_id.into_inner();
+    if let Err(resp) = check_moderator(request_id, user.user_id, &app.pool).await {
+        return resp;
+    }
+    match decide_request(request_id, Some(user.user_id), None, &app.pool).await {
+        Ok(true) => HttpResponse::Ok().body(""),
+        Ok(false) => HttpResponse::NotFound().body("no pending join request found"),
+        Err(e) => HttpResponse::InternalServerError().body(e.to_string()),
+    }
+}
+
+#[delete("join_requests/{request_id}")]
+async fn handle_cancel_join_request(
+    request_id: web::Path<i32>,
+    user: User,
+    app: web::Data<AppState>,
+) -> impl Responder {
+    match sqlx::query!(
+        r#"update group_join_requests set status = 'cancelled', decided_at = now()
+        where id = $1 and user_id = $2 and status = 'pending'"#,
+        request_id.into_inner(),
+        user.user_id
+    )
+    .execute(&app.pool)
+    .await
+    {
+        Ok(res) if res.rows_affected() > 0 => HttpResponse::Ok().body(""),
+        Ok(_) => HttpResponse::NotFound().body("no pending join request found"),
+        Err(e) => HttpResponse::InternalServerError().body(e.to_string()),
+    }
+}
+
+/// The group the request is for, or an error response unless `user_id` moderates it
+async fn check_moderator(
+    request_id: i32,
+    user_id: i32,
+    pool: &Pool<Postgres>,
+) -> Result<i32, HttpResponse> {
+    let group_id = match sqlx::query!(
+        r#"select group_id from group_join_requests where id = $1"#,
+        request_id
+    )
+    .fetch_optional(pool)
+    .await
+    {
+        Ok(Some(rec)) => rec.group_id,
+        Ok(None) => return Err(HttpResponse::NotFound().body("no pending join request found")),
+        Err(e) => return Err(HttpResponse::InternalServerError().body(e.to_string())),
+    };
+    if !has_capability(user_id, group_id, Capability::Moderate, pool).await {
+        return Err(HttpResponse::Unauthorized().body(format!(
+            "user {} is not permitted to decide join requests for group {}",
+            user_id, group_id
+        )));
+    }
+    Ok(group_id)
+}
+
+/// Closes a pending request, approving it & adding the requester to the group
+/// with the given roles when `grant` is set, denying it otherwise.
+/// `decided_by` is `None` when nobody had to look at it (public groups).
+/// Returns false if the request wasn't pending
+async fn decide_request(
+    request_id: i32,
+    decided_by: Option<i32>,
+    grant: Option<Vec<String>>,
+    pool: &Pool<Postgres>,
+) -> Result<bool, sqlx::Error> {
+    let mut tx = pool.begin().await?;
+    let status = match grant {
+        Some(_) => JoinRequestStatus::APPROVED,
+        None => JoinRequestStatus::DENIED,
+    };
+    let request = match sqlx::query!(
+        r#"update group_join_requests set status = $1, decided_by = $2, decided_at = now()
+        where id = $3 and status = 'pending' returning group_id, user_id"#,
+        status as JoinRequestStatus,
+        decided_by,
+        request_id
+    )
+    .fetch_optional(&mut *tx)
+    .await?
+    {
+        Some(rec) => rec,
+        None => return Ok(false),
+    };
+
+    if let Some(roles) = grant {
+        add_to_group(
+            request.group_id,
+            request.user_id,
+            decided_by.unwrap_or(request.user_id),
+            &roles,
+            &mut tx,
+        )
+        .await?;
+        let username = sqlx::query!(
+            r#"select username from users where id = $1"#,
+            request.user_id
+        )
+        .fetch_one(&mut *tx)
+        .await?
+        .username;
+        write_system_message(
+            &mut tx,
+            request.group_id,
+            request.user_id,
+            &format!("{} joined the group", username),
+        )
+        .await?;
+    }
+    tx.commit().await?;
+    Ok(true)
+}
+
+async fn is_member(group_id: i32, user_id: i32, pool: &Pool<Postgres>) -> bool {
+    matches!(
+        sqlx::query!(
+            r#"select 1 as "exists" from group_permissions where group_id = $1 and user_id = $2"#,
+            group_id,
+            user_id
+        )
+        .fetch_optional(pool)
+        .await,
+        Ok(Some(_))
+    )
+}
@@ -1,6 +1,21 @@
+pub mod exports;
 pub mod groups;
+pub mod join_requests;
+pub mod roles;
 use actix_web::web::{self, service};
-use groups::{get_group_members_handle, write_to_group, handle_get_groups, handle_create_group, handle_add_to_group};
+use groups::{get_group_members_handle, write_to_group, handle_get_groups, handle_create_group, handle_add_to_group, handle_update_group,
+    handle_transfer_ownership, handle_get_transfers, handle_accept_transfer, handle_cancel_transfer,
+    handle_delete_group, handle_restore_group};
+use exports::{handle_create_export, handle_download_export, handle_list_exports};
+use join_requests::{handle_request_to_join, handle_get_join_requests, handle_get_my_join_requests,
+    handle_approve_join_request, handle_deny_join_request, handle_cancel_join_request};
+use roles::{handle_get_roles, handle_create_role, handle_update_role, handle_delete_role,
+    handle_assign_role, handle_unassign_role};
+use crate::commands::custom::{handle_list_commands, handle_create_command, handle_delete_command};
+use crate::presence::presence::handle_group_presence;
+use crate::webhooks::incoming::{handle_list_webhooks, handle_create_webhook, handle_delete_webhook};
+use crate::webhooks::subscriptions::{handle_list_group_subscriptions, handle_create_group_subscription,
+    handle_delete_group_subscription, handle_list_group_deliveries, handle_retry_group_delivery};
 
 pub fn group_routes(cfg: &mut web::ServiceConfig) {
     cfg.service(
@@ -9,6 +24,40 @@ pub fn group_routes(cfg: &mut web::ServiceConfig) {
             .service(get_group_members_handle)
             .service(handle_get_groups)
             .service(handle_create_group)
-            .service(handle_add_to_group),
+            .service(handle_add_to_group)
+            .service(handle_update_group)
+            .service(handle_get_transfers)
+            .service(handle_transfer_ownership)
+            .service(handle_accept_transfer)
+            .service(handle_cancel_transfer)
+            .service(handle_delete_group)
+            .service(handle_restore_group)
+            .service(handle_get_my_join_requests)
+            .service(handle_request_to_join)
+            .service(handle_get_join_requests)
+            .service(handle_approve_join_request)
+            .service(handle_deny_join_request)
+            .service(handle_cancel_join_request)
+            .service(handle_get_roles)
+            .service(handle_create_role)
+            .service(handle_update_role)
+            .service(handle_delete_role)
+            .service(handle_assign_role)
+            .service(handle_unassign_role)
+            .service(handle_list_webhooks)
+            .service(handle_create_webhook)
+            .service(handle_delete_webhook)
+            .service(handle_list_group_subscriptions)
+            .service(handle_create_group_subscription)
+            .service(handle_delete_group_subscription)
+            .service(handle_list_group_deliveries)
+            .service(handle_retry_group_delivery)
+            .service(handle_list_commands)
+            .service(handle_create_command)
+            .service(handle_delete_command)
+            .service(handle_group_presence)
+            .service(handle_create_export)
+            .service(handle_list_exports)
+            .service(handle_download_export),
     );
 }
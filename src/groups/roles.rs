@@ -0,0 +1,438 @@
+use crate::audit::audit::audit_event;
+use crate::extractors::extractors::User;
+use crate::server::app_state::AppState;
+use actix_web::{delete, get, post, put, web, HttpRequest, HttpResponse, Responder};
+use serde::{Deserialize, Serialize};
+use serde_json::json;
+use sqlx::{PgConnection, Pool, Postgres};
+
+/// What a role allows its members to do in a group, stored by name in
+/// `group_roles.capabilities`
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq)]
+#[serde(rename_all = "snake_case")]
+pub enum Capability {
+    Read,
+    Write,
+    DeleteOthers,
+    Pin,
+    Invite,
+    // decide join requests & post system notices
+    Moderate,
+    // edit the group's name, topic etc.
+    ManageGroup,
+    ManageRoles,
+    // download the group's whole history
+    Export,
+}
+
+impl Capability {
+    pub const ALL: [Capability; 9] = [
+        Capability::Read,
+        Capability::Write,
+        Capability::DeleteOthers,
+        Capability::Pin,
+        Capability::Invite,
+        Capability::Moderate,
+        Capability::ManageGroup,
+        Capability::ManageRoles,
+        Capability::Export,
+    ];
+
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            Capability::Read => "read",
+            Capability::Write => "write",
+            Capability::DeleteOthers => "delete_others",
+            Capability::Pin => "pin",
+            Capability::Invite => "invite",
+            Capability::Moderate => "moderate",
+            Capability::ManageGroup => "manage_group",
+            Capability::ManageRoles => "manage_roles",
+            Capability::Export => "export",
+        }
+    }
+
+    pub fn from_name(name: &str) -> Option<Capability> {
+        Capability::ALL
+            .into_iter()
+            .find(|capability| capability.as_str() == name)
+    }
+}
+
+/// The roles every group starts with, these mirror the old
+/// read / write / moderate / admin permission flags
+pub const DEFAULT_ROLES: [(&str, &[Capability]); 4] = [
+    ("reader", &[Capability::Read]),
+    ("writer", &[Capability::Read, Capability::Write]),
+    (
+        "moderator",
+        &[
+            Capability::Read,
+            Capability::Write,
+            Capability::DeleteOthers,
+            Capability::Pin,
+            Capability::Invite,
+            Capability::Moderate,
+            Capability::ManageGroup,
+        ],
+    ),
+    ("admin", &Capability::ALL),
+];
+
+/// Whether a user may do something in a group. The owner can do everything,
+/// everyone else needs a role with the capability. Deleted groups allow
+/// nothing. Decided by the `has_capability` SQL function, which queries
+/// filtering on what a user may do use too
+pub async fn has_capability(
+    user_id: i32,
+    group_id: i32,
+    capability: Capability,
+    pool: &Pool<Postgres>,
+) -> bool {
+    match sqlx::query!(
+        r#"select has_capability($1, $2, $3) as "allowed!""#,
+        user_id,
+        group_id,
+        capability.as_str()
+    )
+    .fetch_one(pool)
+    .await
+    {
+        Ok(rec) => rec.allowed,
+        Err(e) => {
+            log::error!("capability check failed: {e}");
+            false
+        }
+    }
+}
+
+pub async fn create_default_roles(
+    group_id: i32,
+    conn: &mut PgConnection,
+) -> Result<(), sqlx::Error> {
+    for (name, capabilities) in DEFAULT_ROLES {
+        sqlx::query!(
+            r#"insert into group_roles (group_id, name, capabilities) values ($1, $2, $3)"#,
+            group_id,
+            name,
+            &capability_names(capabilities)
+        )
+        .execute(&mut *conn)
+        .await?;
+    }
+    Ok(())
+}
+
+/// Gives a member the named roles, names that don't exist in the group are skipped
+pub async fn assign_roles(
+    group_id: i32,
+    user_id: i32,
+    role_names: &[String],
+    conn: &mut PgConnection,
+) -> Result<(), sqlx::Error> {
+    sqlx::query!(
+        r#"insert into group_role_members (role_id, user_id)
+        select id, $2 from group_roles where group_id = $1 and name = any($3)
+        on conflict do nothing"#,
+        group_id,
+        user_id,
+        role_names
+    )
+    .execute(&mut *conn)
+    .await?;
+    Ok(())
+}
+
+fn capability_names(capabilities: &[Capability]) -> Vec<String> {
+    capabilities.iter().map(|c| c.as_str().to_owned()).collect()
+}
+
+#[derive(Serialize)]
+struct RoleResponse {
+    id: i32,
+    name: String,
+    capabilities: Vec<String>,
+    members: Vec<i32>,
+}
+
+#[get("{group_id}/roles")]
+async fn handle_get_roles(
+    group_id: web::Path<i32>,
+    user: User,
+    app: web::Data<AppState>,
+) -> impl Responder {
+    let group_id = group_id.into_inner();
+    if !has_capability(user.user_id, group_id, Capability::Read, &app.pool).await {
+        return HttpResponse::Unauthorized().body(format!(
+            "user {} is not permitted to view roles for group {}",
+            user.user_id, group_id
+        ));
+    }
+    match sqlx::query_as!(
+        RoleResponse,
+        r#"select r.id, r.name, r.capabilities,
+        coalesce(array_agg(m.user_id) filter (where m.user_id is not null), '{}') as "members!"
+        from group_roles r left join group_role_members m on m.role_id = r.id
+        where r.group_id = $1 group by r.id order by r.id"#,
+        group_id
+    )
+    .fetch_all(&app.pool)
+    .await
+    {
+        Ok(rows) => HttpResponse::Ok().json(rows),
+        Err(e) => HttpResponse::InternalServerError().body(e.to_string()),
+    }
+}
+
+#[derive(Deserialize)]
+struct RoleRequest {
+    name: String,
+    capabilities: Vec<Capability>,
+}
+
+#[post("{group_id}/roles")]
+async fn handle_create_role(
+    group_id: web::Path<i32>,
+    role: web::Json<RoleRequest>,
+    user: User,
+    app: web::Data<AppState>,
+    req: HttpRequest,
+) -> impl Responder {
+    let group_id = group_id.into_inner();
+    if let Some(resp) = check_manage_roles(user.user_id, group_id, &app.pool).await {
+        return resp;
+    }
+    if role.name.trim().is_empty() {
+        return HttpResponse::BadRequest().body("role name must not be empty");
+    }
+    match sqlx::query!(
+        r#"insert into group_roles (group_id, name, capabilities) values ($1, $2, $3)
+        on conflict (group_id, name) do nothing returning id"#,
+        group_id,
+        role.name.trim(),
+        &capability_names(&role.capabilities)
+    )
+    .fetch_optional(&app.pool)
+    .await
+    {
+        Ok(Some(rec)) => {
+            audit_event(
+                &app.pool,
+                &req,
+                Some(user.user_id),
+                "group.role.create",
+                Some(format!("role:{}", rec.id)),
+                Some(json!({ "group_id": group_id, "name": role.name.trim(), "capabilities": role.capabilities })),
+            )
+            .await;
+            HttpResponse::Ok().body(rec.id.to_string())
+        }
+        Ok(None) => HttpResponse::Conflict().body(format!("role {} already exists", role.name)),
+        Err(e) => HttpResponse::InternalServerError().body(e.to_string()),
+    }
+}
+
+#[put("{group_id}/roles/{role_id}")]
+async fn handle_update_role(
+    path: web::Path<(i32, i32)>,
+    role: web::Json<RoleRequest>,
+    user: User,
+    app: web::Data<AppState>,
+    req: HttpRequest,
+) -> impl Responder {
+    let (group_id, role_id) = path.into_inner();
+    if let Some(resp) = check_manage_roles(user.user_id, group_id, &app.pool).await {
+        return resp;
+    }
+    if role.name.trim().is_empty() {
+        return HttpResponse::BadRequest().body("role name must not be empty");
+    }
+    match sqlx::query!(
+        r#"update group_roles set name = $1, capabilities = $2 where id = $3 and group_id = $4"#,
+        role.name.trim(),
+        &capability_names(&role.capabilities),
+        role_id,
+        group_id
+    )
+    .execute(&app.pool)
+    .await
+    {
+        Ok(res) if res.rows_affected() > 0 => {
+            audit_event(
+                &app.pool,
+                &req,
+                Some(user.user_id),
+                "group.role.update",
+                Some(format!("role:{role_id}")),
+                Some(json!({ "group_id": group_id, "name": role.name.trim(), "capabilities": role.capabilities })),
+            )
+            .await;
+            HttpResponse::Ok().body("")
+        }
+        Ok(_) => HttpResponse::NotFound().body(format!("role {} not found", role_id)),
+        Err(e) => HttpResponse::InternalServerError().body(e.to_string()),
+    }
+}
+
+#[delete("{group_id}/roles/{role_id}")]
+async fn handle_delete_role(
+    path: web::Path<(i32, i32)>,
+    user: User,
+    app: web::Data<AppState>,
+    req: HttpRequest,
+) -> impl Responder {
+    let (group_id, role_id) = path.into_inner();
+    if let Some(resp) = check_manage_roles(user.user_id, group_id, &app.pool).await {
+        return resp;
+    }
+    match sqlx::query!(
+        r#"delete from group_roles where id = $1 and group_id = $2"#,
+        role_id,
+        group_id
+    )
+    .execute(&app.pool)
+    .await
+    {
+        Ok(res) if res.rows_affected() > 0 => {
+            audit_event(
+                &app.pool,
+                &req,
+                Some(user.user_id),
+                "group.role.delete",
+                Some(format!("role:{role_id}")),
+                Some(json!({ "group_id": group_id })),
+            )
+            .await;
+            HttpResponse::Ok().body("")
+        }
+        Ok(_) => HttpResponse::NotFound().body(format!("role {} not found", role_id)),
+        Err(e) => HttpResponse::InternalServerError().body(e.to_string()),
+    }
+}
+
+#[put("{group_id}/roles/{role_id}/members/{user_id}")]
+async fn handle_assign_role(
+    path: web::Path<(i32, i32, i32)>,
+    user: User,
+    app: web::Data<AppState>,
+    req: HttpRequest,
+) -> impl Responder {
+    let (group_id, role_id, user_id) = path.into_inner();
+    if let Some(resp) = check_manage_roles(user.user_id, group_id, &app.pool).await {
+        return resp;
+    }
+    // only members of the group can hold its roles
+    match sqlx::query!(
+        r#"insert into group_role_members (role_id, user_id)
+        select r.id, gp.user_id from group_roles r
+        join group_permissions gp on gp.group_id = r.group_id
+        where r.id = $1 and r.group_id = $2 and gp.user_id = $3
+        on conflict do nothing"#,
+        role_id,
+        group_id,
+        user_id
+    )
+    .execute(&app.pool)
+    .await
+    {
+        Ok(res) => {
+            // nothing changed when the user already had (or didn't have) the role
+            if res.rows_affected() > 0 {
+                audit_event(
+                    &app.pool,
+                    &req,
+                    Some(user.user_id),
+                    "group.role.assign",
+                    Some(format!("user:{user_id}")),
+                    Some(json!({ "group_id": group_id, "role_id": role_id })),
+                )
+                .await;
+            }
+            HttpResponse::Ok().body("")
+        }
+        Err(e) => HttpResponse::InternalServerError().body(e.to_string()),
+    }
+}
+
+#[delete("{group_id}/roles/{role_id}/members/{user_id}")]
+async fn handle_unassign_role(
+    path: web::Path<(i32, i32, i32)>,
+    user: User,
+    app: web::Data<AppState>,
+    req: HttpRequest,
+) -> impl Responder {
+    let (group_id, role_id, user_id) = path.into_inner();
+    if let Some(resp) = check_manage_roles(user.user_id, group_id, &app.pool).await {
+        return resp;
+    }
+    match sqlx::query!(
+        r#"delete from group_role_members m using group_roles r
+        where m.role_id = r.id and r.id = $1 and r.group_id = $2 and m.user_id = $3"#,
+        role_id,
+        group_id,
+        user_id
+    )
+    .execute(&app.pool)
+    .await
+    {
+        Ok(res) => {
+            // nothing changed when the user already had (or didn't have) the role
+            if res.rows_affected() > 0 {
+                audit_event(
+                    &app.pool,
+                    &req,
+                    Some(user.user_id),
+                    "group.role.unassign",
+                    Some(format!("user:{user_id}")),
+                    Some(json!({ "group_id": group_id, "role_id": role_id })),
+                )
+                .await;
+            }
+            HttpResponse::Ok().body("")
+        }
+        Err(e) => HttpResponse::InternalServerError().body(e.to_string()),
+    }
+}
+
+/// Returns an error response unless `user_id` may hand out roles in the group
+pub async fn check_manage_roles(
+    user_id: i32,
+    group_id: i32,
+    pool: &Pool<Postgres>,
+) -> Option<HttpResponse> {
+    match has_capability(user_id, group_id, Capability::ManageRoles, pool).await {
+        true => None,
+        false => Some(HttpResponse::Unauthorized().body(format!(
+            "user {} is not permitted to manage roles for group {}",
+            user_id, group_id
+        ))),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn capability_names_match_serde() {
+        // the database stores as_str, requests come in through serde, they must agree
+        for capability in Capability::ALL {
+            let json = serde_json::to_string(&capability).unwrap();
+            assert_eq!(json, format!("\"{}\"", capability.as_str()));
+        }
+    }
+
+    #[test]
+    fn admin_role_has_everything() {
+        let (name, capabilities) = DEFAULT_ROLES[3];
+        assert_eq!(name, "admin");
+        assert_eq!(capabilities.len(), Capability::ALL.len());
+    }
+
+    #[test]
+    fn default_roles_can_read() {
+        for (_, capabilities) in DEFAULT_ROLES {
+            assert!(capabilities.contains(&Capability::Read));
+        }
+    }
+}
@@ -0,0 +1,331 @@
+use super::login::create_ciphertext;
+use super::throttle;
+use crate::audit::audit::audit_event;
+use crate::auth::{generate_token, hash_token, TokenStore};
+use crate::custom_types::UserTokenPurpose;
+use crate::extractors::extractors::User;
+use crate::mailer::mailer::{send_later, Email};
+use crate::mailer::templates::Template;
+use crate::server::app_state::AppState;
+use actix_web::{get, post, web, HttpRequest, HttpResponse, Responder};
+use chrono::{Duration, Utc};
+use serde::Deserialize;
+use sqlx::{Pool, Postgres};
+use std::sync::Arc;
+
+// one reset email a minute is plenty, & stops the endpoint being used to spam someone
+const RESET_EMAIL_COOLDOWN_SECS: i64 = 60;
+
+async fn issue_token(
+    user_id: i32,
+    purpose: UserTokenPurpose,
+    email: &str,
+    ttl: Duration,
+    pool: &Pool<Postgres>,
+) -> Result<String, sqlx::Error> {
+    let token = generate_token();
+    sqlx::query!(
+        r#"insert into user_tokens (user_id, purpose, token_hash, email, expires_at)
+        values ($1, $2, $3, $4, $5)"#,
+        user_id,
+        purpose as UserTokenPurpose,
+        hash_token(&token),
+        email,
+        Utc::now() + ttl
+    )
+    .execute(pool)
+    .await?;
+    Ok(token)
+}
+
+struct UsedToken {
+    user_id: i32,
+    email: Option<String>,
+}
+
+/// Uses up a token if it's for `purpose`, unused & unexpired
+async fn consume_token(
+    token: &str,
+    purpose: UserTokenPurpose,
+    pool: &Pool<Postgres>,
+) -> Result<Option<UsedToken>, sqlx::Error> {
+    sqlx::query_as!(
+        UsedToken,
+        r#"update user_tokens set used_at = now()
+        where token_hash = $1 and purpose = $2 and used_at is null and expires_at > now()
+        returning user_id, email"#,
+        hash_token(token),
+        purpose as UserTokenPurpose
+    )
+    .fetch_optional(pool)
+    .await
+}
+
+/// Emails the user a link to confirm `email` is theirs
+pub async fn send_verification(
+    user_id: i32,
+    username: &str,
+    email: &str,
+    app: &AppState,
+) -> Result<(), sqlx::Error> {
+    let ttl = app.config.email_verification_ttl;
+    let token = issue_token(
+        user_id,
+        UserTokenPurpose::VERIFY_EMAIL,
+        email,
+        ttl,
+        &app.pool,
+    )
+    .await?;
+    let template = Template::VerifyEmail {
+        username: username.to_owned(),
+        link: format!(
+            "{}/auth/verify_email?token={}",
+            app.config.public_url, token
+        ),
+        hours: ttl.num_hours(),
+    };
+    send_later(&app.mailer, Email::new(email, &template));
+    Ok(())
+}
+
+#[derive(Deserialize)]
+struct TokenQuery {
+    token: String,
+}
+
+#[get("/verify_email")]
+pub async fn verify_email_handle(
+    app: web::Data<AppState>,
+    query: web::Query<TokenQuery>,
+    req: HttpRequest,
+) -> HttpResponse {
+    let used = match consume_token(&query.token, UserTokenPurpose::VERIFY_EMAIL, &app.pool).await {
+        Ok(Some(used)) => used,
+        Ok(None) => return HttpResponse::BadRequest().json("link is invalid or has expired"),
+        Err(e) => return HttpResponse::InternalServerError().json(e.to_string()),
+    };
+    // if the email was changed since the link was sent, the link is for an old address
+    match sqlx::query!(
+        r#"update users set email_verified_at = now() where id = $1 and email = $2"#,
+        used.user_id,
+        used.email
+    )
+    .execute(&app.pool)
+    .await
+    {
+        Ok(res) if res.rows_affected() > 0 => {
+            audit_event(
+                &app.pool,
+                &req,
+                Some(used.user_id),
+                "auth.email.verify",
+                Some(format!("user:{}", used.user_id)),
+                None,
+            )
+            .await;
+            HttpResponse::Ok().json("email address confirmed")
+        }
+        Ok(_) => HttpResponse::BadRequest().json("link is invalid or has expired"),
+        Err(e) => HttpResponse::InternalServerError().json(e.to_string()),
+    }
+}
+
+#[post("/verify_email/resend")]
+async fn handle_resend_verification(user: User, app: web::Data<AppState>) -> impl Responder {
+    let rec = match sqlx::query!(
+        r#"select username, email, email_verified_at from users where id = $1"#,
+        user.user_id
+    )
+    .fetch_one(&app.pool)
+    .await
+    {
+        Ok(rec) => rec,
+        Err(e) => return HttpResponse::InternalServerError().body(e.to_string()),
+    };
+    if rec.email_verified_at.is_some() {
+        return HttpResponse::Conflict().body("email is already confirmed");
+    }
+    match send_verification(user.user_id, &rec.username, &rec.email, &app).await {
+        Ok(_) => HttpResponse::Ok().body(""),
+        Err(e) => HttpResponse::InternalServerError().body(e.to_string()),
+    }
+}
+
+#[derive(Deserialize)]
+struct ForgotPasswordRequest {
+    email: String,
+}
+
+// the same answer whether or not the address has an account
+const RESET_SENT: &str = "if an account uses that email, a reset link has been sent to it";
+
+#[post("/forgot_password")]
+pub async fn forgot_password_handle(
+    app: web::Data<AppState>,
+    form_data: web::Form<ForgotPasswordRequest>,
+    req: HttpRequest,
+) -> HttpResponse {
+    let users = match sqlx::query!(
+        r#"select u.id, u.username, exists(
+            select 1 from user_tokens t where t.user_id = u.id and t.purpose = 'reset_password'
+            and t.created_at > $2
+        ) as "recently_sent!"
+        from users u where u.email = $1 and u.disabled_at is null and not u.is_bot"#,
+        form_data.email,
+        Utc::now() - Duration::seconds(RESET_EMAIL_COOLDOWN_SECS)
+    )
+    .fetch_all(&app.pool)
+    .await
+    {
+        Ok(users) => users,
+        Err(e) => return HttpResponse::InternalServerError().json(e.to_string()),
+    };
+
+    let ttl = app.config.password_reset_ttl;
+    for user in users.into_iter().filter(|user| !user.recently_sent) {
+        let token = match issue_token(
+            user.id,
+            UserTokenPurpose::RESET_PASSWORD,
+            &form_data.email,
+            ttl,
+            &app.pool,
+        )
+        .await
+        {
+            Ok(token) => token,
+            Err(e) => return HttpResponse::InternalServerError().json(e.to_string()),
+        };
+        let template = Template::PasswordReset {
+            username: user.username,
+            link: format!(
+                "{}/auth/reset_password?token={}",
+                app.config.public_url, token
+            ),
+            minutes: ttl.num_minutes(),
+        };
+        send_later(&app.mailer, Email::new(&form_data.email, &template));
+        audit_event(
+            &app.pool,
+            &req,
+            None,
+            "auth.password.reset_requested",
+            Some(format!("user:{}", user.id)),
+            None,
+        )
+        .await;
+    }
+    HttpResponse::Ok().json(RESET_SENT)
+}
+
+// the page the reset link opens, it only has to post the token back with a new password
+const RESET_FORM: &str = r#"<!doctype html>
+<html>
+<head><title>Reset your password</title></head>
+<body>
+<form method="post" action="/auth/reset_password">
+<input type="hidden" name="token" value="{{token}}">
+<label>New password <input type="password" name="password" required></label>
+<button type="submit">Reset password</button>
+</form>
+</body>
+</html>
+"#;
+
+#[get("/reset_password")]
+pub async fn reset_password_form_handle(query: web::Query<TokenQuery>) -> HttpResponse {
+    // tokens are alphanumeric, anything else didn't come from us
+    if !query.token.chars().all(|c| c.is_ascii_alphanumeric()) {
+        return HttpResponse::BadRequest().json("link is invalid or has expired");
+    }
+    HttpResponse::Ok()
+        .content_type("text/html; charset=utf-8")
+        .body(RESET_FORM.replace("{{token}}", &query.token))
+}
+
+#[derive(Deserialize)]
+struct ResetPasswordRequest {
+    token: String,
+    password: String,
+}
+
+#[post("/reset_password")]
+pub async fn reset_password_handle(
+    app: web::Data<AppState>,
+    tokenstore: web::Data<Arc<TokenStore>>,
+    form_data: web::Form<ResetPasswordRequest>,
+    req: HttpRequest,
+) -> HttpResponse {
+    if form_data.password.is_empty() {
+        return HttpResponse::BadRequest().json("password must not be empty");
+    }
+    let (ciphertext, salt) = match create_ciphertext(&form_data.password) {
+        Ok(val) => val,
+        Err(e) => return HttpResponse::InternalServerError().json(e.to_string()),
+    };
+    let used = match consume_token(
+        &form_data.token,
+        UserTokenPurpose::RESET_PASSWORD,
+        &app.pool,
+    )
+    .await
+    {
+        Ok(Some(used)) => used,
+        Ok(None) => return HttpResponse::BadRequest().json("link is invalid or has expired"),
+        Err(e) => return HttpResponse::InternalServerError().json(e.to_string()),
+    };
+
+    let result = async {
+        let mut tx = app.pool.begin().await?;
+        // getting the link proves the email is theirs too, as long as it hasn't changed since
+        let user = sqlx::query!(
+            r#"update users set password = $1, salt = $2,
+            email_verified_at = case when email = $4 then coalesce(email_verified_at, now())
+                else email_verified_at end
+            where id = $3 returning username, email"#,
+            ciphertext,
+            salt,
+            used.user_id,
+            used.email
+        )
+        .fetch_one(&mut *tx)
+        .await?;
+        // any other links still in someone's inbox stop working
+        sqlx::query!(
+            r#"update user_tokens set used_at = now()
+            where user_id = $1 and purpose = 'reset_password' and used_at is null"#,
+            used.user_id
+        )
+        .execute(&mut *tx)
+        .await?;
+        tx.commit().await?;
+        Ok::<_, sqlx::Error>(user)
+    }
+    .await;
+    let user = match result {
+        Ok(user) => user,
+        Err(e) => return HttpResponse::InternalServerError().json(e.to_string()),
+    };
+
+    let sessions = tokenstore.invalidate_user(used.user_id);
+    if let Err(e) = throttle::clear(&throttle::account_key(&user.username), &app.pool).await {
+        log::error!(
+            "failed to clear login failures for user {}: {e}",
+            used.user_id
+        );
+    }
+    let template = Template::PasswordChanged {
+        username: user.username.clone(),
+    };
+    send_later(&app.mailer, Email::new(&user.email, &template));
+    audit_event(
+        &app.pool,
+        &req,
+        Some(used.user_id),
+        "auth.password.reset",
+        Some(format!("user:{}", used.user_id)),
+        Some(serde_json::json!({ "sessions_ended": sessions })),
+    )
+    .await;
+    HttpResponse::Ok().json("password has been reset, please log in again")
+}
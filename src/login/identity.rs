@@ -0,0 +1,205 @@
+use super::login::create_ciphertext;
+use crate::auth::generate_token;
+use crate::custom_types::UserRole;
+use actix_web::HttpResponse;
+use chrono::{DateTime, Utc};
+use sqlx::{Pool, Postgres};
+
+/// Someone an outside provider (OpenID Connect, LDAP) has vouched for
+pub struct ExternalIdentity {
+    /// which provider, e.g. the OIDC issuer url
+    pub provider: String,
+    /// the provider's own id for them, never reused
+    pub subject: String,
+    /// lowercased, & one the provider has checked
+    pub email: String,
+    pub preferred_username: Option<String>,
+    pub display_name: Option<String>,
+}
+
+/// The local user an identity belongs to
+pub struct IdentityUser {
+    pub id: i32,
+    pub role: UserRole,
+    pub disabled_at: Option<DateTime<Utc>>,
+    /// `"link"` or `"provision"` when the identity got its user just now
+    pub resolution: Option<&'static str>,
+}
+
+pub enum IdentityError {
+    /// a local account has the email but never proved it was theirs
+    Unconfirmed,
+    /// nobody has the email & provisioning is off
+    NoAccount,
+    Db(sqlx::Error),
+}
+
+impl From<sqlx::Error> for IdentityError {
+    fn from(e: sqlx::Error) -> Self {
+        IdentityError::Db(e)
+    }
+}
+
+impl std::fmt::Display for IdentityError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            IdentityError::Unconfirmed => write!(
+                f,
+                "an account with this email exists but hasn't confirmed it, \
+                log in with its password & confirm the email first"
+            ),
+            IdentityError::NoAccount => write!(f, "no account uses this email"),
+            IdentityError::Db(e) => write!(f, "{e}"),
+        }
+    }
+}
+
+impl IdentityError {
+    pub fn response(&self) -> HttpResponse {
+        match self {
+            IdentityError::Unconfirmed => HttpResponse::Conflict().json(self.to_string()),
+            IdentityError::NoAccount => HttpResponse::Forbidden().json(self.to_string()),
+            IdentityError::Db(e) => HttpResponse::InternalServerError().json(e.to_string()),
+        }
+    }
+}
+
+/// Finds the user an identity belongs to, linking it to an account with the
+/// same verified email or making a new one the first time it's seen
+pub async fn resolve_identity(
+    identity: &ExternalIdentity,
+    auto_provision: bool,
+    pool: &Pool<Postgres>,
+) -> Result<IdentityUser, IdentityError> {
+    if let Some(user) = sqlx::query!(
+        r#"select u.id, u.role as "role: UserRole", u.disabled_at
+        from user_identities i join users u on u.id = i.user_id
+        where i.provider = $1 and i.subject = $2"#,
+        identity.provider,
+        identity.subject
+    )
+    .fetch_optional(pool)
+    .await?
+    {
+        return Ok(IdentityUser {
+            id: user.id,
+            role: user.role,
+            disabled_at: user.disabled_at,
+            resolution: None,
+        });
+    }
+
+    let by_email = sqlx::query!(
+        r#"select id, role as "role: UserRole", disabled_at, email_verified_at
+        from users where lower(email) = $1"#,
+        identity.email
+    )
+    .fetch_optional(pool)
+    .await?;
+    match by_email {
+        // anyone can sign up with an address they don't own, so only accounts
+        // that proved it get linked
+        Some(user) if user.email_verified_at.is_none() => Err(IdentityError::Unconfirmed),
+        Some(user) => Ok(IdentityUser {
+            id: user.id,
+            role: user.role,
+            disabled_at: user.disabled_at,
+            resolution: Some("link"),
+        }),
+        None if !auto_provision => Err(IdentityError::NoAccount),
+        None => Ok(IdentityUser {
+            id: provision_user(identity, pool).await?,
+            role: UserRole::NORMAL,
+            disabled_at: None,
+            resolution: Some("provision"),
+        }),
+    }
+}
+
+/// Remembers the identity belongs to `user_id` & when it was last used
+pub async fn record_identity_login(
+    identity: &ExternalIdentity,
+    user_id: i32,
+    pool: &Pool<Postgres>,
+) -> Result<(), sqlx::Error> {
+    sqlx::query!(
+        r#"insert into user_identities (provider, subject, user_id, email, last_login_at)
+        values ($1, $2, $3, $4, now())
+        on conflict (provider, subject) do update set email = $4, last_login_at = now()"#,
+        identity.provider,
+        identity.subject,
+        user_id,
+        identity.email
+    )
+    .execute(pool)
+    .await?;
+    Ok(())
+}
+
+/// A username made from what the provider gave, letters, digits, `.`, `_` & `-` only
+pub fn username_base(preferred_username: Option<&str>, email: &str) -> String {
+    let local_part = email.split('@').next().unwrap_or_default();
+    let clean = |name: &str| -> String {
+        name.chars()
+            .filter(|c| c.is_ascii_alphanumeric() || matches!(c, '.' | '_' | '-'))
+            .collect::<String>()
+            .to_lowercase()
+    };
+    match preferred_username.map(clean) {
+        Some(name) if !name.is_empty() => name,
+        _ => match clean(local_part) {
+            name if name.is_empty() => "user".to_owned(),
+            name => name,
+        },
+    }
+}
+
+/// Makes a new user for someone a provider vouched for. Their email is
+/// already verified, & they get a random password they never see, so they can
+/// only get in through the provider unless they reset it
+async fn provision_user(
+    identity: &ExternalIdentity,
+    pool: &Pool<Postgres>,
+) -> Result<i32, sqlx::Error> {
+    let base = username_base(identity.preferred_username.as_deref(), &identity.email);
+    let taken: Vec<String> = sqlx::query_scalar!(
+        r#"select username from users where username like $1 || '%'"#,
+        base
+    )
+    .fetch_all(pool)
+    .await?;
+    let username = std::iter::once(base.clone())
+        .chain((2..).map(|n| format!("{base}{n}")))
+        .find(|name| !taken.contains(name))
+        .unwrap_or(base);
+    let (ciphertext, salt) =
+        create_ciphertext(&generate_token()).map_err(|e| sqlx::Error::Protocol(e.to_string()))?;
+    let display_name = identity.display_name.clone().unwrap_or(username.clone());
+    sqlx::query_scalar!(
+        r#"insert into users (username, password, salt, display_name, email, email_verified_at)
+        values ($1, $2, $3, $4, $5, now()) returning id"#,
+        username,
+        ciphertext,
+        salt,
+        display_name,
+        identity.email
+    )
+    .fetch_one(pool)
+    .await
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn username_prefers_the_providers_choice() {
+        assert_eq!(
+            username_base(Some("Alice.Smith"), "a@example.com"),
+            "alice.smith"
+        );
+        assert_eq!(username_base(None, "Bob+chat@example.com"), "bobchat");
+        assert_eq!(username_base(Some("  "), "carol@example.com"), "carol");
+        assert_eq!(username_base(Some("李"), "+@example.com"), "user");
+    }
+}
@@ -0,0 +1,288 @@
+use super::identity::{record_identity_login, resolve_identity, ExternalIdentity};
+use super::provider::{AuthProvider, ProviderUser};
+use crate::groups::groups::add_to_group;
+use crate::server::config::LdapConfig;
+use crate::webhooks::events::{enqueue_event, WebhookEvent};
+use async_trait::async_trait;
+use ldap3::{dn_escape, ldap_escape, Ldap, LdapConnAsync, LdapConnSettings, Scope, SearchEntry};
+use serde_json::json;
+use sqlx::{Pool, Postgres};
+
+// LDAP result code for a wrong DN or password
+const INVALID_CREDENTIALS: u32 = 49;
+
+/// What the directory holds about someone who just bound as themselves
+struct DirectoryEntry {
+    dn: String,
+    display_name: Option<String>,
+    email: Option<String>,
+    /// lowercased DNs of the mapped groups they're in
+    groups: Vec<String>,
+}
+
+/// Checks passwords by binding to the directory as the user, then keeps their
+/// display name, email & mapped group memberships in step with it
+pub struct LdapProvider {
+    config: LdapConfig,
+    join_default_roles: Vec<String>,
+}
+
+impl LdapProvider {
+    pub fn new(config: LdapConfig, join_default_roles: Vec<String>) -> Self {
+        LdapProvider {
+            config,
+            join_default_roles,
+        }
+    }
+
+    /// `Ok(None)` if the directory turned the bind down
+    async fn lookup(
+        &self,
+        username: &str,
+        password: &str,
+    ) -> Result<Option<DirectoryEntry>, String> {
+        let settings = LdapConnSettings::new()
+            .set_conn_timeout(self.config.timeout)
+            .set_starttls(self.config.starttls);
+        let (conn, mut ldap) = LdapConnAsync::with_settings(settings, &self.config.url)
+            .await
+            .map_err(|e| e.to_string())?;
+        ldap3::drive!(conn);
+        let entry = self.bind_and_search(&mut ldap, username, password).await;
+        if let Err(e) = ldap.unbind().await {
+            log::warn!("ldap unbind failed: {e}");
+        }
+        entry.map_err(|e| e.to_string())
+    }
+
+    async fn bind_and_search(
+        &self,
+        ldap: &mut Ldap,
+        username: &str,
+        password: &str,
+    ) -> Result<Option<DirectoryEntry>, ldap3::LdapError> {
+        let bind_dn = self
+            .config
+            .bind_dn_template
+            .replace("{username}", &dn_escape(username));
+        let bound = ldap
+            .with_timeout(self.config.timeout)
+            .simple_bind(&bind_dn, password)
+            .await?;
+        if bound.rc == INVALID_CREDENTIALS {
+            return Ok(None);
+        }
+        bound.success()?;
+
+        let filter = self
+            .config
+            .user_filter
+            .replace("{username}", &ldap_escape(username));
+        let attrs = [
+            self.config.display_name_attr.as_str(),
+            self.config.email_attr.as_str(),
+        ];
+        let (entries, _) = ldap
+            .with_timeout(self.config.timeout)
+            .search(&self.config.search_base, Scope::Subtree, &filter, attrs)
+            .await?
+            .success()?;
+        // the bind worked, so an entry not turning up means the filter is wrong
+        let entry = match entries.into_iter().next() {
+            Some(entry) => SearchEntry::construct(entry),
+            None => {
+                return Err(ldap3::LdapError::AdapterInit(format!(
+                    "bound as {bind_dn} but {filter} found nothing under {}",
+                    self.config.search_base
+                )))
+            }
+        };
+        let first = |attr: &str| entry.attrs.get(attr).and_then(|vals| vals.first()).cloned();
+
+        let mut groups = Vec::new();
+        if !self.config.group_map.is_empty() {
+            let filter = self
+                .config
+                .group_filter
+                .replace("{dn}", &ldap_escape(entry.dn.as_str()));
+            let (found, _) = ldap
+                .with_timeout(self.config.timeout)
+                .search(&self.config.group_base, Scope::Subtree, &filter, ["1.1"])
+                .await?
+                .success()?;
+            groups = found
+                .into_iter()
+                .map(|group| SearchEntry::construct(group).dn.to_lowercase())
+                .collect();
+        }
+        Ok(Some(DirectoryEntry {
+            display_name: first(&self.config.display_name_attr),
+            email: first(&self.config.email_attr).map(|email| email.to_lowercase()),
+            dn: entry.dn,
+            groups,
+        }))
+    }
+
+    /// Copies the directory's name & email onto the user. Directory emails are
+    /// vouched for, so they count as verified
+    async fn sync_profile(
+        &self,
+        user_id: i32,
+        entry: &DirectoryEntry,
+        pool: &Pool<Postgres>,
+    ) -> Result<(), sqlx::Error> {
+        sqlx::query!(
+            r#"update users set display_name = coalesce($2, display_name),
+            email_verified_at = case when lower(email) = $3 then coalesce(email_verified_at, now())
+                else now() end,
+            email = $3
+            where id = $1"#,
+            user_id,
+            entry.display_name,
+            entry.email
+        )
+        .execute(pool)
+        .await?;
+        Ok(())
+    }
+
+    /// Adds the user to the chat groups mapped from directory groups they're
+    /// in, & takes them out of ones they were only in because of a directory
+    /// group they've since left. Owners are never removed
+    async fn sync_groups(
+        &self,
+        user_id: i32,
+        entry: &DirectoryEntry,
+        pool: &Pool<Postgres>,
+    ) -> Result<(Vec<i32>, Vec<i32>), sqlx::Error> {
+        let (member_of, not_member_of): (Vec<_>, Vec<_>) = self
+            .config
+            .group_map
+            .iter()
+            .partition(|(dn, _)| entry.groups.contains(dn));
+        let member_of: Vec<i32> = member_of.into_iter().map(|(_, id)| *id).collect();
+        let not_member_of: Vec<i32> = not_member_of
+            .into_iter()
+            .map(|(_, id)| *id)
+            .filter(|id| !member_of.contains(id))
+            .collect();
+
+        let mut tx = pool.begin().await?;
+        let added: Vec<i32> = sqlx::query_scalar!(
+            r#"select g.id from groups g where g.id = any($1) and g.deleted_at is null
+            and not exists(select 1 from group_permissions p where p.group_id = g.id and p.user_id = $2)"#,
+            &member_of,
+            user_id
+        )
+        .fetch_all(&mut *tx)
+        .await?;
+        for group_id in &added {
+            add_to_group(
+                *group_id,
+                user_id,
+                user_id,
+                &self.join_default_roles,
+                &mut tx,
+            )
+            .await?;
+        }
+        sqlx::query!(
+            r#"update group_permissions set directory_synced = true
+            where user_id = $1 and group_id = any($2)"#,
+            user_id,
+            &added
+        )
+        .execute(&mut *tx)
+        .await?;
+
+        let removed: Vec<i32> = sqlx::query_scalar!(
+            r#"delete from group_permissions p using groups g
+            where g.id = p.group_id and p.user_id = $1 and p.group_id = any($2)
+            and p.directory_synced and g.owner_id is distinct from $1
+            returning p.group_id"#,
+            user_id,
+            &not_member_of
+        )
+        .fetch_all(&mut *tx)
+        .await?;
+        sqlx::query!(
+            r#"delete from group_role_members m using group_roles r
+            where r.id = m.role_id and m.user_id = $1 and r.group_id = any($2)"#,
+            user_id,
+            &removed
+        )
+        .execute(&mut *tx)
+        .await?;
+        for group_id in &removed {
+            enqueue_event(
+                &mut *tx,
+                WebhookEvent::MemberLeft,
+                *group_id,
+                json!({ "user_id": user_id, "reason": "directory_sync" }),
+            )
+            .await?;
+        }
+        tx.commit().await?;
+        Ok((added, removed))
+    }
+}
+
+#[async_trait]
+impl AuthProvider for LdapProvider {
+    fn name(&self) -> &'static str {
+        "ldap"
+    }
+
+    async fn authenticate(
+        &self,
+        username: &str,
+        password: &str,
+        pool: &Pool<Postgres>,
+    ) -> Result<Option<ProviderUser>, String> {
+        // an empty password is an anonymous bind, which most servers let through
+        if password.is_empty() {
+            return Ok(None);
+        }
+        let entry = match self.lookup(username, password).await? {
+            Some(entry) => entry,
+            None => return Ok(None),
+        };
+        let email = entry
+            .email
+            .clone()
+            .ok_or_else(|| format!("{} has no {}", entry.dn, self.config.email_attr))?;
+        let identity = ExternalIdentity {
+            provider: self.config.url.clone(),
+            subject: entry.dn.clone(),
+            email,
+            preferred_username: Some(username.to_owned()),
+            display_name: entry.display_name.clone(),
+        };
+        let user = resolve_identity(&identity, self.config.auto_provision, pool)
+            .await
+            .map_err(|e| format!("{}: {e}", entry.dn))?;
+        record_identity_login(&identity, user.id, pool)
+            .await
+            .map_err(|e| e.to_string())?;
+        self.sync_profile(user.id, &entry, pool)
+            .await
+            .map_err(|e| e.to_string())?;
+        let (groups_added, groups_removed) = self
+            .sync_groups(user.id, &entry, pool)
+            .await
+            .map_err(|e| e.to_string())?;
+
+        Ok(Some(ProviderUser {
+            id: user.id,
+            role: user.role,
+            disabled_at: user.disabled_at,
+            details: Some(json!({
+                "provider": "ldap",
+                "dn": entry.dn,
+                "resolution": user.resolution,
+                "groups_added": groups_added,
+                "groups_removed": groups_removed,
+            })),
+        }))
+    }
+}
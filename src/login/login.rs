@@ -1,11 +1,19 @@
+use super::email::send_verification;
+use super::throttle;
+use super::two_factor::{login_step, LoginStep};
+use crate::audit::audit::{audit_event, client_ip};
+use crate::auth::{generate_token, Authentication};
+use crate::custom_types::UserRole;
 use crate::{auth::TokenStore, server::app_state::AppState};
-use actix_web::{http::StatusCode, post, put, web, HttpRequest, HttpResponse};
+use actix_web::http::{header, StatusCode};
+use actix_web::{post, put, web, HttpRequest, HttpResponse};
 use argon2::{
     password_hash::{rand_core::OsRng, PasswordHash, PasswordHasher, PasswordVerifier, SaltString},
     Argon2,
 };
 use serde::{Deserialize, Serialize};
-use std::sync::Arc;
+use serde_json::json;
+use std::sync::{Arc, OnceLock};
 
 #[derive(Serialize, Deserialize, Debug)]
 struct LoginRequest {
@@ -13,8 +21,7 @@ struct LoginRequest {
     password: String,
 }
 
-
-/// A generic http response builder, giving INTERNAL_SERVER_ERROR 
+/// A generic http response builder, giving INTERNAL_SERVER_ERROR
 /// or a response of given status with message attached
 /// ```
 /// use login::resp;
@@ -30,36 +37,205 @@ fn resp(message: &str, status: Option<StatusCode>) -> HttpResponse {
 }
 
 #[derive(Serialize, Deserialize)]
-struct LoginResponse {
+pub struct LoginResponse {
+    pub user_id: i32,
+    pub token: String,
+}
+
+/// Sent instead of a session when the password was right but a second factor
+/// is still needed, either a code for `/auth/login/2fa` or, with
+/// `enrollment_required`, setting one up through `/auth/2fa/enroll`
+#[derive(Serialize)]
+struct ChallengeResponse {
     user_id: i32,
-    token: String,
+    challenge: String,
+    enrollment_required: bool,
 }
 
+// the same answer for unknown users & wrong passwords, so logins can't be used to find usernames
+const LOGIN_FAILED: &str = "invalid username or password";
+
 #[post("/login")]
 pub async fn login_handle(
     app: web::Data<AppState>,
     tokenstore: web::Data<Arc<TokenStore>>,
     form_data: web::Form<LoginRequest>,
+    req: HttpRequest,
 ) -> HttpResponse {
     println!("{:?}", tokenstore);
-    let (user_id, password) = match sqlx::query!(
-        r#"select id, password from users where username=$1"#,
-        form_data.username
+    let target = Some(format!("username:{}", form_data.username));
+    let account_key = throttle::account_key(&form_data.username);
+    let ip_key = client_ip(&req).map(|ip| throttle::ip_key(&ip));
+    let keys: Vec<String> = std::iter::once(account_key.clone())
+        .chain(ip_key.clone())
+        .collect();
+
+    match throttle::locked_until(&keys, &app.pool).await {
+        Ok(None) => {}
+        Ok(Some(until)) => {
+            audit_event(
+                &app.pool,
+                &req,
+                None,
+                "auth.login.locked",
+                target,
+                Some(json!({ "locked_until": until })),
+            )
+            .await;
+            let wait = (until - chrono::Utc::now()).num_seconds().max(1);
+            return HttpResponse::build(StatusCode::TOO_MANY_REQUESTS)
+                .insert_header((header::RETRY_AFTER, wait.to_string()))
+                .json("too many failed login attempts, try again later");
+        }
+        Err(e) => return resp(&e.to_string(), None),
+    }
+
+    let mut user = None;
+    for provider in &app.auth_providers {
+        match provider
+            .authenticate(&form_data.username, &form_data.password, &app.pool)
+            .await
+        {
+            Ok(Some(found)) => {
+                user = Some(found);
+                break;
+            }
+            Ok(None) => {}
+            Err(e) => log::error!("{} login failed, trying the next provider: {e}", provider.name()),
+        }
+    }
+
+    let user = match user {
+        Some(user) => user,
+        None => {
+            let user_id = match sqlx::query_scalar!(
+                r#"select id from users where username = $1"#,
+                form_data.username
+            )
+            .fetch_optional(&app.pool)
+            .await
+            {
+                Ok(user_id) => user_id,
+                Err(e) => return resp(&e.to_string(), None),
+            };
+            let reason = match user_id {
+                Some(_) => "bad password",
+                None => "unknown user",
+            };
+            for key in &keys {
+                let policy = match key == &account_key {
+                    true => &app.config.login_account_policy,
+                    false => &app.config.login_ip_policy,
+                };
+                if let Err(e) = throttle::record_failure(
+                    key,
+                    policy,
+                    app.config.login_failure_window,
+                    &app.pool,
+                )
+                .await
+                {
+                    log::error!("failed to record login failure for {key}: {e}");
+                }
+            }
+            audit_event(
+                &app.pool,
+                &req,
+                user_id,
+                "auth.login.failure",
+                target,
+                Some(json!({ "reason": reason })),
+            )
+            .await;
+            return resp(LOGIN_FAILED, Some(StatusCode::UNAUTHORIZED));
+        }
+    };
+
+    // only said once the password is right, so it doesn't give away the account either
+    if user.disabled_at.is_some() {
+        audit_event(
+            &app.pool,
+            &req,
+            Some(user.id),
+            "auth.login.failure",
+            target,
+            Some(json!({ "reason": "disabled" })),
+        )
+        .await;
+        return resp("account is disabled", Some(StatusCode::FORBIDDEN));
+    }
+
+    if let Err(e) = throttle::clear(&account_key, &app.pool).await {
+        log::error!("failed to clear login failures for {account_key}: {e}");
+    }
+    complete_login(
+        user.id,
+        user.role,
+        target,
+        user.details,
+        &app,
+        &tokenstore,
+        &req,
     )
-    .fetch_one(&app.pool)
     .await
-    {
-        Ok(ret) => (ret.id, ret.password),
-        Err(_e) => return resp("user not found", None),
-    };
+}
 
-    match check_password(&form_data.password, &password) {
-        true => {
-            let token = tokenstore.validate_user(user_id);
-            HttpResponse::build(StatusCode::OK).json(LoginResponse { token, user_id })
-        }
-        false => return resp("password doesn't match", Some(StatusCode::UNAUTHORIZED)),
+/// The end of every way of logging in, once the user has proven who they are:
+/// hands out a session, or a challenge if a second factor is still needed
+pub async fn complete_login(
+    user_id: i32,
+    role: UserRole,
+    target: Option<String>,
+    details: Option<serde_json::Value>,
+    app: &AppState,
+    tokenstore: &TokenStore,
+    req: &HttpRequest,
+) -> HttpResponse {
+    let enrollment_required = match login_step(user_id, role, &app.pool).await {
+        Ok(LoginStep::Session) => None,
+        Ok(LoginStep::Challenge) => Some(false),
+        Ok(LoginStep::Enroll) => Some(true),
+        Err(e) => return resp(&e.to_string(), None),
+    };
+    if let Some(enrollment_required) = enrollment_required {
+        audit_event(
+            &app.pool,
+            req,
+            Some(user_id),
+            "auth.login.challenge",
+            target,
+            Some(json!({ "enrollment_required": enrollment_required, "details": details })),
+        )
+        .await;
+        return HttpResponse::Ok().json(ChallengeResponse {
+            user_id,
+            challenge: tokenstore.issue_challenge(user_id),
+            enrollment_required,
+        });
     }
+
+    audit_event(
+        &app.pool,
+        req,
+        Some(user_id),
+        "auth.login.success",
+        target,
+        details,
+    )
+    .await;
+    let token = tokenstore.validate_user(user_id);
+    HttpResponse::build(StatusCode::OK).json(LoginResponse { token, user_id })
+}
+
+/// A real argon2 hash of nothing in particular, for checking passwords against
+/// when the user doesn't exist
+pub fn dummy_ciphertext() -> &'static String {
+    static DUMMY: OnceLock<String> = OnceLock::new();
+    DUMMY.get_or_init(|| {
+        create_ciphertext(&generate_token())
+            .map(|(ciphertext, _salt)| ciphertext)
+            .expect("hashing a random password")
+    })
 }
 
 // Register
@@ -98,20 +274,22 @@ async fn check_user_duplicate(
     }
 }
 
-fn check_password(plaintext: &String, ciphertext: &String) -> bool {
+/// False for a stored hash that can't be parsed, nothing matches it
+pub fn check_password(plaintext: &str, ciphertext: &str) -> bool {
     let argon2 = Argon2::default();
     // let salt_str = SaltString::from_b64(salt).unwrap();
     // let password_hash = argon2.hash_password(plaintext.as_bytes(), &salt_str).unwrap();
-    // let parsed_hash = PasswordHash::new(&ciphertext).unwrap();
-    argon2
-        .verify_password(
-            plaintext.as_bytes(),
-            &PasswordHash::new(ciphertext).unwrap(),
-        )
-        .is_ok()
+    match PasswordHash::new(ciphertext) {
+        Ok(parsed_hash) => argon2
+            .verify_password(plaintext.as_bytes(), &parsed_hash)
+            .is_ok(),
+        Err(_) => false,
+    }
 }
 
-fn create_ciphertext(plaintext: &String) -> Result<(String, String), argon2::password_hash::Error> {
+pub fn create_ciphertext(
+    plaintext: &String,
+) -> Result<(String, String), argon2::password_hash::Error> {
     let salt = SaltString::generate(&mut OsRng);
     let argon2 = Argon2::default();
 
@@ -156,6 +334,7 @@ pub async fn register_handle(
     tokenstore: web::Data<Arc<TokenStore>>,
     // data: web::Json<RegisterRequest>,
     form_data: web::Form<RegisterRequest>,
+    req: HttpRequest,
 ) -> HttpResponse {
     // first check that username / email is not already entered
     match check_user_duplicate(
@@ -169,31 +348,58 @@ pub async fn register_handle(
         _ => {}
     }
 
+    let username = form_data.username.clone();
+    let email = form_data.email.clone();
     let user_id = match insert_user(&app.pool, form_data).await {
         Ok(id) => id,
         Err(val) => return val,
     };
+    if let Some(email) = email {
+        let username = username.unwrap_or_default();
+        if let Err(e) = send_verification(user_id, &username, &email, &app).await {
+            log::error!("failed to send verification email to user {user_id}: {e}");
+        }
+    }
 
+    audit_event(
+        &app.pool,
+        &req,
+        Some(user_id),
+        "auth.register",
+        Some(format!("user:{user_id}")),
+        None,
+    )
+    .await;
     let token = tokenstore.validate_user(user_id);
     HttpResponse::Ok().json(LoginResponse { token, user_id })
 }
 
 #[put("/logout")]
 pub async fn logout_handle(
+    app: web::Data<AppState>,
     tokenstore: web::Data<Arc<TokenStore>>,
+    auth: Authentication,
     req: HttpRequest,
 ) -> HttpResponse {
     // by being here, the user should already be logged in & verified
     // so just need to remove the user from the mutex
-    let token = req
-        .headers()
-        .get("Authorization")
-        .unwrap()
-        .to_str()
-        .unwrap();
-    // .parse::<String>()
-    // .unwrap();
-    let user_id = tokenstore.invalidate_token(token.to_owned());
+    let token = match auth {
+        Authentication::Session { token } => token,
+        // AuthStruct doesn't let api tokens through to here
+        Authentication::ApiToken { .. } => {
+            return resp("api tokens are revoked, not logged out", Some(StatusCode::FORBIDDEN))
+        }
+    };
+    let user_id = tokenstore.invalidate_token(token);
+    audit_event(
+        &app.pool,
+        &req,
+        Some(user_id),
+        "auth.logout",
+        Some(format!("user:{user_id}")),
+        None,
+    )
+    .await;
     HttpResponse::Ok().json(format!("logged out user {user_id}"))
 }
 
@@ -212,4 +418,10 @@ mod tests {
         let (ciphertext, _salt) = create_ciphertext(&password).unwrap();
         assert!(check_password(&password, &ciphertext));
     }
+
+    #[test]
+    fn unparseable_hashes_match_nothing() {
+        assert!(!check_password("!", "!"));
+        assert!(!check_password("", ""));
+    }
 }
@@ -1 +1,35 @@
-pub mod login;
\ No newline at end of file
+pub mod email;
+pub mod identity;
+pub mod ldap;
+pub mod login;
+pub mod oidc;
+pub mod privacy;
+pub mod provider;
+pub mod throttle;
+pub mod totp;
+pub mod two_factor;
+use actix_web::web;
+use email::handle_resend_verification;
+use privacy::{handle_cancel_deletion, handle_download_my_data, handle_request_deletion};
+use two_factor::{
+    handle_confirm, handle_disable_two_factor, handle_enroll, handle_get_two_factor,
+    handle_regenerate_recovery_codes,
+};
+
+pub fn account_routes(cfg: &mut web::ServiceConfig) {
+    cfg.service(
+        web::scope("/account/2fa")
+            .service(handle_get_two_factor)
+            .service(handle_enroll)
+            .service(handle_confirm)
+            .service(handle_regenerate_recovery_codes)
+            .service(handle_disable_two_factor),
+    )
+    .service(
+        web::scope("/account")
+            .service(handle_resend_verification)
+            .service(handle_download_my_data)
+            .service(handle_request_deletion)
+            .service(handle_cancel_deletion),
+    );
+}
@@ -0,0 +1,392 @@
+use crate::audit::audit::{audit_event, record_audit};
+use crate::auth::TokenStore;
+use crate::custom_types::{JoinRequestStatus, MessageType, ScheduledMessageStatus, UserRole};
+use crate::extractors::extractors::User;
+use crate::server::app_state::AppState;
+use actix_web::{delete, get, post, web, HttpRequest, HttpResponse, Responder};
+use chrono::{DateTime, Duration, Utc};
+use serde::Serialize;
+use serde_json::json;
+use sqlx::{Pool, Postgres};
+use std::sync::Arc;
+
+#[derive(Serialize)]
+struct Profile {
+    id: i32,
+    username: String,
+    display_name: String,
+    email: String,
+    bio: Option<String>,
+    time_zone: Option<String>,
+    pronouns: Option<String>,
+    avatar_url: Option<String>,
+    status_text: Option<String>,
+    status_emoji: Option<String>,
+    role: UserRole,
+    created_at: Option<DateTime<Utc>>,
+    email_verified_at: Option<DateTime<Utc>>,
+    deletion_requested_at: Option<DateTime<Utc>>,
+}
+
+#[derive(Serialize)]
+struct Identity {
+    provider: String,
+    email: Option<String>,
+    created_at: DateTime<Utc>,
+    last_login_at: Option<DateTime<Utc>>,
+}
+
+#[derive(Serialize)]
+struct Membership {
+    group_id: i32,
+    group_name: String,
+    joined_at: Option<DateTime<Utc>>,
+    roles: Vec<String>,
+}
+
+#[derive(Serialize)]
+struct AuthoredMessage {
+    id: i32,
+    group_id: i32,
+    content: String,
+    message_type: MessageType,
+    payload: Option<serde_json::Value>,
+    sent_at: Option<DateTime<Utc>>,
+}
+
+#[derive(Serialize)]
+struct ScheduledMessage {
+    id: i32,
+    group_id: i32,
+    content: String,
+    send_at: DateTime<Utc>,
+    status: ScheduledMessageStatus,
+}
+
+#[derive(Serialize)]
+struct PollVote {
+    message_id: i32,
+    option_index: i32,
+    voted_at: DateTime<Utc>,
+}
+
+#[derive(Serialize)]
+struct JoinRequest {
+    group_id: i32,
+    note: Option<String>,
+    status: JoinRequestStatus,
+    created_at: Option<DateTime<Utc>>,
+}
+
+#[derive(Serialize)]
+struct ApiToken {
+    name: String,
+    scopes: Vec<String>,
+    created_at: DateTime<Utc>,
+    expires_at: Option<DateTime<Utc>>,
+    revoked_at: Option<DateTime<Utc>>,
+}
+
+/// Everything stored about a user, secrets aside
+#[derive(Serialize)]
+struct UserData {
+    exported_at: DateTime<Utc>,
+    profile: Profile,
+    identities: Vec<Identity>,
+    memberships: Vec<Membership>,
+    messages: Vec<AuthoredMessage>,
+    scheduled_messages: Vec<ScheduledMessage>,
+    poll_votes: Vec<PollVote>,
+    join_requests: Vec<JoinRequest>,
+    api_tokens: Vec<ApiToken>,
+}
+
+async fn collect_user_data(user_id: i32, pool: &Pool<Postgres>) -> Result<UserData, sqlx::Error> {
+    Ok(UserData {
+        exported_at: Utc::now(),
+        profile: sqlx::query_as!(
+            Profile,
+            r#"select id, username, display_name, email, bio, time_zone, pronouns, avatar_url,
+            status_text, status_emoji, role as "role: UserRole", created_at,
+            email_verified_at, deletion_requested_at from users where id = $1"#,
+            user_id
+        )
+        .fetch_one(pool)
+        .await?,
+        identities: sqlx::query_as!(
+            Identity,
+            r#"select provider, email, created_at, last_login_at from user_identities
+            where user_id = $1 order by created_at"#,
+            user_id
+        )
+        .fetch_all(pool)
+        .await?,
+        memberships: sqlx::query_as!(
+            Membership,
+            r#"select g.id as group_id, g.group_name, p.created_at as joined_at,
+            array(select r.name from group_roles r join group_role_members rm on rm.role_id = r.id
+                where r.group_id = g.id and rm.user_id = p.user_id order by r.name) as "roles!"
+            from group_permissions p join groups g on g.id = p.group_id
+            where p.user_id = $1 order by g.id"#,
+            user_id
+        )
+        .fetch_all(pool)
+        .await?,
+        messages: sqlx::query_as!(
+            AuthoredMessage,
+            r#"select id, group_id, content, message_type as "message_type: MessageType", payload,
+            sent_at from messages where sender_user_id = $1 order by sent_at, id"#,
+            user_id
+        )
+        .fetch_all(pool)
+        .await?,
+        scheduled_messages: sqlx::query_as!(
+            ScheduledMessage,
+            r#"select id, group_id, content, send_at, status as "status: ScheduledMessageStatus"
+            from scheduled_messages where user_id = $1 order by send_at"#,
+            user_id
+        )
+        .fetch_all(pool)
+        .await?,
+        poll_votes: sqlx::query_as!(
+            PollVote,
+            r#"select message_id, option_index, voted_at from poll_votes
+            where user_id = $1 order by voted_at"#,
+            user_id
+        )
+        .fetch_all(pool)
+        .await?,
+        join_requests: sqlx::query_as!(
+            JoinRequest,
+            r#"select group_id, note, status as "status: JoinRequestStatus", created_at
+            from group_join_requests where user_id = $1 order by created_at"#,
+            user_id
+        )
+        .fetch_all(pool)
+        .await?,
+        api_tokens: sqlx::query_as!(
+            ApiToken,
+            r#"select name, scopes, created_at, expires_at, revoked_at from api_tokens
+            where user_id = $1 order by created_at"#,
+            user_id
+        )
+        .fetch_all(pool)
+        .await?,
+    })
+}
+
+/// Downloads everything stored about the caller as one JSON document
+#[get("/data")]
+async fn handle_download_my_data(
+    user: User,
+    app: web::Data<AppState>,
+    req: HttpRequest,
+) -> impl Responder {
+    match collect_user_data(user.user_id, &app.pool).await {
+        Ok(data) => {
+            audit_event(
+                &app.pool,
+                &req,
+                Some(user.user_id),
+                "account.data.download",
+                Some(format!("user:{}", user.user_id)),
+                None,
+            )
+            .await;
+            HttpResponse::Ok()
+                .insert_header((
+                    "Content-Disposition",
+                    format!(
+                        "attachment; filename=\"user-{}-data-{}.json\"",
+                        user.user_id,
+                        data.exported_at.format("%Y%m%d")
+                    ),
+                ))
+                .json(data)
+        }
+        Err(e) => HttpResponse::InternalServerError().body(e.to_string()),
+    }
+}
+
+#[derive(Serialize)]
+struct DeletionResponse {
+    delete_after: DateTime<Utc>,
+}
+
+/// Asks for the caller's account to be deleted once the cooling off period has
+/// passed. Every session is ended now & api tokens stop working until it's
+/// cancelled, logging back in & cancelling stops it
+#[post("/delete")]
+async fn handle_request_deletion(
+    user: User,
+    app: web::Data<AppState>,
+    req: HttpRequest,
+    tokenstore: web::Data<Arc<TokenStore>>,
+) -> impl Responder {
+    let account = match sqlx::query!(
+        r#"select is_bot, deletion_requested_at,
+        array(select id from groups where owner_id = users.id and deleted_at is null order by id)
+        as "owned_groups!"
+        from users where id = $1"#,
+        user.user_id
+    )
+    .fetch_one(&app.pool)
+    .await
+    {
+        Ok(account) => account,
+        Err(e) => return HttpResponse::InternalServerError().body(e.to_string()),
+    };
+    if account.is_bot {
+        return HttpResponse::BadRequest().body("bots are deleted by their owner");
+    }
+    if !account.owned_groups.is_empty() {
+        return HttpResponse::Conflict().body(format!(
+            "hand over or delete the groups you own first: {:?}",
+            account.owned_groups
+        ));
+    }
+    if let Some(requested_at) = account.deletion_requested_at {
+        return HttpResponse::Conflict().json(DeletionResponse {
+            delete_after: requested_at + app.config.account_deletion_grace,
+        });
+    }
+    let requested_at = match sqlx::query_scalar!(
+        r#"update users set deletion_requested_at = now() where id = $1
+        returning deletion_requested_at as "requested_at!""#,
+        user.user_id
+    )
+    .fetch_one(&app.pool)
+    .await
+    {
+        Ok(requested_at) => requested_at,
+        Err(e) => return HttpResponse::InternalServerError().body(e.to_string()),
+    };
+    let sessions = tokenstore.invalidate_user(user.user_id);
+    let delete_after = requested_at + app.config.account_deletion_grace;
+    audit_event(
+        &app.pool,
+        &req,
+        Some(user.user_id),
+        "account.deletion.request",
+        Some(format!("user:{}", user.user_id)),
+        Some(json!({ "delete_after": delete_after, "sessions_ended": sessions })),
+    )
+    .await;
+    HttpResponse::Ok().json(DeletionResponse { delete_after })
+}
+
+#[delete("/delete")]
+async fn handle_cancel_deletion(
+    user: User,
+    app: web::Data<AppState>,
+    req: HttpRequest,
+) -> impl Responder {
+    match sqlx::query!(
+        r#"update users set deletion_requested_at = null
+        where id = $1 and deletion_requested_at is not null"#,
+        user.user_id
+    )
+    .execute(&app.pool)
+    .await
+    {
+        Ok(res) if res.rows_affected() > 0 => {
+            audit_event(
+                &app.pool,
+                &req,
+                Some(user.user_id),
+                "account.deletion.cancel",
+                Some(format!("user:{}", user.user_id)),
+                None,
+            )
+            .await;
+            HttpResponse::Ok().body("")
+        }
+        Ok(_) => HttpResponse::NotFound().body("no deletion was requested"),
+        Err(e) => HttpResponse::InternalServerError().body(e.to_string()),
+    }
+}
+
+/// Deletes accounts whose cooling off period is over. Their messages are kept
+/// under the deleted user placeholder, their credentials, profile, memberships
+/// & votes go with the row through the foreign key cascades, & anything they
+/// did for others is kept without them. Their personal bots are disabled & the
+/// bots' tokens revoked. Anyone who came to own a group since asking is
+/// skipped until they hand it over
+pub async fn purge_deleted_accounts(
+    grace: Duration,
+    tokenstore: &TokenStore,
+    pool: &Pool<Postgres>,
+) -> Result<u64, sqlx::Error> {
+    let due = sqlx::query_scalar!(
+        r#"select id from users u where deletion_requested_at < $1
+        and not exists (select 1 from groups where owner_id = u.id)"#,
+        Utc::now() - grace
+    )
+    .fetch_all(pool)
+    .await?;
+    let mut purged = 0;
+    for user_id in due {
+        let mut tx = pool.begin().await?;
+        let moved = sqlx::query!(
+            r#"update messages set sender_user_id = (select id from users where is_placeholder)
+            where sender_user_id = $1"#,
+            user_id
+        )
+        .execute(&mut *tx)
+        .await?
+        .rows_affected();
+        // their personal bots would be left with nobody to manage them, the
+        // ones behind group webhooks & commands stay with the group's admins
+        let bots = sqlx::query_scalar!(
+            r#"update users u set disabled_at = coalesce(disabled_at, now())
+            where is_bot and bot_owner_id = $1
+            and not exists (select 1 from incoming_webhooks where bot_user_id = u.id)
+            and not exists (select 1 from group_commands where bot_user_id = u.id)
+            returning id"#,
+            user_id
+        )
+        .fetch_all(&mut *tx)
+        .await?;
+        let revoked = sqlx::query!(
+            r#"update api_tokens set revoked_at = now()
+            where user_id = any($1) and revoked_at is null"#,
+            &bots
+        )
+        .execute(&mut *tx)
+        .await?
+        .rows_affected();
+        let deleted = sqlx::query!(
+            r#"delete from users where id = $1 and deletion_requested_at < $2"#,
+            user_id,
+            Utc::now() - grace
+        )
+        .execute(&mut *tx)
+        .await?
+        .rows_affected();
+        if deleted == 0 {
+            // cancelled in the meantime
+            tx.rollback().await?;
+            continue;
+        }
+        record_audit(
+            &mut tx,
+            None,
+            "account.delete",
+            Some(format!("user:{user_id}")),
+            Some(json!({
+                "messages_anonymized": moved,
+                "bots_disabled": bots.len(),
+                "bot_tokens_revoked": revoked,
+            })),
+            None,
+        )
+        .await?;
+        tx.commit().await?;
+        tokenstore.invalidate_user(user_id);
+        for bot_id in bots {
+            tokenstore.invalidate_user(bot_id);
+        }
+        purged += 1;
+    }
+    Ok(purged)
+}
@@ -0,0 +1,89 @@
+use super::ldap::LdapProvider;
+use super::login::{check_password, dummy_ciphertext};
+use crate::custom_types::UserRole;
+use crate::server::config::Config;
+use async_trait::async_trait;
+use chrono::{DateTime, Utc};
+use serde_json::Value;
+use sqlx::{Pool, Postgres};
+use std::sync::Arc;
+
+/// A user a provider let in, already matched up with their local account
+pub struct ProviderUser {
+    pub id: i32,
+    pub role: UserRole,
+    pub disabled_at: Option<DateTime<Utc>>,
+    /// kept with the login in the audit log
+    pub details: Option<Value>,
+}
+
+/// Something that can check a username & password for `/auth/login`, picked
+/// at startup from the config & tried in order
+#[async_trait]
+pub trait AuthProvider: Send + Sync {
+    fn name(&self) -> &'static str;
+
+    /// `Ok(None)` if the username & password weren't accepted, `Err` if the
+    /// provider couldn't give an answer, so the next one should be asked
+    async fn authenticate(
+        &self,
+        username: &str,
+        password: &str,
+        pool: &Pool<Postgres>,
+    ) -> Result<Option<ProviderUser>, String>;
+}
+
+/// Passwords stored (argon2 hashed) in `users`. Bots never log in this way,
+/// they only have api tokens
+pub struct LocalProvider;
+
+#[async_trait]
+impl AuthProvider for LocalProvider {
+    fn name(&self) -> &'static str {
+        "local"
+    }
+
+    async fn authenticate(
+        &self,
+        username: &str,
+        password: &str,
+        pool: &Pool<Postgres>,
+    ) -> Result<Option<ProviderUser>, String> {
+        let user = sqlx::query!(
+            r#"select id, password, disabled_at, role as "role: UserRole" from users
+            where username = $1 and not is_bot and not is_placeholder"#,
+            username
+        )
+        .fetch_optional(pool)
+        .await
+        .map_err(|e| e.to_string())?;
+        // unknown users still pay for a password check so they take as long as known ones
+        Ok(match user {
+            Some(user) if check_password(password, &user.password) => Some(ProviderUser {
+                id: user.id,
+                role: user.role,
+                disabled_at: user.disabled_at,
+                details: None,
+            }),
+            Some(_) => None,
+            None => {
+                check_password(password, dummy_ciphertext());
+                None
+            }
+        })
+    }
+}
+
+/// The directory first when there is one, then local accounts, so people
+/// without a directory entry (or while it's down) can still log in
+pub fn providers_from_config(config: &Config) -> Vec<Arc<dyn AuthProvider>> {
+    let mut providers: Vec<Arc<dyn AuthProvider>> = Vec::new();
+    if let Some(ldap) = &config.ldap {
+        providers.push(Arc::new(LdapProvider::new(
+            ldap.clone(),
+            config.join_default_roles.clone(),
+        )));
+    }
+    providers.push(Arc::new(LocalProvider));
+    providers
+}
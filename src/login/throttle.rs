@@ -0,0 +1,140 @@
+use chrono::{DateTime, Duration, Utc};
+use sqlx::{Pool, Postgres};
+
+/// How many failures a key gets before it's locked out & for how long.
+/// Every failure past `free_attempts` doubles the lockout, up to `max_lockout`
+#[derive(Debug, Clone, Copy)]
+pub struct ThrottlePolicy {
+    pub free_attempts: i32,
+    pub base_lockout: Duration,
+    pub max_lockout: Duration,
+}
+
+impl ThrottlePolicy {
+    pub fn lockout_for(&self, failures: i32) -> Option<Duration> {
+        if failures < self.free_attempts {
+            return None;
+        }
+        // past 2^20 the cap has long since kicked in, this just keeps the shift sane
+        let doublings = (failures - self.free_attempts).min(20) as u32;
+        let lockout = self.base_lockout * 2i32.pow(doublings);
+        Some(lockout.min(self.max_lockout))
+    }
+}
+
+pub fn account_key(username: &str) -> String {
+    format!("user:{}", username.to_lowercase())
+}
+
+pub fn ip_key(ip: &str) -> String {
+    format!("ip:{ip}")
+}
+
+/// When the latest lock on any of `keys` runs out, `None` if none of them are locked
+pub async fn locked_until(
+    keys: &[String],
+    pool: &Pool<Postgres>,
+) -> Result<Option<DateTime<Utc>>, sqlx::Error> {
+    Ok(sqlx::query!(
+        r#"select max(locked_until) as locked_until from login_throttles
+        where key = any($1) and locked_until > now()"#,
+        keys
+    )
+    .fetch_one(pool)
+    .await?
+    .locked_until)
+}
+
+/// Counts a failed attempt against `key`, locking it if the policy says so.
+/// Failures older than `window` are forgotten rather than added to
+pub async fn record_failure(
+    key: &str,
+    policy: &ThrottlePolicy,
+    window: Duration,
+    pool: &Pool<Postgres>,
+) -> Result<(), sqlx::Error> {
+    let mut tx = pool.begin().await?;
+    let failures = sqlx::query!(
+        r#"insert into login_throttles (key, failures) values ($1, 1)
+        on conflict (key) do update set
+            failures = case when login_throttles.last_failure_at < $2
+                then 1 else login_throttles.failures + 1 end,
+            last_failure_at = now()
+        returning failures"#,
+        key,
+        Utc::now() - window
+    )
+    .fetch_one(&mut *tx)
+    .await?
+    .failures;
+
+    if let Some(lockout) = policy.lockout_for(failures) {
+        sqlx::query!(
+            r#"update login_throttles set locked_until = $1 where key = $2"#,
+            Utc::now() + lockout,
+            key
+        )
+        .execute(&mut *tx)
+        .await?;
+    }
+    tx.commit().await
+}
+
+/// Forgets the failures for `key`, after a successful login or when an admin unlocks it.
+/// Returns false if there was nothing to clear
+pub async fn clear(key: &str, pool: &Pool<Postgres>) -> Result<bool, sqlx::Error> {
+    let res = sqlx::query!(r#"delete from login_throttles where key = $1"#, key)
+        .execute(pool)
+        .await?;
+    Ok(res.rows_affected() > 0)
+}
+
+/// Drops counters that haven't seen a failure in `window` & aren't locked
+pub async fn purge_stale(window: Duration, pool: &Pool<Postgres>) -> Result<u64, sqlx::Error> {
+    let res = sqlx::query!(
+        r#"delete from login_throttles
+        where last_failure_at < $1 and (locked_until is null or locked_until < now())"#,
+        Utc::now() - window
+    )
+    .execute(pool)
+    .await?;
+    Ok(res.rows_affected())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn policy() -> ThrottlePolicy {
+        ThrottlePolicy {
+            free_attempts: 3,
+            base_lockout: Duration::seconds(30),
+            max_lockout: Duration::minutes(15),
+        }
+    }
+
+    #[test]
+    fn free_attempts_arent_locked() {
+        assert_eq!(policy().lockout_for(0), None);
+        assert_eq!(policy().lockout_for(2), None);
+    }
+
+    #[test]
+    fn lockout_doubles() {
+        assert_eq!(policy().lockout_for(3), Some(Duration::seconds(30)));
+        assert_eq!(policy().lockout_for(4), Some(Duration::seconds(60)));
+        assert_eq!(policy().lockout_for(6), Some(Duration::seconds(240)));
+    }
+
+    #[test]
+    fn lockout_is_capped() {
+        assert_eq!(policy().lockout_for(10), Some(Duration::minutes(15)));
+        assert_eq!(policy().lockout_for(i32::MAX), Some(Duration::minutes(15)));
+    }
+
+    #[test]
+    fn account_keys_ignore_case() {
+        assert_eq!(account_key("Alice"), account_key("alice"));
+        assert_ne!(account_key("alice"), ip_key("alice"));
+    }
+}
@@ -0,0 +1,188 @@
+use base32::Alphabet;
+use hmac::{Hmac, Mac};
+use rand::{thread_rng, Rng, RngCore};
+use sha1::Sha1;
+use sha2::{Digest, Sha256};
+
+// RFC 6238 defaults, which is what authenticator apps assume
+const STEP_SECONDS: i64 = 30;
+const DIGITS: u32 = 6;
+const SECRET_BYTES: usize = 20;
+// accept the code either side of now, clocks on phones drift
+const ALLOWED_DRIFT: i64 = 1;
+const ISSUER: &str = "rust-server";
+
+const RECOVERY_CODE_COUNT: usize = 10;
+const RECOVERY_CODE_ALPHABET: &[u8] = b"abcdefghjkmnpqrstuvwxyz23456789";
+
+const BASE32: Alphabet = Alphabet::RFC4648 { padding: false };
+
+/// A new random secret, base32 encoded the way authenticator apps expect it
+pub fn generate_secret() -> String {
+    let mut bytes = [0u8; SECRET_BYTES];
+    thread_rng().fill_bytes(&mut bytes);
+    base32::encode(BASE32, &bytes)
+}
+
+/// The uri authenticator apps read from a qr code
+pub fn otpauth_uri(secret: &str, account: &str) -> String {
+    format!(
+        "otpauth://totp/{issuer}:{account}?secret={secret}&issuer={issuer}&algorithm=SHA1&digits={DIGITS}&period={STEP_SECONDS}",
+        issuer = ISSUER,
+        account = url_encode(account),
+    )
+}
+
+/// Which 30 second step a unix timestamp falls in
+pub fn time_step(unix_seconds: i64) -> i64 {
+    unix_seconds.div_euclid(STEP_SECONDS)
+}
+
+/// The code for one time step, `None` if the secret isn't valid base32
+pub fn code_at(secret: &str, step: i64) -> Option<String> {
+    let key = base32::decode(BASE32, secret)?;
+    Some(hotp(&key, step as u64))
+}
+
+/// Checks `code` against the steps around `now_step` & returns the step it matched.
+/// Steps at or before `last_used_step` are refused so a code can't be replayed
+pub fn verify(secret: &str, code: &str, now_step: i64, last_used_step: Option<i64>) -> Option<i64> {
+    let code = code.trim();
+    if code.len() != DIGITS as usize || !code.chars().all(|c| c.is_ascii_digit()) {
+        return None;
+    }
+    (now_step - ALLOWED_DRIFT..=now_step + ALLOWED_DRIFT)
+        .filter(|step| last_used_step.is_none_or(|last| *step > last))
+        .find(|step| {
+            code_at(secret, *step)
+                .is_some_and(|expected| constant_time_eq(expected.as_bytes(), code.as_bytes()))
+        })
+}
+
+/// RFC 4226 HOTP with dynamic truncation
+fn hotp(key: &[u8], counter: u64) -> String {
+    let mut mac = Hmac::<Sha1>::new_from_slice(key).expect("hmac takes keys of any length");
+    mac.update(&counter.to_be_bytes());
+    let hash = mac.finalize().into_bytes();
+    let offset = (hash[hash.len() - 1] & 0x0f) as usize;
+    let binary = u32::from_be_bytes([
+        hash[offset] & 0x7f,
+        hash[offset + 1],
+        hash[offset + 2],
+        hash[offset + 3],
+    ]);
+    format!(
+        "{:0width$}",
+        binary % 10u32.pow(DIGITS),
+        width = DIGITS as usize
+    )
+}
+
+fn constant_time_eq(a: &[u8], b: &[u8]) -> bool {
+    a.len() == b.len() && a.iter().zip(b).fold(0u8, |acc, (x, y)| acc | (x ^ y)) == 0
+}
+
+fn url_encode(value: &str) -> String {
+    value
+        .bytes()
+        .map(|b| match b {
+            b'A'..=b'Z' | b'a'..=b'z' | b'0'..=b'9' | b'-' | b'.' | b'_' | b'~' => {
+                (b as char).to_string()
+            }
+            _ => format!("%{:02X}", b),
+        })
+        .collect()
+}
+
+/// Single use codes for when the authenticator is lost, shown to the user once
+/// & only stored hashed. Formatted `xxxxx-xxxxx` to be easy to read out
+pub fn generate_recovery_codes() -> Vec<String> {
+    let mut rng = thread_rng();
+    (0..RECOVERY_CODE_COUNT)
+        .map(|_| {
+            let chars: String = (0..10)
+                .map(|_| {
+                    RECOVERY_CODE_ALPHABET[rng.gen_range(0..RECOVERY_CODE_ALPHABET.len())] as char
+                })
+                .collect();
+            format!("{}-{}", &chars[..5], &chars[5..])
+        })
+        .collect()
+}
+
+/// What gets stored for a recovery code, ignoring case & the dash so
+/// however the user types it back in it still matches
+pub fn hash_recovery_code(code: &str) -> String {
+    let normalised: String = code
+        .chars()
+        .filter(|c| c.is_ascii_alphanumeric())
+        .map(|c| c.to_ascii_lowercase())
+        .collect();
+    format!("{:x}", Sha256::digest(normalised.as_bytes()))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // the SHA1 secret from RFC 6238 appendix B, "12345678901234567890"
+    const RFC_SECRET: &str = "GEZDGNBVGY3TQOJQGEZDGNBVGY3TQOJQ";
+
+    #[test]
+    fn matches_rfc_6238_vectors() {
+        // the rfc gives 8 digit codes, the last 6 digits are the 6 digit code
+        for (time, code) in [
+            (59, "287082"),
+            (1111111109, "081804"),
+            (1111111111, "050471"),
+            (1234567890, "005924"),
+            (2000000000, "279037"),
+        ] {
+            assert_eq!(code_at(RFC_SECRET, time_step(time)).unwrap(), code);
+        }
+    }
+
+    #[test]
+    fn accepts_drift_either_side() {
+        let step = time_step(1234567890);
+        let code = code_at(RFC_SECRET, step).unwrap();
+        assert_eq!(verify(RFC_SECRET, &code, step + 1, None), Some(step));
+        assert_eq!(verify(RFC_SECRET, &code, step - 1, None), Some(step));
+        assert_eq!(verify(RFC_SECRET, &code, step + 2, None), None);
+    }
+
+    #[test]
+    fn refuses_replayed_codes() {
+        let step = time_step(1234567890);
+        let code = code_at(RFC_SECRET, step).unwrap();
+        assert_eq!(verify(RFC_SECRET, &code, step, Some(step)), None);
+        assert_eq!(verify(RFC_SECRET, &code, step, Some(step - 1)), Some(step));
+    }
+
+    #[test]
+    fn refuses_malformed_codes() {
+        let step = time_step(59);
+        assert_eq!(verify(RFC_SECRET, "28708", step, None), None);
+        assert_eq!(verify(RFC_SECRET, "28708a", step, None), None);
+        assert_eq!(verify("not base32!", "287082", step, None), None);
+    }
+
+    #[test]
+    fn generated_secrets_round_trip() {
+        let secret = generate_secret();
+        assert_eq!(base32::decode(BASE32, &secret).unwrap().len(), SECRET_BYTES);
+        assert!(otpauth_uri(&secret, "a b").contains("totp/rust-server:a%20b?secret="));
+    }
+
+    #[test]
+    fn recovery_codes_match_however_typed() {
+        let codes = generate_recovery_codes();
+        assert_eq!(codes.len(), RECOVERY_CODE_COUNT);
+        let code = &codes[0];
+        assert_eq!(
+            hash_recovery_code(code),
+            hash_recovery_code(&code.to_uppercase().replace('-', " "))
+        );
+        assert_ne!(hash_recovery_code(&codes[0]), hash_recovery_code(&codes[1]));
+    }
+}
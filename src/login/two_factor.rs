@@ -0,0 +1,516 @@
+use super::login::LoginResponse;
+use super::throttle;
+use super::totp;
+use crate::admin::settings::require_admin_2fa;
+use crate::audit::audit::audit_event;
+use crate::auth::TokenStore;
+use crate::custom_types::UserRole;
+use crate::extractors::extractors::User;
+use crate::server::app_state::AppState;
+use actix_web::{delete, get, post, web, HttpRequest, HttpResponse, Responder};
+use serde::{Deserialize, Serialize};
+use serde_json::json;
+use sqlx::{PgConnection, Pool, Postgres};
+use std::sync::Arc;
+
+/// What a login has to do once the password is right
+pub enum LoginStep {
+    Session,
+    // the user has two factor set up & has to give a code
+    Challenge,
+    // two factor is required for the user's role but they haven't set it up
+    Enroll,
+}
+
+pub async fn login_step(
+    user_id: i32,
+    role: UserRole,
+    pool: &Pool<Postgres>,
+) -> Result<LoginStep, sqlx::Error> {
+    if has_two_factor(user_id, pool).await? {
+        return Ok(LoginStep::Challenge);
+    }
+    if requires_two_factor(role, pool).await? {
+        return Ok(LoginStep::Enroll);
+    }
+    Ok(LoginStep::Session)
+}
+
+/// Whether the user has confirmed an authenticator
+pub async fn has_two_factor(user_id: i32, pool: &Pool<Postgres>) -> Result<bool, sqlx::Error> {
+    Ok(sqlx::query!(
+        r#"select exists(
+            select 1 from user_totp where user_id = $1 and confirmed_at is not null
+        ) as "enabled!""#,
+        user_id
+    )
+    .fetch_one(pool)
+    .await?
+    .enabled)
+}
+
+async fn requires_two_factor(role: UserRole, pool: &Pool<Postgres>) -> Result<bool, sqlx::Error> {
+    match role {
+        UserRole::ADMIN | UserRole::SUPER => require_admin_2fa(pool).await,
+        _ => Ok(false),
+    }
+}
+
+/// How a second factor was proven, for the audit log
+#[derive(Debug, Clone, Copy)]
+enum Method {
+    Totp,
+    RecoveryCode,
+}
+
+/// Checks a code from the user's authenticator, falling back to their recovery codes.
+/// Either way the code is used up
+async fn check_second_factor(
+    user_id: i32,
+    code: &str,
+    pool: &Pool<Postgres>,
+) -> Result<Option<Method>, sqlx::Error> {
+    let totp = sqlx::query!(
+        r#"select secret, last_used_step from user_totp where user_id = $1 and confirmed_at is not null"#,
+        user_id
+    )
+    .fetch_optional(pool)
+    .await?;
+    let totp = match totp {
+        Some(totp) => totp,
+        None => return Ok(None),
+    };
+
+    let now_step = totp::time_step(chrono::Utc::now().timestamp());
+    if let Some(step) = totp::verify(&totp.secret, code, now_step, totp.last_used_step) {
+        // the condition stops two requests racing to use the same code
+        let res = sqlx::query!(
+            r#"update user_totp set last_used_step = $2
+            where user_id = $1 and (last_used_step is null or last_used_step < $2)"#,
+            user_id,
+            step
+        )
+        .execute(pool)
+        .await?;
+        return Ok((res.rows_affected() > 0).then_some(Method::Totp));
+    }
+
+    let res = sqlx::query!(
+        r#"update user_recovery_codes set used_at = now()
+        where user_id = $1 and code_hash = $2 and used_at is null"#,
+        user_id,
+        totp::hash_recovery_code(code)
+    )
+    .execute(pool)
+    .await?;
+    Ok((res.rows_affected() > 0).then_some(Method::RecoveryCode))
+}
+
+/// Throws away the user's recovery codes & makes a new set
+async fn replace_recovery_codes(
+    user_id: i32,
+    conn: &mut PgConnection,
+) -> Result<Vec<String>, sqlx::Error> {
+    let codes = totp::generate_recovery_codes();
+    let hashes: Vec<String> = codes
+        .iter()
+        .map(|code| totp::hash_recovery_code(code))
+        .collect();
+    sqlx::query!(
+        r#"delete from user_recovery_codes where user_id = $1"#,
+        user_id
+    )
+    .execute(&mut *conn)
+    .await?;
+    sqlx::query!(
+        r#"insert into user_recovery_codes (user_id, code_hash) select $1, unnest($2::text[])"#,
+        user_id,
+        &hashes
+    )
+    .execute(&mut *conn)
+    .await?;
+    Ok(codes)
+}
+
+#[derive(Serialize)]
+struct EnrollResponse {
+    secret: String,
+    otpauth_uri: String,
+}
+
+/// Makes a new, unconfirmed secret for the user. Refused once two factor is
+/// confirmed, it has to be turned off first so a stolen password can't replace it
+async fn start_enrollment(user_id: i32, pool: &Pool<Postgres>) -> HttpResponse {
+    let user = match sqlx::query!(
+        r#"select u.username, t.confirmed_at as "confirmed_at?"
+        from users u left join user_totp t on t.user_id = u.id where u.id = $1"#,
+        user_id
+    )
+    .fetch_optional(pool)
+    .await
+    {
+        Ok(Some(user)) => user,
+        Ok(None) => return HttpResponse::NotFound().body(format!("user {} not found", user_id)),
+        Err(e) => return HttpResponse::InternalServerError().body(e.to_string()),
+    };
+    if user.confirmed_at.is_some() {
+        return HttpResponse::Conflict().body("two factor auth is already set up");
+    }
+
+    let secret = totp::generate_secret();
+    match sqlx::query!(
+        r#"insert into user_totp (user_id, secret) values ($1, $2)
+        on conflict (user_id) do update set secret = $2, created_at = now(), last_used_step = null"#,
+        user_id,
+        secret
+    )
+    .execute(pool)
+    .await
+    {
+        Ok(_) => HttpResponse::Ok().json(EnrollResponse {
+            otpauth_uri: totp::otpauth_uri(&secret, &user.username),
+            secret,
+        }),
+        Err(e) => HttpResponse::InternalServerError().body(e.to_string()),
+    }
+}
+
+/// Confirms the pending secret with a first code, turning two factor on.
+/// Returns the recovery codes, which are never shown again
+async fn confirm_enrollment(
+    user_id: i32,
+    code: &str,
+    pool: &Pool<Postgres>,
+) -> Result<Vec<String>, HttpResponse> {
+    let internal = |e: sqlx::Error| HttpResponse::InternalServerError().body(e.to_string());
+    let mut tx = pool.begin().await.map_err(internal)?;
+    let pending = sqlx::query!(
+        r#"select secret from user_totp where user_id = $1 and confirmed_at is null for update"#,
+        user_id
+    )
+    .fetch_optional(&mut *tx)
+    .await
+    .map_err(internal)?
+    .ok_or_else(|| HttpResponse::NotFound().body("no two factor enrollment in progress"))?;
+
+    let now_step = totp::time_step(chrono::Utc::now().timestamp());
+    let step = totp::verify(&pending.secret, code, now_step, None)
+        .ok_or_else(|| HttpResponse::Unauthorized().body("code doesn't match"))?;
+    sqlx::query!(
+        r#"update user_totp set confirmed_at = now(), last_used_step = $2 where user_id = $1"#,
+        user_id,
+        step
+    )
+    .execute(&mut *tx)
+    .await
+    .map_err(internal)?;
+    let codes = replace_recovery_codes(user_id, &mut tx)
+        .await
+        .map_err(internal)?;
+    tx.commit().await.map_err(internal)?;
+    Ok(codes)
+}
+
+#[derive(Deserialize)]
+struct ChallengeRequest {
+    challenge: String,
+    code: String,
+}
+
+/// The second half of a login for users with two factor on, swaps the challenge
+/// from `/auth/login` & a code for a session
+#[post("/login/2fa")]
+pub async fn two_factor_login_handle(
+    app: web::Data<AppState>,
+    tokenstore: web::Data<Arc<TokenStore>>,
+    form_data: web::Form<ChallengeRequest>,
+    req: HttpRequest,
+) -> HttpResponse {
+    let user_id = match tokenstore.check_challenge(&form_data.challenge) {
+        Some(user_id) => user_id,
+        None => return HttpResponse::Unauthorized().json("challenge is invalid or has expired"),
+    };
+    let method = match check_second_factor(user_id, &form_data.code, &app.pool).await {
+        Ok(method) => method,
+        Err(e) => return HttpResponse::InternalServerError().json(e.to_string()),
+    };
+    let method = match method {
+        Some(method) => method,
+        None => {
+            tokenstore.fail_challenge(&form_data.challenge);
+            // wrong codes count towards the account's lockout like wrong passwords
+            if let Ok(Some(rec)) =
+                sqlx::query!(r#"select username from users where id = $1"#, user_id)
+                    .fetch_optional(&app.pool)
+                    .await
+            {
+                if let Err(e) = throttle::record_failure(
+                    &throttle::account_key(&rec.username),
+                    &app.config.login_account_policy,
+                    app.config.login_failure_window,
+                    &app.pool,
+                )
+                .await
+                {
+                    log::error!("failed to record two factor failure for user {user_id}: {e}");
+                }
+            }
+            audit_event(
+                &app.pool,
+                &req,
+                Some(user_id),
+                "auth.login.failure",
+                Some(format!("user:{user_id}")),
+                Some(json!({ "reason": "bad two factor code" })),
+            )
+            .await;
+            return HttpResponse::Unauthorized().json("code doesn't match");
+        }
+    };
+
+    match tokenstore.complete_challenge(&form_data.challenge) {
+        Some((user_id, token)) => {
+            audit_event(
+                &app.pool,
+                &req,
+                Some(user_id),
+                "auth.login.success",
+                Some(format!("user:{user_id}")),
+                Some(json!({ "second_factor": format!("{method:?}") })),
+            )
+            .await;
+            HttpResponse::Ok().json(LoginResponse { user_id, token })
+        }
+        None => HttpResponse::Unauthorized().json("challenge is invalid or has expired"),
+    }
+}
+
+#[derive(Deserialize)]
+struct ChallengeOnly {
+    challenge: String,
+}
+
+/// Enrollment for users whose role requires two factor but who haven't set it up,
+/// they can't get a session to use `/api/account/2fa` until they have
+#[post("/2fa/enroll")]
+pub async fn challenge_enroll_handle(
+    app: web::Data<AppState>,
+    tokenstore: web::Data<Arc<TokenStore>>,
+    form_data: web::Form<ChallengeOnly>,
+) -> HttpResponse {
+    match tokenstore.check_challenge(&form_data.challenge) {
+        Some(user_id) => start_enrollment(user_id, &app.pool).await,
+        None => HttpResponse::Unauthorized().json("challenge is invalid or has expired"),
+    }
+}
+
+#[derive(Serialize)]
+struct ChallengeConfirmResponse {
+    user_id: i32,
+    token: String,
+    recovery_codes: Vec<String>,
+}
+
+#[post("/2fa/confirm")]
+pub async fn challenge_confirm_handle(
+    app: web::Data<AppState>,
+    tokenstore: web::Data<Arc<TokenStore>>,
+    form_data: web::Form<ChallengeRequest>,
+    req: HttpRequest,
+) -> HttpResponse {
+    let user_id = match tokenstore.check_challenge(&form_data.challenge) {
+        Some(user_id) => user_id,
+        None => return HttpResponse::Unauthorized().json("challenge is invalid or has expired"),
+    };
+    let recovery_codes = match confirm_enrollment(user_id, &form_data.code, &app.pool).await {
+        Ok(codes) => codes,
+        Err(resp) => {
+            tokenstore.fail_challenge(&form_data.challenge);
+            return resp;
+        }
+    };
+    audit_event(
+        &app.pool,
+        &req,
+        Some(user_id),
+        "auth.2fa.enable",
+        Some(format!("user:{user_id}")),
+        None,
+    )
+    .await;
+    match tokenstore.complete_challenge(&form_data.challenge) {
+        Some((user_id, token)) => HttpResponse::Ok().json(ChallengeConfirmResponse {
+            user_id,
+            token,
+            recovery_codes,
+        }),
+        None => HttpResponse::Unauthorized().json("challenge is invalid or has expired"),
+    }
+}
+
+#[derive(Serialize)]
+struct TwoFactorStatus {
+    enabled: bool,
+    required: bool,
+    recovery_codes_left: i64,
+}
+
+#[get("")]
+async fn handle_get_two_factor(user: User, app: web::Data<AppState>) -> impl Responder {
+    let rec = match sqlx::query!(
+        r#"select role as "role: UserRole",
+        exists(select 1 from user_totp where user_id = $1 and confirmed_at is not null) as "enabled!",
+        (select count(*) from user_recovery_codes where user_id = $1 and used_at is null) as "recovery_codes_left!"
+        from users where id = $1"#,
+        user.user_id
+    )
+    .fetch_one(&app.pool)
+    .await
+    {
+        Ok(rec) => rec,
+        Err(e) => return HttpResponse::InternalServerError().body(e.to_string()),
+    };
+    match requires_two_factor(rec.role, &app.pool).await {
+        Ok(required) => HttpResponse::Ok().json(TwoFactorStatus {
+            enabled: rec.enabled,
+            required,
+            recovery_codes_left: rec.recovery_codes_left,
+        }),
+        Err(e) => HttpResponse::InternalServerError().body(e.to_string()),
+    }
+}
+
+#[post("/enroll")]
+async fn handle_enroll(user: User, app: web::Data<AppState>) -> impl Responder {
+    start_enrollment(user.user_id, &app.pool).await
+}
+
+#[derive(Deserialize)]
+struct CodeRequest {
+    code: String,
+}
+
+#[derive(Serialize)]
+struct RecoveryCodesResponse {
+    recovery_codes: Vec<String>,
+}
+
+#[post("/confirm")]
+async fn handle_confirm(
+    body: web::Json<CodeRequest>,
+    user: User,
+    app: web::Data<AppState>,
+    req: HttpRequest,
+) -> impl Responder {
+    match confirm_enrollment(user.user_id, &body.code, &app.pool).await {
+        Ok(recovery_codes) => {
+            audit_event(
+                &app.pool,
+                &req,
+                Some(user.user_id),
+                "auth.2fa.enable",
+                Some(format!("user:{}", user.user_id)),
+                None,
+            )
+            .await;
+            HttpResponse::Ok().json(RecoveryCodesResponse { recovery_codes })
+        }
+        Err(resp) => resp,
+    }
+}
+
+#[post("/recovery_codes")]
+async fn handle_regenerate_recovery_codes(
+    body: web::Json<CodeRequest>,
+    user: User,
+    app: web::Data<AppState>,
+    req: HttpRequest,
+) -> impl Responder {
+    match check_second_factor(user.user_id, &body.code, &app.pool).await {
+        Ok(Some(_)) => {}
+        Ok(None) => return HttpResponse::Unauthorized().body("code doesn't match"),
+        Err(e) => return HttpResponse::InternalServerError().body(e.to_string()),
+    }
+    let mut conn = match app.pool.acquire().await {
+        Ok(conn) => conn,
+        Err(e) => return HttpResponse::InternalServerError().body(e.to_string()),
+    };
+    match replace_recovery_codes(user.user_id, &mut conn).await {
+        Ok(recovery_codes) => {
+            audit_event(
+                &app.pool,
+                &req,
+                Some(user.user_id),
+                "auth.2fa.recovery_codes",
+                Some(format!("user:{}", user.user_id)),
+                None,
+            )
+            .await;
+            HttpResponse::Ok().json(RecoveryCodesResponse { recovery_codes })
+        }
+        Err(e) => HttpResponse::InternalServerError().body(e.to_string()),
+    }
+}
+
+/// Turns two factor off, which takes a current code (or recovery code).
+/// Not allowed when the user's role requires it
+#[delete("")]
+async fn handle_disable_two_factor(
+    body: web::Json<CodeRequest>,
+    user: User,
+    app: web::Data<AppState>,
+    req: HttpRequest,
+) -> impl Responder {
+    let role = match sqlx::query!(
+        r#"select role as "role: UserRole" from users where id = $1"#,
+        user.user_id
+    )
+    .fetch_one(&app.pool)
+    .await
+    {
+        Ok(rec) => rec.role,
+        Err(e) => return HttpResponse::InternalServerError().body(e.to_string()),
+    };
+    match requires_two_factor(role, &app.pool).await {
+        Ok(false) => {}
+        Ok(true) => {
+            return HttpResponse::Forbidden().body("two factor auth is required for your role")
+        }
+        Err(e) => return HttpResponse::InternalServerError().body(e.to_string()),
+    }
+    match check_second_factor(user.user_id, &body.code, &app.pool).await {
+        Ok(Some(_)) => {}
+        Ok(None) => return HttpResponse::Unauthorized().body("code doesn't match"),
+        Err(e) => return HttpResponse::InternalServerError().body(e.to_string()),
+    }
+    // recovery codes are only a backup for the authenticator, so they go too
+    let result = async {
+        let mut tx = app.pool.begin().await?;
+        sqlx::query!(r#"delete from user_totp where user_id = $1"#, user.user_id)
+            .execute(&mut *tx)
+            .await?;
+        sqlx::query!(
+            r#"delete from user_recovery_codes where user_id = $1"#,
+            user.user_id
+        )
+        .execute(&mut *tx)
+        .await?;
+        tx.commit().await
+    }
+    .await;
+    match result {
+        Ok(_) => {
+            audit_event(
+                &app.pool,
+                &req,
+                Some(user.user_id),
+                "auth.2fa.disable",
+                Some(format!("user:{}", user.user_id)),
+                None,
+            )
+            .await;
+            HttpResponse::Ok().body("")
+        }
+        Err(e) => HttpResponse::InternalServerError().body(e.to_string()),
+    }
+}
@@ -0,0 +1,137 @@
+use async_trait::async_trait;
+use lettre::message::Mailbox;
+use lettre::transport::smtp::authentication::Credentials;
+use lettre::{AsyncSmtpTransport, AsyncTransport, Message, Tokio1Executor};
+use std::path::PathBuf;
+use std::sync::Arc;
+
+use super::templates::Template;
+
+/// A rendered email ready to go out
+#[derive(Debug, Clone)]
+pub struct Email {
+    pub to: String,
+    pub subject: String,
+    pub body: String,
+}
+
+impl Email {
+    pub fn new(to: &str, template: &Template) -> Self {
+        Email {
+            to: to.to_owned(),
+            subject: template.subject(),
+            body: template.body(),
+        }
+    }
+}
+
+/// Somewhere emails can be sent, picked at startup by `MAILER`
+#[async_trait]
+pub trait Mailer: Send + Sync {
+    async fn send(&self, email: &Email) -> Result<(), String>;
+}
+
+/// Sends an email without waiting for it, so a slow mail server doesn't hold up
+/// the request (or give away through timing whether an email was sent at all)
+pub fn send_later(mailer: &Arc<dyn Mailer>, email: Email) {
+    let mailer = Arc::clone(mailer);
+    tokio::spawn(async move {
+        if let Err(e) = mailer.send(&email).await {
+            log::error!("failed to send \"{}\" to {}: {e}", email.subject, email.to);
+        }
+    });
+}
+
+/// Sends through an SMTP server. Plain SMTP unless `tls` is set, so it can
+/// talk to a local sink (e.g. mailpit on port 1025) while developing
+pub struct SmtpMailer {
+    from: Mailbox,
+    transport: AsyncSmtpTransport<Tokio1Executor>,
+}
+
+impl SmtpMailer {
+    pub fn new(
+        from: &str,
+        host: &str,
+        port: u16,
+        tls: bool,
+        credentials: Option<(String, String)>,
+    ) -> Result<Self, String> {
+        let from = from.parse::<Mailbox>().map_err(|e| e.to_string())?;
+        let mut builder = match tls {
+            true => AsyncSmtpTransport::<Tokio1Executor>::starttls_relay(host)
+                .map_err(|e| e.to_string())?,
+            false => AsyncSmtpTransport::<Tokio1Executor>::builder_dangerous(host),
+        }
+        .port(port);
+        if let Some((username, password)) = credentials {
+            builder = builder.credentials(Credentials::new(username, password));
+        }
+        Ok(SmtpMailer {
+            from,
+            transport: builder.build(),
+        })
+    }
+}
+
+#[async_trait]
+impl Mailer for SmtpMailer {
+    async fn send(&self, email: &Email) -> Result<(), String> {
+        let message = Message::builder()
+            .from(self.from.clone())
+            .to(email.to.parse::<Mailbox>().map_err(|e| e.to_string())?)
+            .subject(&email.subject)
+            .body(email.body.clone())
+            .map_err(|e| e.to_string())?;
+        self.transport
+            .send(message)
+            .await
+            .map(|_| ())
+            .map_err(|e| e.to_string())
+    }
+}
+
+/// Writes each email to its own file in `dir`, for development
+pub struct FileMailer {
+    dir: PathBuf,
+}
+
+impl FileMailer {
+    pub fn new(dir: &str) -> Self {
+        FileMailer {
+            dir: PathBuf::from(dir),
+        }
+    }
+}
+
+#[async_trait]
+impl Mailer for FileMailer {
+    async fn send(&self, email: &Email) -> Result<(), String> {
+        tokio::fs::create_dir_all(&self.dir)
+            .await
+            .map_err(|e| e.to_string())?;
+        let name = format!(
+            "{}-{}.eml",
+            chrono::Utc::now().format("%Y%m%dT%H%M%S%.6f"),
+            email.to.replace(|c: char| !c.is_ascii_alphanumeric(), "_")
+        );
+        let contents = format!(
+            "To: {}\nSubject: {}\n\n{}\n",
+            email.to, email.subject, email.body
+        );
+        tokio::fs::write(self.dir.join(name), contents)
+            .await
+            .map_err(|e| e.to_string())
+    }
+}
+
+/// Just logs emails, the default so nothing is sent by accident
+pub struct LogMailer;
+
+#[async_trait]
+impl Mailer for LogMailer {
+    async fn send(&self, email: &Email) -> Result<(), String> {
+        log::info!("email to {}: {}\n{}", email.to, email.subject, email.body);
+        Ok(())
+    }
+}
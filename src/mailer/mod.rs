@@ -0,0 +1,27 @@
+pub mod mailer;
+pub mod templates;
+use mailer::{FileMailer, LogMailer, Mailer, SmtpMailer};
+use std::sync::Arc;
+
+use crate::server::config::{Config, MailerKind};
+
+/// Builds the mailer picked by `MAILER`, falling back to logging emails if the
+/// SMTP settings don't work so the server still starts
+pub fn mailer_from_config(config: &Config) -> Arc<dyn Mailer> {
+    match &config.mailer {
+        MailerKind::Smtp {
+            host,
+            port,
+            tls,
+            credentials,
+        } => match SmtpMailer::new(&config.mail_from, host, *port, *tls, credentials.clone()) {
+            Ok(mailer) => Arc::new(mailer),
+            Err(e) => {
+                log::error!("could not set up smtp mailer, logging emails instead: {e}");
+                Arc::new(LogMailer)
+            }
+        },
+        MailerKind::File { dir } => Arc::new(FileMailer::new(dir)),
+        MailerKind::Log => Arc::new(LogMailer),
+    }
+}
@@ -0,0 +1,131 @@
+const VERIFY_EMAIL: &str = "Hi {{username}},
+
+Please confirm this is your email address by opening the link below:
+
+{{link}}
+
+The link works for {{hours}} hours. If you didn't sign up you can ignore this email.
+";
+
+const PASSWORD_RESET: &str = "Hi {{username}},
+
+Someone asked to reset the password for your account. If it was you, open the link below
+to choose a new one:
+
+{{link}}
+
+The link works for {{minutes}} minutes & only once. If it wasn't you, you can ignore this email,
+your password hasn't changed.
+";
+
+const PASSWORD_CHANGED: &str = "Hi {{username}},
+
+The password for your account was just reset & you've been logged out everywhere.
+If this wasn't you, contact an administrator straight away.
+";
+
+/// The emails the server sends, each knows its subject & fills in its own body
+#[derive(Debug, Clone)]
+pub enum Template {
+    VerifyEmail {
+        username: String,
+        link: String,
+        hours: i64,
+    },
+    PasswordReset {
+        username: String,
+        link: String,
+        minutes: i64,
+    },
+    PasswordChanged {
+        username: String,
+    },
+}
+
+impl Template {
+    pub fn subject(&self) -> String {
+        match self {
+            Template::VerifyEmail { .. } => "Confirm your email address",
+            Template::PasswordReset { .. } => "Reset your password",
+            Template::PasswordChanged { .. } => "Your password was reset",
+        }
+        .to_owned()
+    }
+
+    pub fn body(&self) -> String {
+        match self {
+            Template::VerifyEmail {
+                username,
+                link,
+                hours,
+            } => render(
+                VERIFY_EMAIL,
+                &[
+                    ("username", username),
+                    ("link", link),
+                    ("hours", &hours.to_string()),
+                ],
+            ),
+            Template::PasswordReset {
+                username,
+                link,
+                minutes,
+            } => render(
+                PASSWORD_RESET,
+                &[
+                    ("username", username),
+                    ("link", link),
+                    ("minutes", &minutes.to_string()),
+                ],
+            ),
+            Template::PasswordChanged { username } => {
+                render(PASSWORD_CHANGED, &[("username", username)])
+            }
+        }
+    }
+}
+
+/// Swaps each `{{name}}` in the template for its value
+fn render(template: &str, values: &[(&str, &str)]) -> String {
+    values
+        .iter()
+        .fold(template.to_owned(), |text, (name, value)| {
+            text.replace(&format!("{{{{{name}}}}}"), value)
+        })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn fills_in_every_placeholder() {
+        let template = Template::PasswordReset {
+            username: "alice".to_owned(),
+            link: "http://localhost/reset?token=abc".to_owned(),
+            minutes: 60,
+        };
+        let body = template.body();
+        assert!(body.contains("Hi alice,"));
+        assert!(body.contains("http://localhost/reset?token=abc"));
+        assert!(body.contains("60 minutes"));
+        assert!(!body.contains("{{"));
+    }
+
+    #[test]
+    fn every_template_renders_fully() {
+        for template in [
+            Template::VerifyEmail {
+                username: "a".to_owned(),
+                link: "l".to_owned(),
+                hours: 24,
+            },
+            Template::PasswordChanged {
+                username: "a".to_owned(),
+            },
+        ] {
+            assert!(!template.body().contains("{{"));
+            assert!(!template.subject().is_empty());
+        }
+    }
+}
@@ -8,22 +8,49 @@ use std::process;
 use tokio;
 // use futures::future::FutureExt;
 use std::sync::{Arc, Mutex};
+mod admin;
+mod audit;
 mod auth;
+mod commands;
 mod custom_types;
 mod db;
 mod extractors;
 mod groups;
 mod login;
+mod mailer;
 mod messages;
+mod presence;
+mod profiles;
 mod server;
+mod tokens;
+mod webhooks;
 use crate::auth::TokenStore;
+use admin::admin_routes;
+use admin::slack_import::{import_slack, SlackExport};
+use audit::audit::verify_audit_log;
 use auth::AuthStruct;
 use custom_types::GroupType;
 use db::setup_database;
-use groups::group_routes;
+use groups::{exports, group_routes, groups::purge_deleted_groups};
 use login::login::{login_handle, logout_handle, register_handle};
-use messages::message_routes;
+use login::oidc::{self, oidc_callback_handle, oidc_login_handle};
+use login::{privacy, throttle};
+use login::two_factor::{
+    challenge_confirm_handle, challenge_enroll_handle, two_factor_login_handle,
+};
+use login::account_routes;
+use tokens::token_routes;
+use webhooks::{hook_routes, outgoing};
+use login::email::{
+    forgot_password_handle, reset_password_form_handle, reset_password_handle, verify_email_handle,
+};
+use mailer::mailer_from_config;
+use messages::{ephemeral, message_routes, polls, retention, scheduled};
+use presence::presence::PresenceHub;
+use presence::{presence as presence_state, presence_routes};
+use profiles::profile_routes;
 use server::app_state::AppState;
+use server::config::Config;
 
 #[derive(Serialize, Deserialize, Debug)]
 struct MessageRequest {
@@ -38,7 +65,41 @@ async fn main() -> std::io::Result<()> {
     // std::env::set_var("RUST_LOG", "debug");
     env_logger::init();
     log::info!("process id is {}", process::id());
+    let config = Config::from_env();
+    let mailer = mailer_from_config(&config);
     let pool = setup_database().await.unwrap();
+
+    // `verify-audit` checks the audit log's hash chain & exits instead of serving
+    if std::env::args().nth(1).as_deref() == Some("verify-audit") {
+        let result = verify_audit_log(&pool).await.unwrap();
+        println!("{}", serde_json::to_string_pretty(&result).unwrap());
+        process::exit(if result.broken_at.is_some() { 1 } else { 0 });
+    }
+
+    // `import-slack <export.zip|dir> <admin username> [--dry-run]` imports a
+    // Slack export, printing what it did (or would do) & exits
+    if std::env::args().nth(1).as_deref() == Some("import-slack") {
+        let args: Vec<String> = std::env::args().skip(2).collect();
+        let dry_run = args.iter().any(|arg| arg == "--dry-run");
+        let paths: Vec<&String> = args.iter().filter(|arg| !arg.starts_with("--")).collect();
+        let [path, admin] = paths[..] else {
+            eprintln!("usage: import-slack <export.zip|dir> <admin username> [--dry-run]");
+            process::exit(2);
+        };
+        let result = match SlackExport::open(std::path::Path::new(path)) {
+            Ok(export) => import_slack(&export, admin, dry_run, &pool).await,
+            Err(e) => Err(e),
+        };
+        match result {
+            Ok(report) => println!("{}", serde_json::to_string_pretty(&report).unwrap()),
+            Err(e) => {
+                eprintln!("import failed: {e}");
+                process::exit(1);
+            }
+        }
+        process::exit(0);
+    }
+
     let b = pool.num_idle();
     println!("number idle {:?}", b);
     let redis_connection_string = "localhost:6379";
@@ -46,6 +107,7 @@ async fn main() -> std::io::Result<()> {
     let token_store = Arc::new(TokenStore::new());
 
     let thread_token_store = Arc::clone(&token_store);
+    let purge_token_store = Arc::clone(&token_store);
     // spawns a thread that checks expiry every 60 seconds
     tokio::spawn(async move {
         let mut interval = tokio::time::interval(tokio::time::Duration::from_secs(60));
@@ -56,6 +118,173 @@ async fn main() -> std::io::Result<()> {
         }
     });
     let token_storage = web::Data::new(token_store);
+    let presence_hub = Arc::new(PresenceHub::new());
+
+    let purge_pool = pool.clone();
+    let deletion_grace = config.group_deletion_grace;
+    let login_failure_window = config.login_failure_window;
+    let export_purge_config = config.clone();
+    let account_deletion_grace = config.account_deletion_grace;
+    let delivery_retention = config.webhook_delivery_retention;
+    // purges groups whose deletion grace period has run out, old login failures,
+    // abandoned single sign-on logins, messages past their retention, expired
+    // exports, accounts whose cooling off period is over & old webhook
+    // deliveries, every hour
+    tokio::spawn(async move {
+        let mut interval = tokio::time::interval(tokio::time::Duration::from_secs(60 * 60));
+        loop {
+            interval.tick().await;
+            match purge_deleted_groups(deletion_grace, &purge_pool).await {
+                Ok(purged) => log::info!("purged {purged} deleted groups"),
+                Err(e) => log::error!("failed to purge deleted groups: {e}"),
+            }
+            match throttle::purge_stale(login_failure_window, &purge_pool).await {
+                Ok(purged) => log::info!("purged {purged} stale login throttles"),
+                Err(e) => log::error!("failed to purge login throttles: {e}"),
+            }
+            match oidc::purge_login_states(&purge_pool).await {
+                Ok(purged) => log::info!("purged {purged} abandoned single sign-on logins"),
+                Err(e) => log::error!("failed to purge single sign-on logins: {e}"),
+            }
+            match retention::purge_expired_messages(&purge_pool).await {
+                Ok(purged) => log::info!("purged {purged} messages past their retention"),
+                Err(e) => log::error!("failed to purge expired messages: {e}"),
+            }
+            match exports::purge_expired_exports(&export_purge_config, &purge_pool).await {
+                Ok(purged) => log::info!("removed {purged} expired exports"),
+                Err(e) => log::error!("failed to remove expired exports: {e}"),
+            }
+            match privacy::purge_deleted_accounts(
+                account_deletion_grace,
+                &purge_token_store,
+                &purge_pool,
+            )
+            .await
+            {
+                Ok(purged) => log::info!("deleted {purged} accounts"),
+                Err(e) => log::error!("failed to delete accounts: {e}"),
+            }
+            match outgoing::prune_deliveries(delivery_retention, &purge_pool).await {
+                Ok(pruned) => log::info!("pruned {pruned} old webhook deliveries"),
+                Err(e) => log::error!("failed to prune webhook deliveries: {e}"),
+            }
+        }
+    });
+
+    let delivery_pool = pool.clone();
+    let delivery_config = config.clone();
+    // sends queued outgoing webhook events every 5 seconds, draining the
+    // outbox a batch at a time
+    tokio::spawn(async move {
+        let client = outgoing::delivery_client(&delivery_config);
+        let mut interval = tokio::time::interval(tokio::time::Duration::from_secs(5));
+        loop {
+            interval.tick().await;
+            loop {
+                match outgoing::deliver_due(&client, &delivery_config, &delivery_pool).await {
+                    Ok(0) => break,
+                    Ok(tried) => log::info!("tried {tried} webhook deliveries"),
+                    Err(e) => {
+                        log::error!("failed to send webhook deliveries: {e}");
+                        break;
+                    }
+                }
+            }
+        }
+    });
+
+    let poll_pool = pool.clone();
+    // posts the results of polls whose closing time has passed
+    tokio::spawn(async move {
+        let mut interval = tokio::time::interval(tokio::time::Duration::from_secs(30));
+        loop {
+            interval.tick().await;
+            match polls::close_due_polls(&poll_pool).await {
+                Ok(0) => {}
+                Ok(closed) => log::info!("closed {closed} polls"),
+                Err(e) => log::error!("failed to close polls: {e}"),
+            }
+        }
+    });
+
+    let scheduler_pool = pool.clone();
+    // posts scheduled messages once their time comes
+    tokio::spawn(async move {
+        let mut interval = tokio::time::interval(tokio::time::Duration::from_secs(10));
+        loop {
+            interval.tick().await;
+            match scheduled::send_due_messages(&scheduler_pool).await {
+                Ok(0) => {}
+                Ok(sent) => log::info!("sent {sent} scheduled messages"),
+                Err(e) => log::error!("failed to send scheduled messages: {e}"),
+            }
+        }
+    });
+
+    let sweeper_pool = pool.clone();
+    // removes disappearing messages once they've expired
+    tokio::spawn(async move {
+        let mut interval = tokio::time::interval(tokio::time::Duration::from_secs(30));
+        loop {
+            interval.tick().await;
+            match ephemeral::sweep_expired_messages(&sweeper_pool).await {
+                Ok(0) => {}
+                Ok(swept) => log::info!("removed {swept} expired messages"),
+                Err(e) => log::error!("failed to remove expired messages: {e}"),
+            }
+        }
+    });
+
+    let export_pool = pool.clone();
+    let export_config = config.clone();
+    // writes out queued group exports
+    tokio::spawn(async move {
+        let mut interval = tokio::time::interval(tokio::time::Duration::from_secs(10));
+        loop {
+            interval.tick().await;
+            match exports::run_pending_exports(&export_config, &export_pool).await {
+                Ok(0) => {}
+                Ok(ran) => log::info!("ran {ran} group exports"),
+                Err(e) => log::error!("failed to run group exports: {e}"),
+            }
+        }
+    });
+
+    let sweep_pool = pool.clone();
+    let sweep_config = config.clone();
+    // takes users whose connections stopped sending heartbeats offline, & those
+    // who stopped doing anything away
+    tokio::spawn(async move {
+        let mut interval = tokio::time::interval(tokio::time::Duration::from_secs(15));
+        loop {
+            interval.tick().await;
+            match presence_state::sweep_presence(&sweep_config, &sweep_pool).await {
+                Ok(0) => {}
+                Ok(changed) => log::info!("updated presence for {changed} users"),
+                Err(e) => log::error!("failed to sweep presence: {e}"),
+            }
+        }
+    });
+
+    let presence_pool = pool.clone();
+    let publish_hub = Arc::clone(&presence_hub);
+    // passes presence changes made on any instance to this one's subscribers
+    tokio::spawn(async move {
+        let mut interval = tokio::time::interval(tokio::time::Duration::from_secs(2));
+        let mut version = presence_state::current_version(&presence_pool).await.unwrap_or(0);
+        loop {
+            interval.tick().await;
+            match presence_state::poll_changes(version, &presence_pool).await {
+                Ok((changes, next)) => {
+                    for change in changes.iter() {
+                        publish_hub.publish(change);
+                    }
+                    version = next;
+                }
+                Err(e) => log::error!("failed to poll presence changes: {e}"),
+            }
+        }
+    });
 
     let server = HttpServer::new(move || {
         App::new()
@@ -65,12 +294,22 @@ async fn main() -> std::io::Result<()> {
                 secret_key.clone(),
             ))
             .app_data(token_storage.clone())
-            .app_data(web::Data::new(AppState::new(&pool)))
+            .app_data(web::Data::new(Arc::clone(&presence_hub)))
+            .app_data(web::Data::new(AppState::new(&pool, &config, &mailer)))
             .service(
                 // these are not protected
                 web::scope("/auth")
                     .service(login_handle)
-                    .service(register_handle),
+                    .service(two_factor_login_handle)
+                    .service(challenge_enroll_handle)
+                    .service(challenge_confirm_handle)
+                    .service(register_handle)
+                    .service(verify_email_handle)
+                    .service(forgot_password_handle)
+                    .service(reset_password_form_handle)
+                    .service(reset_password_handle)
+                    .service(oidc_login_handle)
+                    .service(oidc_callback_handle),
             )
             .service(
                 // protected
@@ -81,8 +320,16 @@ async fn main() -> std::io::Result<()> {
                 web::scope("/api")
                     .wrap(AuthStruct)
                     .configure(group_routes)
-                    .configure(message_routes),
+                    .configure(message_routes)
+                    .configure(admin_routes)
+                    // before account_routes, whose /account scope would swallow /account/tokens
+                    .configure(token_routes)
+                    .configure(profile_routes)
+                    .configure(presence_routes)
+                    .configure(account_routes),
             )
+            // not protected, each webhook's url carries its own token
+            .configure(hook_routes)
     })
     .bind(("localhost", 8080))?
     .workers(4)
@@ -0,0 +1,355 @@
+use crate::custom_types::MessageType;
+use chrono::{DateTime, Utc};
+use serde::{Deserialize, Serialize};
+
+const MAX_TEXT_LENGTH: usize = 4000;
+const MAX_BLOCKS: usize = 50;
+const MAX_FIELDS: usize = 10;
+const MAX_BUTTONS: usize = 5;
+const MAX_LABEL_LENGTH: usize = 100;
+const MAX_POLL_OPTIONS: usize = 10;
+
+/// Everything that can be posted into a group, tagged by `type` in the request body
+/// ```json
+/// {"type": "text", "content": "Hello world"}
+/// ```
+#[derive(Serialize, Deserialize, Debug, Clone)]
+#[serde(tag = "type", rename_all = "lowercase")]
+pub enum MessageContent {
+    Text {
+        content: String,
+    },
+    System {
+        content: String,
+    },
+    Blocks {
+        blocks: Vec<Block>,
+        fallback: Option<String>,
+    },
+    /// members vote on `options` until `closes_at`, or until it's closed by hand
+    Poll {
+        question: String,
+        options: Vec<String>,
+        #[serde(default)]
+        multiple_choice: bool,
+        #[serde(default)]
+        anonymous: bool,
+        closes_at: Option<DateTime<Utc>>,
+    },
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+#[serde(tag = "type", rename_all = "lowercase")]
+pub enum Block {
+    Section { text: String },
+    Fields { fields: Vec<Field> },
+    Actions { buttons: Vec<Button> },
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct Field {
+    pub label: String,
+    pub value: String,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct Button {
+    pub label: String,
+    pub value: Option<String>,
+    pub url: Option<String>,
+}
+
+impl MessageContent {
+    pub fn message_type(&self) -> MessageType {
+        match self {
+            MessageContent::Text { .. } => MessageType::TEXT,
+            MessageContent::System { .. } => MessageType::SYSTEM,
+            MessageContent::Blocks { .. } => MessageType::BLOCKS,
+            MessageContent::Poll { .. } => MessageType::POLL,
+        }
+    }
+
+    /// The text stored in `messages.content`, for blocks this is the fallback
+    /// (or the section text joined up) so older clients still show something
+    pub fn text(&self) -> String {
+        match self {
+            MessageContent::Text { content } | MessageContent::System { content } => {
+                content.clone()
+            }
+            MessageContent::Blocks { blocks, fallback } => match fallback {
+                Some(fallback) => fallback.clone(),
+                None => blocks
+                    .iter()
+                    .filter_map(|block| match block {
+                        Block::Section { text } => Some(text.as_str()),
+                        _ => None,
+                    })
+                    .collect::<Vec<&str>>()
+                    .join("\n"),
+            },
+            MessageContent::Poll { question, .. } => question.clone(),
+        }
+    }
+
+    /// The structured part stored in `messages.payload`, for blocks & polls
+    pub fn payload(&self) -> Option<serde_json::Value> {
+        match self {
+            MessageContent::Blocks { blocks, .. } => serde_json::to_value(blocks).ok(),
+            MessageContent::Poll {
+                options,
+                multiple_choice,
+                anonymous,
+                closes_at,
+                ..
+            } => Some(serde_json::json!({
+                "options": options,
+                "multiple_choice": multiple_choice,
+                "anonymous": anonymous,
+                "closes_at": closes_at,
+            })),
+            _ => None,
+        }
+    }
+
+    pub fn validate(&self) -> Result<(), String> {
+        match self {
+            MessageContent::Text { content } | MessageContent::System { content } => {
+                check_text("content", content)
+            }
+            MessageContent::Blocks { blocks, fallback } => {
+                if blocks.is_empty() {
+                    return Err("blocks must not be empty".to_owned());
+                }
+                if blocks.len() > MAX_BLOCKS {
+                    return Err(format!("at most {} blocks are allowed", MAX_BLOCKS));
+                }
+                let has_section = blocks
+                    .iter()
+                    .any(|block| matches!(block, Block::Section { .. }));
+                match fallback {
+                    Some(fallback) => check_text("fallback", fallback)?,
+                    // otherwise the stored text would be empty for older clients
+                    None if !has_section => {
+                        return Err("blocks need a fallback or a section block".to_owned())
+                    }
+                    None => {}
+                }
+                for block in blocks {
+                    block.validate()?;
+                }
+                Ok(())
+            }
+            MessageContent::Poll {
+                question,
+                options,
+                closes_at,
+                ..
+            } => {
+                check_text("question", question)?;
+                if options.len() < 2 || options.len() > MAX_POLL_OPTIONS {
+                    return Err(format!("a poll needs 2 to {} options", MAX_POLL_OPTIONS));
+                }
+                for (i, option) in options.iter().enumerate() {
+                    check_label("option", option)?;
+                    if options[..i].contains(option) {
+                        return Err(format!("option \"{}\" is given twice", option));
+                    }
+                }
+                match closes_at {
+                    Some(closes_at) if *closes_at <= Utc::now() => {
+                        Err("closes_at must be in the future".to_owned())
+                    }
+                    _ => Ok(()),
+                }
+            }
+        }
+    }
+}
+
+impl Block {
+    fn validate(&self) -> Result<(), String> {
+        match self {
+            Block::Section { text } => check_text("section text", text),
+            Block::Fields { fields } => {
+                if fields.is_empty() || fields.len() > MAX_FIELDS {
+                    return Err(format!("fields block needs 1 to {} fields", MAX_FIELDS));
+                }
+                for field in fields {
+                    check_label("field label", &field.label)?;
+                    check_text("field value", &field.value)?;
+                }
+                Ok(())
+            }
+            Block::Actions { buttons } => {
+                if buttons.is_empty() || buttons.len() > MAX_BUTTONS {
+                    return Err(format!("actions block needs 1 to {} buttons", MAX_BUTTONS));
+                }
+                for button in buttons {
+                    check_label("button label", &button.label)?;
+                    match (&button.value, &button.url) {
+                        (None, None) => {
+                            return Err(format!(
+                                "button \"{}\" needs a value or a url",
+                                button.label
+                            ))
+                        }
+                        (_, Some(url)) if !is_http_url(url) => {
+                            return Err(format!("button url \"{}\" must be http(s)", url))
+                        }
+                        _ => {}
+                    }
+                }
+                Ok(())
+            }
+        }
+    }
+}
+
+fn check_text(name: &str, text: &str) -> Result<(), String> {
+    if text.trim().is_empty() {
+        return Err(format!("{} must not be empty", name));
+    }
+    if text.chars().count() > MAX_TEXT_LENGTH {
+        return Err(format!(
+            "{} is longer than {} characters",
+            name, MAX_TEXT_LENGTH
+        ));
+    }
+    Ok(())
+}
+
+fn check_label(name: &str, label: &str) -> Result<(), String> {
+    if label.trim().is_empty() {
+        return Err(format!("{} must not be empty", name));
+    }
+    if label.chars().count() > MAX_LABEL_LENGTH {
+        return Err(format!(
+            "{} is longer than {} characters",
+            name, MAX_LABEL_LENGTH
+        ));
+    }
+    Ok(())
+}
+
+fn is_http_url(url: &str) -> bool {
+    url.starts_with("https://") || url.starts_with("http://")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parses_tagged_content() {
+        let content: MessageContent =
+            serde_json::from_str(r#"{"type": "text", "content": "Hello world"}"#).unwrap();
+        assert!(content.validate().is_ok());
+        assert_eq!(content.text(), "Hello world");
+        assert!(content.payload().is_none());
+    }
+
+    #[test]
+    fn polls_need_distinct_options() {
+        let poll: MessageContent = serde_json::from_str(
+            r#"{"type": "poll", "question": "Ship it?", "options": ["go", "no go"]}"#,
+        )
+        .unwrap();
+        assert!(poll.validate().is_ok());
+        assert_eq!(poll.text(), "Ship it?");
+
+        let poll: MessageContent = serde_json::from_str(
+            r#"{"type": "poll", "question": "Ship it?", "options": ["go", "go"]}"#,
+        )
+        .unwrap();
+        assert!(poll.validate().is_err());
+        let poll: MessageContent = serde_json::from_str(
+            r#"{"type": "poll", "question": "Ship it?", "options": ["go", "no go"],
+            "closes_at": "2001-01-01T00:00:00Z"}"#,
+        )
+        .unwrap();
+        assert_eq!(
+            poll.validate(),
+            Err("closes_at must be in the future".to_owned())
+        );
+    }
+
+    #[test]
+    fn rejects_empty_text() {
+        let content = MessageContent::Text {
+            content: "   ".to_owned(),
+        };
+        assert!(content.validate().is_err());
+    }
+
+    #[test]
+    fn blocks_fall_back_to_section_text() {
+        let content: MessageContent = serde_json::from_str(
+            r#"{"type": "blocks", "blocks": [
+                {"type": "section", "text": "Deploy finished"},
+                {"type": "fields", "fields": [{"label": "env", "value": "prod"}]},
+                {"type": "actions", "buttons": [{"label": "Logs", "url": "https://ci/1"}]}
+            ]}"#,
+        )
+        .unwrap();
+        assert!(content.validate().is_ok());
+        assert_eq!(content.text(), "Deploy finished");
+        assert!(content.payload().unwrap().is_array());
+    }
+
+    #[test]
+    fn rejects_bad_buttons() {
+        let no_action = MessageContent::Blocks {
+            blocks: vec![Block::Actions {
+                buttons: vec![Button {
+                    label: "Go".to_owned(),
+                    value: None,
+                    url: None,
+                }],
+            }],
+            fallback: None,
+        };
+        assert!(no_action.validate().is_err());
+
+        let bad_url = MessageContent::Blocks {
+            blocks: vec![Block::Actions {
+                buttons: vec![Button {
+                    label: "Go".to_owned(),
+                    value: None,
+                    url: Some("javascript:alert(1)".to_owned()),
+                }],
+            }],
+            fallback: None,
+        };
+        assert!(bad_url.validate().is_err());
+    }
+
+    #[test]
+    fn blocks_need_some_text() {
+        let fields = || Block::Fields {
+            fields: vec![Field {
+                label: "env".to_owned(),
+                value: "prod".to_owned(),
+            }],
+        };
+        let no_text = MessageContent::Blocks {
+            blocks: vec![fields()],
+            fallback: None,
+        };
+        assert!(no_text.validate().is_err());
+        let with_fallback = MessageContent::Blocks {
+            blocks: vec![fields()],
+            fallback: Some("env: prod".to_owned()),
+        };
+        assert!(with_fallback.validate().is_ok());
+        assert_eq!(with_fallback.text(), "env: prod");
+    }
+
+    #[test]
+    fn rejects_empty_blocks() {
+        let content = MessageContent::Blocks {
+            blocks: vec![],
+            fallback: Some("nothing".to_owned()),
+        };
+        assert!(content.validate().is_err());
+    }
+}
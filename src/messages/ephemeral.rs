@@ -0,0 +1,216 @@
+use super::content::MessageContent;
+use crate::extractors::extractors::User;
+use crate::groups::roles::{has_capability, Capability};
+use crate::server::app_state::AppState;
+use crate::webhooks::events::{enqueue_event, WebhookEvent};
+use actix_web::{get, web, HttpResponse, Responder};
+use chrono::{DateTime, Duration, Utc};
+use serde::{Deserialize, Serialize};
+use serde_json::{json, Value};
+use sqlx::{Pool, Postgres};
+use std::collections::HashMap;
+
+/// A year, the longest a message can be set to last
+pub const MAX_DISAPPEAR_AFTER_MINS: i32 = 525600;
+/// Messages removed per sweep batch
+const SWEEP_BATCH: i64 = 500;
+/// How long clients have to notice an expired message before its id is forgotten too
+const EXPIRED_KEPT_DAYS: i64 = 30;
+
+/// How a message disappears, whatever isn't given comes from the group's settings
+#[derive(Deserialize, Debug, Default, Clone, Copy)]
+pub struct Disappearing {
+    /// minutes after it's sent
+    pub disappear_after_mins: Option<i32>,
+    /// as soon as someone other than the sender reads it
+    pub disappear_after_read: Option<bool>,
+}
+
+impl Disappearing {
+    pub fn validate(&self) -> Result<(), String> {
+        match self.disappear_after_mins {
+            Some(mins) if !(1..=MAX_DISAPPEAR_AFTER_MINS).contains(&mins) => Err(format!(
+                "disappear_after_mins must be 1 to {MAX_DISAPPEAR_AFTER_MINS}"
+            )),
+            _ => Ok(()),
+        }
+    }
+}
+
+/// What a `message.created` event carries. A disappearing message's content is
+/// left out, the event would otherwise keep it long after the message is gone
+pub fn created_event_data(
+    message_id: i32,
+    sender_user_id: i32,
+    content: &MessageContent,
+    disappears: bool,
+) -> Value {
+    match disappears {
+        true => json!({
+            "message_id": message_id,
+            "sender_user_id": sender_user_id,
+            "disappearing": true,
+        }),
+        false => json!({
+            "message_id": message_id,
+            "sender_user_id": sender_user_id,
+            "message": content,
+        }),
+    }
+}
+
+/// Starts the clock on disappear after read messages `reader_id` was just sent,
+/// they're still in this read but gone for the next. Returns when each expires
+pub async fn expire_read_messages(
+    message_ids: &[i32],
+    reader_id: i32,
+    pool: &Pool<Postgres>,
+) -> Result<HashMap<i32, DateTime<Utc>>, sqlx::Error> {
+    if message_ids.is_empty() {
+        return Ok(HashMap::new());
+    }
+    let rows = sqlx::query!(
+        r#"update messages set expires_at = least(expires_at, now())
+        where id = any($1) and expire_after_read and sender_user_id <> $2
+        returning id, expires_at as "expires_at!""#,
+        message_ids,
+        reader_id
+    )
+    .fetch_all(pool)
+    .await?;
+    Ok(rows
+        .into_iter()
+        .map(|row| (row.id, row.expires_at))
+        .collect())
+}
+
+/// Deletes expired messages a batch at a time, leaving only their ids behind for
+/// clients & sending `message.expired` to webhooks. Their `message.created`
+/// deliveries go too, sent or not, in case they were queued with the content.
+/// Groups under legal hold keep theirs, though readers no longer see them
+pub async fn sweep_expired_messages(pool: &Pool<Postgres>) -> Result<u64, sqlx::Error> {
+    let mut swept = 0;
+    loop {
+        let mut tx = pool.begin().await?;
+        let expired = sqlx::query!(
+            r#"delete from messages where id in (
+                select m.id from messages m join groups g on g.id = m.group_id
+                where m.expires_at <= now() and g.legal_hold_at is null
+                limit $1 for update of m skip locked
+            ) returning id, group_id, sender_user_id"#,
+            SWEEP_BATCH
+        )
+        .fetch_all(&mut *tx)
+        .await?;
+        for message in expired.iter() {
+            sqlx::query!(
+                r#"insert into expired_messages (message_id, group_id) values ($1, $2)
+                on conflict do nothing"#,
+                message.id,
+                message.group_id
+            )
+            .execute(&mut *tx)
+            .await?;
+            enqueue_event(
+                &mut *tx,
+                WebhookEvent::MessageExpired,
+                message.group_id,
+                json!({ "message_id": message.id, "sender_user_id": message.sender_user_id }),
+            )
+            .await?;
+        }
+        let ids: Vec<i32> = expired.iter().map(|message| message.id).collect();
+        sqlx::query!(
+            r#"delete from webhook_deliveries where event_type = 'message.created'
+            and (payload->'data'->>'message_id')::int = any($1)"#,
+            &ids
+        )
+        .execute(&mut *tx)
+        .await?;
+        tx.commit().await?;
+        swept += expired.len() as u64;
+        if (expired.len() as i64) < SWEEP_BATCH {
+            break;
+        }
+    }
+    sqlx::query!(
+        r#"delete from expired_messages where expired_at < $1"#,
+        Utc::now() - Duration::days(EXPIRED_KEPT_DAYS)
+    )
+    .execute(pool)
+    .await?;
+    Ok(swept)
+}
+
+#[derive(Deserialize)]
+struct ExpiredQuery {
+    since: Option<DateTime<Utc>>,
+}
+
+#[derive(Serialize)]
+struct ExpiredMessage {
+    message_id: i32,
+    expired_at: DateTime<Utc>,
+}
+
+/// Ids of the group's messages that disappeared since `since`, for clients to
+/// drop their copies
+#[get("{group_id}/expired")]
+async fn handle_get_expired(
+    group_id: web::Path<i32>,
+    query: web::Query<ExpiredQuery>,
+    user: User,
+    app: web::Data<AppState>,
+) -> impl Responder {
+    let group_id = group_id.into_inner();
+    if !has_capability(user.user_id, group_id, Capability::Read, &app.pool).await {
+        return HttpResponse::Unauthorized().body(format!(
+            "User {} is not permitted to read group {}",
+            user.user_id, group_id
+        ));
+    }
+    match sqlx::query_as!(
+        ExpiredMessage,
+        r#"select message_id, expired_at from expired_messages
+        where group_id = $1 and ($2::timestamptz is null or expired_at > $2)
+        order by expired_at"#,
+        group_id,
+        query.since
+    )
+    .fetch_all(&app.pool)
+    .await
+    {
+        Ok(expired) => HttpResponse::Ok().json(expired),
+        Err(e) => HttpResponse::InternalServerError().body(e.to_string()),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn disappearing_times_are_bounded() {
+        assert!(Disappearing::default().validate().is_ok());
+        let after = |mins| Disappearing {
+            disappear_after_mins: Some(mins),
+            disappear_after_read: None,
+        };
+        assert!(after(60).validate().is_ok());
+        assert!(after(0).validate().is_err());
+        assert!(after(MAX_DISAPPEAR_AFTER_MINS + 1).validate().is_err());
+    }
+
+    #[test]
+    fn disappearing_content_stays_out_of_webhook_events() {
+        let content = MessageContent::Text {
+            content: "the wifi password is hunter2".to_owned(),
+        };
+        let kept = created_event_data(1, 2, &content, false);
+        assert_eq!(kept["message"]["content"], "the wifi password is hunter2");
+        let scrubbed = created_event_data(1, 2, &content, true);
+        assert_eq!(scrubbed["message_id"], 1);
+        assert_eq!(scrubbed["disappearing"], true);
+        assert!(!scrubbed.to_string().contains("hunter2"));
+    }
+}
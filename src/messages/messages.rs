@@ -1,57 +1,193 @@
 use std::time::{Duration, Instant};
 
-use crate::custom_types;
+use super::content::MessageContent;
+use super::ephemeral::{created_event_data, expire_read_messages, Disappearing};
+use super::polls::{create_poll, poll_tallies, PollTally};
+use crate::audit::audit::audit_event;
+use crate::commands::commands::{literal_text, parse_command, run_command};
+use crate::custom_types::MessageType;
+use crate::groups::roles::{has_capability, Capability};
 use crate::extractors::extractors::User;
+use crate::presence::presence::{
+    drop_connection, presence_of, refresh_presence, touch_connection, visible_presence,
+    PresenceChange, PresenceHub,
+};
 use crate::server::app_state::AppState;
-use actix::{AsyncContext, Running};
+use crate::webhooks::events::{enqueue_event, WebhookEvent};
+use actix::{ActorContext, AsyncContext, Handler, Running};
 use actix::{Actor, StreamHandler};
 use actix_web::web::Payload;
-use actix_web::{get, put, web, HttpRequest, HttpResponse, HttpResponseBuilder, Responder};
+use actix_web::{delete, get, put, web, HttpRequest, HttpResponse, HttpResponseBuilder, Responder};
 use actix_web_actors::ws;
 use log::log;
 use serde::{Deserialize, Serialize};
+use serde_json::json;
 use sqlx::prelude::FromRow;
 use sqlx::types::chrono;
-use sqlx::{Pool, Postgres};
+use sqlx::{Executor, PgConnection, Pool, Postgres};
+use std::sync::Arc;
 use uuid::Uuid;
 
 #[derive(Deserialize)]
-struct Message {
-    content: String,
+pub struct Message {
+    pub content: String,
+    #[serde(flatten)]
+    pub disappearing: Disappearing,
 }
 
+/// Posts a text message, or runs it when it's a slash command like `/topic ...`,
+/// answering with what the command did instead of the new message's id
 #[put("{group_id}")]
 async fn handle_write_message(
     group_id: web::Path<i32>,
     message: web::Json<Message>,
     user: User,
     app: web::Data<AppState>,
+    req: HttpRequest,
 ) -> impl Responder {
-    let state = write_message(group_id.abs(), user.user_id, message.0, &app.pool).await;
-    match state {
-        0 => HttpResponse::Unauthorized().body(format!(
-            "User {} does not have write permissions for group {}",
-            user.user_id,
-            group_id.abs()
-        )),
-        _ => HttpResponse::Ok().body(state.to_string()),
+    let Message {
+        content: text,
+        disappearing,
+    } = message.into_inner();
+    if let Some(invocation) = parse_command(&text) {
+        return match run_command(group_id.abs(), user.user_id, invocation, &app, &req).await {
+            Ok(reply) => HttpResponse::Ok().json(reply),
+            Err(resp) => resp,
+        };
+    }
+    let content = MessageContent::Text {
+        content: literal_text(text),
+    };
+    match write_message_with(group_id.abs(), user.user_id, content, disappearing, &app.pool).await {
+        Ok(id) => HttpResponse::Ok().body(id.to_string()),
+        Err(resp) => resp,
     }
 }
 
-async fn write_message(
+/// The single write path for user posted messages, validates the content then
+/// checks the user may post it into the group before storing it
+pub async fn write_message(
+    group_id: i32,
+    user_id: i32,
+    content: MessageContent,
+    pool: &Pool<Postgres>,
+) -> Result<i32, HttpResponse> {
+    write_message_with(group_id, user_id, content, Disappearing::default(), pool).await
+}
+
+/// `write_message` for a message with its own disappearing settings
+pub async fn write_message_with(
     group_id: i32,
     user_id: i32,
-    message: Message,
+    content: MessageContent,
+    disappearing: Disappearing,
     pool: &Pool<Postgres>,
-) -> i32 {
-    // first need to check that user has write access
-    let count = sqlx::query!(r#"select count(*) from group_permissions where user_id = $1 and group_id = $2 and write limit 1"#, user_id, group_id).fetch_one(pool).await.unwrap().count.unwrap();
-    if count < 1 {
-        return 0;
+) -> Result<i32, HttpResponse> {
+    let internal_error = |e: sqlx::Error| HttpResponse::InternalServerError().body(e.to_string());
+    let mut tx = pool.begin().await.map_err(internal_error)?;
+    let id = write_message_in(&mut tx, group_id, user_id, content, disappearing, pool).await?;
+    tx.commit().await.map_err(internal_error)?;
+    Ok(id)
+}
+
+/// `write_message` inside the caller's transaction, for posts that have to
+/// commit along with something else
+pub async fn write_message_in(
+    conn: &mut PgConnection,
+    group_id: i32,
+    user_id: i32,
+    content: MessageContent,
+    disappearing: Disappearing,
+    pool: &Pool<Postgres>,
+) -> Result<i32, HttpResponse> {
+    if let Err(reason) = content.validate().and(disappearing.validate()) {
+        return Err(HttpResponse::BadRequest().body(reason));
+    }
+
+    // first need to check that user has write access, system notices need moderate too
+    let needed = match content {
+        MessageContent::System { .. } => Capability::Moderate,
+        _ => Capability::Write,
+    };
+    if !has_capability(user_id, group_id, needed, pool).await {
+        return Err(HttpResponse::Unauthorized().body(format!(
+            "User {} does not have write permissions for group {}",
+            user_id, group_id
+        )));
     }
 
-    sqlx::query!(r#"insert into messages (sender_user_id, group_id, content) values ($1, $2, $3) returning id"#,
-        user_id, group_id, message.content).fetch_one(pool).await.unwrap().id
+    // archived groups keep their history but don't accept new posts
+    match sqlx::query!(r#"select archived_at, deleted_at from groups where id = $1"#, group_id)
+        .fetch_one(pool)
+        .await
+    {
+        Ok(rec) if rec.deleted_at.is_some() => {
+            return Err(HttpResponse::NotFound().body(format!("Group {} is deleted", group_id)))
+        }
+        Ok(rec) if rec.archived_at.is_some() => {
+            return Err(HttpResponse::Forbidden()
+                .body(format!("Group {} is archived and read-only", group_id)))
+        }
+        Ok(_) => {}
+        Err(e) => return Err(HttpResponse::InternalServerError().body(e.to_string())),
+    }
+
+    insert_message(conn, group_id, user_id, &content, disappearing)
+        .await
+        .map_err(|e| HttpResponse::InternalServerError().body(e.to_string()))
+}
+
+/// Posts a notice into a group on behalf of `user_id`, e.g. "alice renamed the group".
+/// Skips the write permission & archive checks, callers are expected to have done
+/// their own authorization.
+pub async fn write_system_message(
+    conn: &mut PgConnection,
+    group_id: i32,
+    user_id: i32,
+    content: &str,
+) -> Result<i32, sqlx::Error> {
+    let content = MessageContent::System {
+        content: content.to_owned(),
+    };
+    insert_message(conn, group_id, user_id, &content, Disappearing::default()).await
+}
+
+/// Stores a message & queues its `message.created` webhook event. Disappearing
+/// settings the message doesn't give come from its group
+async fn insert_message(
+    conn: &mut PgConnection,
+    group_id: i32,
+    user_id: i32,
+    content: &MessageContent,
+    disappearing: Disappearing,
+) -> Result<i32, sqlx::Error> {
+    let rec = sqlx::query!(
+        r#"insert into messages
+        (sender_user_id, group_id, content, message_type, payload, expires_at, expire_after_read)
+        select $1, $2, $3, $4, $5,
+            now() + make_interval(mins => coalesce($6, g.disappear_after_mins)),
+            coalesce($7, g.disappear_after_read)
+        from groups g where g.id = $2
+        returning id, expires_at is not null or expire_after_read as "disappears!""#,
+        user_id,
+        group_id,
+        content.text(),
+        content.message_type() as MessageType,
+        content.payload(),
+        disappearing.disappear_after_mins,
+        disappearing.disappear_after_read
+    )
+    .fetch_one(&mut *conn)
+    .await?;
+    create_poll(rec.id, group_id, content, &mut *conn).await?;
+    enqueue_event(
+        &mut *conn,
+        WebhookEvent::MessageCreated,
+        group_id,
+        created_event_data(rec.id, user_id, content, rec.disappears),
+    )
+    .await?;
+    Ok(rec.id)
 }
 
 #[get("{group_id}")]
@@ -61,100 +197,361 @@ async fn handle_get_messages(
     app: web::Data<AppState>,
 ) -> impl Responder {
     match get_messages(group_id.abs(), user.user_id, &app.pool).await {
-        Some(val) => HttpResponse::Ok().json(val),
-        None => HttpResponse::Unauthorized().body("nah"),
+        Ok(Some(val)) => HttpResponse::Ok().json(val),
+        Ok(None) => HttpResponse::Unauthorized().body("nah"),
+        Err(e) => HttpResponse::InternalServerError().body(e.to_string()),
     }
 }
 
 #[derive(Serialize, FromRow)]
 struct MessageResponse {
+    id: i32,
     sender_user_id: i32,
+    /// the sender's display name & avatar, so clients needn't look each one up
+    sender_display_name: String,
+    sender_avatar_url: Option<String>,
     group_id: i32,
     content: String,
+    message_type: MessageType,
+    payload: Option<serde_json::Value>,
     sent_at: Option<chrono::DateTime<chrono::Utc>>,
+    pinned_at: Option<chrono::DateTime<chrono::Utc>>,
+    /// when it disappears, clients should drop their copy then too
+    expires_at: Option<chrono::DateTime<chrono::Utc>>,
+    expire_after_read: bool,
+}
+
+/// A message along with its poll's live tally, if it's a poll
+#[derive(Serialize)]
+struct MessageWithPoll {
+    #[serde(flatten)]
+    message: MessageResponse,
+    poll: Option<PollTally>,
 }
+
+/// The group's messages, `None` if the user can't read it
 async fn get_messages(
     group_id: i32,
     user_id: i32,
     pool: &Pool<Postgres>,
-) -> Option<Vec<MessageResponse>> {
-    let count = sqlx::query!(
-        r#"select count(*) from group_permissions where user_id = $1 and group_id = $2 and write"#,
-        user_id,
+) -> Result<Option<Vec<MessageWithPoll>>, sqlx::Error> {
+    if !has_capability(user_id, group_id, Capability::Read, pool).await {
+        return Ok(None);
+    }
+
+    let offset = 0;
+    // expired messages the sweeper hasn't got to yet are already gone as far as readers go
+    let mut resp = sqlx::query_as!(MessageResponse, r#"select m.id, m.sender_user_id, u.display_name as sender_display_name, u.avatar_url as sender_avatar_url, m.group_id, m.content, m.message_type as "message_type: MessageType", m.payload, m.sent_at, m.pinned_at, m.expires_at, m.expire_after_read from messages m join users u on u.id = m.sender_user_id where m.group_id = $1 and (m.expires_at is null or m.expires_at > now()) order by m.sent_at asc limit 100 offset $2"#, group_id, offset).fetch_all(pool).await?;
+    let read_ids: Vec<i32> = resp
+        .iter()
+        .filter(|message| message.expire_after_read && message.sender_user_id != user_id)
+        .map(|message| message.id)
+        .collect();
+    let expiring = expire_read_messages(&read_ids, user_id, pool).await?;
+    for message in resp.iter_mut() {
+        if let Some(expires_at) = expiring.get(&message.id) {
+            message.expires_at = Some(*expires_at);
+        }
+    }
+    let poll_ids: Vec<i32> = resp
+        .iter()
+        .filter(|message| matches!(message.message_type, MessageType::POLL))
+        .map(|message| message.id)
+        .collect();
+    let mut tallies = poll_tallies(&poll_ids, user_id, pool).await?;
+    Ok(Some(
+        resp.into_iter()
+            .map(|message| MessageWithPoll {
+                poll: tallies.remove(&message.id),
+                message,
+            })
+            .collect(),
+    ))
+}
+
+#[delete("{group_id}/{message_id}")]
+async fn handle_delete_message(
+    path: web::Path<(i32, i32)>,
+    user: User,
+    app: web::Data<AppState>,
+    req: HttpRequest,
+) -> impl Responder {
+    let (group_id, message_id) = path.into_inner();
+    let sender = match sqlx::query!(
+        r#"select m.sender_user_id, g.legal_hold_at is not null as "held!"
+        from messages m join groups g on g.id = m.group_id where m.id = $1 and m.group_id = $2"#,
+        message_id,
         group_id
     )
-    .fetch_one(pool)
+    .fetch_optional(&app.pool)
     .await
-    .unwrap()
-    .count
-    .unwrap();
-    if count < 1 {
-        return None;
+    {
+        Ok(Some(rec)) if rec.held => {
+            return HttpResponse::Conflict()
+                .body(format!("Group {} is under legal hold, messages can't be deleted", group_id))
+        }
+        Ok(Some(rec)) => rec.sender_user_id,
+        Ok(None) => return HttpResponse::NotFound().body(format!("message {} not found", message_id)),
+        Err(e) => return HttpResponse::InternalServerError().body(e.to_string()),
+    };
+    // anyone who can still read the group can take back their own messages
+    let needed = match sender == user.user_id {
+        true => Capability::Read,
+        false => Capability::DeleteOthers,
+    };
+    if !has_capability(user.user_id, group_id, needed, &app.pool).await {
+        return HttpResponse::Unauthorized().body(format!(
+            "User {} is not permitted to delete message {}",
+            user.user_id, message_id
+        ));
     }
+    let result = async {
+        let mut tx = app.pool.begin().await?;
+        sqlx::query!(r#"delete from messages where id = $1"#, message_id)
+            .execute(&mut *tx)
+            .await?;
+        enqueue_event(
+            &mut *tx,
+            WebhookEvent::MessageDeleted,
+            group_id,
+            json!({ "message_id": message_id, "sender_user_id": sender, "deleted_by": user.user_id }),
+        )
+        .await?;
+        tx.commit().await
+    }
+    .await;
+    match result {
+        Ok(_) => {
+            audit_event(
+                &app.pool,
+                &req,
+                Some(user.user_id),
+                "message.delete",
+                Some(format!("message:{message_id}")),
+                Some(json!({ "group_id": group_id, "sender_user_id": sender })),
+            )
+            .await;
+            HttpResponse::Ok().body("")
+        }
+        Err(e) => HttpResponse::InternalServerError().body(e.to_string()),
+    }
+}
 
-    let offset = 0;
-    let resp = sqlx::query_as!(MessageResponse, r#"select sender_user_id, group_id, content, sent_at from messages where group_id = $1 order by sent_at asc limit 100 offset $2"#, group_id, offset).fetch_all(pool).await.unwrap();
-    Some(resp)
+#[put("{group_id}/{message_id}/pin")]
+async fn handle_pin_message(
+    path: web::Path<(i32, i32)>,
+    user: User,
+    app: web::Data<AppState>,
+) -> impl Responder {
+    let (group_id, message_id) = path.into_inner();
+    set_pinned(group_id, message_id, Some(user.user_id), user.user_id, &app.pool).await
+}
+
+#[delete("{group_id}/{message_id}/pin")]
+async fn handle_unpin_message(
+    path: web::Path<(i32, i32)>,
+    user: User,
+    app: web::Data<AppState>,
+) -> impl Responder {
+    let (group_id, message_id) = path.into_inner();
+    set_pinned(group_id, message_id, None, user.user_id, &app.pool).await
+}
+
+/// Pins the message for `pinned_by`, or unpins it when that's `None`
+async fn set_pinned(
+    group_id: i32,
+    message_id: i32,
+    pinned_by: Option<i32>,
+    user_id: i32,
+    pool: &Pool<Postgres>,
+) -> HttpResponse {
+    if !has_capability(user_id, group_id, Capability::Pin, pool).await {
+        return HttpResponse::Unauthorized().body(format!(
+            "User {} is not permitted to pin messages in group {}",
+            user_id, group_id
+        ));
+    }
+    match sqlx::query!(
+        r#"update messages set pinned_by = $1,
+        pinned_at = case when $1::integer is null then null else now() end
+        where id = $2 and group_id = $3"#,
+        pinned_by,
+        message_id,
+        group_id
+    )
+    .execute(pool)
+    .await
+    {
+        Ok(res) if res.rows_affected() > 0 => HttpResponse::Ok().body(""),
+        Ok(_) => HttpResponse::NotFound().body(format!("message {} not found", message_id)),
+        Err(e) => HttpResponse::InternalServerError().body(e.to_string()),
+    }
 }
 
+/// How often the server pings, & how long a client has to answer
+const HEARTBEAT_INTERVAL: Duration = Duration::from_secs(5);
 const CLIENT_TIMEOUT: Duration = Duration::from_secs(10);
+/// How often a live session tells the database it's still there, well inside PRESENCE_TIMEOUT_SECS
+const PRESENCE_TOUCH_INTERVAL: Duration = Duration::from_secs(20);
 
+/// A signed in user's websocket. It keeps them present while it's open &
+/// passes on presence changes for the users they've subscribed to
 pub struct WsChatSession {
     pub session_id: Uuid,
     pub heartbeat: Instant,
     pub user_id: i32,
-    pub group_id: i32,
+    /// whether the client said it was active since presence was last touched
+    pub active: bool,
+    pub app: web::Data<AppState>,
+    pub hub: web::Data<Arc<PresenceHub>>,
+}
+
+/// What clients send over the websocket
+#[derive(Deserialize)]
+#[serde(tag = "type", rename_all = "snake_case")]
+enum ClientEvent {
+    Subscribe { user_ids: Vec<i32> },
+    Unsubscribe { user_ids: Vec<i32> },
+    /// the user did something, so they aren't away
+    Active,
 }
 
 impl Actor for WsChatSession {
     type Context = ws::WebsocketContext<Self>;
 
     fn started(&mut self, ctx: &mut Self::Context) {
+        self.hub.register(&self.session_id.to_string(), ctx.address().recipient());
         self.start_heartbeat(ctx);
+        self.touch_presence(true);
     }
 
-    fn stopping(&mut self, ctx: &mut Self::Context) -> actix::prelude::Running {
+    fn stopping(&mut self, _ctx: &mut Self::Context) -> actix::prelude::Running {
+        let session_id = self.session_id.to_string();
+        self.hub.remove(&session_id);
+        let (user_id, app) = (self.user_id, self.app.clone());
+        actix::spawn(async move {
+            let result = async {
+                drop_connection(&session_id, &app.pool).await?;
+                refresh_presence(&[user_id], &app.config, &app.pool).await
+            };
+            if let Err(e) = result.await {
+                log::error!("failed to drop presence connection {session_id}: {e}");
+            }
+        });
         Running::Stop
     }
-
 }
 
 impl WsChatSession {
     fn start_heartbeat(&self, ctx: &mut ws::WebsocketContext<Self>) {
-        ctx.run_interval(CLIENT_TIMEOUT, |act, _ctx| {
+        ctx.run_interval(HEARTBEAT_INTERVAL, |act, ctx| {
             if Instant::now().duration_since(act.heartbeat) > CLIENT_TIMEOUT {
-                log::error!("Heartbeat duration timeout");
-                // todo: disconnect
+                log::info!("websocket {} missed its heartbeat, disconnecting", act.session_id);
+                ctx.stop();
                 return;
             }
-            // send ping to client
-            _ctx.ping("".as_bytes());
+            ctx.ping(b"");
+        });
+        ctx.run_interval(PRESENCE_TOUCH_INTERVAL, |act, _ctx| {
+            act.touch_presence(false);
+            act.active = false;
+        });
+    }
+
+    /// Keeps the session's connection alive in the database, marking the user
+    /// active if `active`, & updates their presence
+    fn touch_presence(&self, active: bool) {
+        let session_id = self.session_id.to_string();
+        let (user_id, app) = (self.user_id, self.app.clone());
+        actix::spawn(async move {
+            let result = async {
+                touch_connection(&session_id, user_id, active, &app.pool).await?;
+                refresh_presence(&[user_id], &app.config, &app.pool).await
+            };
+            if let Err(e) = result.await {
+                log::error!("failed to update presence for session {session_id}: {e}");
+            }
         });
     }
+
+    fn handle_client_event(&mut self, event: ClientEvent, ctx: &mut ws::WebsocketContext<Self>) {
+        let session_id = self.session_id.to_string();
+        match event {
+            ClientEvent::Subscribe { user_ids } => {
+                // only users sharing a group with the caller, others are left out.
+                // current presence first, changes follow as they happen
+                let (user_id, app, hub) = (self.user_id, self.app.clone(), self.hub.clone());
+                let recipient = ctx.address().recipient();
+                actix::spawn(async move {
+                    let result = async {
+                        let user_ids = visible_presence(user_id, &user_ids, &app.pool).await?;
+                        hub.subscribe(&session_id, &user_ids);
+                        presence_of(&user_ids, &app.pool).await
+                    };
+                    match result.await {
+                        Ok(presence) => presence.into_iter().for_each(|p| recipient.do_send(p)),
+                        Err(e) => log::error!("failed to look up presence: {e}"),
+                    }
+                });
+            }
+            ClientEvent::Unsubscribe { user_ids } => self.hub.unsubscribe(&session_id, &user_ids),
+            ClientEvent::Active if !self.active => {
+                self.active = true;
+                self.touch_presence(true);
+            }
+            ClientEvent::Active => {}
+        }
+    }
 }
 
-struct MyWs;
+impl Handler<PresenceChange> for WsChatSession {
+    type Result = ();
 
-impl Actor for MyWs {
-    type Context = ws::WebsocketContext<Self>;
+    fn handle(&mut self, change: PresenceChange, ctx: &mut Self::Context) {
+        ctx.text(json!({ "type": "presence", "user_id": change.user_id, "state": change.state }).to_string());
+    }
 }
 
-/// Handler for ws::Message message
-impl StreamHandler<Result<ws::Message, ws::ProtocolError>> for MyWs {
+impl StreamHandler<Result<ws::Message, ws::ProtocolError>> for WsChatSession {
     fn handle(&mut self, msg: Result<ws::Message, ws::ProtocolError>, ctx: &mut Self::Context) {
         match msg {
-            Ok(ws::Message::Ping(msg)) => ctx.pong(&msg),
-            Ok(ws::Message::Text(text)) => ctx.text(text),
-            Ok(ws::Message::Binary(bin)) => ctx.binary(bin),
+            Ok(ws::Message::Ping(msg)) => {
+                self.heartbeat = Instant::now();
+                ctx.pong(&msg);
+            }
+            Ok(ws::Message::Pong(_)) => self.heartbeat = Instant::now(),
+            Ok(ws::Message::Text(text)) => {
+                self.heartbeat = Instant::now();
+                match serde_json::from_str(&text) {
+                    Ok(event) => self.handle_client_event(event, ctx),
+                    Err(e) => ctx.text(json!({ "type": "error", "error": e.to_string() }).to_string()),
+                }
+            }
+            Ok(ws::Message::Close(reason)) => {
+                ctx.close(reason);
+                ctx.stop();
+            }
+            Err(_) => ctx.stop(),
             _ => (),
         }
     }
 }
 
 #[get("ws")]
-async fn message_ws(req: HttpRequest, stream: Payload) -> impl Responder {
-    let ws_actor = MyWs {};
-    let value = ws::start(ws_actor, &req, stream);
-    println!("value {:?}", value);
-    format!("hi")
+async fn message_ws(
+    req: HttpRequest,
+    stream: Payload,
+    user: User,
+    app: web::Data<AppState>,
+    hub: web::Data<Arc<PresenceHub>>,
+) -> Result<HttpResponse, actix_web::Error> {
+    let session = WsChatSession {
+        session_id: Uuid::new_v4(),
+        heartbeat: Instant::now(),
+        user_id: user.user_id,
+        active: true,
+        app,
+        hub,
+    };
+    ws::start(session, &req, stream)
 }
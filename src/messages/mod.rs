@@ -1,13 +1,37 @@
+pub mod content;
+pub mod ephemeral;
 pub mod messages;
+pub mod polls;
+pub mod retention;
+pub mod scheduled;
 use actix_web::web;
-use messages::{handle_get_messages, handle_write_message, message_ws};
+use messages::{
+    handle_delete_message, handle_get_messages, handle_pin_message, handle_unpin_message,
+    handle_write_message, message_ws,
+};
+use ephemeral::handle_get_expired;
+use polls::{handle_close_poll, handle_vote};
+use scheduled::{
+    handle_cancel_scheduled, handle_edit_scheduled, handle_list_scheduled, handle_schedule_message,
+};
 
 pub fn message_routes(cfg: &mut web::ServiceConfig) {
     cfg.service(
         web::scope("/messages")
             .service(handle_write_message)
+            // before handle_get_messages, whose {group_id} would take "ws" & fail to parse it
+            .service(message_ws)
             .service(handle_get_messages)
-            .service(message_ws),
+            .service(handle_delete_message)
+            .service(handle_pin_message)
+            .service(handle_unpin_message)
+            .service(handle_vote)
+            .service(handle_close_poll)
+            .service(handle_schedule_message)
+            .service(handle_list_scheduled)
+            .service(handle_edit_scheduled)
+            .service(handle_cancel_scheduled)
+            .service(handle_get_expired),
             
     );
 }
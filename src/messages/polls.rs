@@ -0,0 +1,409 @@
+use super::content::MessageContent;
+use super::messages::write_system_message;
+use crate::extractors::extractors::User;
+use crate::groups::roles::{has_capability, Capability};
+use crate::server::app_state::AppState;
+use actix_web::{post, put, web, HttpResponse, Responder};
+use chrono::{DateTime, Utc};
+use serde::{Deserialize, Serialize};
+use sqlx::{PgConnection, Pool, Postgres};
+use std::collections::HashMap;
+
+/// Stores the voting side of a poll that was just posted as `message_id`
+pub async fn create_poll(
+    message_id: i32,
+    group_id: i32,
+    content: &MessageContent,
+    conn: &mut PgConnection,
+) -> Result<(), sqlx::Error> {
+    if let MessageContent::Poll {
+        question,
+        options,
+        multiple_choice,
+        anonymous,
+        closes_at,
+    } = content
+    {
+        sqlx::query!(
+            r#"insert into polls
+            (message_id, group_id, question, options, multiple_choice, anonymous, closes_at)
+            values ($1, $2, $3, $4, $5, $6, $7)"#,
+            message_id,
+            group_id,
+            question,
+            options,
+            multiple_choice,
+            anonymous,
+            *closes_at
+        )
+        .execute(&mut *conn)
+        .await?;
+    }
+    Ok(())
+}
+
+struct PollRow {
+    message_id: i32,
+    options: Vec<String>,
+    multiple_choice: bool,
+    anonymous: bool,
+    closes_at: Option<DateTime<Utc>>,
+    closed_at: Option<DateTime<Utc>>,
+}
+
+#[derive(Serialize, Debug, PartialEq)]
+pub struct PollOption {
+    text: String,
+    votes: i64,
+    /// who voted for it, left out of anonymous polls
+    voters: Option<Vec<i32>>,
+}
+
+/// A poll's live standing, as sent with its message
+#[derive(Serialize, Debug, PartialEq)]
+pub struct PollTally {
+    options: Vec<PollOption>,
+    multiple_choice: bool,
+    anonymous: bool,
+    closes_at: Option<DateTime<Utc>>,
+    closed_at: Option<DateTime<Utc>>,
+    /// members who voted at all
+    total_voters: i64,
+    /// the option indexes the member looking at it voted for
+    my_votes: Vec<i32>,
+}
+
+/// Counts `votes`, `(option_index, user_id)` pairs, as seen by `viewer_id`
+fn tally(poll: &PollRow, votes: &[(i32, i32)], viewer_id: i32) -> PollTally {
+    let mut options: Vec<PollOption> = poll
+        .options
+        .iter()
+        .map(|text| PollOption {
+            text: text.clone(),
+            votes: 0,
+            voters: (!poll.anonymous).then(Vec::new),
+        })
+        .collect();
+    let mut voters: Vec<i32> = Vec::new();
+    let mut my_votes = Vec::new();
+    for &(option_index, user_id) in votes {
+        let Some(option) = options.get_mut(option_index as usize) else {
+            continue;
+        };
+        option.votes += 1;
+        if let Some(option_voters) = &mut option.voters {
+            option_voters.push(user_id);
+        }
+        if !voters.contains(&user_id) {
+            voters.push(user_id);
+        }
+        if user_id == viewer_id {
+            my_votes.push(option_index);
+        }
+    }
+    my_votes.sort();
+    PollTally {
+        options,
+        multiple_choice: poll.multiple_choice,
+        anonymous: poll.anonymous,
+        closes_at: poll.closes_at,
+        closed_at: poll.closed_at,
+        total_voters: voters.len() as i64,
+        my_votes,
+    }
+}
+
+/// Tallies for the polls among `message_ids`, keyed by message id
+pub async fn poll_tallies(
+    message_ids: &[i32],
+    viewer_id: i32,
+    pool: &Pool<Postgres>,
+) -> Result<HashMap<i32, PollTally>, sqlx::Error> {
+    if message_ids.is_empty() {
+        return Ok(HashMap::new());
+    }
+    let polls = sqlx::query_as!(
+        PollRow,
+        r#"select message_id, options, multiple_choice, anonymous, closes_at, closed_at
+        from polls where message_id = any($1)"#,
+        message_ids
+    )
+    .fetch_all(pool)
+    .await?;
+    let votes = sqlx::query!(
+        r#"select message_id, option_index, user_id from poll_votes
+        where message_id = any($1) order by voted_at, user_id"#,
+        message_ids
+    )
+    .fetch_all(pool)
+    .await?;
+    let mut by_poll: HashMap<i32, Vec<(i32, i32)>> = HashMap::new();
+    for vote in votes {
+        by_poll
+            .entry(vote.message_id)
+            .or_default()
+            .push((vote.option_index, vote.user_id));
+    }
+    Ok(polls
+        .iter()
+        .map(|poll| {
+            let votes = by_poll.remove(&poll.message_id).unwrap_or_default();
+            (poll.message_id, tally(poll, &votes, viewer_id))
+        })
+        .collect())
+}
+
+/// The notice posted when a poll closes, e.g. `Poll closed: Lunch? Pizza 3, Thai 1`
+fn format_results(question: &str, options: &[String], counts: &[i64]) -> String {
+    let results: Vec<String> = options
+        .iter()
+        .zip(counts)
+        .map(|(option, count)| format!("{option} {count}"))
+        .collect();
+    format!("Poll closed: {question} {}", results.join(", "))
+}
+
+/// Closes a poll & posts its final result, `None` if it was already closed.
+/// Run in a transaction, the poll row stays locked until it commits
+async fn close_poll(message_id: i32, conn: &mut PgConnection) -> Result<Option<i32>, sqlx::Error> {
+    let poll = match sqlx::query!(
+        r#"update polls p set closed_at = now() from messages m
+        where p.message_id = $1 and m.id = p.message_id and p.closed_at is null
+        returning p.group_id, p.question, p.options, m.sender_user_id"#,
+        message_id
+    )
+    .fetch_optional(&mut *conn)
+    .await?
+    {
+        Some(poll) => poll,
+        None => return Ok(None),
+    };
+    let counts = sqlx::query!(
+        r#"select option_index, count(*) as "votes!" from poll_votes
+        where message_id = $1 group by option_index"#,
+        message_id
+    )
+    .fetch_all(&mut *conn)
+    .await?;
+    let mut totals = vec![0; poll.options.len()];
+    for count in counts {
+        if let Some(total) = totals.get_mut(count.option_index as usize) {
+            *total = count.votes;
+        }
+    }
+    let result_id = write_system_message(
+        &mut *conn,
+        poll.group_id,
+        poll.sender_user_id,
+        &format_results(&poll.question, &poll.options, &totals),
+    )
+    .await?;
+    sqlx::query!(
+        r#"update polls set result_message_id = $2 where message_id = $1"#,
+        message_id,
+        result_id
+    )
+    .execute(&mut *conn)
+    .await?;
+    Ok(Some(result_id))
+}
+
+/// Closes polls whose time is up one at a time, skipping any another server
+/// is already closing, so each result is posted once
+pub async fn close_due_polls(pool: &Pool<Postgres>) -> Result<usize, sqlx::Error> {
+    let mut closed = 0;
+    loop {
+        let mut tx = pool.begin().await?;
+        let due = sqlx::query_scalar!(
+            r#"select message_id from polls where closed_at is null and closes_at <= now()
+            order by closes_at limit 1 for update skip locked"#
+        )
+        .fetch_optional(&mut *tx)
+        .await?;
+        let Some(message_id) = due else {
+            return Ok(closed);
+        };
+        close_poll(message_id, &mut tx).await?;
+        tx.commit().await?;
+        closed += 1;
+    }
+}
+
+#[derive(Deserialize)]
+struct VoteRequest {
+    /// indexes into the poll's options, empty takes the vote back
+    options: Vec<i32>,
+}
+
+/// Casts (or replaces) the member's vote, answering with the new tally
+#[put("{group_id}/{message_id}/vote")]
+async fn handle_vote(
+    path: web::Path<(i32, i32)>,
+    vote: web::Json<VoteRequest>,
+    user: User,
+    app: web::Data<AppState>,
+) -> impl Responder {
+    let (group_id, message_id) = path.into_inner();
+    if !has_capability(user.user_id, group_id, Capability::Read, &app.pool).await {
+        return HttpResponse::Unauthorized().body(format!(
+            "User {} is not permitted to vote in group {}",
+            user.user_id, group_id
+        ));
+    }
+    let mut options = vote.into_inner().options;
+    options.sort();
+    options.dedup();
+
+    let result = async {
+        let mut tx = app.pool.begin().await?;
+        // locked so a vote can't slip in while the poll is being closed
+        let poll = sqlx::query!(
+            r#"select options, multiple_choice,
+            closed_at is not null or coalesce(closes_at <= now(), false) as "closed!"
+            from polls where message_id = $1 and group_id = $2 for update"#,
+            message_id,
+            group_id
+        )
+        .fetch_optional(&mut *tx)
+        .await?;
+        let poll = match poll {
+            Some(poll) => poll,
+            None => {
+                return Ok(Err(
+                    HttpResponse::NotFound().body(format!("poll {message_id} not found"))
+                ))
+            }
+        };
+        if poll.closed {
+            return Ok(Err(HttpResponse::Conflict().body("the poll is closed")));
+        }
+        if options
+            .iter()
+            .any(|&i| i < 0 || i as usize >= poll.options.len())
+        {
+            return Ok(Err(HttpResponse::BadRequest().body("no such option")));
+        }
+        if options.len() > 1 && !poll.multiple_choice {
+            return Ok(Err(
+                HttpResponse::BadRequest().body("the poll allows one choice")
+            ));
+        }
+        sqlx::query!(
+            r#"delete from poll_votes where message_id = $1 and user_id = $2"#,
+            message_id,
+            user.user_id
+        )
+        .execute(&mut *tx)
+        .await?;
+        sqlx::query!(
+            r#"insert into poll_votes (message_id, user_id, option_index)
+            select $1, $2, unnest($3::int[])"#,
+            message_id,
+            user.user_id,
+            &options
+        )
+        .execute(&mut *tx)
+        .await?;
+        tx.commit().await?;
+        Ok::<_, sqlx::Error>(Ok(()))
+    }
+    .await;
+    match result {
+        Ok(Ok(())) => {}
+        Ok(Err(resp)) => return resp,
+        Err(e) => return HttpResponse::InternalServerError().body(e.to_string()),
+    }
+    match poll_tallies(&[message_id], user.user_id, &app.pool).await {
+        Ok(mut tallies) => HttpResponse::Ok().json(tallies.remove(&message_id)),
+        Err(e) => HttpResponse::InternalServerError().body(e.to_string()),
+    }
+}
+
+/// Closes a poll early, for whoever posted it or a moderator
+#[post("{group_id}/{message_id}/close")]
+async fn handle_close_poll(
+    path: web::Path<(i32, i32)>,
+    user: User,
+    app: web::Data<AppState>,
+) -> impl Responder {
+    let (group_id, message_id) = path.into_inner();
+    let sender = match sqlx::query_scalar!(
+        r#"select m.sender_user_id from messages m join polls p on p.message_id = m.id
+        where m.id = $1 and m.group_id = $2"#,
+        message_id,
+        group_id
+    )
+    .fetch_optional(&app.pool)
+    .await
+    {
+        Ok(Some(sender)) => sender,
+        Ok(None) => return HttpResponse::NotFound().body(format!("poll {message_id} not found")),
+        Err(e) => return HttpResponse::InternalServerError().body(e.to_string()),
+    };
+    let needed = match sender == user.user_id {
+        true => Capability::Read,
+        false => Capability::Moderate,
+    };
+    if !has_capability(user.user_id, group_id, needed, &app.pool).await {
+        return HttpResponse::Unauthorized().body(format!(
+            "User {} is not permitted to close poll {}",
+            user.user_id, message_id
+        ));
+    }
+    let result = async {
+        let mut tx = app.pool.begin().await?;
+        let result_id = close_poll(message_id, &mut tx).await?;
+        tx.commit().await?;
+        Ok::<_, sqlx::Error>(result_id)
+    }
+    .await;
+    match result {
+        Ok(Some(result_id)) => HttpResponse::Ok().body(result_id.to_string()),
+        Ok(None) => HttpResponse::Conflict().body("the poll is already closed"),
+        Err(e) => HttpResponse::InternalServerError().body(e.to_string()),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn poll(anonymous: bool) -> PollRow {
+        PollRow {
+            message_id: 1,
+            options: vec!["go".to_owned(), "no go".to_owned()],
+            multiple_choice: true,
+            anonymous,
+            closes_at: None,
+            closed_at: None,
+        }
+    }
+
+    #[test]
+    fn tallies_count_votes_and_voters() {
+        let votes = [(0, 7), (1, 7), (0, 8), (5, 9)];
+        let tally = tally(&poll(false), &votes, 7);
+        assert_eq!(tally.options[0].votes, 2);
+        assert_eq!(tally.options[0].voters, Some(vec![7, 8]));
+        assert_eq!(tally.options[1].votes, 1);
+        // the vote for an option that doesn't exist is ignored
+        assert_eq!(tally.total_voters, 2);
+        assert_eq!(tally.my_votes, vec![0, 1]);
+    }
+
+    #[test]
+    fn anonymous_tallies_hide_voters() {
+        let tally = tally(&poll(true), &[(0, 7)], 8);
+        assert_eq!(tally.options[0].votes, 1);
+        assert_eq!(tally.options[0].voters, None);
+        assert!(tally.my_votes.is_empty());
+    }
+
+    #[test]
+    fn results_list_every_option() {
+        assert_eq!(
+            format_results("Lunch?", &["Pizza".to_owned(), "Thai".to_owned()], &[3, 0]),
+            "Poll closed: Lunch? Pizza 3, Thai 0"
+        );
+    }
+}
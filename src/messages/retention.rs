@@ -0,0 +1,230 @@
+use crate::admin::admin::require_platform_admin;
+use crate::admin::settings::{message_retention_days, parse_retention_days};
+use crate::audit::audit::{audit_event, record_audit};
+use crate::extractors::extractors::User;
+use crate::server::app_state::AppState;
+use actix_web::{delete, put, web, HttpRequest, HttpResponse, Responder};
+use chrono::{Duration, Utc};
+use serde::Deserialize;
+use serde_json::{json, Value};
+use sqlx::{Pool, Postgres};
+
+/// Messages deleted per statement, small enough that no lock is held for long
+const PURGE_BATCH: i64 = 1000;
+
+/// Deletes messages older than their group's retention, or the platform wide
+/// default, a batch at a time, along with the copies of their text in webhook
+/// deliveries & scheduled messages. Groups under legal hold are skipped, & a
+/// hold placed part way through stops the purge at the next batch. Each
+/// group's purge is written to the audit log
+pub async fn purge_expired_messages(pool: &Pool<Postgres>) -> Result<u64, sqlx::Error> {
+    let default_days = message_retention_days(pool).await?;
+    let groups = sqlx::query!(
+        r#"select id, coalesce(retention_days, $1) as "days!" from groups
+        where legal_hold_at is null and coalesce(retention_days, $1) is not null"#,
+        default_days
+    )
+    .fetch_all(pool)
+    .await?;
+
+    let mut total = 0;
+    for group in groups {
+        let cutoff = Utc::now() - Duration::days(group.days.into());
+        let mut deleted = 0;
+        loop {
+            let batch = sqlx::query!(
+                r#"delete from messages where id in (
+                    select m.id from messages m join groups g on g.id = m.group_id
+                    where m.group_id = $1 and m.sent_at < $2 and g.legal_hold_at is null
+                    limit $3 for update of m skip locked
+                )"#,
+                group.id,
+                cutoff,
+                PURGE_BATCH
+            )
+            .execute(pool)
+            .await?
+            .rows_affected();
+            deleted += batch;
+            if batch < PURGE_BATCH as u64 {
+                break;
+            }
+        }
+        // message.created events carry the text, sent or not they're as old as their message
+        let deliveries = sqlx::query!(
+            r#"delete from webhook_deliveries d using groups g
+            where g.id = $1 and g.legal_hold_at is null and d.event_type = 'message.created'
+            and (d.payload->>'group_id')::int = g.id and d.created_at < $2"#,
+            group.id,
+            cutoff
+        )
+        .execute(pool)
+        .await?
+        .rows_affected();
+        // pending ones haven't been sent yet, the rest hold the text of a message
+        // that went out, or never did, before the cutoff
+        let scheduled = sqlx::query!(
+            r#"delete from scheduled_messages s using groups g
+            where g.id = $1 and g.legal_hold_at is null and s.group_id = g.id
+            and s.status <> 'pending' and s.send_at < $2"#,
+            group.id,
+            cutoff
+        )
+        .execute(pool)
+        .await?
+        .rows_affected();
+        if deleted + deliveries + scheduled > 0 {
+            let mut conn = pool.acquire().await?;
+            record_audit(
+                &mut conn,
+                None,
+                "retention.purge",
+                Some(format!("group:{}", group.id)),
+                Some(json!({
+                    "deleted": deleted,
+                    "webhook_deliveries": deliveries,
+                    "scheduled_messages": scheduled,
+                    "retention_days": group.days,
+                    "cutoff": cutoff,
+                })),
+                None,
+            )
+            .await?;
+        }
+        total += deleted;
+    }
+    Ok(total)
+}
+
+#[derive(Deserialize)]
+struct RetentionRequest {
+    /// `null` goes back to the platform wide default
+    days: Value,
+}
+
+/// Sets how long a group's messages are kept
+#[put("/groups/{group_id}/retention")]
+async fn handle_set_retention(
+    group_id: web::Path<i32>,
+    request: web::Json<RetentionRequest>,
+    user: User,
+    app: web::Data<AppState>,
+    req: HttpRequest,
+) -> impl Responder {
+    if let Err(resp) = require_platform_admin(user.user_id, &app.pool).await {
+        return resp;
+    }
+    let group_id = group_id.into_inner();
+    let days = match parse_retention_days(&request.days) {
+        Ok(days) => days,
+        Err(reason) => return HttpResponse::BadRequest().body(reason),
+    };
+    match sqlx::query!(
+        r#"update groups set retention_days = $2 where id = $1"#,
+        group_id,
+        days
+    )
+    .execute(&app.pool)
+    .await
+    {
+        Ok(res) if res.rows_affected() > 0 => {
+            audit_event(
+                &app.pool,
+                &req,
+                Some(user.user_id),
+                "admin.groups.retention",
+                Some(format!("group:{group_id}")),
+                Some(json!({ "retention_days": days })),
+            )
+            .await;
+            HttpResponse::Ok().body("")
+        }
+        Ok(_) => HttpResponse::NotFound().body(format!("group {} not found", group_id)),
+        Err(e) => HttpResponse::InternalServerError().body(e.to_string()),
+    }
+}
+
+#[derive(Deserialize)]
+struct LegalHoldRequest {
+    reason: String,
+}
+
+/// Suspends every deletion in the group until the hold is lifted
+#[put("/groups/{group_id}/legal-hold")]
+async fn handle_place_legal_hold(
+    group_id: web::Path<i32>,
+    request: web::Json<LegalHoldRequest>,
+    user: User,
+    app: web::Data<AppState>,
+    req: HttpRequest,
+) -> impl Responder {
+    if let Err(resp) = require_platform_admin(user.user_id, &app.pool).await {
+        return resp;
+    }
+    let group_id = group_id.into_inner();
+    let reason = request.into_inner().reason;
+    if reason.trim().is_empty() {
+        return HttpResponse::BadRequest().body("a legal hold needs a reason");
+    }
+    match sqlx::query!(
+        r#"update groups set legal_hold_at = coalesce(legal_hold_at, now()),
+        legal_hold_by = $2, legal_hold_reason = $3 where id = $1"#,
+        group_id,
+        user.user_id,
+        reason
+    )
+    .execute(&app.pool)
+    .await
+    {
+        Ok(res) if res.rows_affected() > 0 => {
+            audit_event(
+                &app.pool,
+                &req,
+                Some(user.user_id),
+                "admin.groups.legal_hold.place",
+                Some(format!("group:{group_id}")),
+                Some(json!({ "reason": reason })),
+            )
+            .await;
+            HttpResponse::Ok().body("")
+        }
+        Ok(_) => HttpResponse::NotFound().body(format!("group {} not found", group_id)),
+        Err(e) => HttpResponse::InternalServerError().body(e.to_string()),
+    }
+}
+
+#[delete("/groups/{group_id}/legal-hold")]
+async fn handle_lift_legal_hold(
+    group_id: web::Path<i32>,
+    user: User,
+    app: web::Data<AppState>,
+    req: HttpRequest,
+) -> impl Responder {
+    if let Err(resp) = require_platform_admin(user.user_id, &app.pool).await {
+        return resp;
+    }
+    let group_id = group_id.into_inner();
+    match sqlx::query!(
+        r#"update groups set legal_hold_at = null, legal_hold_by = null, legal_hold_reason = null
+        where id = $1 and legal_hold_at is not null"#,
+        group_id
+    )
+    .execute(&app.pool)
+    .await
+    {
+        Ok(res) if res.rows_affected() > 0 => {
+            audit_event(
+                &app.pool,
+                &req,
+                Some(user.user_id),
+                "admin.groups.legal_hold.lift",
+                Some(format!("group:{group_id}")),
+                None,
+            )
+            .await;
+            HttpResponse::Ok().body("")
+        }
+        Ok(_) => HttpResponse::NotFound().body(format!("group {} isn't on hold", group_id)),
+        Err(e) => HttpResponse::InternalServerError().body(e.to_string()),
+    }
+}
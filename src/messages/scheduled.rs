@@ -0,0 +1,291 @@
+use super::content::MessageContent;
+use super::ephemeral::Disappearing;
+use super::messages::write_message_in;
+use crate::commands::commands::{literal_text, parse_command};
+use crate::custom_types::ScheduledMessageStatus;
+use crate::extractors::extractors::User;
+use crate::groups::roles::{has_capability, Capability};
+use crate::server::app_state::AppState;
+use actix_web::body::MessageBody;
+use actix_web::{delete, get, patch, post, web, HttpResponse, Responder};
+use chrono::{DateTime, Utc};
+use serde::{Deserialize, Serialize};
+use sqlx::{Pool, Postgres};
+
+#[derive(Serialize)]
+struct ScheduledMessage {
+    id: i32,
+    group_id: i32,
+    content: String,
+    send_at: DateTime<Utc>,
+    status: ScheduledMessageStatus,
+    created_at: DateTime<Utc>,
+    updated_at: DateTime<Utc>,
+    error: Option<String>,
+}
+
+/// Turns the text of a scheduled message into what will be posted, commands
+/// run when they're sent so they can't be put off
+fn scheduled_content(text: String) -> Result<MessageContent, HttpResponse> {
+    if parse_command(&text).is_some() {
+        return Err(HttpResponse::BadRequest()
+            .body("commands can't be scheduled, start the message with // to post it as text"));
+    }
+    let content = MessageContent::Text {
+        content: literal_text(text),
+    };
+    content
+        .validate()
+        .map_err(|reason| HttpResponse::BadRequest().body(reason))?;
+    Ok(content)
+}
+
+fn check_send_at(send_at: DateTime<Utc>) -> Result<(), HttpResponse> {
+    match send_at > Utc::now() {
+        true => Ok(()),
+        false => Err(HttpResponse::BadRequest().body("send_at must be in the future")),
+    }
+}
+
+#[derive(Deserialize)]
+struct ScheduleRequest {
+    content: String,
+    send_at: DateTime<Utc>,
+}
+
+/// Queues a message to be posted into the group at `send_at`
+#[post("{group_id}/scheduled")]
+async fn handle_schedule_message(
+    group_id: web::Path<i32>,
+    request: web::Json<ScheduleRequest>,
+    user: User,
+    app: web::Data<AppState>,
+) -> impl Responder {
+    let group_id = group_id.into_inner();
+    let ScheduleRequest { content, send_at } = request.into_inner();
+    if let Err(resp) = check_send_at(send_at) {
+        return resp;
+    }
+    let content = match scheduled_content(content) {
+        Ok(content) => content.text(),
+        Err(resp) => return resp,
+    };
+    // checked again when it's sent, this is so mistakes show up straight away
+    if !has_capability(user.user_id, group_id, Capability::Write, &app.pool).await {
+        return HttpResponse::Unauthorized().body(format!(
+            "User {} does not have write permissions for group {}",
+            user.user_id, group_id
+        ));
+    }
+    match sqlx::query_as!(
+        ScheduledMessage,
+        r#"insert into scheduled_messages (group_id, user_id, content, send_at)
+        values ($1, $2, $3, $4)
+        returning id, group_id, content, send_at, status as "status: ScheduledMessageStatus",
+        created_at, updated_at, error"#,
+        group_id,
+        user.user_id,
+        content,
+        send_at
+    )
+    .fetch_one(&app.pool)
+    .await
+    {
+        Ok(scheduled) => HttpResponse::Created().json(scheduled),
+        Err(e) => HttpResponse::InternalServerError().body(e.to_string()),
+    }
+}
+
+/// The user's own messages still waiting to go out into the group, along with
+/// any that couldn't be sent
+#[get("{group_id}/scheduled")]
+async fn handle_list_scheduled(
+    group_id: web::Path<i32>,
+    user: User,
+    app: web::Data<AppState>,
+) -> impl Responder {
+    match sqlx::query_as!(
+        ScheduledMessage,
+        r#"select id, group_id, content, send_at, status as "status: ScheduledMessageStatus",
+        created_at, updated_at, error
+        from scheduled_messages
+        where group_id = $1 and user_id = $2 and status in ('pending', 'failed')
+        order by send_at"#,
+        group_id.into_inner(),
+        user.user_id
+    )
+    .fetch_all(&app.pool)
+    .await
+    {
+        Ok(scheduled) => HttpResponse::Ok().json(scheduled),
+        Err(e) => HttpResponse::InternalServerError().body(e.to_string()),
+    }
+}
+
+#[derive(Deserialize)]
+struct EditScheduledRequest {
+    content: Option<String>,
+    send_at: Option<DateTime<Utc>>,
+}
+
+/// Changes the text or time of a message that hasn't been sent yet
+#[patch("{group_id}/scheduled/{scheduled_id}")]
+async fn handle_edit_scheduled(
+    path: web::Path<(i32, i32)>,
+    request: web::Json<EditScheduledRequest>,
+    user: User,
+    app: web::Data<AppState>,
+) -> impl Responder {
+    let (group_id, scheduled_id) = path.into_inner();
+    let EditScheduledRequest { content, send_at } = request.into_inner();
+    if let Some(Err(resp)) = send_at.map(check_send_at) {
+        return resp;
+    }
+    let content = match content.map(scheduled_content).transpose() {
+        Ok(content) => content.map(|content| content.text()),
+        Err(resp) => return resp,
+    };
+    // waits out a send in progress, after which it's no longer pending
+    match sqlx::query_as!(
+        ScheduledMessage,
+        r#"update scheduled_messages set
+            content = coalesce($4, content),
+            send_at = coalesce($5, send_at),
+            updated_at = now()
+        where id = $1 and group_id = $2 and user_id = $3 and status = 'pending'
+        returning id, group_id, content, send_at, status as "status: ScheduledMessageStatus",
+        created_at, updated_at, error"#,
+        scheduled_id,
+        group_id,
+        user.user_id,
+        content,
+        send_at
+    )
+    .fetch_optional(&app.pool)
+    .await
+    {
+        Ok(Some(scheduled)) => HttpResponse::Ok().json(scheduled),
+        Ok(None) => {
+            HttpResponse::NotFound().body(format!("no pending scheduled message {scheduled_id}"))
+        }
+        Err(e) => HttpResponse::InternalServerError().body(e.to_string()),
+    }
+}
+
+#[delete("{group_id}/scheduled/{scheduled_id}")]
+async fn handle_cancel_scheduled(
+    path: web::Path<(i32, i32)>,
+    user: User,
+    app: web::Data<AppState>,
+) -> impl Responder {
+    let (group_id, scheduled_id) = path.into_inner();
+    match sqlx::query!(
+        r#"update scheduled_messages set status = 'cancelled', updated_at = now()
+        where id = $1 and group_id = $2 and user_id = $3 and status = 'pending'"#,
+        scheduled_id,
+        group_id,
+        user.user_id
+    )
+    .execute(&app.pool)
+    .await
+    {
+        Ok(done) if done.rows_affected() == 1 => HttpResponse::Ok().body(""),
+        Ok(_) => {
+            HttpResponse::NotFound().body(format!("no pending scheduled message {scheduled_id}"))
+        }
+        Err(e) => HttpResponse::InternalServerError().body(e.to_string()),
+    }
+}
+
+/// Sends scheduled messages whose time has come one at a time. Each is claimed
+/// with `skip locked` & marked sent in the same transaction that posts it, so
+/// with several servers running each one still goes out exactly once
+pub async fn send_due_messages(pool: &Pool<Postgres>) -> Result<usize, sqlx::Error> {
+    let mut sent = 0;
+    loop {
+        let mut tx = pool.begin().await?;
+        let due = sqlx::query!(
+            r#"select id, group_id, user_id, content from scheduled_messages
+            where status = 'pending' and send_at <= now()
+            order by send_at limit 1 for update skip locked"#
+        )
+        .fetch_optional(&mut *tx)
+        .await?;
+        let Some(due) = due else {
+            return Ok(sent);
+        };
+        let content = MessageContent::Text {
+            content: due.content,
+        };
+        // the response isn't Send, so only its status & text are kept
+        let posted = write_message_in(
+            &mut tx,
+            due.group_id,
+            due.user_id,
+            content,
+            Disappearing::default(),
+            pool,
+        )
+        .await
+        .map_err(|resp| {
+            let status = resp.status();
+            let reason = resp
+                .into_body()
+                .try_into_bytes()
+                .map(|body| String::from_utf8_lossy(&body).into_owned())
+                .unwrap_or_default();
+            (status, reason)
+        });
+        match posted {
+            Ok(message_id) => {
+                sqlx::query!(
+                    r#"update scheduled_messages set status = 'sent', message_id = $2,
+                    updated_at = now() where id = $1"#,
+                    due.id,
+                    message_id
+                )
+                .execute(&mut *tx)
+                .await?;
+                sent += 1;
+            }
+            // the sender can't post there any more, it's kept to show them why
+            Err((status, reason)) if status.is_client_error() => {
+                log::info!("scheduled message {} couldn't be sent: {reason}", due.id);
+                sqlx::query!(
+                    r#"update scheduled_messages set status = 'failed', error = $2,
+                    updated_at = now() where id = $1"#,
+                    due.id,
+                    reason
+                )
+                .execute(&mut *tx)
+                .await?;
+            }
+            // rolled back & tried again next time round
+            Err((status, reason)) => {
+                log::error!(
+                    "failed to send scheduled message {}: {status} {reason}",
+                    due.id
+                );
+                return Ok(sent);
+            }
+        }
+        tx.commit().await?;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn commands_cant_be_scheduled() {
+        assert!(scheduled_content("/topic later".to_owned()).is_err());
+        assert!(scheduled_content(String::new()).is_err());
+        assert_eq!(
+            scheduled_content("//topic as text".to_owned())
+                .unwrap()
+                .text(),
+            "/topic as text"
+        );
+    }
+}
@@ -0,0 +1,11 @@
+pub mod presence;
+use actix_web::web;
+use presence::{handle_heartbeat, handle_set_presence};
+
+pub fn presence_routes(cfg: &mut web::ServiceConfig) {
+    cfg.service(
+        web::scope("/presence")
+            .service(handle_set_presence)
+            .service(handle_heartbeat),
+    );
+}
@@ -0,0 +1,397 @@
+use crate::custom_types::PresenceState;
+use crate::extractors::extractors::User;
+use crate::groups::roles::{has_capability, Capability};
+use crate::server::app_state::AppState;
+use crate::server::config::Config;
+use actix::{Message, Recipient};
+use actix_web::{get, post, put, web, HttpResponse, Responder};
+use chrono::{DateTime, Utc};
+use serde::{Deserialize, Serialize};
+use sqlx::{Pool, Postgres};
+use std::collections::{HashMap, HashSet};
+use std::sync::Mutex;
+
+/// A user's presence changed, sent to the sessions subscribed to them
+#[derive(Message, Serialize, Clone, Debug)]
+#[rtype(result = "()")]
+pub struct PresenceChange {
+    pub user_id: i32,
+    pub state: PresenceState,
+}
+
+struct Subscriber {
+    recipient: Recipient<PresenceChange>,
+    user_ids: HashSet<i32>,
+}
+
+/// This instance's websocket sessions & whose presence each is following.
+/// Changes reach it through [`poll_changes`], whichever instance made them
+#[derive(Default)]
+pub struct PresenceHub {
+    sessions: Mutex<HashMap<String, Subscriber>>,
+}
+
+impl PresenceHub {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn register(&self, session_id: &str, recipient: Recipient<PresenceChange>) {
+        self.sessions.lock().unwrap().insert(
+            session_id.to_owned(),
+            Subscriber {
+                recipient,
+                user_ids: HashSet::new(),
+            },
+        );
+    }
+
+    pub fn remove(&self, session_id: &str) {
+        self.sessions.lock().unwrap().remove(session_id);
+    }
+
+    pub fn subscribe(&self, session_id: &str, user_ids: &[i32]) {
+        if let Some(subscriber) = self.sessions.lock().unwrap().get_mut(session_id) {
+            subscriber.user_ids.extend(user_ids);
+        }
+    }
+
+    pub fn unsubscribe(&self, session_id: &str, user_ids: &[i32]) {
+        if let Some(subscriber) = self.sessions.lock().unwrap().get_mut(session_id) {
+            for user_id in user_ids {
+                subscriber.user_ids.remove(user_id);
+            }
+        }
+    }
+
+    /// Hands the change to every session following the user, returns how many
+    pub fn publish(&self, change: &PresenceChange) -> usize {
+        let sessions = self.sessions.lock().unwrap();
+        sessions
+            .values()
+            .filter(|subscriber| subscriber.user_ids.contains(&change.user_id))
+            .map(|subscriber| subscriber.recipient.do_send(change.clone()))
+            .count()
+    }
+}
+
+/// Records that a connection is still there, & that its user did something if `active`
+pub async fn touch_connection(
+    connection_id: &str,
+    user_id: i32,
+    active: bool,
+    pool: &Pool<Postgres>,
+) -> Result<(), sqlx::Error> {
+    sqlx::query!(
+        r#"insert into presence_connections (id, user_id) values ($1, $2)
+        on conflict (id) do update set last_seen_at = now(),
+        last_active_at = case when $3 then now() else presence_connections.last_active_at end"#,
+        connection_id,
+        user_id,
+        active
+    )
+    .execute(pool)
+    .await?;
+    if active {
+        sqlx::query!(
+            r#"insert into user_presence (user_id, last_active_at) values ($1, now())
+            on conflict (user_id) do update set last_active_at = now()"#,
+            user_id
+        )
+        .execute(pool)
+        .await?;
+    }
+    Ok(())
+}
+
+pub async fn drop_connection(
+    connection_id: &str,
+    pool: &Pool<Postgres>,
+) -> Result<(), sqlx::Error> {
+    sqlx::query!(
+        r#"delete from presence_connections where id = $1"#,
+        connection_id
+    )
+    .execute(pool)
+    .await?;
+    Ok(())
+}
+
+/// Works out the users' presence from their live connections & what they
+/// picked, storing it where it changed. Offline users stay offline whatever
+/// they picked, & an offline pick hides them even when they're connected
+pub async fn refresh_presence(
+    user_ids: &[i32],
+    config: &Config,
+    pool: &Pool<Postgres>,
+) -> Result<Vec<PresenceChange>, sqlx::Error> {
+    sqlx::query!(
+        r#"insert into user_presence (user_id) select unnest($1::int[]) on conflict do nothing"#,
+        user_ids
+    )
+    .execute(pool)
+    .await?;
+    let now = Utc::now();
+    sqlx::query_as!(
+        PresenceChange,
+        r#"update user_presence p set state = s.state, changed_at = now(),
+            changed_xid = pg_current_xact_id()
+        from (
+            select p.user_id, case
+                when p.manual = 'offline' or c.last_seen_at is null then 'offline'::presence_state
+                when p.manual is not null then p.manual
+                when c.last_active_at > $3 then 'online'::presence_state
+                else 'away'::presence_state end as state
+            from user_presence p left join lateral (
+                select max(last_seen_at) as last_seen_at, max(last_active_at) as last_active_at
+                from presence_connections pc where pc.user_id = p.user_id and pc.last_seen_at > $2
+            ) c on true
+            where p.user_id = any($1)
+        ) s
+        where p.user_id = s.user_id and p.state <> s.state
+        returning p.user_id, p.state as "state: PresenceState""#,
+        user_ids,
+        now - config.presence_timeout,
+        now - config.presence_away_after
+    )
+    .fetch_all(pool)
+    .await
+}
+
+/// Forgets connections that stopped sending heartbeats, e.g. from an instance
+/// that went away, & refreshes everyone who isn't offline so they can go away
+/// or offline. Returns how many users changed
+pub async fn sweep_presence(config: &Config, pool: &Pool<Postgres>) -> Result<usize, sqlx::Error> {
+    sqlx::query!(
+        r#"delete from presence_connections where last_seen_at <= $1"#,
+        Utc::now() - config.presence_timeout
+    )
+    .execute(pool)
+    .await?;
+    let user_ids = sqlx::query_scalar!(
+        r#"select user_id as "user_id!" from user_presence where state <> 'offline'
+        union select user_id from presence_connections"#
+    )
+    .fetch_all(pool)
+    .await?;
+    Ok(refresh_presence(&user_ids, config, pool).await?.len())
+}
+
+/// Where this instance starts passing changes on from: the oldest transaction
+/// still running, everything before it has finished
+pub async fn current_version(pool: &Pool<Postgres>) -> Result<i64, sqlx::Error> {
+    sqlx::query_scalar!(
+        r#"select pg_snapshot_xmin(pg_current_snapshot())::text::bigint as "version!""#
+    )
+    .fetch_one(pool)
+    .await
+}
+
+/// Changes made on any instance since `since`, & the version to ask from next
+/// time. Changes from transactions that are still running are left for later,
+/// so one committing after a newer one was read isn't missed
+pub async fn poll_changes(
+    since: i64,
+    pool: &Pool<Postgres>,
+) -> Result<(Vec<PresenceChange>, i64), sqlx::Error> {
+    let next = current_version(pool).await?;
+    let changes = sqlx::query_as!(
+        PresenceChange,
+        r#"select user_id, state as "state: PresenceState" from user_presence
+        where changed_xid >= $1::bigint::text::xid8 and changed_xid < $2::bigint::text::xid8
+        order by changed_xid"#,
+        since,
+        next
+    )
+    .fetch_all(pool)
+    .await?;
+    Ok((changes, next))
+}
+
+/// Which of `user_ids` the viewer may follow: themselves & members of groups
+/// they can read, like [`handle_group_presence`] shows
+pub async fn visible_presence(
+    viewer_id: i32,
+    user_ids: &[i32],
+    pool: &Pool<Postgres>,
+) -> Result<Vec<i32>, sqlx::Error> {
+    sqlx::query_scalar!(
+        r#"select u.id as "id!" from unnest($2::int[]) u(id) where u.id = $1 or exists(
+            select 1 from group_permissions m
+            where m.user_id = u.id and has_capability($1, m.group_id, $3)
+        )"#,
+        viewer_id,
+        user_ids,
+        Capability::Read.as_str()
+    )
+    .fetch_all(pool)
+    .await
+}
+
+pub async fn presence_of(
+    user_ids: &[i32],
+    pool: &Pool<Postgres>,
+) -> Result<Vec<PresenceChange>, sqlx::Error> {
+    sqlx::query_as!(
+        PresenceChange,
+        r#"select u.id as "user_id!", coalesce(p.state, 'offline') as "state!: PresenceState"
+        from unnest($1::int[]) u(id) left join user_presence p on p.user_id = u.id"#,
+        user_ids
+    )
+    .fetch_all(pool)
+    .await
+}
+
+#[derive(Deserialize)]
+struct SetPresenceRequest {
+    /// `null` goes back to presence worked out from activity
+    state: Option<PresenceState>,
+}
+
+#[derive(Serialize)]
+struct PresenceResponse {
+    state: PresenceState,
+    manual: Option<PresenceState>,
+}
+
+/// Picks a presence to show while connected, e.g. DND
+#[put("")]
+async fn handle_set_presence(
+    request: web::Json<SetPresenceRequest>,
+    user: User,
+    app: web::Data<AppState>,
+) -> impl Responder {
+    let result = async {
+        sqlx::query!(
+            r#"insert into user_presence (user_id, manual) values ($1, $2)
+            on conflict (user_id) do update set manual = $2"#,
+            user.user_id,
+            request.state as Option<PresenceState>
+        )
+        .execute(&app.pool)
+        .await?;
+        refresh_presence(&[user.user_id], &app.config, &app.pool).await?;
+        sqlx::query_as!(
+            PresenceResponse,
+            r#"select state as "state: PresenceState", manual as "manual: PresenceState"
+            from user_presence where user_id = $1"#,
+            user.user_id
+        )
+        .fetch_one(&app.pool)
+        .await
+    }
+    .await;
+    match result {
+        Ok(presence) => HttpResponse::Ok().json(presence),
+        Err(e) => HttpResponse::InternalServerError().body(e.to_string()),
+    }
+}
+
+#[derive(Deserialize)]
+struct HeartbeatRequest {
+    #[serde(default = "default_active")]
+    active: bool,
+}
+
+fn default_active() -> bool {
+    true
+}
+
+/// Keeps a client without a websocket connected, it should be sent more often
+/// than PRESENCE_TIMEOUT_SECS. `active: false` keeps the user connected but
+/// lets them go away
+#[post("heartbeat")]
+async fn handle_heartbeat(
+    request: web::Json<HeartbeatRequest>,
+    user: User,
+    app: web::Data<AppState>,
+) -> impl Responder {
+    let connection_id = format!("http:{}", user.user_id);
+    let result = async {
+        touch_connection(&connection_id, user.user_id, request.active, &app.pool).await?;
+        refresh_presence(&[user.user_id], &app.config, &app.pool).await?;
+        presence_of(&[user.user_id], &app.pool).await
+    }
+    .await;
+    match result {
+        Ok(mut presence) => HttpResponse::Ok().json(presence.pop()),
+        Err(e) => HttpResponse::InternalServerError().body(e.to_string()),
+    }
+}
+
+#[derive(Serialize)]
+struct MemberPresence {
+    user_id: i32,
+    state: PresenceState,
+    last_active_at: Option<DateTime<Utc>>,
+}
+
+/// Every member's presence at once. Members who picked offline don't show
+/// when they were last active either
+#[get("{group_id}/presence")]
+async fn handle_group_presence(
+    group_id: web::Path<i32>,
+    user: User,
+    app: web::Data<AppState>,
+) -> impl Responder {
+    let group_id = group_id.into_inner();
+    if !has_capability(user.user_id, group_id, Capability::Read, &app.pool).await {
+        return HttpResponse::Unauthorized().body(format!(
+            "User {} is not permitted to read group {}",
+            user.user_id, group_id
+        ));
+    }
+    match sqlx::query_as!(
+        MemberPresence,
+        r#"select m.user_id, coalesce(p.state, 'offline') as "state!: PresenceState",
+        case when p.manual = 'offline' then null else p.last_active_at end as last_active_at
+        from group_permissions m left join user_presence p on p.user_id = m.user_id
+        where m.group_id = $1 order by m.user_id"#,
+        group_id
+    )
+    .fetch_all(&app.pool)
+    .await
+    {
+        Ok(members) => HttpResponse::Ok().json(members),
+        Err(e) => HttpResponse::InternalServerError().body(e.to_string()),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use actix::{Actor, Context, Handler};
+    use std::sync::Arc;
+
+    struct Collector(Arc<Mutex<Vec<i32>>>);
+
+    impl Actor for Collector {
+        type Context = Context<Self>;
+    }
+
+    impl Handler<PresenceChange> for Collector {
+        type Result = ();
+        fn handle(&mut self, change: PresenceChange, _: &mut Context<Self>) {
+            self.0.lock().unwrap().push(change.user_id);
+        }
+    }
+
+    #[actix::test]
+    async fn changes_only_reach_subscribers() {
+        let seen = Arc::new(Mutex::new(Vec::new()));
+        let hub = PresenceHub::new();
+        hub.register("a", Collector(Arc::clone(&seen)).start().recipient());
+        hub.subscribe("a", &[1, 2]);
+        hub.unsubscribe("a", &[2]);
+        let change = |user_id| PresenceChange {
+            user_id,
+            state: PresenceState::AWAY,
+        };
+        assert_eq!(hub.publish(&change(1)), 1);
+        assert_eq!(hub.publish(&change(2)), 0);
+        hub.remove("a");
+        assert_eq!(hub.publish(&change(1)), 0);
+        tokio::task::yield_now().await;
+        assert_eq!(*seen.lock().unwrap(), vec![1]);
+    }
+}
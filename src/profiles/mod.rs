@@ -0,0 +1,20 @@
+pub mod profiles;
+use actix_web::web;
+use profiles::{
+    handle_clear_status, handle_get_my_profile, handle_get_profile, handle_set_status,
+    handle_update_profile,
+};
+
+pub fn profile_routes(cfg: &mut web::ServiceConfig) {
+    cfg.service(
+        web::scope("/account/profile")
+            .service(handle_get_my_profile)
+            .service(handle_update_profile),
+    )
+    .service(
+        web::scope("/account/status")
+            .service(handle_set_status)
+            .service(handle_clear_status),
+    )
+    .service(web::scope("/users").service(handle_get_profile));
+}
@@ -0,0 +1,339 @@
+use crate::audit::audit::audit_event;
+use crate::extractors::extractors::User;
+use crate::server::app_state::AppState;
+use actix_web::{delete, get, patch, put, web, HttpRequest, HttpResponse, Responder};
+use chrono::{DateTime, Utc};
+use serde::{Deserialize, Serialize};
+use serde_json::json;
+use sqlx::{Pool, Postgres};
+
+const MAX_DISPLAY_NAME: usize = 64;
+const MAX_BIO: usize = 500;
+const MAX_PRONOUNS: usize = 40;
+const MAX_URL: usize = 2048;
+const MAX_STATUS_TEXT: usize = 100;
+const MAX_STATUS_EMOJI: usize = 32;
+
+#[derive(Serialize, Debug, PartialEq)]
+pub struct Status {
+    pub text: Option<String>,
+    pub emoji: Option<String>,
+    pub expires_at: Option<DateTime<Utc>>,
+}
+
+/// What anyone signed in can see about a user
+#[derive(Serialize)]
+pub struct PublicProfile {
+    id: i32,
+    username: String,
+    display_name: String,
+    bio: Option<String>,
+    time_zone: Option<String>,
+    pronouns: Option<String>,
+    avatar_url: Option<String>,
+    is_bot: bool,
+    /// `None` when there isn't one or it has expired
+    status: Option<Status>,
+}
+
+/// Fields left out aren't changed, an empty string clears all but the display name
+#[derive(Deserialize, Debug, Default)]
+pub struct ProfileUpdate {
+    pub display_name: Option<String>,
+    pub bio: Option<String>,
+    pub time_zone: Option<String>,
+    pub pronouns: Option<String>,
+    pub avatar_url: Option<String>,
+}
+
+#[derive(Deserialize, Debug)]
+pub struct StatusUpdate {
+    pub text: Option<String>,
+    pub emoji: Option<String>,
+    /// cleared once this passes, kept until it's changed otherwise
+    pub expires_at: Option<DateTime<Utc>>,
+}
+
+/// An IANA zone name like `Europe/Berlin` or `America/Argentina/Buenos_Aires`, or `UTC`.
+/// Only the shape is checked, there's no zone database to check it against
+fn valid_time_zone(zone: &str) -> bool {
+    let part = |part: &str| {
+        !part.is_empty()
+            && part.starts_with(|c: char| c.is_ascii_alphabetic())
+            && part
+                .chars()
+                .all(|c| c.is_ascii_alphanumeric() || matches!(c, '_' | '-' | '+'))
+    };
+    zone == "UTC" || (zone.len() <= 64 && zone.contains('/') && zone.split('/').all(part))
+}
+
+fn check_length(field: &str, value: &str, max: usize) -> Result<(), String> {
+    match value.chars().count() > max {
+        true => Err(format!("{field} can be at most {max} characters")),
+        false => Ok(()),
+    }
+}
+
+impl ProfileUpdate {
+    pub fn validate(&self) -> Result<(), String> {
+        if let Some(name) = &self.display_name {
+            if name.trim().is_empty() || name.chars().any(char::is_control) {
+                return Err("display_name can't be empty or contain control characters".to_owned());
+            }
+            check_length("display_name", name, MAX_DISPLAY_NAME)?;
+        }
+        if let Some(bio) = &self.bio {
+            check_length("bio", bio, MAX_BIO)?;
+        }
+        if let Some(pronouns) = &self.pronouns {
+            check_length("pronouns", pronouns, MAX_PRONOUNS)?;
+        }
+        match &self.time_zone {
+            Some(zone) if !zone.is_empty() && !valid_time_zone(zone) => {
+                return Err(format!("{zone} isn't a time zone name like Europe/Berlin"))
+            }
+            _ => {}
+        }
+        if let Some(url) = self.avatar_url.as_deref().filter(|url| !url.is_empty()) {
+            if !url.starts_with("https://") && !url.starts_with("http://") {
+                return Err("avatar_url must be an http(s) url".to_owned());
+            }
+            check_length("avatar_url", url, MAX_URL)?;
+        }
+        Ok(())
+    }
+}
+
+impl StatusUpdate {
+    pub fn validate(&self, now: DateTime<Utc>) -> Result<(), String> {
+        let text = self.text.as_deref().unwrap_or_default();
+        let emoji = self.emoji.as_deref().unwrap_or_default();
+        if text.trim().is_empty() && emoji.trim().is_empty() {
+            return Err("a status needs text or an emoji, DELETE clears it".to_owned());
+        }
+        check_length("text", text, MAX_STATUS_TEXT)?;
+        check_length("emoji", emoji, MAX_STATUS_EMOJI)?;
+        match self.expires_at {
+            Some(expires_at) if expires_at <= now => Err("expires_at is in the past".to_owned()),
+            _ => Ok(()),
+        }
+    }
+}
+
+/// Empty strings clear a field
+fn blank_to_none(value: Option<String>) -> Option<Option<String>> {
+    value.map(|value| Some(value.trim().to_owned()).filter(|value| !value.is_empty()))
+}
+
+pub async fn get_profile(
+    user_id: i32,
+    pool: &Pool<Postgres>,
+) -> Result<Option<PublicProfile>, sqlx::Error> {
+    let row = sqlx::query!(
+        r#"select id, username, display_name, bio, time_zone, pronouns, avatar_url, is_bot,
+        status_text, status_emoji, status_expires_at,
+        coalesce(status_expires_at <= now(), false) as "status_expired!"
+        from users where id = $1"#,
+        user_id
+    )
+    .fetch_optional(pool)
+    .await?;
+    Ok(row.map(|row| {
+        let has_status = row.status_text.is_some() || row.status_emoji.is_some();
+        PublicProfile {
+            id: row.id,
+            username: row.username,
+            display_name: row.display_name,
+            bio: row.bio,
+            time_zone: row.time_zone,
+            pronouns: row.pronouns,
+            avatar_url: row.avatar_url,
+            is_bot: row.is_bot,
+            status: (has_status && !row.status_expired).then_some(Status {
+                text: row.status_text,
+                emoji: row.status_emoji,
+                expires_at: row.status_expires_at,
+            }),
+        }
+    }))
+}
+
+#[get("{user_id}")]
+async fn handle_get_profile(
+    user_id: web::Path<i32>,
+    _user: User,
+    app: web::Data<AppState>,
+) -> impl Responder {
+    let user_id = user_id.into_inner();
+    match get_profile(user_id, &app.pool).await {
+        Ok(Some(profile)) => HttpResponse::Ok().json(profile),
+        Ok(None) => HttpResponse::NotFound().body(format!("user {} not found", user_id)),
+        Err(e) => HttpResponse::InternalServerError().body(e.to_string()),
+    }
+}
+
+#[get("")]
+async fn handle_get_my_profile(user: User, app: web::Data<AppState>) -> impl Responder {
+    match get_profile(user.user_id, &app.pool).await {
+        Ok(Some(profile)) => HttpResponse::Ok().json(profile),
+        Ok(None) => HttpResponse::NotFound().body(format!("user {} not found", user.user_id)),
+        Err(e) => HttpResponse::InternalServerError().body(e.to_string()),
+    }
+}
+
+#[patch("")]
+async fn handle_update_profile(
+    update: web::Json<ProfileUpdate>,
+    user: User,
+    app: web::Data<AppState>,
+    req: HttpRequest,
+) -> impl Responder {
+    let update = update.into_inner();
+    if let Err(reason) = update.validate() {
+        return HttpResponse::BadRequest().body(reason);
+    }
+    let changed: Vec<&str> = [
+        ("display_name", update.display_name.is_some()),
+        ("bio", update.bio.is_some()),
+        ("time_zone", update.time_zone.is_some()),
+        ("pronouns", update.pronouns.is_some()),
+        ("avatar_url", update.avatar_url.is_some()),
+    ]
+    .into_iter()
+    .filter_map(|(field, given)| given.then_some(field))
+    .collect();
+    let bio = blank_to_none(update.bio);
+    let time_zone = blank_to_none(update.time_zone);
+    let pronouns = blank_to_none(update.pronouns);
+    let avatar_url = blank_to_none(update.avatar_url);
+    // each optional field is only set when it was given, `$n` says whether it was
+    let result = sqlx::query!(
+        r#"update users set display_name = coalesce($2, display_name),
+        bio = case when $3 then $4 else bio end,
+        time_zone = case when $5 then $6 else time_zone end,
+        pronouns = case when $7 then $8 else pronouns end,
+        avatar_url = case when $9 then $10 else avatar_url end
+        where id = $1"#,
+        user.user_id,
+        update.display_name.map(|name| name.trim().to_owned()),
+        bio.is_some(),
+        bio.flatten(),
+        time_zone.is_some(),
+        time_zone.flatten(),
+        pronouns.is_some(),
+        pronouns.flatten(),
+        avatar_url.is_some(),
+        avatar_url.flatten()
+    )
+    .execute(&app.pool)
+    .await;
+    if let Err(e) = result {
+        return HttpResponse::InternalServerError().body(e.to_string());
+    }
+    audit_event(
+        &app.pool,
+        &req,
+        Some(user.user_id),
+        "account.profile.update",
+        Some(format!("user:{}", user.user_id)),
+        Some(json!({ "fields": changed })),
+    )
+    .await;
+    match get_profile(user.user_id, &app.pool).await {
+        Ok(profile) => HttpResponse::Ok().json(profile),
+        Err(e) => HttpResponse::InternalServerError().body(e.to_string()),
+    }
+}
+
+#[put("")]
+async fn handle_set_status(
+    status: web::Json<StatusUpdate>,
+    user: User,
+    app: web::Data<AppState>,
+) -> impl Responder {
+    let status = status.into_inner();
+    if let Err(reason) = status.validate(Utc::now()) {
+        return HttpResponse::BadRequest().body(reason);
+    }
+    match sqlx::query!(
+        r#"update users set status_text = $2, status_emoji = $3, status_expires_at = $4
+        where id = $1"#,
+        user.user_id,
+        blank_to_none(status.text).flatten(),
+        blank_to_none(status.emoji).flatten(),
+        status.expires_at
+    )
+    .execute(&app.pool)
+    .await
+    {
+        Ok(_) => HttpResponse::Ok().body(""),
+        Err(e) => HttpResponse::InternalServerError().body(e.to_string()),
+    }
+}
+
+#[delete("")]
+async fn handle_clear_status(user: User, app: web::Data<AppState>) -> impl Responder {
+    match sqlx::query!(
+        r#"update users set status_text = null, status_emoji = null, status_expires_at = null
+        where id = $1"#,
+        user.user_id
+    )
+    .execute(&app.pool)
+    .await
+    {
+        Ok(_) => HttpResponse::Ok().body(""),
+        Err(e) => HttpResponse::InternalServerError().body(e.to_string()),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use chrono::Duration;
+
+    #[test]
+    fn time_zones_need_an_iana_shape() {
+        assert!(valid_time_zone("UTC"));
+        assert!(valid_time_zone("Europe/Berlin"));
+        assert!(valid_time_zone("America/Argentina/Buenos_Aires"));
+        assert!(valid_time_zone("Etc/GMT+5"));
+        assert!(!valid_time_zone("Berlin"));
+        assert!(!valid_time_zone("Europe/"));
+        assert!(!valid_time_zone("../etc/passwd"));
+    }
+
+    #[test]
+    fn profile_updates_are_checked() {
+        assert!(ProfileUpdate::default().validate().is_ok());
+        let update = |f: fn(&mut ProfileUpdate)| {
+            let mut update = ProfileUpdate::default();
+            f(&mut update);
+            update.validate()
+        };
+        assert!(update(|u| u.display_name = Some("Ana María".to_owned())).is_ok());
+        assert!(update(|u| u.display_name = Some("  ".to_owned())).is_err());
+        assert!(update(|u| u.display_name = Some("x".repeat(MAX_DISPLAY_NAME + 1))).is_err());
+        assert!(update(|u| u.time_zone = Some(String::new())).is_ok());
+        assert!(update(|u| u.time_zone = Some("mars".to_owned())).is_err());
+        assert!(update(|u| u.avatar_url = Some("javascript:alert(1)".to_owned())).is_err());
+        assert!(update(|u| u.avatar_url = Some("https://x.io/a.png".to_owned())).is_ok());
+    }
+
+    #[test]
+    fn statuses_need_something_to_show() {
+        let now = Utc::now();
+        let status = |text: &str, expires_at| StatusUpdate {
+            text: Some(text.to_owned()),
+            emoji: None,
+            expires_at,
+        };
+        assert!(status("in a meeting", None).validate(now).is_ok());
+        assert!(status("in a meeting", Some(now + Duration::hours(1)))
+            .validate(now)
+            .is_ok());
+        assert!(status("in a meeting", Some(now - Duration::hours(1)))
+            .validate(now)
+            .is_err());
+        assert!(status(" ", None).validate(now).is_err());
+    }
+}
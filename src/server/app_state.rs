@@ -1,12 +1,25 @@
 use sqlx::{Pool, Postgres};
+use std::sync::Arc;
 
+use super::config::Config;
+use crate::login::provider::{providers_from_config, AuthProvider};
+use crate::mailer::mailer::Mailer;
 
 pub struct AppState {
     pub pool: sqlx::PgPool,
+    pub config: Config,
+    pub mailer: Arc<dyn Mailer>,
+    /// what `/auth/login` checks passwords against, in order
+    pub auth_providers: Vec<Arc<dyn AuthProvider>>,
 }
 
 impl AppState {
-    pub fn new(pool: &Pool<Postgres>) -> Self {
-        AppState { pool: pool.clone() }
+    pub fn new(pool: &Pool<Postgres>, config: &Config, mailer: &Arc<dyn Mailer>) -> Self {
+        AppState {
+            pool: pool.clone(),
+            config: config.clone(),
+            mailer: Arc::clone(mailer),
+            auth_providers: providers_from_config(config),
+        }
     }
 }
@@ -0,0 +1,290 @@
+use crate::login::throttle::ThrottlePolicy;
+use chrono::Duration;
+use std::env::var;
+use std::str::FromStr;
+
+/// Settings read from the environment (or `.env`) once at startup
+#[derive(Debug, Clone)]
+pub struct Config {
+    /// how long a deleted group can still be restored before it's purged
+    pub group_deletion_grace: Duration,
+    /// roles given to a user when their join request is approved
+    pub join_default_roles: Vec<String>,
+    /// failed logins allowed against one username before it's locked
+    pub login_account_policy: ThrottlePolicy,
+    /// failed logins allowed from one address, across every username
+    pub login_ip_policy: ThrottlePolicy,
+    /// how long failed logins are remembered for
+    pub login_failure_window: Duration,
+    /// where emails go, see [`MailerKind`]
+    pub mailer: MailerKind,
+    /// the From address on every email
+    pub mail_from: String,
+    /// where the server can be reached, for links in emails
+    pub public_url: String,
+    pub email_verification_ttl: Duration,
+    pub password_reset_ttl: Duration,
+    /// single sign-on through an OpenID Connect provider, off unless `OIDC_ISSUER_URL` is set
+    pub oidc: Option<OidcConfig>,
+    /// logging in with a directory password, off unless `LDAP_URL` is set
+    pub ldap: Option<LdapConfig>,
+    /// posts an incoming webhook takes a minute, unless it's made with its own limit
+    pub webhook_rate_limit_per_minute: i32,
+    /// tries at sending an outgoing webhook event before it's dead lettered
+    pub webhook_max_attempts: i32,
+    /// wait before the first retry, doubling after each failure
+    pub webhook_retry_base: Duration,
+    pub webhook_timeout: std::time::Duration,
+    /// lets outgoing webhooks & custom commands post to loopback & private
+    /// network addresses, for trying them out locally
+    pub webhook_allow_internal_urls: bool,
+    /// how long delivered & dead lettered webhook events stay in the delivery log
+    pub webhook_delivery_retention: Duration,
+    /// how long a group's own slash command endpoint gets to answer
+    pub command_timeout: std::time::Duration,
+    /// where finished group exports are written
+    pub export_dir: String,
+    /// how long an export can be downloaded before its file is removed
+    pub export_ttl: Duration,
+    /// how long a user has to change their mind after asking for their account to be deleted
+    pub account_deletion_grace: Duration,
+    /// how long a connection lasts without a heartbeat before its user goes offline
+    pub presence_timeout: Duration,
+    /// how long without activity before a connected user shows as away
+    pub presence_away_after: Duration,
+}
+
+#[derive(Debug, Clone)]
+pub struct OidcConfig {
+    pub issuer_url: String,
+    pub client_id: String,
+    // not needed for public clients, PKCE covers them
+    pub client_secret: Option<String>,
+    pub scopes: Vec<String>,
+    pub redirect_url: String,
+    /// create accounts for people who sign in without one, otherwise they're turned away
+    pub auto_provision: bool,
+}
+
+impl OidcConfig {
+    fn from_env(public_url: &str) -> Option<Self> {
+        let issuer_url = var("OIDC_ISSUER_URL").ok()?;
+        let client_id = match var("OIDC_CLIENT_ID") {
+            Ok(client_id) => client_id,
+            Err(_) => {
+                log::warn!("OIDC_ISSUER_URL is set without OIDC_CLIENT_ID, single sign-on is off");
+                return None;
+            }
+        };
+        Some(OidcConfig {
+            issuer_url,
+            client_id,
+            client_secret: var("OIDC_CLIENT_SECRET").ok(),
+            scopes: env_list("OIDC_SCOPES", &["openid", "email", "profile"]),
+            redirect_url: env_or(
+                "OIDC_REDIRECT_URL",
+                format!("{public_url}/auth/oidc/callback"),
+            ),
+            auto_provision: env_or("OIDC_AUTO_PROVISION", true),
+        })
+    }
+}
+
+/// Checks passwords by binding to an LDAP server as the user. Local accounts
+/// are still tried when the directory turns a login down
+#[derive(Debug, Clone)]
+pub struct LdapConfig {
+    /// e.g. `ldap://localhost:389` or `ldaps://...`
+    pub url: String,
+    pub starttls: bool,
+    pub timeout: std::time::Duration,
+    /// the DN to bind as, `{username}` is swapped for the (escaped) username
+    pub bind_dn_template: String,
+    /// where to look up the user's entry once bound
+    pub search_base: String,
+    /// `{username}` is swapped for the (escaped) username
+    pub user_filter: String,
+    pub display_name_attr: String,
+    pub email_attr: String,
+    /// where directory groups live, searched with `group_filter`
+    pub group_base: String,
+    /// `{dn}` is swapped for the user's DN
+    pub group_filter: String,
+    /// directory group DN to chat group id, see [`parse_group_map`]
+    pub group_map: Vec<(String, i32)>,
+    pub auto_provision: bool,
+}
+
+impl LdapConfig {
+    fn from_env() -> Option<Self> {
+        let url = var("LDAP_URL").ok()?;
+        let (bind_dn_template, search_base) =
+            match (var("LDAP_BIND_DN_TEMPLATE"), var("LDAP_SEARCH_BASE")) {
+                (Ok(template), Ok(base)) => (template, base),
+                _ => {
+                    log::warn!(
+                        "LDAP_URL is set without LDAP_BIND_DN_TEMPLATE & LDAP_SEARCH_BASE, \
+                        directory logins are off"
+                    );
+                    return None;
+                }
+            };
+        Some(LdapConfig {
+            url,
+            starttls: env_or("LDAP_STARTTLS", false),
+            timeout: std::time::Duration::from_secs(env_or("LDAP_TIMEOUT_SECS", 5)),
+            user_filter: env_or("LDAP_USER_FILTER", "(uid={username})".to_owned()),
+            display_name_attr: env_or("LDAP_DISPLAY_NAME_ATTR", "cn".to_owned()),
+            email_attr: env_or("LDAP_EMAIL_ATTR", "mail".to_owned()),
+            group_base: env_or("LDAP_GROUP_BASE", search_base.clone()),
+            group_filter: env_or(
+                "LDAP_GROUP_FILTER",
+                "(&(objectClass=groupOfNames)(member={dn}))".to_owned(),
+            ),
+            group_map: parse_group_map(&var("LDAP_GROUP_MAP").unwrap_or_default()),
+            auto_provision: env_or("LDAP_AUTO_PROVISION", true),
+            bind_dn_template,
+            search_base,
+        })
+    }
+}
+
+/// Reads `LDAP_GROUP_MAP`, pairs of `<group dn>=><chat group id>` separated by
+/// `;` (DNs have commas in them), e.g.
+/// `cn=eng,ou=groups,dc=example,dc=org=>3;cn=ops,ou=groups,dc=example,dc=org=>4`
+pub fn parse_group_map(val: &str) -> Vec<(String, i32)> {
+    val.split(';')
+        .filter(|pair| !pair.trim().is_empty())
+        .filter_map(|pair| {
+            let parsed = pair
+                .rsplit_once("=>")
+                .and_then(|(dn, id)| Some((dn.trim().to_lowercase(), id.trim().parse().ok()?)));
+            if parsed.is_none() {
+                log::warn!("could not parse LDAP_GROUP_MAP entry {pair}, skipping it");
+            }
+            parsed
+        })
+        .collect()
+}
+
+/// `MAILER=smtp` sends through `SMTP_HOST`, `MAILER=file` writes emails into
+/// `MAIL_DIR`, anything else just logs them
+#[derive(Debug, Clone)]
+pub enum MailerKind {
+    Smtp {
+        host: String,
+        port: u16,
+        // STARTTLS, leave off for a local sink
+        tls: bool,
+        credentials: Option<(String, String)>,
+    },
+    File {
+        dir: String,
+    },
+    Log,
+}
+
+impl MailerKind {
+    fn from_env() -> Self {
+        match var("MAILER").unwrap_or_default().as_str() {
+            "smtp" => MailerKind::Smtp {
+                host: env_or("SMTP_HOST", "localhost".to_owned()),
+                port: env_or("SMTP_PORT", 1025),
+                tls: env_or("SMTP_TLS", false),
+                credentials: match (var("SMTP_USERNAME"), var("SMTP_PASSWORD")) {
+                    (Ok(username), Ok(password)) => Some((username, password)),
+                    _ => None,
+                },
+            },
+            "file" => MailerKind::File {
+                dir: env_or("MAIL_DIR", "mail".to_owned()),
+            },
+            _ => MailerKind::Log,
+        }
+    }
+}
+
+impl Config {
+    pub fn from_env() -> Self {
+        let public_url = env_or("PUBLIC_URL", "http://localhost:8080".to_owned());
+        Config {
+            group_deletion_grace: Duration::days(env_or("GROUP_DELETION_GRACE_DAYS", 30)),
+            join_default_roles: env_list("JOIN_DEFAULT_ROLES", &["writer"]),
+            login_account_policy: ThrottlePolicy {
+                free_attempts: env_or("LOGIN_ACCOUNT_FREE_ATTEMPTS", 5),
+                base_lockout: Duration::seconds(env_or("LOGIN_BASE_LOCKOUT_SECS", 30)),
+                max_lockout: Duration::seconds(env_or("LOGIN_MAX_LOCKOUT_SECS", 60 * 60)),
+            },
+            login_ip_policy: ThrottlePolicy {
+                free_attempts: env_or("LOGIN_IP_FREE_ATTEMPTS", 20),
+                base_lockout: Duration::seconds(env_or("LOGIN_BASE_LOCKOUT_SECS", 30)),
+                max_lockout: Duration::seconds(env_or("LOGIN_MAX_LOCKOUT_SECS", 60 * 60)),
+            },
+            login_failure_window: Duration::minutes(env_or("LOGIN_FAILURE_WINDOW_MINUTES", 60)),
+            mailer: MailerKind::from_env(),
+            mail_from: env_or("MAIL_FROM", "rust-server <noreply@localhost>".to_owned()),
+            oidc: OidcConfig::from_env(&public_url),
+            ldap: LdapConfig::from_env(),
+            public_url,
+            email_verification_ttl: Duration::hours(env_or("EMAIL_VERIFICATION_HOURS", 24)),
+            password_reset_ttl: Duration::minutes(env_or("PASSWORD_RESET_MINUTES", 60)),
+            webhook_rate_limit_per_minute: env_or("WEBHOOK_RATE_LIMIT_PER_MINUTE", 60),
+            webhook_max_attempts: env_or("WEBHOOK_MAX_ATTEMPTS", 8),
+            webhook_retry_base: Duration::seconds(env_or("WEBHOOK_RETRY_BASE_SECS", 30)),
+            webhook_timeout: std::time::Duration::from_secs(env_or("WEBHOOK_TIMEOUT_SECS", 10)),
+            webhook_allow_internal_urls: env_or("WEBHOOK_ALLOW_INTERNAL_URLS", false),
+            webhook_delivery_retention: Duration::days(env_or(
+                "WEBHOOK_DELIVERY_RETENTION_DAYS",
+                30,
+            )),
+            command_timeout: std::time::Duration::from_secs(env_or("COMMAND_TIMEOUT_SECS", 5)),
+            export_dir: env_or("EXPORT_DIR", "exports".to_owned()),
+            export_ttl: Duration::hours(env_or("EXPORT_TTL_HOURS", 72)),
+            account_deletion_grace: Duration::days(env_or("ACCOUNT_DELETION_GRACE_DAYS", 14)),
+            presence_timeout: Duration::seconds(env_or("PRESENCE_TIMEOUT_SECS", 60)),
+            presence_away_after: Duration::minutes(env_or("PRESENCE_AWAY_MINUTES", 5)),
+        }
+    }
+}
+
+/// Parses an environment variable, falling back to `default` when it's unset
+/// or doesn't parse
+fn env_or<T: FromStr>(name: &str, default: T) -> T {
+    match var(name) {
+        Ok(val) => val.parse::<T>().unwrap_or_else(|_| {
+            log::warn!("could not parse {name}={val}, using default");
+            default
+        }),
+        Err(_) => default,
+    }
+}
+
+/// Reads a comma separated list from the environment, e.g. `JOIN_DEFAULT_ROLES=reader,writer`
+fn env_list(name: &str, default: &[&str]) -> Vec<String> {
+    match var(name) {
+        Ok(val) => val
+            .split(',')
+            .map(|item| item.trim().to_owned())
+            .filter(|item| !item.is_empty())
+            .collect(),
+        Err(_) => default.iter().map(|item| item.to_string()).collect(),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn group_map_keeps_commas_in_dns() {
+        assert_eq!(
+            parse_group_map("cn=Eng,ou=groups,dc=example,dc=org=>3; cn=ops,dc=org => 4;"),
+            vec![
+                ("cn=eng,ou=groups,dc=example,dc=org".to_owned(), 3),
+                ("cn=ops,dc=org".to_owned(), 4)
+            ]
+        );
+        assert_eq!(parse_group_map("cn=eng,dc=org=>x;cn=ops"), vec![]);
+        assert_eq!(parse_group_map(""), vec![]);
+    }
+}
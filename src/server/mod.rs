@@ -1 +1,2 @@
-pub mod app_state;
\ No newline at end of file
+pub mod app_state;
+pub mod config;
@@ -0,0 +1,231 @@
+use super::tokens::{create_token, list_tokens, revoke_token, CreateTokenRequest};
+use crate::audit::audit::audit_event;
+use crate::auth::generate_token;
+use crate::extractors::extractors::User;
+use crate::groups::groups::add_to_group;
+use crate::login::login::create_ciphertext;
+use crate::server::app_state::AppState;
+use actix_web::{delete, get, post, web, HttpRequest, HttpResponse, Responder};
+use chrono::{DateTime, Utc};
+use serde::{Deserialize, Serialize};
+use serde_json::json;
+use sqlx::{Executor, PgConnection, Pool, Postgres};
+
+/// Checks `bot_id` is a bot `user_id` looks after
+async fn require_bot_owner(
+    bot_id: i32,
+    user_id: i32,
+    pool: &Pool<Postgres>,
+) -> Result<(), HttpResponse> {
+    match sqlx::query!(
+        r#"select 1 as "found" from users where id = $1 and is_bot and bot_owner_id = $2"#,
+        bot_id,
+        user_id
+    )
+    .fetch_optional(pool)
+    .await
+    {
+        Ok(Some(_)) => Ok(()),
+        Ok(None) => Err(HttpResponse::NotFound().body(format!("you have no bot {bot_id}"))),
+        Err(e) => Err(HttpResponse::InternalServerError().body(e.to_string())),
+    }
+}
+
+#[derive(Serialize)]
+struct Bot {
+    id: i32,
+    username: String,
+    display_name: String,
+    created_at: Option<DateTime<Utc>>,
+    disabled_at: Option<DateTime<Utc>>,
+}
+
+#[get("")]
+async fn handle_list_bots(user: User, app: web::Data<AppState>) -> impl Responder {
+    match sqlx::query_as!(
+        Bot,
+        r#"select id, username, display_name, created_at, disabled_at from users
+        where is_bot and bot_owner_id = $1 order by id"#,
+        user.user_id
+    )
+    .fetch_all(&app.pool)
+    .await
+    {
+        Ok(bots) => HttpResponse::Ok().json(bots),
+        Err(e) => HttpResponse::InternalServerError().body(e.to_string()),
+    }
+}
+
+#[derive(Deserialize)]
+struct CreateBotRequest {
+    username: String,
+    display_name: Option<String>,
+}
+
+/// Makes a bot user looked after by `owner_id`, `None` if the username is
+/// taken. It gets a password nobody knows & an address that can't receive
+/// mail, so it can only act through tokens (or whatever made it, e.g. a webhook)
+pub async fn create_bot_user<'c, E>(
+    username: &str,
+    display_name: &str,
+    owner_id: i32,
+    executor: E,
+) -> Result<Option<i32>, sqlx::Error>
+where
+    E: Executor<'c, Database = Postgres>,
+{
+    let (ciphertext, salt) =
+        create_ciphertext(&generate_token()).map_err(|e| sqlx::Error::Protocol(e.to_string()))?;
+    sqlx::query_scalar!(
+        r#"insert into users (username, password, salt, display_name, email, is_bot, bot_owner_id)
+        values ($1, $2, $3, $4, $1 || '@bots.invalid', true, $5)
+        on conflict do nothing returning id"#,
+        username,
+        ciphertext,
+        salt,
+        display_name,
+        owner_id
+    )
+    .fetch_optional(executor)
+    .await
+}
+
+/// Makes the bot an integration (a webhook, a slash command) posts into
+/// `group_id` as, joined as a writer & named `<kind>-<random>`
+pub async fn create_group_bot(
+    kind: &str,
+    display_name: &str,
+    group_id: i32,
+    owner_id: i32,
+    conn: &mut PgConnection,
+) -> Result<i32, sqlx::Error> {
+    let username = format!("{kind}-{}", generate_token()[..12].to_lowercase());
+    let bot_id = create_bot_user(&username, display_name, owner_id, &mut *conn)
+        .await?
+        .ok_or_else(|| sqlx::Error::Protocol(format!("username {username} is taken")))?;
+    add_to_group(group_id, bot_id, owner_id, &["writer".to_owned()], conn).await?;
+    Ok(bot_id)
+}
+
+#[post("")]
+async fn handle_create_bot(
+    user: User,
+    request: web::Json<CreateBotRequest>,
+    app: web::Data<AppState>,
+    req: HttpRequest,
+) -> impl Responder {
+    let username = request.username.trim();
+    if username.is_empty() {
+        return HttpResponse::BadRequest().body("bot username must not be empty");
+    }
+    let display_name = request
+        .display_name
+        .clone()
+        .unwrap_or_else(|| username.to_owned());
+    let bot_id = match create_bot_user(username, &display_name, user.user_id, &app.pool).await {
+        Ok(Some(id)) => id,
+        Ok(None) => {
+            return HttpResponse::Conflict().body(format!("username {username} is already taken"))
+        }
+        Err(e) => return HttpResponse::InternalServerError().body(e.to_string()),
+    };
+    audit_event(
+        &app.pool,
+        &req,
+        Some(user.user_id),
+        "bot.create",
+        Some(format!("user:{bot_id}")),
+        Some(json!({ "username": username })),
+    )
+    .await;
+    HttpResponse::Created().json(json!({ "id": bot_id, "username": username }))
+}
+
+/// Switches a bot off for good, its tokens stop working straight away
+#[delete("/{bot_id}")]
+async fn handle_delete_bot(
+    bot_id: web::Path<i32>,
+    user: User,
+    app: web::Data<AppState>,
+    req: HttpRequest,
+) -> impl Responder {
+    let bot_id = bot_id.into_inner();
+    if let Err(resp) = require_bot_owner(bot_id, user.user_id, &app.pool).await {
+        return resp;
+    }
+    let result = async {
+        let mut tx = app.pool.begin().await?;
+        sqlx::query!(
+            r#"update users set disabled_at = coalesce(disabled_at, now()) where id = $1"#,
+            bot_id
+        )
+        .execute(&mut *tx)
+        .await?;
+        sqlx::query!(
+            r#"update api_tokens set revoked_at = now() where user_id = $1 and revoked_at is null"#,
+            bot_id
+        )
+        .execute(&mut *tx)
+        .await?;
+        tx.commit().await
+    }
+    .await;
+    if let Err(e) = result {
+        return HttpResponse::InternalServerError().body(e.to_string());
+    }
+    audit_event(
+        &app.pool,
+        &req,
+        Some(user.user_id),
+        "bot.delete",
+        Some(format!("user:{bot_id}")),
+        None,
+    )
+    .await;
+    HttpResponse::Ok().body("")
+}
+
+#[get("/{bot_id}/tokens")]
+async fn handle_list_bot_tokens(
+    bot_id: web::Path<i32>,
+    user: User,
+    app: web::Data<AppState>,
+) -> impl Responder {
+    let bot_id = bot_id.into_inner();
+    if let Err(resp) = require_bot_owner(bot_id, user.user_id, &app.pool).await {
+        return resp;
+    }
+    match list_tokens(bot_id, &app.pool).await {
+        Ok(tokens) => HttpResponse::Ok().json(tokens),
+        Err(e) => HttpResponse::InternalServerError().body(e.to_string()),
+    }
+}
+
+#[post("/{bot_id}/tokens")]
+async fn handle_create_bot_token(
+    bot_id: web::Path<i32>,
+    user: User,
+    request: web::Json<CreateTokenRequest>,
+    app: web::Data<AppState>,
+    req: HttpRequest,
+) -> impl Responder {
+    let bot_id = bot_id.into_inner();
+    if let Err(resp) = require_bot_owner(bot_id, user.user_id, &app.pool).await {
+        return resp;
+    }
+    create_token(bot_id, user.user_id, request.into_inner(), &app, &req).await
+}
+
+#[delete("/{bot_id}/tokens/{token_id}")]
+async fn handle_revoke_bot_token(
+    path: web::Path<(i32, i32)>,
+    user: User,
+    app: web::Data<AppState>,
+    req: HttpRequest,
+) -> impl Responder {
+    let (bot_id, token_id) = path.into_inner();
+    if let Err(resp) = require_bot_owner(bot_id, user.user_id, &app.pool).await {
+        return resp;
+    }
+    revoke_token(bot_id, token_id, user.user_id, &app, &req).await
+}
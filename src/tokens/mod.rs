@@ -0,0 +1,26 @@
+pub mod bots;
+pub mod tokens;
+use actix_web::web;
+use bots::{
+    handle_create_bot, handle_create_bot_token, handle_delete_bot, handle_list_bot_tokens,
+    handle_list_bots, handle_revoke_bot_token,
+};
+use tokens::{handle_create_token, handle_list_tokens, handle_revoke_token};
+
+pub fn token_routes(cfg: &mut web::ServiceConfig) {
+    cfg.service(
+        web::scope("/account/tokens")
+            .service(handle_list_tokens)
+            .service(handle_create_token)
+            .service(handle_revoke_token),
+    )
+    .service(
+        web::scope("/bots")
+            .service(handle_list_bots)
+            .service(handle_create_bot)
+            .service(handle_delete_bot)
+            .service(handle_list_bot_tokens)
+            .service(handle_create_bot_token)
+            .service(handle_revoke_bot_token),
+    );
+}
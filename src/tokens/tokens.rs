@@ -0,0 +1,315 @@
+use crate::admin::admin::require_platform_admin;
+use crate::audit::audit::audit_event;
+use crate::auth::{generate_token, hash_token};
+use crate::extractors::extractors::User;
+use crate::server::app_state::AppState;
+use actix_web::http::Method;
+use actix_web::{delete, get, post, web, HttpRequest, HttpResponse, Responder};
+use chrono::{DateTime, Duration, Utc};
+use serde::{Deserialize, Serialize};
+use serde_json::json;
+use sqlx::{Pool, Postgres};
+
+/// Api tokens start with this so they can't be mistaken for session tokens
+pub const TOKEN_PREFIX: &str = "pat_";
+
+/// What an api token may be used for, stored by name in `api_tokens.scopes`
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq)]
+#[serde(rename_all = "snake_case")]
+pub enum TokenScope {
+    /// GET requests under `/api`
+    Read,
+    /// everything else under `/api`
+    Write,
+    /// `/api/admin`, still only for platform admins
+    Admin,
+}
+
+impl TokenScope {
+    pub const ALL: [TokenScope; 3] = [TokenScope::Read, TokenScope::Write, TokenScope::Admin];
+
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            TokenScope::Read => "read",
+            TokenScope::Write => "write",
+            TokenScope::Admin => "admin",
+        }
+    }
+
+    pub fn from_name(name: &str) -> Option<TokenScope> {
+        TokenScope::ALL
+            .into_iter()
+            .find(|scope| scope.as_str() == name)
+    }
+}
+
+/// The scope a request needs when it's made with an api token. `None` for
+/// things tokens can't do at all: logging out & managing credentials, so a
+/// leaked token can't be used to mint more
+pub fn required_scope(method: &Method, path: &str) -> Option<TokenScope> {
+    let under = |prefix: &str| path == prefix || path.starts_with(&format!("{prefix}/"));
+    if !under("/api") || under("/api/account") || under("/api/bots") {
+        return None;
+    }
+    if under("/api/admin") {
+        return Some(TokenScope::Admin);
+    }
+    match *method {
+        Method::GET | Method::HEAD | Method::OPTIONS => Some(TokenScope::Read),
+        _ => Some(TokenScope::Write),
+    }
+}
+
+fn scope_names(scopes: &[TokenScope]) -> Vec<String> {
+    scopes
+        .iter()
+        .map(|scope| scope.as_str().to_owned())
+        .collect()
+}
+
+/// A live api token that was just used
+pub struct ApiTokenUse {
+    pub id: i32,
+    pub user_id: i32,
+    pub scopes: Vec<TokenScope>,
+}
+
+/// Looks up an api token, noting it was used. `None` if it's unknown, revoked,
+/// expired or belongs to a disabled user or one who asked to be deleted
+pub async fn check_api_token(
+    token: &str,
+    pool: &Pool<Postgres>,
+) -> Result<Option<ApiTokenUse>, sqlx::Error> {
+    let rec = sqlx::query!(
+        r#"update api_tokens t set last_used_at = now() from users u
+        where t.token_hash = $1 and u.id = t.user_id and u.disabled_at is null
+        and u.deletion_requested_at is null
+        and t.revoked_at is null and (t.expires_at is null or t.expires_at > now())
+        returning t.id, t.user_id, t.scopes"#,
+        hash_token(token)
+    )
+    .fetch_optional(pool)
+    .await?;
+    Ok(rec.map(|rec| ApiTokenUse {
+        id: rec.id,
+        user_id: rec.user_id,
+        scopes: rec
+            .scopes
+            .iter()
+            .filter_map(|name| TokenScope::from_name(name))
+            .collect(),
+    }))
+}
+
+#[derive(Serialize)]
+pub struct ApiToken {
+    id: i32,
+    name: String,
+    scopes: Vec<String>,
+    created_by: Option<i32>,
+    created_at: DateTime<Utc>,
+    expires_at: Option<DateTime<Utc>>,
+    last_used_at: Option<DateTime<Utc>>,
+    revoked_at: Option<DateTime<Utc>>,
+}
+
+/// Every token `user_id` has had, newest first
+pub async fn list_tokens(
+    user_id: i32,
+    pool: &Pool<Postgres>,
+) -> Result<Vec<ApiToken>, sqlx::Error> {
+    sqlx::query_as!(
+        ApiToken,
+        r#"select id, name, scopes, created_by, created_at, expires_at, last_used_at, revoked_at
+        from api_tokens where user_id = $1 order by id desc"#,
+        user_id
+    )
+    .fetch_all(pool)
+    .await
+}
+
+#[derive(Deserialize)]
+pub struct CreateTokenRequest {
+    name: String,
+    scopes: Vec<TokenScope>,
+    expires_in_days: Option<i64>,
+}
+
+/// Sent once when a token is made, the token itself can't be seen again
+#[derive(Serialize)]
+struct CreatedToken {
+    id: i32,
+    token: String,
+    expires_at: Option<DateTime<Utc>>,
+}
+
+/// Makes a token for `user_id` on behalf of `actor_id` (themselves, or a bot's owner)
+pub async fn create_token(
+    user_id: i32,
+    actor_id: i32,
+    request: CreateTokenRequest,
+    app: &AppState,
+    req: &HttpRequest,
+) -> HttpResponse {
+    let name = request.name.trim();
+    if name.is_empty() {
+        return HttpResponse::BadRequest().body("token name must not be empty");
+    }
+    if request.scopes.is_empty() {
+        return HttpResponse::BadRequest().body("a token needs at least one scope");
+    }
+    let expires_at = match request.expires_in_days {
+        Some(days) if days <= 0 => {
+            return HttpResponse::BadRequest().body("expires_in_days must be positive")
+        }
+        Some(days) => Some(Utc::now() + Duration::days(days)),
+        None => None,
+    };
+    if request.scopes.contains(&TokenScope::Admin) {
+        if let Err(resp) = require_platform_admin(user_id, &app.pool).await {
+            return resp;
+        }
+    }
+
+    let token = format!("{TOKEN_PREFIX}{}", generate_token());
+    let id = match sqlx::query_scalar!(
+        r#"insert into api_tokens (user_id, name, token_hash, scopes, created_by, expires_at)
+        values ($1, $2, $3, $4, $5, $6) returning id"#,
+        user_id,
+        name,
+        hash_token(&token),
+        &scope_names(&request.scopes),
+        actor_id,
+        expires_at
+    )
+    .fetch_one(&app.pool)
+    .await
+    {
+        Ok(id) => id,
+        Err(e) => return HttpResponse::InternalServerError().body(e.to_string()),
+    };
+    audit_event(
+        &app.pool,
+        req,
+        Some(actor_id),
+        "token.create",
+        Some(format!("user:{user_id}")),
+        Some(json!({ "token_id": id, "name": name, "scopes": request.scopes, "expires_at": expires_at })),
+    )
+    .await;
+    HttpResponse::Created().json(CreatedToken {
+        id,
+        token,
+        expires_at,
+    })
+}
+
+/// Revokes one of `user_id`'s tokens
+pub async fn revoke_token(
+    user_id: i32,
+    token_id: i32,
+    actor_id: i32,
+    app: &AppState,
+    req: &HttpRequest,
+) -> HttpResponse {
+    match sqlx::query!(
+        r#"update api_tokens set revoked_at = now()
+        where id = $1 and user_id = $2 and revoked_at is null"#,
+        token_id,
+        user_id
+    )
+    .execute(&app.pool)
+    .await
+    {
+        Ok(res) if res.rows_affected() > 0 => {
+            audit_event(
+                &app.pool,
+                req,
+                Some(actor_id),
+                "token.revoke",
+                Some(format!("user:{user_id}")),
+                Some(json!({ "token_id": token_id })),
+            )
+            .await;
+            HttpResponse::Ok().body("")
+        }
+        Ok(_) => HttpResponse::NotFound().body(format!("no live token {token_id}")),
+        Err(e) => HttpResponse::InternalServerError().body(e.to_string()),
+    }
+}
+
+#[get("")]
+async fn handle_list_tokens(user: User, app: web::Data<AppState>) -> impl Responder {
+    match list_tokens(user.user_id, &app.pool).await {
+        Ok(tokens) => HttpResponse::Ok().json(tokens),
+        Err(e) => HttpResponse::InternalServerError().body(e.to_string()),
+    }
+}
+
+#[post("")]
+async fn handle_create_token(
+    user: User,
+    request: web::Json<CreateTokenRequest>,
+    app: web::Data<AppState>,
+    req: HttpRequest,
+) -> impl Responder {
+    create_token(user.user_id, user.user_id, request.into_inner(), &app, &req).await
+}
+
+#[delete("/{token_id}")]
+async fn handle_revoke_token(
+    token_id: web::Path<i32>,
+    user: User,
+    app: web::Data<AppState>,
+    req: HttpRequest,
+) -> impl Responder {
+    revoke_token(
+        user.user_id,
+        token_id.into_inner(),
+        user.user_id,
+        &app,
+        &req,
+    )
+    .await
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn scope_names_match_serde() {
+        for scope in TokenScope::ALL {
+            let json = serde_json::to_string(&scope).unwrap();
+            assert_eq!(json, format!("\"{}\"", scope.as_str()));
+            assert_eq!(TokenScope::from_name(scope.as_str()), Some(scope));
+        }
+    }
+
+    #[test]
+    fn scopes_follow_method_and_path() {
+        assert_eq!(
+            required_scope(&Method::GET, "/api/groups/3/messages"),
+            Some(TokenScope::Read)
+        );
+        assert_eq!(
+            required_scope(&Method::PUT, "/api/groups/3/messages"),
+            Some(TokenScope::Write)
+        );
+        assert_eq!(
+            required_scope(&Method::GET, "/api/admin/users"),
+            Some(TokenScope::Admin)
+        );
+        assert_eq!(
+            required_scope(&Method::GET, "/api/administrators"),
+            Some(TokenScope::Read)
+        );
+    }
+
+    #[test]
+    fn tokens_cannot_manage_credentials() {
+        assert_eq!(required_scope(&Method::POST, "/api/account/tokens"), None);
+        assert_eq!(required_scope(&Method::GET, "/api/bots"), None);
+        assert_eq!(required_scope(&Method::PUT, "/auth/logout"), None);
+    }
+}
@@ -0,0 +1,171 @@
+use hyper::client::connect::dns::Name;
+use reqwest::dns::{Addrs, Resolve, Resolving};
+use std::net::{IpAddr, SocketAddr};
+
+/// Addresses a group admin mustn't be able to make the server post to:
+/// loopback, private & carrier-grade NAT networks, link-local ones (cloud
+/// metadata lives at 169.254.169.254), multicast & unspecified ones
+pub fn is_internal(ip: IpAddr) -> bool {
+    match ip {
+        IpAddr::V4(ip) => {
+            let [a, b, ..] = ip.octets();
+            ip.is_loopback()
+                || ip.is_private()
+                || ip.is_link_local()
+                || ip.is_unspecified()
+                || ip.is_broadcast()
+                || ip.is_multicast()
+                || a == 0
+                || (a == 100 && (64..128).contains(&b))
+        }
+        IpAddr::V6(ip) => {
+            let segments = ip.segments();
+            // IPv4 addresses written as IPv6, directly or through NAT64
+            let embedded = match segments {
+                [0, 0, 0, 0, 0, 0xffff, ..] | [0x64, 0xff9b, 0, 0, 0, 0, ..] => {
+                    let [.., high, low] = segments;
+                    Some(((high as u32) << 16 | low as u32).into())
+                }
+                _ => None,
+            };
+            match embedded {
+                Some(v4) => is_internal(IpAddr::V4(v4)),
+                None => {
+                    ip.is_loopback()
+                        || ip.is_unspecified()
+                        || ip.is_multicast()
+                        // unique local fc00::/7, link-local fe80::/10 & site-local fec0::/10
+                        || segments[0] & 0xfe00 == 0xfc00
+                        || segments[0] & 0xffc0 == 0xfe80
+                        || segments[0] & 0xffc0 == 0xfec0
+                }
+            }
+        }
+    }
+}
+
+/// Checks a url a webhook subscription or custom command posts to: it has to
+/// be http(s), & unless `allow_internal` its host can't be, or resolve to, an
+/// internal address
+pub async fn check_destination(url: &str, allow_internal: bool) -> Result<(), String> {
+    let url = match reqwest::Url::parse(url) {
+        Ok(url) if matches!(url.scheme(), "http" | "https") => url,
+        _ => return Err("url must be an http or https url".to_owned()),
+    };
+    if allow_internal {
+        return Ok(());
+    }
+    let host = url.host_str().ok_or("url must have a host")?;
+    let literal = host.trim_start_matches('[').trim_end_matches(']');
+    let addresses: Vec<IpAddr> = match literal.parse::<IpAddr>() {
+        Ok(ip) => vec![ip],
+        Err(_) => tokio::net::lookup_host((host, url.port_or_known_default().unwrap_or(80)))
+            .await
+            .map_err(|e| format!("could not look up {host}: {e}"))?
+            .map(|address| address.ip())
+            .collect(),
+    };
+    match addresses.into_iter().find(|ip| is_internal(*ip)) {
+        Some(ip) => Err(format!(
+            "{host} is an internal address ({ip}), only public ones can be posted to"
+        )),
+        None => Ok(()),
+    }
+}
+
+/// Looks up hosts for outgoing webhooks & commands, leaving out internal
+/// addresses, so a name that was public when it was checked can't be pointed
+/// inside later. Hosts given as addresses never get here, [`check_destination`]
+/// covers those
+pub struct PublicResolver;
+
+impl Resolve for PublicResolver {
+    fn resolve(&self, name: Name) -> Resolving {
+        Box::pin(async move {
+            let addresses: Vec<SocketAddr> = tokio::net::lookup_host((name.as_str(), 0))
+                .await?
+                .filter(|address| !is_internal(address.ip()))
+                .collect();
+            if addresses.is_empty() {
+                return Err(
+                    format!("{} only resolves to internal addresses", name.as_str()).into(),
+                );
+            }
+            Ok(Box::new(addresses.into_iter()) as Addrs)
+        })
+    }
+}
+
+/// What posts to webhook subscriptions & custom commands. Redirects aren't
+/// followed, so only the url that was checked is ever posted to
+pub fn outgoing_client(timeout: std::time::Duration, allow_internal: bool) -> reqwest::Client {
+    let builder = reqwest::Client::builder()
+        .timeout(timeout)
+        .redirect(reqwest::redirect::Policy::none());
+    match allow_internal {
+        true => builder,
+        false => builder.dns_resolver(std::sync::Arc::new(PublicResolver)),
+    }
+    .build()
+    .expect("outgoing client settings are valid")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn internal(ip: &str) -> bool {
+        is_internal(ip.parse().unwrap())
+    }
+
+    #[test]
+    fn internal_addresses_are_recognized() {
+        for ip in [
+            "127.0.0.1",
+            "10.1.2.3",
+            "172.16.0.1",
+            "192.168.1.1",
+            "169.254.169.254",
+            "100.64.0.1",
+            "0.0.0.0",
+            "::1",
+            "::",
+            "fd00::1",
+            "fe80::1",
+            "::ffff:127.0.0.1",
+            "64:ff9b::a9fe:a9fe",
+        ] {
+            assert!(internal(ip), "{ip} should be internal");
+        }
+        for ip in [
+            "93.184.216.34",
+            "1.1.1.1",
+            "2606:4700::1111",
+            "::ffff:8.8.8.8",
+        ] {
+            assert!(!internal(ip), "{ip} should be public");
+        }
+    }
+
+    #[actix_web::test]
+    async fn destinations_are_checked() {
+        assert!(check_destination("ftp://example.org/", false)
+            .await
+            .is_err());
+        assert!(check_destination("http://169.254.169.254/latest", false)
+            .await
+            .is_err());
+        assert!(check_destination("http://[::1]:9100/", false)
+            .await
+            .is_err());
+        assert!(check_destination("http://localhost:9100/", false)
+            .await
+            .is_err());
+        assert!(check_destination("http://localhost:9100/", true)
+            .await
+            .is_ok());
+        assert!(check_destination("https://93.184.216.34/hook", false)
+            .await
+            .is_ok());
+    }
+}
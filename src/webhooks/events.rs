@@ -0,0 +1,92 @@
+use chrono::Utc;
+use serde::{Deserialize, Serialize};
+use serde_json::{json, Value};
+use sqlx::{Executor, Postgres};
+
+/// Something a webhook subscription can be sent, named like `message.created`
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq)]
+pub enum WebhookEvent {
+    #[serde(rename = "message.created")]
+    MessageCreated,
+    #[serde(rename = "message.deleted")]
+    MessageDeleted,
+    /// a disappearing message was removed, receivers should drop their copy
+    #[serde(rename = "message.expired")]
+    MessageExpired,
+    #[serde(rename = "member.joined")]
+    MemberJoined,
+    #[serde(rename = "member.left")]
+    MemberLeft,
+    /// only sent to subscriptions covering every group
+    #[serde(rename = "group.created")]
+    GroupCreated,
+}
+
+impl WebhookEvent {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            WebhookEvent::MessageCreated => "message.created",
+            WebhookEvent::MessageDeleted => "message.deleted",
+            WebhookEvent::MessageExpired => "message.expired",
+            WebhookEvent::MemberJoined => "member.joined",
+            WebhookEvent::MemberLeft => "member.left",
+            WebhookEvent::GroupCreated => "group.created",
+        }
+    }
+}
+
+/// The body every delivery is sent with
+pub fn event_body(event: WebhookEvent, group_id: i32, data: Value) -> Value {
+    json!({
+        "event": event.as_str(),
+        "group_id": group_id,
+        "occurred_at": Utc::now(),
+        "data": data,
+    })
+}
+
+/// Queues `event` for every live subscription that wants it, either on
+/// `group_id` or on every group. Run it with the transaction making the change,
+/// so an event goes out if & only if the change is committed
+pub async fn enqueue_event<'c, E>(
+    executor: E,
+    event: WebhookEvent,
+    group_id: i32,
+    data: Value,
+) -> Result<(), sqlx::Error>
+where
+    E: Executor<'c, Database = Postgres>,
+{
+    sqlx::query!(
+        r#"insert into webhook_deliveries (subscription_id, event_type, payload)
+        select id, $1, $3 from webhook_subscriptions
+        where disabled_at is null and $1 = any(event_types)
+        and (group_id is null or group_id = $2)"#,
+        event.as_str(),
+        group_id,
+        event_body(event, group_id, data)
+    )
+    .execute(executor)
+    .await?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn event_names_match_serde() {
+        for event in [
+            WebhookEvent::MessageCreated,
+            WebhookEvent::MessageDeleted,
+            WebhookEvent::MessageExpired,
+            WebhookEvent::MemberJoined,
+            WebhookEvent::MemberLeft,
+            WebhookEvent::GroupCreated,
+        ] {
+            let json = serde_json::to_string(&event).unwrap();
+            assert_eq!(json, format!("\"{}\"", event.as_str()));
+        }
+    }
+}
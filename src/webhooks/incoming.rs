@@ -0,0 +1,384 @@
+use super::signing::{verify_signature, SIGNATURE_HEADER, TIMESTAMP_HEADER};
+use crate::admin::admin::require_platform_admin;
+use crate::audit::audit::audit_event;
+use crate::auth::{generate_token, hash_token};
+use crate::extractors::extractors::User;
+use crate::groups::roles::{has_capability, Capability};
+use crate::messages::content::MessageContent;
+use crate::messages::messages::write_message;
+use crate::server::app_state::AppState;
+use crate::tokens::bots::create_group_bot;
+use actix_web::http::header;
+use actix_web::{delete, get, post, web, HttpRequest, HttpResponse, Responder};
+use chrono::{DateTime, Utc};
+use serde::{Deserialize, Serialize};
+use serde_json::json;
+use sqlx::{Pool, Postgres};
+
+/// What a webhook accepts, either a full message (`{"type": "blocks", ...}`)
+/// or just `{"text": "..."}` like most monitoring tools send
+#[derive(Deserialize)]
+#[serde(untagged)]
+pub enum WebhookPayload {
+    Content(MessageContent),
+    Text { text: String },
+}
+
+impl WebhookPayload {
+    pub fn into_content(self) -> Result<MessageContent, &'static str> {
+        match self {
+            // webhooks post as a member, not as the group's moderators
+            WebhookPayload::Content(MessageContent::System { .. }) => {
+                Err("webhooks can't post system messages")
+            }
+            WebhookPayload::Content(content) => Ok(content),
+            WebhookPayload::Text { text } => Ok(MessageContent::Text { content: text }),
+        }
+    }
+}
+
+/// Counts a post the webhook turned away, passing `resp` back
+async fn rejected(pool: &Pool<Postgres>, resp: HttpResponse, hook_id: i32) -> HttpResponse {
+    if let Err(e) = sqlx::query!(
+        r#"update incoming_webhooks set rejected_count = rejected_count + 1 where id = $1"#,
+        hook_id
+    )
+    .execute(pool)
+    .await
+    {
+        log::error!("failed to count rejected post to webhook {hook_id}: {e}");
+    }
+    resp
+}
+
+fn header_str<'a>(req: &'a HttpRequest, name: &str) -> Option<&'a str> {
+    req.headers()
+        .get(name)
+        .and_then(|value| value.to_str().ok())
+}
+
+/// Where outside systems post to, no session needed, the token in the url is
+/// the credential
+#[post("/{token}")]
+pub async fn handle_incoming_webhook(
+    token: web::Path<String>,
+    body: web::Bytes,
+    app: web::Data<AppState>,
+    req: HttpRequest,
+) -> impl Responder {
+    let hook = match sqlx::query!(
+        r#"select id, group_id, bot_user_id, secret from incoming_webhooks
+        where token_hash = $1 and disabled_at is null"#,
+        hash_token(&token)
+    )
+    .fetch_optional(&app.pool)
+    .await
+    {
+        Ok(Some(hook)) => hook,
+        Ok(None) => return HttpResponse::NotFound().body("no such webhook"),
+        Err(e) => return HttpResponse::InternalServerError().body(e.to_string()),
+    };
+
+    if let Some(secret) = &hook.secret {
+        let timestamp = header_str(&req, TIMESTAMP_HEADER).and_then(|ts| ts.parse::<i64>().ok());
+        let signature = header_str(&req, SIGNATURE_HEADER);
+        let checked = match (timestamp, signature) {
+            (Some(timestamp), Some(signature)) => {
+                verify_signature(secret, timestamp, signature, &body, Utc::now().timestamp())
+            }
+            _ => Err("post must be signed"),
+        };
+        if let Err(reason) = checked {
+            return rejected(
+                &app.pool,
+                HttpResponse::Unauthorized().body(reason),
+                hook.id,
+            )
+            .await;
+        }
+    }
+
+    // counts posts in a fixed minute long window, starting a new one once it's passed
+    let window = match sqlx::query!(
+        r#"update incoming_webhooks set
+            window_count = case when window_started_at > now() - interval '1 minute'
+                then window_count + 1 else 1 end,
+            window_started_at = case when window_started_at > now() - interval '1 minute'
+                then window_started_at else now() end
+        where id = $1
+        returning window_count, rate_limit_per_minute, window_started_at"#,
+        hook.id
+    )
+    .fetch_one(&app.pool)
+    .await
+    {
+        Ok(window) => window,
+        Err(e) => return HttpResponse::InternalServerError().body(e.to_string()),
+    };
+    if window.window_count > window.rate_limit_per_minute {
+        let wait = (window.window_started_at + chrono::Duration::minutes(1) - Utc::now())
+            .num_seconds()
+            .max(1);
+        return rejected(
+            &app.pool,
+            HttpResponse::TooManyRequests()
+                .insert_header((header::RETRY_AFTER, wait.to_string()))
+                .body("webhook rate limit reached"),
+            hook.id,
+        )
+        .await;
+    }
+
+    let content = match serde_json::from_slice::<WebhookPayload>(&body)
+        .map_err(|e| e.to_string())
+        .and_then(|payload| payload.into_content().map_err(|e| e.to_owned()))
+    {
+        Ok(content) => content,
+        Err(e) => return rejected(&app.pool, HttpResponse::BadRequest().body(e), hook.id).await,
+    };
+    let message_id = match write_message(hook.group_id, hook.bot_user_id, content, &app.pool).await
+    {
+        Ok(id) => id,
+        Err(resp) => return rejected(&app.pool, resp, hook.id).await,
+    };
+    if let Err(e) = sqlx::query!(
+        r#"update incoming_webhooks set delivery_count = delivery_count + 1, last_delivery_at = now()
+        where id = $1"#,
+        hook.id
+    )
+    .execute(&app.pool)
+    .await
+    {
+        log::error!("failed to count delivery to webhook {}: {e}", hook.id);
+    }
+    HttpResponse::Ok().json(json!({ "message_id": message_id }))
+}
+
+#[derive(Serialize)]
+struct Webhook {
+    id: i32,
+    group_id: i32,
+    bot_user_id: i32,
+    name: String,
+    signed: bool,
+    rate_limit_per_minute: i32,
+    delivery_count: i64,
+    rejected_count: i64,
+    last_delivery_at: Option<DateTime<Utc>>,
+    created_by: Option<i32>,
+    created_at: DateTime<Utc>,
+    disabled_at: Option<DateTime<Utc>>,
+}
+
+pub async fn require_manage_group(
+    user_id: i32,
+    group_id: i32,
+    app: &AppState,
+) -> Option<HttpResponse> {
+    match has_capability(user_id, group_id, Capability::ManageGroup, &app.pool).await {
+        true => None,
+        false => Some(HttpResponse::Unauthorized().body(format!(
+            "user {user_id} is not permitted to manage webhooks for group {group_id}"
+        ))),
+    }
+}
+
+#[get("{group_id}/webhooks")]
+pub async fn handle_list_webhooks(
+    group_id: web::Path<i32>,
+    user: User,
+    app: web::Data<AppState>,
+) -> impl Responder {
+    let group_id = group_id.into_inner();
+    if let Some(resp) = require_manage_group(user.user_id, group_id, &app).await {
+        return resp;
+    }
+    match sqlx::query_as!(
+        Webhook,
+        r#"select id, group_id, bot_user_id, name, secret is not null as "signed!",
+        rate_limit_per_minute, delivery_count, rejected_count, last_delivery_at, created_by,
+        created_at, disabled_at
+        from incoming_webhooks where group_id = $1 order by id"#,
+        group_id
+    )
+    .fetch_all(&app.pool)
+    .await
+    {
+        Ok(hooks) => HttpResponse::Ok().json(hooks),
+        Err(e) => HttpResponse::InternalServerError().body(e.to_string()),
+    }
+}
+
+#[derive(Deserialize)]
+struct CreateWebhookRequest {
+    name: String,
+    // hand out a signing secret & refuse unsigned posts
+    #[serde(default)]
+    signed: bool,
+    rate_limit_per_minute: Option<i32>,
+}
+
+/// Sent once when a webhook is made, neither the url nor the secret can be seen again
+#[derive(Serialize)]
+struct CreatedWebhook {
+    id: i32,
+    url: String,
+    secret: Option<String>,
+}
+
+/// Makes a webhook, along with the bot it posts as, which joins the group as a writer
+#[post("{group_id}/webhooks")]
+pub async fn handle_create_webhook(
+    group_id: web::Path<i32>,
+    request: web::Json<CreateWebhookRequest>,
+    user: User,
+    app: web::Data<AppState>,
+    req: HttpRequest,
+) -> impl Responder {
+    let group_id = group_id.into_inner();
+    if let Some(resp) = require_manage_group(user.user_id, group_id, &app).await {
+        return resp;
+    }
+    let name = request.name.trim();
+    if name.is_empty() {
+        return HttpResponse::BadRequest().body("webhook name must not be empty");
+    }
+    let rate_limit = request
+        .rate_limit_per_minute
+        .unwrap_or(app.config.webhook_rate_limit_per_minute);
+    if rate_limit <= 0 {
+        return HttpResponse::BadRequest().body("rate_limit_per_minute must be positive");
+    }
+    let token = generate_token();
+    let secret = request.signed.then(generate_token);
+
+    let result = async {
+        let mut tx = app.pool.begin().await?;
+        let bot_id = create_group_bot("webhook", name, group_id, user.user_id, &mut tx).await?;
+        let id = sqlx::query_scalar!(
+            r#"insert into incoming_webhooks
+            (group_id, bot_user_id, name, token_hash, secret, rate_limit_per_minute, created_by)
+            values ($1, $2, $3, $4, $5, $6, $7) returning id"#,
+            group_id,
+            bot_id,
+            name,
+            hash_token(&token),
+            secret,
+            rate_limit,
+            user.user_id
+        )
+        .fetch_one(&mut *tx)
+        .await?;
+        tx.commit().await?;
+        Ok::<_, sqlx::Error>((id, bot_id))
+    }
+    .await;
+    let (id, bot_id) = match result {
+        Ok(ids) => ids,
+        Err(e) => return HttpResponse::InternalServerError().body(e.to_string()),
+    };
+    audit_event(
+        &app.pool,
+        &req,
+        Some(user.user_id),
+        "webhook.create",
+        Some(format!("group:{group_id}")),
+        Some(json!({ "webhook_id": id, "name": name, "bot_user_id": bot_id, "signed": secret.is_some() })),
+    )
+    .await;
+    HttpResponse::Created().json(CreatedWebhook {
+        id,
+        url: format!("{}/hooks/{token}", app.config.public_url),
+        secret,
+    })
+}
+
+/// Turns a webhook off for good, & its bot with it
+#[delete("{group_id}/webhooks/{webhook_id}")]
+pub async fn handle_delete_webhook(
+    path: web::Path<(i32, i32)>,
+    user: User,
+    app: web::Data<AppState>,
+    req: HttpRequest,
+) -> impl Responder {
+    let (group_id, webhook_id) = path.into_inner();
+    if let Some(resp) = require_manage_group(user.user_id, group_id, &app).await {
+        return resp;
+    }
+    let result = async {
+        let mut tx = app.pool.begin().await?;
+        let bot_id = sqlx::query_scalar!(
+            r#"update incoming_webhooks set disabled_at = now()
+            where id = $1 and group_id = $2 and disabled_at is null returning bot_user_id"#,
+            webhook_id,
+            group_id
+        )
+        .fetch_optional(&mut *tx)
+        .await?;
+        if let Some(bot_id) = bot_id {
+            sqlx::query!(
+                r#"update users set disabled_at = coalesce(disabled_at, now()) where id = $1"#,
+                bot_id
+            )
+            .execute(&mut *tx)
+            .await?;
+        }
+        tx.commit().await?;
+        Ok::<_, sqlx::Error>(bot_id)
+    }
+    .await;
+    match result {
+        Ok(Some(_)) => {
+            audit_event(
+                &app.pool,
+                &req,
+                Some(user.user_id),
+                "webhook.delete",
+                Some(format!("group:{group_id}")),
+                Some(json!({ "webhook_id": webhook_id })),
+            )
+            .await;
+            HttpResponse::Ok().body("")
+        }
+        Ok(None) => HttpResponse::NotFound().body(format!("no live webhook {webhook_id}")),
+        Err(e) => HttpResponse::InternalServerError().body(e.to_string()),
+    }
+}
+
+/// Every webhook on the platform, busiest first
+#[get("/webhooks")]
+pub async fn handle_admin_list_webhooks(user: User, app: web::Data<AppState>) -> impl Responder {
+    if let Err(resp) = require_platform_admin(user.user_id, &app.pool).await {
+        return resp;
+    }
+    match sqlx::query_as!(
+        Webhook,
+        r#"select id, group_id, bot_user_id, name, secret is not null as "signed!",
+        rate_limit_per_minute, delivery_count, rejected_count, last_delivery_at, created_by,
+        created_at, disabled_at
+        from incoming_webhooks order by delivery_count desc, id"#
+    )
+    .fetch_all(&app.pool)
+    .await
+    {
+        Ok(hooks) => HttpResponse::Ok().json(hooks),
+        Err(e) => HttpResponse::InternalServerError().body(e.to_string()),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn plain_text_payloads_are_accepted() {
+        let payload: WebhookPayload = serde_json::from_str(r#"{"text": "disk full"}"#).unwrap();
+        assert!(matches!(
+            payload.into_content(),
+            Ok(MessageContent::Text { content }) if content == "disk full"
+        ));
+        let payload: WebhookPayload =
+            serde_json::from_str(r#"{"type": "system", "content": "x"}"#).unwrap();
+        assert!(payload.into_content().is_err());
+    }
+}
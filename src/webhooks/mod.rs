@@ -0,0 +1,13 @@
+pub mod destinations;
+pub mod events;
+pub mod incoming;
+pub mod outgoing;
+pub mod signing;
+pub mod subscriptions;
+use actix_web::web;
+use incoming::handle_incoming_webhook;
+
+/// Where webhooks are posted to, outside `/api` since they carry their own credential
+pub fn hook_routes(cfg: &mut web::ServiceConfig) {
+    cfg.service(web::scope("/hooks").service(handle_incoming_webhook));
+}
@@ -0,0 +1,180 @@
+use super::destinations::{check_destination, outgoing_client};
+use super::signing::{sign, SIGNATURE_HEADER, TIMESTAMP_HEADER};
+use crate::custom_types::WebhookDeliveryStatus;
+use crate::server::config::Config;
+use chrono::{Duration, Utc};
+use futures::future::join_all;
+use serde_json::Value;
+use sqlx::{Pool, Postgres};
+
+pub const EVENT_HEADER: &str = "X-Webhook-Event";
+pub const DELIVERY_HEADER: &str = "X-Webhook-Delivery";
+
+// deliveries claimed at a time by one pass of the worker
+const BATCH_SIZE: i64 = 20;
+// longest wait between retries, however many times a delivery has failed
+const MAX_BACKOFF_SECS: i64 = 6 * 60 * 60;
+
+/// Wait before the next try after `attempts` failed ones, doubling from `base`
+pub fn backoff(attempts: i32, base: Duration) -> Duration {
+    let doublings = (attempts - 1).clamp(0, 20) as u32;
+    let secs = base.num_seconds().saturating_mul(1 << doublings);
+    Duration::seconds(secs.min(MAX_BACKOFF_SECS))
+}
+
+/// What sends outgoing webhook events, only ever to public addresses unless
+/// WEBHOOK_ALLOW_INTERNAL_URLS is set
+pub fn delivery_client(config: &Config) -> reqwest::Client {
+    outgoing_client(config.webhook_timeout, config.webhook_allow_internal_urls)
+}
+
+struct Attempt {
+    status_code: Option<i32>,
+    error: Option<String>,
+}
+
+async fn attempt(
+    client: &reqwest::Client,
+    config: &Config,
+    id: i64,
+    url: &str,
+    secret: &str,
+    event_type: &str,
+    payload: &Value,
+) -> Attempt {
+    // checked again, the host may have been pointed somewhere else since
+    if let Err(e) = check_destination(url, config.webhook_allow_internal_urls).await {
+        return Attempt {
+            status_code: None,
+            error: Some(e),
+        };
+    }
+    let body = payload.to_string();
+    let timestamp = Utc::now().timestamp();
+    let result = client
+        .post(url)
+        .header(reqwest::header::CONTENT_TYPE, "application/json")
+        .header(EVENT_HEADER, event_type)
+        .header(DELIVERY_HEADER, id.to_string())
+        .header(TIMESTAMP_HEADER, timestamp.to_string())
+        .header(SIGNATURE_HEADER, sign(secret, timestamp, body.as_bytes()))
+        .body(body)
+        .send()
+        .await;
+    match result {
+        Ok(resp) if resp.status().is_success() => Attempt {
+            status_code: Some(resp.status().as_u16() as i32),
+            error: None,
+        },
+        Ok(resp) => {
+            let status = resp.status();
+            let text = resp.text().await.unwrap_or_default();
+            Attempt {
+                status_code: Some(status.as_u16() as i32),
+                error: Some(text.chars().take(500).collect()),
+            }
+        }
+        Err(e) => Attempt {
+            status_code: None,
+            error: Some(e.to_string()),
+        },
+    }
+}
+
+/// Sends one batch of due deliveries, returning how many were tried. Claimed
+/// rows are pushed back for a while first, so other servers skip them & a
+/// worker dying mid batch only delays them
+pub async fn deliver_due(
+    client: &reqwest::Client,
+    config: &Config,
+    pool: &Pool<Postgres>,
+) -> Result<usize, sqlx::Error> {
+    let lease = Duration::from_std(config.webhook_timeout).unwrap_or(Duration::seconds(10))
+        + Duration::seconds(60);
+    let due = sqlx::query!(
+        r#"update webhook_deliveries d set next_attempt_at = $2
+        from webhook_subscriptions s
+        where s.id = d.subscription_id and d.id in (
+            select id from webhook_deliveries
+            where status = 'pending' and next_attempt_at <= now()
+            order by next_attempt_at limit $1 for update skip locked
+        )
+        returning d.id, d.event_type, d.payload, d.attempts, s.url, s.secret"#,
+        BATCH_SIZE,
+        Utc::now() + lease
+    )
+    .fetch_all(pool)
+    .await?;
+
+    let tried = due.len();
+    let results = join_all(due.into_iter().map(|delivery| async move {
+        let outcome = attempt(
+            client,
+            config,
+            delivery.id,
+            &delivery.url,
+            &delivery.secret,
+            &delivery.event_type,
+            &delivery.payload,
+        )
+        .await;
+        (delivery.id, delivery.attempts + 1, outcome)
+    }))
+    .await;
+
+    for (id, attempts, outcome) in results {
+        let status = match (&outcome.error, attempts >= config.webhook_max_attempts) {
+            (None, _) => WebhookDeliveryStatus::DELIVERED,
+            (Some(_), true) => WebhookDeliveryStatus::DEAD,
+            (Some(_), false) => WebhookDeliveryStatus::PENDING,
+        };
+        if status == WebhookDeliveryStatus::DEAD {
+            log::warn!("webhook delivery {id} failed {attempts} times, giving up on it");
+        }
+        sqlx::query!(
+            r#"update webhook_deliveries set status = $2, attempts = $3, last_attempt_at = now(),
+            last_status_code = $4, last_error = $5, next_attempt_at = $6,
+            delivered_at = case when $2::webhook_delivery_status = 'delivered' then now() end
+            where id = $1"#,
+            id,
+            status as WebhookDeliveryStatus,
+            attempts,
+            outcome.status_code,
+            outcome.error,
+            Utc::now() + backoff(attempts, config.webhook_retry_base)
+        )
+        .execute(pool)
+        .await?;
+    }
+    Ok(tried)
+}
+
+/// Deletes delivered & dead lettered events older than `retention` from the
+/// delivery log, pending ones are kept however old. Returns how many went
+pub async fn prune_deliveries(
+    retention: Duration,
+    pool: &Pool<Postgres>,
+) -> Result<u64, sqlx::Error> {
+    Ok(sqlx::query!(
+        r#"delete from webhook_deliveries where status in ('delivered', 'dead')
+        and coalesce(last_attempt_at, created_at) < $1"#,
+        Utc::now() - retention
+    )
+    .execute(pool)
+    .await?
+    .rows_affected())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn backoff_doubles_then_caps() {
+        let base = Duration::seconds(30);
+        assert_eq!(backoff(1, base), Duration::seconds(30));
+        assert_eq!(backoff(2, base), Duration::seconds(60));
+        assert_eq!(backoff(4, base), Duration::seconds(240));
+        assert_eq!(backoff(50, base), Duration::seconds(MAX_BACKOFF_SECS));
+    }
+}
@@ -0,0 +1,91 @@
+use hmac::{Hmac, Mac};
+use sha2::Sha256;
+
+pub const SIGNATURE_HEADER: &str = "X-Webhook-Signature";
+pub const TIMESTAMP_HEADER: &str = "X-Webhook-Timestamp";
+
+// how far a signed post's timestamp may be from now, so captured posts can't be replayed later
+const SIGNATURE_TOLERANCE_SECS: u64 = 5 * 60;
+
+fn mac(secret: &str, timestamp: i64, body: &[u8]) -> Hmac<Sha256> {
+    let mut mac =
+        Hmac::<Sha256>::new_from_slice(secret.as_bytes()).expect("hmac takes keys of any length");
+    mac.update(format!("{timestamp}.").as_bytes());
+    mac.update(body);
+    mac
+}
+
+/// The `X-Webhook-Signature` value for a body sent at `timestamp`,
+/// `sha256=<hex>` of an hmac-sha256 over `<timestamp>.<body>`
+pub fn sign(secret: &str, timestamp: i64, body: &[u8]) -> String {
+    let hex: String = mac(secret, timestamp, body)
+        .finalize()
+        .into_bytes()
+        .iter()
+        .map(|byte| format!("{byte:02x}"))
+        .collect();
+    format!("sha256={hex}")
+}
+
+fn decode_hex(hex: &str) -> Option<Vec<u8>> {
+    if !hex.len().is_multiple_of(2) {
+        return None;
+    }
+    (0..hex.len())
+        .step_by(2)
+        .map(|i| u8::from_str_radix(hex.get(i..i + 2)?, 16).ok())
+        .collect()
+}
+
+/// Checks a `sha256=<hex>` signature header in constant time, & that the
+/// timestamp it was made with is recent
+pub fn verify_signature(
+    secret: &str,
+    timestamp: i64,
+    signature: &str,
+    body: &[u8],
+    now: i64,
+) -> Result<(), &'static str> {
+    // the timestamp comes straight from a header, so it can be anything
+    if now.abs_diff(timestamp) > SIGNATURE_TOLERANCE_SECS {
+        return Err("signature timestamp is too old or in the future");
+    }
+    let expected = signature
+        .strip_prefix("sha256=")
+        .and_then(decode_hex)
+        .ok_or("signature must look like sha256=<hex>")?;
+    mac(secret, timestamp, body)
+        .verify_slice(&expected)
+        .map_err(|_| "signature does not match")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn signatures_round_trip() {
+        let body = br#"{"text":"deploy finished"}"#;
+        let signature = sign("s3cret", 1_700_000_000, body);
+        assert!(verify_signature("s3cret", 1_700_000_000, &signature, body, 1_700_000_010).is_ok());
+        // wrong secret, changed body & replayed much later are all refused
+        assert!(verify_signature("other", 1_700_000_000, &signature, body, 1_700_000_010).is_err());
+        assert!(
+            verify_signature("s3cret", 1_700_000_000, &signature, b"{}", 1_700_000_010).is_err()
+        );
+        assert!(
+            verify_signature("s3cret", 1_700_000_000, &signature, body, 1_700_009_000).is_err()
+        );
+        // timestamps far enough out to overflow are refused, not a panic
+        assert!(verify_signature("s3cret", i64::MIN, &signature, body, 1_700_000_010).is_err());
+        assert!(verify_signature("s3cret", i64::MAX, &signature, body, -1).is_err());
+    }
+
+    #[test]
+    fn signature_must_be_hex() {
+        assert!(verify_signature("s", 0, "sha256=zz", b"", 0).is_err());
+        assert!(verify_signature("s", 0, "abc", b"", 0).is_err());
+        assert_eq!(decode_hex("00ff10"), Some(vec![0, 255, 16]));
+        assert_eq!(decode_hex("0"), None);
+    }
+}
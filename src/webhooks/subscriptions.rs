@@ -0,0 +1,425 @@
+use super::destinations::check_destination;
+use super::events::WebhookEvent;
+use super::incoming::require_manage_group;
+use crate::admin::admin::require_platform_admin;
+use crate::audit::audit::audit_event;
+use crate::auth::generate_token;
+use crate::custom_types::WebhookDeliveryStatus;
+use crate::extractors::extractors::User;
+use crate::server::app_state::AppState;
+use actix_web::{delete, get, post, web, HttpRequest, HttpResponse, Responder};
+use chrono::{DateTime, Utc};
+use serde::{Deserialize, Serialize};
+use serde_json::{json, Value};
+
+// most deliveries sent back in one page of the delivery log
+const MAX_DELIVERY_PAGE: i64 = 200;
+
+/// Subscriptions are either on one group (`Some`), managed by its admins, or
+/// on every group (`None`), managed by platform admins
+type SubscriptionScope = Option<i32>;
+
+#[derive(Serialize)]
+struct Subscription {
+    id: i32,
+    group_id: Option<i32>,
+    url: String,
+    event_types: Vec<String>,
+    created_by: Option<i32>,
+    created_at: DateTime<Utc>,
+    disabled_at: Option<DateTime<Utc>>,
+    pending_count: i64,
+    dead_count: i64,
+}
+
+async fn list_subscriptions(scope: SubscriptionScope, app: &AppState) -> HttpResponse {
+    match sqlx::query_as!(
+        Subscription,
+        r#"select s.id, s.group_id, s.url, s.event_types, s.created_by, s.created_at, s.disabled_at,
+        count(d.id) filter (where d.status = 'pending') as "pending_count!",
+        count(d.id) filter (where d.status = 'dead') as "dead_count!"
+        from webhook_subscriptions s left join webhook_deliveries d on d.subscription_id = s.id
+        where $1::int is null or s.group_id = $1
+        group by s.id order by s.id"#,
+        scope
+    )
+    .fetch_all(&app.pool)
+    .await
+    {
+        Ok(subscriptions) => HttpResponse::Ok().json(subscriptions),
+        Err(e) => HttpResponse::InternalServerError().body(e.to_string()),
+    }
+}
+
+#[derive(Deserialize)]
+pub struct CreateSubscriptionRequest {
+    url: String,
+    events: Vec<WebhookEvent>,
+}
+
+/// Sent once when a subscription is made, the secret can't be seen again
+#[derive(Serialize)]
+struct CreatedSubscription {
+    id: i32,
+    secret: String,
+}
+
+async fn create_subscription(
+    scope: SubscriptionScope,
+    user_id: i32,
+    request: CreateSubscriptionRequest,
+    app: &AppState,
+    req: &HttpRequest,
+) -> HttpResponse {
+    if let Err(reason) =
+        check_destination(&request.url, app.config.webhook_allow_internal_urls).await
+    {
+        return HttpResponse::BadRequest().body(reason);
+    }
+    if request.events.is_empty() {
+        return HttpResponse::BadRequest().body("a subscription needs at least one event");
+    }
+    if scope.is_some() && request.events.contains(&WebhookEvent::GroupCreated) {
+        return HttpResponse::BadRequest()
+            .body("group.created is only sent to subscriptions on every group");
+    }
+    let event_types: Vec<String> = request
+        .events
+        .iter()
+        .map(|event| event.as_str().to_owned())
+        .collect();
+    let secret = generate_token();
+    let id = match sqlx::query_scalar!(
+        r#"insert into webhook_subscriptions (group_id, url, secret, event_types, created_by)
+        values ($1, $2, $3, $4, $5) returning id"#,
+        scope,
+        request.url,
+        secret,
+        &event_types,
+        user_id
+    )
+    .fetch_one(&app.pool)
+    .await
+    {
+        Ok(id) => id,
+        Err(e) => return HttpResponse::InternalServerError().body(e.to_string()),
+    };
+    audit_event(
+        &app.pool,
+        req,
+        Some(user_id),
+        "webhook.subscription.create",
+        scope.map(|group_id| format!("group:{group_id}")),
+        Some(json!({ "subscription_id": id, "url": request.url, "events": event_types })),
+    )
+    .await;
+    HttpResponse::Created().json(CreatedSubscription { id, secret })
+}
+
+/// Stops a subscription, whatever it still had queued is dead lettered
+async fn delete_subscription(
+    scope: SubscriptionScope,
+    subscription_id: i32,
+    user_id: i32,
+    app: &AppState,
+    req: &HttpRequest,
+) -> HttpResponse {
+    let result = async {
+        let mut tx = app.pool.begin().await?;
+        let found = sqlx::query!(
+            r#"update webhook_subscriptions set disabled_at = now()
+            where id = $1 and ($2::int is null or group_id = $2) and disabled_at is null"#,
+            subscription_id,
+            scope
+        )
+        .execute(&mut *tx)
+        .await?
+        .rows_affected()
+            > 0;
+        sqlx::query!(
+            r#"update webhook_deliveries set status = 'dead', last_error = 'subscription deleted'
+            where subscription_id = $1 and status = 'pending'"#,
+            subscription_id
+        )
+        .execute(&mut *tx)
+        .await?;
+        tx.commit().await?;
+        Ok::<_, sqlx::Error>(found)
+    }
+    .await;
+    match result {
+        Ok(true) => {
+            audit_event(
+                &app.pool,
+                req,
+                Some(user_id),
+                "webhook.subscription.delete",
+                scope.map(|group_id| format!("group:{group_id}")),
+                Some(json!({ "subscription_id": subscription_id })),
+            )
+            .await;
+            HttpResponse::Ok().body("")
+        }
+        Ok(false) => {
+            HttpResponse::NotFound().body(format!("no live subscription {subscription_id}"))
+        }
+        Err(e) => HttpResponse::InternalServerError().body(e.to_string()),
+    }
+}
+
+#[derive(Serialize)]
+struct Delivery {
+    id: i64,
+    event_type: String,
+    payload: Value,
+    status: WebhookDeliveryStatus,
+    attempts: i32,
+    next_attempt_at: DateTime<Utc>,
+    last_attempt_at: Option<DateTime<Utc>>,
+    last_status_code: Option<i32>,
+    last_error: Option<String>,
+    delivered_at: Option<DateTime<Utc>>,
+    created_at: DateTime<Utc>,
+}
+
+#[derive(Deserialize)]
+struct DeliveryQuery {
+    status: Option<WebhookDeliveryStatus>,
+    /// page backwards from this delivery id
+    before: Option<i64>,
+    limit: Option<i64>,
+}
+
+/// A subscription's delivery log, newest first
+async fn list_deliveries(
+    scope: SubscriptionScope,
+    subscription_id: i32,
+    query: DeliveryQuery,
+    app: &AppState,
+) -> HttpResponse {
+    match sqlx::query!(
+        r#"select 1 as "found" from webhook_subscriptions
+        where id = $1 and ($2::int is null or group_id = $2)"#,
+        subscription_id,
+        scope
+    )
+    .fetch_optional(&app.pool)
+    .await
+    {
+        Ok(Some(_)) => {}
+        Ok(None) => {
+            return HttpResponse::NotFound().body(format!("no subscription {subscription_id}"))
+        }
+        Err(e) => return HttpResponse::InternalServerError().body(e.to_string()),
+    }
+    match sqlx::query_as!(
+        Delivery,
+        r#"select id, event_type, payload, status as "status: WebhookDeliveryStatus", attempts,
+        next_attempt_at, last_attempt_at, last_status_code, last_error, delivered_at, created_at
+        from webhook_deliveries
+        where subscription_id = $1 and ($2::webhook_delivery_status is null or status = $2)
+        and ($3::bigint is null or id < $3)
+        order by id desc limit $4"#,
+        subscription_id,
+        query.status as Option<WebhookDeliveryStatus>,
+        query.before,
+        query.limit.unwrap_or(50).clamp(1, MAX_DELIVERY_PAGE)
+    )
+    .fetch_all(&app.pool)
+    .await
+    {
+        Ok(deliveries) => HttpResponse::Ok().json(deliveries),
+        Err(e) => HttpResponse::InternalServerError().body(e.to_string()),
+    }
+}
+
+/// Puts a dead lettered delivery back in the outbox with a fresh set of attempts
+async fn retry_delivery(
+    scope: SubscriptionScope,
+    subscription_id: i32,
+    delivery_id: i64,
+    user_id: i32,
+    app: &AppState,
+    req: &HttpRequest,
+) -> HttpResponse {
+    match sqlx::query!(
+        r#"update webhook_deliveries d set status = 'pending', attempts = 0, next_attempt_at = now()
+        from webhook_subscriptions s
+        where d.id = $1 and d.subscription_id = $2 and s.id = d.subscription_id
+        and ($3::int is null or s.group_id = $3) and s.disabled_at is null and d.status = 'dead'"#,
+        delivery_id,
+        subscription_id,
+        scope
+    )
+    .execute(&app.pool)
+    .await
+    {
+        Ok(res) if res.rows_affected() > 0 => {
+            audit_event(
+                &app.pool,
+                req,
+                Some(user_id),
+                "webhook.delivery.retry",
+                scope.map(|group_id| format!("group:{group_id}")),
+                Some(json!({ "subscription_id": subscription_id, "delivery_id": delivery_id })),
+            )
+            .await;
+            HttpResponse::Ok().body("")
+        }
+        Ok(_) => HttpResponse::NotFound().body(format!(
+            "no dead delivery {delivery_id} on a live subscription {subscription_id}"
+        )),
+        Err(e) => HttpResponse::InternalServerError().body(e.to_string()),
+    }
+}
+
+#[get("{group_id}/subscriptions")]
+pub async fn handle_list_group_subscriptions(
+    group_id: web::Path<i32>,
+    user: User,
+    app: web::Data<AppState>,
+) -> impl Responder {
+    let group_id = group_id.into_inner();
+    if let Some(resp) = require_manage_group(user.user_id, group_id, &app).await {
+        return resp;
+    }
+    list_subscriptions(Some(group_id), &app).await
+}
+
+#[post("{group_id}/subscriptions")]
+pub async fn handle_create_group_subscription(
+    group_id: web::Path<i32>,
+    request: web::Json<CreateSubscriptionRequest>,
+    user: User,
+    app: web::Data<AppState>,
+    req: HttpRequest,
+) -> impl Responder {
+    let group_id = group_id.into_inner();
+    if let Some(resp) = require_manage_group(user.user_id, group_id, &app).await {
+        return resp;
+    }
+    create_subscription(
+        Some(group_id),
+        user.user_id,
+        request.into_inner(),
+        &app,
+        &req,
+    )
+    .await
+}
+
+#[delete("{group_id}/subscriptions/{subscription_id}")]
+pub async fn handle_delete_group_subscription(
+    path: web::Path<(i32, i32)>,
+    user: User,
+    app: web::Data<AppState>,
+    req: HttpRequest,
+) -> impl Responder {
+    let (group_id, subscription_id) = path.into_inner();
+    if let Some(resp) = require_manage_group(user.user_id, group_id, &app).await {
+        return resp;
+    }
+    delete_subscription(Some(group_id), subscription_id, user.user_id, &app, &req).await
+}
+
+#[get("{group_id}/subscriptions/{subscription_id}/deliveries")]
+pub async fn handle_list_group_deliveries(
+    path: web::Path<(i32, i32)>,
+    query: web::Query<DeliveryQuery>,
+    user: User,
+    app: web::Data<AppState>,
+) -> impl Responder {
+    let (group_id, subscription_id) = path.into_inner();
+    if let Some(resp) = require_manage_group(user.user_id, group_id, &app).await {
+        return resp;
+    }
+    list_deliveries(Some(group_id), subscription_id, query.into_inner(), &app).await
+}
+
+#[post("{group_id}/subscriptions/{subscription_id}/deliveries/{delivery_id}/retry")]
+pub async fn handle_retry_group_delivery(
+    path: web::Path<(i32, i32, i64)>,
+    user: User,
+    app: web::Data<AppState>,
+    req: HttpRequest,
+) -> impl Responder {
+    let (group_id, subscription_id, delivery_id) = path.into_inner();
+    if let Some(resp) = require_manage_group(user.user_id, group_id, &app).await {
+        return resp;
+    }
+    retry_delivery(
+        Some(group_id),
+        subscription_id,
+        delivery_id,
+        user.user_id,
+        &app,
+        &req,
+    )
+    .await
+}
+
+/// Every subscription, on single groups as well as on every group
+#[get("/subscriptions")]
+pub async fn handle_admin_list_subscriptions(
+    user: User,
+    app: web::Data<AppState>,
+) -> impl Responder {
+    if let Err(resp) = require_platform_admin(user.user_id, &app.pool).await {
+        return resp;
+    }
+    list_subscriptions(None, &app).await
+}
+
+/// Makes a subscription on every group
+#[post("/subscriptions")]
+pub async fn handle_admin_create_subscription(
+    request: web::Json<CreateSubscriptionRequest>,
+    user: User,
+    app: web::Data<AppState>,
+    req: HttpRequest,
+) -> impl Responder {
+    if let Err(resp) = require_platform_admin(user.user_id, &app.pool).await {
+        return resp;
+    }
+    create_subscription(None, user.user_id, request.into_inner(), &app, &req).await
+}
+
+#[delete("/subscriptions/{subscription_id}")]
+pub async fn handle_admin_delete_subscription(
+    subscription_id: web::Path<i32>,
+    user: User,
+    app: web::Data<AppState>,
+    req: HttpRequest,
+) -> impl Responder {
+    if let Err(resp) = require_platform_admin(user.user_id, &app.pool).await {
+        return resp;
+    }
+    delete_subscription(None, subscription_id.into_inner(), user.user_id, &app, &req).await
+}
+
+#[get("/subscriptions/{subscription_id}/deliveries")]
+pub async fn handle_admin_list_deliveries(
+    subscription_id: web::Path<i32>,
+    query: web::Query<DeliveryQuery>,
+    user: User,
+    app: web::Data<AppState>,
+) -> impl Responder {
+    if let Err(resp) = require_platform_admin(user.user_id, &app.pool).await {
+        return resp;
+    }
+    list_deliveries(None, subscription_id.into_inner(), query.into_inner(), &app).await
+}
+
+#[post("/subscriptions/{subscription_id}/deliveries/{delivery_id}/retry")]
+pub async fn handle_admin_retry_delivery(
+    path: web::Path<(i32, i64)>,
+    user: User,
+    app: web::Data<AppState>,
+    req: HttpRequest,
+) -> impl Responder {
+    let (subscription_id, delivery_id) = path.into_inner();
+    if let Err(resp) = require_platform_admin(user.user_id, &app.pool).await {
+        return resp;
+    }
+    retry_delivery(None, subscription_id, delivery_id, user.user_id, &app, &req).await
+}